@@ -0,0 +1,70 @@
+use ::image::RgbImage;
+use glam::UVec2;
+
+use pixelart_gen::{color::Color, metric::Metric, solver::SuperPixel};
+
+/// Outcome of the `--require-*` quality gates, printed as JSON at the end of the run.
+#[derive(Debug, serde::Serialize)]
+pub struct AcceptanceReport {
+    pub passed: bool,
+    pub mean_delta_e: f64,
+    pub colors: usize,
+    // Every gate the pattern failed, empty when it passed
+    pub failures: Vec<String>,
+}
+
+impl AcceptanceReport {
+    pub fn new(
+        mean_delta_e: f64,
+        colors: usize,
+        max_delta_e: Option<f64>,
+        min_colors: Option<u32>,
+    ) -> Self {
+        let mut failures = vec![];
+        if let Some(max_delta_e) = max_delta_e.filter(|max| mean_delta_e >= *max) {
+            failures.push(format!(
+                "mean ΔE {mean_delta_e:.2} is not below {max_delta_e}"
+            ));
+        }
+        if let Some(min_colors) = min_colors.filter(|min| colors < *min as usize) {
+            failures.push(format!("{colors} colors is fewer than {min_colors}"));
+        }
+
+        AcceptanceReport {
+            passed: failures.is_empty(),
+            mean_delta_e,
+            colors,
+            failures,
+        }
+    }
+}
+
+/// Mean difference with `metric` between the stitches of `output` and the input colors of the
+/// super pixels they are made from, unstitched cells left out.
+pub fn mean_delta_e(
+    super_pixels: &[SuperPixel],
+    sp_size: UVec2,
+    factor: u32,
+    output: &RgbImage,
+    unstitched: &[bool],
+    metric: Metric,
+) -> f64 {
+    let (sum, count) = output
+        .enumerate_pixels()
+        .zip(unstitched)
+        .filter(|(_, unstitched)| !**unstitched)
+        .map(|((x, y, pixel), _)| {
+            let mut input = Color::BLACK;
+            for sp_y in (y * factor)..((y + 1) * factor) {
+                for sp_x in (x * factor)..((x + 1) * factor) {
+                    input += super_pixels[(sp_x + sp_y * sp_size.x) as usize].sp_color();
+                }
+            }
+            input /= (factor * factor) as f64;
+
+            input.difference(Color::from_srgb(pixel.0), metric)
+        })
+        .fold((0.0, 0), |(sum, count), delta_e| (sum + delta_e, count + 1));
+
+    sum / count.max(1) as f64
+}
@@ -0,0 +1,239 @@
+use std::io::{self, Write};
+
+use glam::UVec2;
+
+const HEADER_MAGIC: u16 = 0xa5e0;
+const FRAME_MAGIC: u16 = 0xf1fa;
+const LAYER_CHUNK: u16 = 0x2004;
+const CEL_CHUNK: u16 = 0x2005;
+const PALETTE_CHUNK: u16 = 0x2019;
+// Bits per pixel of indexed sprites
+const INDEXED_DEPTH: u16 = 8;
+
+/// Writes an indexed Aseprite sprite of `size` with a single layer and one cel per frame. Every
+/// frame is a row major list of indices into `palette` along with its duration in milliseconds.
+/// The layer is transparent where the pixels use `transparent` and opaque throughout without it.
+pub fn write_aseprite(
+    out: &mut impl Write,
+    size: UVec2,
+    palette: &[[u8; 3]],
+    transparent: Option<u8>,
+    frames: &[(&[u8], u16)],
+) -> io::Result<()> {
+    assert!(!palette.is_empty() && palette.len() <= 256);
+
+    let mut body = vec![];
+    for (idx, (pixels, duration)) in frames.iter().enumerate() {
+        let mut chunks = vec![];
+        // The palette and the layer are set up once in the first frame
+        if idx == 0 {
+            chunks.push(chunk(PALETTE_CHUNK, palette_chunk(palette)));
+            chunks.push(chunk(LAYER_CHUNK, layer_chunk(transparent.is_none())));
+        }
+        chunks.push(chunk(CEL_CHUNK, cel_chunk(size, pixels)));
+
+        let frame_size = 16 + chunks.iter().map(Vec::len).sum::<usize>();
+        put_u32(&mut body, frame_size as u32);
+        put_u16(&mut body, FRAME_MAGIC);
+        put_u16(&mut body, chunks.len() as u16);
+        put_u16(&mut body, *duration);
+        body.extend_from_slice(&[0; 2]);
+        put_u32(&mut body, chunks.len() as u32);
+        body.extend(chunks.concat());
+    }
+
+    let mut header = Vec::with_capacity(128);
+    put_u32(&mut header, 128 + body.len() as u32);
+    put_u16(&mut header, HEADER_MAGIC);
+    put_u16(&mut header, frames.len() as u16);
+    put_u16(&mut header, size.x as u16);
+    put_u16(&mut header, size.y as u16);
+    put_u16(&mut header, INDEXED_DEPTH);
+    // The opacity of the layer is valid
+    put_u32(&mut header, 1);
+    // Deprecated frame duration, the frames have their own
+    put_u16(
+        &mut header,
+        frames.first().map_or(100, |(_, duration)| *duration),
+    );
+    header.extend_from_slice(&[0; 8]);
+    header.push(transparent.unwrap_or(0));
+    header.extend_from_slice(&[0; 3]);
+    // 0 stands for 256
+    put_u16(&mut header, palette.len() as u16 % 256);
+    // Square pixels
+    header.extend_from_slice(&[1, 1]);
+    // No grid
+    header.extend_from_slice(&[0; 8]);
+    header.resize(128, 0);
+
+    out.write_all(&header)?;
+    out.write_all(&body)
+}
+
+fn palette_chunk(palette: &[[u8; 3]]) -> Vec<u8> {
+    let mut data = vec![];
+    put_u32(&mut data, palette.len() as u32);
+    put_u32(&mut data, 0);
+    put_u32(&mut data, palette.len() as u32 - 1);
+    data.extend_from_slice(&[0; 8]);
+    for [r, g, b] in palette {
+        // No name
+        put_u16(&mut data, 0);
+        data.extend_from_slice(&[*r, *g, *b, 255]);
+    }
+
+    data
+}
+
+fn layer_chunk(background: bool) -> Vec<u8> {
+    let mut data = vec![];
+    // Visible and editable, and the background layer when nothing is transparent
+    put_u16(&mut data, if background { 1 | 2 | 8 } else { 1 | 2 });
+    // Normal layer at the top level
+    put_u16(&mut data, 0);
+    put_u16(&mut data, 0);
+    // Ignored default size
+    put_u16(&mut data, 0);
+    put_u16(&mut data, 0);
+    // Normal blending at full opacity
+    put_u16(&mut data, 0);
+    data.push(255);
+    data.extend_from_slice(&[0; 3]);
+    put_string(&mut data, "Pattern");
+
+    data
+}
+
+fn cel_chunk(size: UVec2, pixels: &[u8]) -> Vec<u8> {
+    let mut data = vec![];
+    // First layer at the top left
+    put_u16(&mut data, 0);
+    put_u16(&mut data, 0);
+    put_u16(&mut data, 0);
+    data.push(255);
+    // Uncompressed image
+    put_u16(&mut data, 0);
+    data.extend_from_slice(&[0; 7]);
+    put_u16(&mut data, size.x as u16);
+    put_u16(&mut data, size.y as u16);
+    data.extend_from_slice(pixels);
+
+    data
+}
+
+// Prefixes `data` with its size and type
+fn chunk(kind: u16, data: Vec<u8>) -> Vec<u8> {
+    let mut chunk = Vec::with_capacity(6 + data.len());
+    put_u32(&mut chunk, 6 + data.len() as u32);
+    put_u16(&mut chunk, kind);
+    chunk.extend(data);
+
+    chunk
+}
+
+fn put_u16(data: &mut Vec<u8>, value: u16) {
+    data.extend_from_slice(&value.to_le_bytes());
+}
+
+fn put_u32(data: &mut Vec<u8>, value: u32) {
+    data.extend_from_slice(&value.to_le_bytes());
+}
+
+fn put_string(data: &mut Vec<u8>, value: &str) {
+    put_u16(data, value.len() as u16);
+    data.extend_from_slice(value.as_bytes());
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn u16_at(data: &[u8], at: usize) -> u16 {
+        u16::from_le_bytes([data[at], data[at + 1]])
+    }
+
+    fn u32_at(data: &[u8], at: usize) -> u32 {
+        u32::from_le_bytes(data[at..at + 4].try_into().unwrap())
+    }
+
+    #[test]
+    fn writes_one_cel_per_frame() {
+        let palette = [[0, 0, 0], [255, 255, 255], [255, 0, 0]];
+        let first = [0, 1, 2, 1, 0, 2];
+        let second = [2, 2, 2, 0, 0, 0];
+        let mut sprite = vec![];
+        write_aseprite(
+            &mut sprite,
+            UVec2::new(3, 2),
+            &palette,
+            Some(0),
+            &[(&first, 100), (&second, 250)],
+        )
+        .unwrap();
+
+        assert_eq!(u32_at(&sprite, 0) as usize, sprite.len());
+        assert_eq!(u16_at(&sprite, 4), HEADER_MAGIC);
+        assert_eq!(u16_at(&sprite, 6), 2);
+        assert_eq!((u16_at(&sprite, 8), u16_at(&sprite, 10)), (3, 2));
+        assert_eq!(u16_at(&sprite, 12), INDEXED_DEPTH);
+        assert_eq!(sprite[28], 0);
+        assert_eq!(u16_at(&sprite, 32), 3);
+
+        // Walks the frames and their chunks, keeping the chunk kinds and the pixels of the cels
+        let mut at = 128;
+        let mut frames = vec![];
+        while at < sprite.len() {
+            let frame_end = at + u32_at(&sprite, at) as usize;
+            assert_eq!(u16_at(&sprite, at + 4), FRAME_MAGIC);
+            let chunk_count = u32_at(&sprite, at + 12) as usize;
+            assert_eq!(u16_at(&sprite, at + 6) as usize, chunk_count);
+            let duration = u16_at(&sprite, at + 8);
+
+            let mut chunk_at = at + 16;
+            let mut kinds = vec![];
+            let mut pixels = vec![];
+            for _ in 0..chunk_count {
+                let chunk_end = chunk_at + u32_at(&sprite, chunk_at) as usize;
+                let kind = u16_at(&sprite, chunk_at + 4);
+                if kind == CEL_CHUNK {
+                    pixels = sprite[chunk_end - first.len()..chunk_end].to_vec();
+                }
+                kinds.push(kind);
+                chunk_at = chunk_end;
+            }
+            assert_eq!(chunk_at, frame_end);
+
+            frames.push((kinds, duration, pixels));
+            at = frame_end;
+        }
+
+        assert_eq!(
+            frames,
+            [
+                (
+                    vec![PALETTE_CHUNK, LAYER_CHUNK, CEL_CHUNK],
+                    100,
+                    first.to_vec()
+                ),
+                (vec![CEL_CHUNK], 250, second.to_vec()),
+            ]
+        );
+    }
+
+    #[test]
+    fn counts_a_full_palette_as_zero() {
+        let palette = (0..=255).map(|c| [c, c, c]).collect::<Vec<_>>();
+        let mut sprite = vec![];
+        write_aseprite(
+            &mut sprite,
+            UVec2::new(1, 1),
+            &palette,
+            None,
+            &[(&[7], 100)],
+        )
+        .unwrap();
+
+        assert_eq!(u16_at(&sprite, 32), 0);
+    }
+}
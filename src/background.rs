@@ -0,0 +1,47 @@
+use ::image::{GrayImage, Luma};
+use glam::UVec2;
+
+use crate::{color::Color, image::LabImage};
+
+// Mask values below this are background
+const BACKGROUND_LEVEL: u8 = 128;
+
+/// Whether a subject mask marks `coord` as background.
+pub fn is_background(mask: &GrayImage, coord: UVec2) -> bool {
+    mask.get_pixel(coord.x, coord.y).0[0] < BACKGROUND_LEVEL
+}
+
+/// Masks out the background by flood filling from the border of the image through the pixels
+/// within `tolerance` of the average border color. The subject is white, the background black.
+pub fn detect_background(img: &LabImage, tolerance: f64) -> GrayImage {
+    let size = img.size;
+    let border = (0..size.x)
+        .flat_map(|x| [UVec2::new(x, 0), UVec2::new(x, size.y - 1)])
+        .chain((0..size.y).flat_map(|y| [UVec2::new(0, y), UVec2::new(size.x - 1, y)]))
+        .collect::<Vec<_>>();
+    let border_color = border.iter().map(|coord| img[*coord]).sum::<Color>() / border.len() as f64;
+
+    let mut mask = GrayImage::from_pixel(size.x, size.y, Luma([255]));
+    let mut stack = border;
+    while let Some(coord) = stack.pop() {
+        if is_background(&mask, coord) || img[coord].distance(border_color) > tolerance {
+            continue;
+        }
+        mask.put_pixel(coord.x, coord.y, Luma([0]));
+
+        if coord.x > 0 {
+            stack.push(coord - UVec2::X);
+        }
+        if coord.y > 0 {
+            stack.push(coord - UVec2::Y);
+        }
+        if coord.x + 1 < size.x {
+            stack.push(coord + UVec2::X);
+        }
+        if coord.y + 1 < size.y {
+            stack.push(coord + UVec2::Y);
+        }
+    }
+
+    mask
+}
@@ -0,0 +1,335 @@
+use std::{fs, path::Path};
+
+use anyhow::{bail, Context};
+use glam::{DVec2, IVec2, UVec2};
+use image::{imageops::FilterType, Rgb, RgbaImage};
+
+use crate::pattern_file::{parse_hex, xml_tags};
+
+// Neighbors an outline pixel is joined to, with the sides a diagonal goes past. A diagonal is
+// left out when a straight line of the same color already turns the corner through one of them
+const OUTLINE_STEPS: [(IVec2, &[IVec2]); 4] = [
+    (IVec2::X, &[]),
+    (IVec2::Y, &[]),
+    (IVec2::ONE, &[IVec2::X, IVec2::Y]),
+    (IVec2::new(-1, 1), &[IVec2::NEG_X, IVec2::Y]),
+];
+
+/// A backstitch between two holes of the fabric, the corners of the stitches, counted in
+/// stitches from the top left corner of the design.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Backstitch {
+    pub from: UVec2,
+    pub to: UVec2,
+    pub color: Rgb<u8>,
+}
+
+impl Backstitch {
+    /// Length of the line, in stitches.
+    pub fn length(&self) -> f64 {
+        self.from.as_dvec2().distance(self.to.as_dvec2())
+    }
+}
+
+/// Loads the backstitches of a design `size` stitches large from an SVG drawing, or from an
+/// image of the outlines with a pixel for every corner of the stitches.
+pub fn load_backstitches(path: &Path, size: UVec2) -> anyhow::Result<Vec<Backstitch>> {
+    let extension = path
+        .extension()
+        .and_then(|ext| ext.to_str())
+        .map(|ext| ext.to_ascii_lowercase());
+
+    let backstitches = match extension.as_deref() {
+        Some("svg") => parse_svg(&fs::read_to_string(path)?, size),
+        _ => image::open(path)
+            .map(|img| outline_backstitches(&img.to_rgba8(), size))
+            .map_err(anyhow::Error::from),
+    }
+    .with_context(|| format!("failed to load backstitches {}", path.display()))?;
+
+    if backstitches.is_empty() {
+        bail!("{} has no backstitches", path.display());
+    }
+
+    Ok(backstitches)
+}
+
+// The image is resized to one pixel per corner of the stitches, a pixel more than the design
+// each way. Neighboring pixels of the same color are joined, transparent and white ones are
+// left out
+fn outline_backstitches(img: &RgbaImage, size: UVec2) -> Vec<Backstitch> {
+    let corners = size + 1;
+    let img = image::imageops::resize(img, corners.x, corners.y, FilterType::Nearest);
+    let color = |corner: IVec2| {
+        if corner.cmplt(IVec2::ZERO).any() || corner.cmpge(corners.as_ivec2()).any() {
+            return None;
+        }
+
+        let [r, g, b, a] = img.get_pixel(corner.x as u32, corner.y as u32).0;
+        (a >= 128 && [r, g, b] != [255, 255, 255]).then_some(Rgb([r, g, b]))
+    };
+
+    let mut backstitches = vec![];
+    for y in 0..corners.y as i32 {
+        for x in 0..corners.x as i32 {
+            let from = IVec2::new(x, y);
+            let Some(line_color) = color(from) else {
+                continue;
+            };
+
+            for (step, sides) in OUTLINE_STEPS {
+                if color(from + step) == Some(line_color)
+                    && sides
+                        .iter()
+                        .all(|side| color(from + *side) != Some(line_color))
+                {
+                    backstitches.push(Backstitch {
+                        from: from.as_uvec2(),
+                        to: (from + step).as_uvec2(),
+                        color: line_color,
+                    });
+                }
+            }
+        }
+    }
+
+    backstitches
+}
+
+// Lines, polylines, polygons and paths of straight segments in the color of their stroke, or of
+// the stroke of their group, black without one. The viewBox is stretched over the design and
+// every point moves to the nearest corner of a stitch. Transforms aren't applied
+fn parse_svg(svg: &str, size: UVec2) -> anyhow::Result<Vec<Backstitch>> {
+    let mut view = None;
+    let mut strokes: Vec<Option<String>> = vec![];
+    let mut backstitches = vec![];
+
+    for (name, attributes) in xml_tags(svg) {
+        let number = |key: &str| -> anyhow::Result<f64> {
+            let value = attributes
+                .get(key)
+                .with_context(|| format!("<{name}> has no {key} attribute"))?;
+            value
+                .trim()
+                .trim_end_matches("px")
+                .parse()
+                .with_context(|| format!("invalid {key} {value:?}"))
+        };
+        let stroke = attributes
+            .get("style")
+            .and_then(|style| {
+                style.split(';').find_map(|property| {
+                    let (key, value) = property.split_once(':')?;
+                    (key.trim() == "stroke").then(|| value.trim().to_string())
+                })
+            })
+            .or_else(|| {
+                attributes
+                    .get("stroke")
+                    .map(|stroke| stroke.trim().to_string())
+            })
+            .or_else(|| strokes.last().cloned().flatten());
+
+        let points = match name {
+            "svg" => {
+                let (min, extent) = match attributes.get("viewBox") {
+                    Some(view_box) => {
+                        let values = numbers(view_box)?;
+                        let [x, y, width, height] = values[..] else {
+                            bail!("invalid viewBox {view_box:?}");
+                        };
+                        (DVec2::new(x, y), DVec2::new(width, height))
+                    }
+                    None => (DVec2::ZERO, DVec2::new(number("width")?, number("height")?)),
+                };
+                view = Some((min, extent));
+                continue;
+            }
+            "g" => {
+                strokes.push(stroke);
+                continue;
+            }
+            "/g" => {
+                strokes.pop();
+                continue;
+            }
+            "line" | "polyline" | "polygon" | "path" if stroke.as_deref() == Some("none") => {
+                continue
+            }
+            "line" => vec![vec![
+                DVec2::new(number("x1")?, number("y1")?),
+                DVec2::new(number("x2")?, number("y2")?),
+            ]],
+            "polyline" | "polygon" => {
+                let values = numbers(attributes.get("points").map_or("", String::as_str))?;
+                let mut points = values
+                    .chunks_exact(2)
+                    .map(|point| DVec2::new(point[0], point[1]))
+                    .collect::<Vec<_>>();
+                if name == "polygon" {
+                    points.extend(points.first().copied());
+                }
+                vec![points]
+            }
+            "path" => path_lines(attributes.get("d").map_or("", String::as_str))?,
+            _ => continue,
+        };
+
+        let color = match stroke {
+            Some(stroke) => svg_color(&stroke)?,
+            None => Rgb([0, 0, 0]),
+        };
+        let (min, extent) = view.context("the lines come before the <svg> tag")?;
+        let corner = |point: DVec2| {
+            ((point - min) / extent * size.as_dvec2())
+                .round()
+                .clamp(DVec2::ZERO, size.as_dvec2())
+                .as_uvec2()
+        };
+
+        for line in points {
+            for pair in line.windows(2) {
+                let (from, to) = (corner(pair[0]), corner(pair[1]));
+                if from != to {
+                    backstitches.push(Backstitch { from, to, color });
+                }
+            }
+        }
+    }
+
+    Ok(backstitches)
+}
+
+// Points of the subpaths of straight segments in `d`, curves aren't supported
+fn path_lines(d: &str) -> anyhow::Result<Vec<Vec<DVec2>>> {
+    let mut lines: Vec<Vec<DVec2>> = vec![];
+    let mut pen = DVec2::ZERO;
+    let mut command = None;
+    let mut values = vec![];
+
+    // Every command is run once its arguments are in, repeated for as many as follow it
+    for token in path_tokens(d)? {
+        match token {
+            PathToken::Command(letter) => {
+                command = Some(letter);
+                if letter.eq_ignore_ascii_case(&'z') {
+                    if let Some(line) = lines.last_mut() {
+                        if let Some(start) = line.first().copied() {
+                            line.push(start);
+                            pen = start;
+                        }
+                    }
+                }
+                continue;
+            }
+            PathToken::Number(value) => values.push(value),
+        }
+
+        let Some(letter) = command else {
+            bail!("path {d:?} doesn't start with a command");
+        };
+        let needed = match letter.to_ascii_lowercase() {
+            'm' | 'l' => 2,
+            'h' | 'v' => 1,
+            'z' => bail!("path {d:?} has numbers after a closepath"),
+            _ => bail!("path {d:?} has curves, only straight lines can be backstitched"),
+        };
+        if values.len() < needed {
+            continue;
+        }
+
+        let relative = letter.is_ascii_lowercase();
+        let offset = if relative { pen } else { DVec2::ZERO };
+        pen = match letter.to_ascii_lowercase() {
+            'h' => DVec2::new(values[0] + if relative { pen.x } else { 0.0 }, pen.y),
+            'v' => DVec2::new(pen.x, values[0] + if relative { pen.y } else { 0.0 }),
+            _ => DVec2::new(values[0], values[1]) + offset,
+        };
+        values.clear();
+
+        if letter.eq_ignore_ascii_case(&'m') {
+            lines.push(vec![pen]);
+            // Coordinates after the first pair of a moveto are lines
+            command = Some(if relative { 'l' } else { 'L' });
+        } else {
+            match lines.last_mut() {
+                Some(line) => line.push(pen),
+                None => bail!("path {d:?} doesn't start with a moveto"),
+            }
+        }
+    }
+
+    Ok(lines)
+}
+
+enum PathToken {
+    Command(char),
+    Number(f64),
+}
+
+fn path_tokens(d: &str) -> anyhow::Result<Vec<PathToken>> {
+    let mut tokens = vec![];
+    let mut chars = d.char_indices().peekable();
+
+    while let Some((start, c)) = chars.next() {
+        if c.is_whitespace() || c == ',' {
+            continue;
+        }
+        if c.is_ascii_alphabetic() {
+            tokens.push(PathToken::Command(c));
+            continue;
+        }
+
+        // A number runs until a character that can't continue it, a sign only continues an
+        // exponent
+        let mut end = start + c.len_utf8();
+        let mut previous = c;
+        while let Some(&(idx, next)) = chars.peek() {
+            let continues = next.is_ascii_digit()
+                || next == '.'
+                || next == 'e'
+                || next == 'E'
+                || ((next == '-' || next == '+') && (previous == 'e' || previous == 'E'));
+            if !continues {
+                break;
+            }
+            end = idx + next.len_utf8();
+            previous = next;
+            chars.next();
+        }
+
+        let number = &d[start..end];
+        tokens.push(PathToken::Number(
+            number
+                .parse()
+                .with_context(|| format!("invalid number {number:?} in path"))?,
+        ));
+    }
+
+    Ok(tokens)
+}
+
+// Numbers of a list separated by whitespace or commas
+fn numbers(list: &str) -> anyhow::Result<Vec<f64>> {
+    list.split(|c: char| c.is_whitespace() || c == ',')
+        .filter(|value| !value.is_empty())
+        .map(|value| {
+            value
+                .parse()
+                .with_context(|| format!("invalid number {value:?}"))
+        })
+        .collect()
+}
+
+// Colors as `#rrggbb` or `#rgb`, or black
+fn svg_color(color: &str) -> anyhow::Result<Rgb<u8>> {
+    let digits = color.trim_start_matches('#');
+    match digits.len() {
+        _ if color == "black" => Ok(Rgb([0, 0, 0])),
+        3 if color.starts_with('#') => {
+            let long = digits.chars().flat_map(|c| [c, c]).collect::<String>();
+            parse_hex(&long).map(Rgb)
+        }
+        _ => parse_hex(color).map(Rgb),
+    }
+}
@@ -1,19 +1,46 @@
 use std::{
-    collections::{hash_map::RandomState, HashMap},
+    borrow::Cow,
+    cmp::Reverse,
+    collections::{hash_map::RandomState, BTreeMap, BTreeSet, HashMap, HashSet},
+    f64::consts::{SQRT_2, TAU},
     fs,
     io::BufWriter,
     ops::Range,
-    path::PathBuf,
+    path::{Path, PathBuf},
 };
 
+use anyhow::Context;
 use clap::Parser;
 use glam::{DVec2, UVec2};
-use image::{DynamicImage, GenericImageView, Rgb, RgbImage};
-use palette::{chromatic_adaptation::AdaptFrom, color_difference::EuclideanDistance};
+use image::{DynamicImage, GenericImageView, Rgb, RgbImage, Rgba, RgbaImage};
+use lcms2::{Flags, Intent, PixelFormat, Profile, Transform};
+use palette::chromatic_adaptation::AdaptFrom;
+use pixelart_gen::{
+    backstitch::{load_backstitches, Backstitch},
+    chart::{
+        draw_assembly_diagram, draw_block_boxes, draw_chart, draw_glue_edges, draw_page_map,
+        draw_registration_marks, legend_slots, load_bookmarks, paginate, spiral_order,
+        true_size_stitches, Bookmark, ChartArea, ChartBackend, ChartOverlays, LegendSlot,
+        Placement, RowNumbers, Stroke, GRID, MAX_SCALE, MMPI, PAGE_STITCHES,
+    },
+    cvd::{confusable, Deficiency},
+    image::parse_aspect,
+    metric::Metric,
+    palette_file::{
+        anchor_palette, delica_palette, dmc_names, dmc_to_anchor, load_palette, PaletteColor,
+    },
+    palette_index::PaletteIndex,
+    pattern_file::{load_pattern, parse_hex, Pattern, PatternColor},
+    pattern_size::{check_pattern_size, parse_size, DEFAULT_FABRIC_COUNT},
+    specialty::{load_specialty_stitches, SpecialtyKind, SpecialtyStitch},
+};
 use printpdf::{
-    ImageTransform, IndirectFontRef, Line, Mm, PdfDocument, PdfDocumentReference,
-    PdfLayerReference, Point,
+    lopdf::{self, dictionary},
+    Actions, CurTransMat, Destination, ImageTransform, IndirectFontRef, Line, LinkAnnotation, Mm,
+    PdfDocument, PdfDocumentReference, PdfLayerReference, PdfPageIndex, PdfPageReference, Point,
+    Pt, Rect, TextRenderingMode,
 };
+use sha2::{Digest, Sha256};
 
 const SYMBOLS: [char; 200] = [
     'A', 'B', 'C', 'D', 'E', 'F', 'G', 'H', 'I', 'J', 'K', 'L', 'M', 'N', 'O', 'P', 'Q', 'R', 'S',
@@ -29,29 +56,152 @@ const SYMBOLS: [char; 200] = [
     '⧮', '⧲', '⨀', '⨁', '⨇', '⨊', '⨎', '⨳', '⨷', '⨿',
 ];
 
-const REGULAR: &[u8] = include_bytes!("/usr/share/fonts/noto/NotoSans-Regular.ttf");
-const BOLD: &[u8] = include_bytes!("/usr/share/fonts/noto/NotoSans-Bold.ttf");
-const ITALIC: &[u8] = include_bytes!("/usr/share/fonts/noto/NotoSans-Italic.ttf");
-const FONT_SYMBOLS: &[u8] = include_bytes!("/usr/share/fonts/noto/NotoSansSymbols-Regular.ttf");
-const FONT_SYMBOLS_2: &[u8] = include_bytes!("/usr/share/fonts/noto/NotoSansSymbols2-Regular.ttf");
+// Symbols of --large-print charts, the ones told apart most easily when they are hard to see.
+// Letters and digits of similar shapes, like O, Q and 0 or I and 1, and the lowercase letters are
+// left out
+const DISTINCT_SYMBOLS: [char; 37] = [
+    'A', 'C', 'E', 'F', 'H', 'K', 'L', 'M', 'N', 'P', 'R', 'T', 'U', 'V', 'W', 'X', 'Y', '3', '4',
+    '7', '+', '=', '#', '%', '&', '@', '~', '✓', '✖', '❤', '◆', '◇', '◉', '✿', '❄', '☂', '⌘',
+];
+
+// Symbols further down the line a color gets passed over for to tell it apart from the colors
+// colorblind stitchers could take it for
+const CVD_SYMBOL_CHOICES: usize = 12;
+
+// Gray of the stitches in the other colors on the --highlight-pages
+const HIGHLIGHT_GRAY: u8 = 225;
+
+// Dots per stitch of --simulate-cvd
+const CVD_PREVIEW_SCALE: u32 = 4;
+
+// How much larger everything is drawn on --large-print charts, fewer stitches fit on the pages
+const LARGE_PRINT_SCALE: f64 = 1.4;
+
+// Line width of the check mark of a ticked check box field, relative to the box
+const CHECK_MARK_STROKE: f64 = 0.12;
+
+// Regular, bold and italic text, then the two fonts of the symbols
+const FONT_FILES: [&str; 5] = [
+    "NotoSans-Regular.ttf",
+    "NotoSans-Bold.ttf",
+    "NotoSans-Italic.ttf",
+    "NotoSansSymbols-Regular.ttf",
+    "NotoSansSymbols2-Regular.ttf",
+];
+
+// The text fonts at the front of FONT_FILES
+const TEXT_FONTS: usize = 3;
+
+// How deep the fonts are looked for in the font directories, which group them by family
+const FONT_SEARCH_DEPTH: u32 = 4;
+
+// Symbols with less ink than this share of the median symbol get enlarged or emboldened
+const MIN_RELATIVE_INK: f64 = 0.5;
+
+// Largest a symbol gets enlarged before it gets emboldened instead
+const MAX_SYMBOL_SCALE: f64 = 1.4;
+
+// Outline added to emboldened symbols, relative to their size
+const BOLD_STROKE: f64 = 0.04;
+
+// Height of the middle of a symbol above its baseline, in ems
+const SYMBOL_MIDDLE: f64 = 0.35;
+
+// Contrast ratio between a symbol and its cell below which the symbol gets a halo
+const MIN_SYMBOL_CONTRAST: f64 = 4.5;
+
+// Width of the halo around low contrast symbols, relative to their size
+const HALO_STROKE: f64 = 0.12;
+
+// Radius of the geometric symbols, in ems
+const GEOMETRIC_RADIUS: f64 = 0.36;
+
+// Size of the hole of the hollow geometric symbols, relative to the whole mark
+const GEOMETRIC_HOLE: f64 = 0.55;
+
+// Geometric symbols there are, hollow, filled, half filled either way and dotted of each shape
+const GEOMETRIC_MARKS: usize = 50;
+
+// Outline and number of bookmarked cells
+const BOOKMARK_COLOR: [f64; 3] = [0.85, 0.1, 0.45];
+
+// Space between two lines of the bookmark list, in millimeters
+const BOOKMARK_LINE: f64 = 5.0;
+
+// Left and right margin of actual size charts, most printers can't print closer to the edge
+const TRUE_SIZE_MARGIN: f64 = 5.0;
+
+// Room of the chart parts below the header and above the page number, in millimeters
+const PART_MARGINS: f64 = 40.0;
+
+// Strip of the colors of a part below it, its height and the space between its rows in
+// millimeters, the size of its swatches in millimeters and of its text in points
+const MINI_LEGEND_HEIGHT: f64 = 18.0;
+const MINI_LEGEND_ROW: f64 = 4.0;
+const MINI_LEGEND_ROWS: usize = 3;
+const MINI_LEGEND_SWATCH: f64 = 3.0;
+const MINI_LEGEND_SIZE: f64 = 6.5;
+
+// Written rows of C2C patterns, in points and millimeters. A page has as many lines as fit
+// between the heading and the page number
+const INSTRUCTION_SIZE: f64 = 11.0;
+const INSTRUCTION_LINE: f64 = 6.0;
+// Room the heading and the page number take
+const INSTRUCTION_MARGINS: f64 = 65.0;
+// Room the continuation lines of a row leave for its number and direction, in millimeters
+const INSTRUCTION_INDENT: f64 = 24.0;
+
+// Width and height of a Delica 11/0 on a loom, 1.3 mm along its hole and 1.6 mm across
+const BEAD_LOOM_ASPECT: UVec2 = UVec2 { x: 13, y: 16 };
+
+// About as many Delica 11/0 beads make a gram
+const DELICAS_PER_GRAM: f64 = 200.0;
+
+// A DMC skein is 8 m of floss twisted from 6 strands, in millimeters
+const SKEIN_LENGTH: f64 = 8000.0;
+const SKEIN_STRANDS: u32 = 6;
+
+// Floss a full cross takes in stitch widths, both diagonals on the front and about a side on the
+// back for each, with a fifth more for starting and ending threads
+const STITCH_THREAD: f64 = (2.0 * SQRT_2 + 2.0) * 1.2;
+
+// Floss a backstitch takes in stitch widths, once on the front and twice on the back, with a fifth
+// more for starting and ending threads
+const BACKSTITCH_THREAD: f64 = 3.0 * 1.2;
+
+// Thickness of the backstitches on the charts, in points
+const BACKSTITCH_STROKE: f64 = 2.4;
 
-const OUTPUT_STITCH_SIZE: UVec2 = UVec2 { x: 50, y: 70 };
+// Floss a French knot and a lazy daisy take in stitch widths, a knot wrapped twice and a loop
+// about two stitches long with its tie, with a fifth more for starting and ending threads
+const FRENCH_KNOT_THREAD: f64 = 4.0 * 1.2;
+const LAZY_DAISY_THREAD: f64 = 5.0 * 1.2;
 
-const MMPI: f64 = 25.4;
+// Gray of the watermark outlines, and how much of the page diagonal the watermark spans
+const WATERMARK_GRAY: f64 = 0.6;
+const WATERMARK_SPAN: f64 = 0.8;
 
-const DPI: f64 = 300.0;
+// Size of the floss name and skein estimate below a legend entry, in points, and the room it gets
+// before the next column, in millimeters. Longer ones are set smaller to fit
+const LEGEND_DETAILS_SIZE: f64 = 7.0;
+const LEGEND_DETAILS_WIDTH: f64 = 31.0;
 
-const DPMM: f64 = DPI / MMPI;
+// Width and height of a knitted stitch, at a typical gauge of 5 stitches and 7 rows to the inch
+const KNITTING_ASPECT: UVec2 = UVec2 { x: 7, y: 5 };
 
-const PORTRAIT_SIZE: (Mm, Mm) = (Mm(210.0), Mm(297.0));
+// Fabric left around the design on every side by default, in centimeters
+const DEFAULT_FABRIC_MARGIN: f64 = 5.0;
 
-const IMAGE_PADDING: f64 = 5.0;
+// Share of the stitches that have to match their mirror image for a design to count as symmetric
+const SYMMETRY_THRESHOLD: f64 = 0.98;
 
-#[derive(Debug, Parser)]
+#[derive(Debug, Parser, serde::Serialize)]
 pub struct Args {
-    // Path to the input image
-    #[arg(short)]
-    input: PathBuf,
+    // Path to the input image, to an .oxs or .csv chart whose flosses and symbols are kept, or
+    // to a .pxproj project written by pixelart-gen.
+    // Repeat it to bundle several designs in one document
+    #[arg(short, required = true)]
+    input: Vec<PathBuf>,
     // Path to the output image
     #[arg(short)]
     output: String,
@@ -61,52 +211,940 @@ pub struct Args {
     // The piece is by
     #[arg(short)]
     by: Option<String>,
+    // Designer of the pattern, on the cover and in the document info
+    #[arg(long)]
+    author: Option<String>,
+    // Name in the header of every page, and on the cover without --author
+    #[arg(long, default_value = "needlethreading")]
+    branding: String,
+    // Leave the branding out of the pages and the cover
+    #[arg(long, conflicts_with = "branding")]
+    no_branding: bool,
+    // Web address printed on the cover
+    #[arg(long)]
+    website: Option<String>,
+    // Notice printed at the foot of every page, like `© 2026 Jane Doe, for personal use only`
+    #[arg(long)]
+    copyright: Option<String>,
+    // Text stamped across the cover, preview and chart pages, like `PREVIEW — DO NOT STITCH`,
+    // for sharing a preview of a pattern
+    #[arg(long)]
+    watermark: Option<String>,
+    // Palette file (JSON, GPL or ASE) to snap colors to instead of the built-in DMC colors
+    #[arg(long)]
+    palette: Option<PathBuf>,
+    // Thread brand listed in the legend, `both` adds the Anchor equivalent of each DMC color
+    #[arg(long, value_enum, default_value_t = Brand::Dmc, conflicts_with = "palette")]
+    brand: Brand,
+    // Color difference used to snap to the flosses and to find stash substitutes
+    #[arg(long, value_enum, default_value_t = Metric::Euclidean)]
+    metric: Metric,
+    // Printer ICC profile to soft proof the cover and preview pages against
+    #[arg(long)]
+    proof: Option<PathBuf>,
+    // Order of the chart part pages, `spiral` starts at the center and works outwards
+    #[arg(long, value_enum, default_value_t = PageOrder::Rows)]
+    page_order: PageOrder,
+    // Paper the document is laid out for
+    #[arg(long, value_enum, default_value_t = PageSize::A4)]
+    page_size: PageSize,
+    // Orientation of the pages, `auto` turns the preview and overview of wide designs sideways
+    // and keeps the others portrait
+    #[arg(long, value_enum, default_value_t = Orientation::Auto)]
+    orientation: Orientation,
+    // Largest stitch grid generated without --force
+    #[arg(long, value_parser = parse_size, default_value = "500x500")]
+    max_stitches: UVec2,
+    // Generate patterns above --max-stitches
+    #[arg(long)]
+    force: bool,
+    // What the pattern is for, `knitting` numbers the rows from the bottom and names the yarns,
+    // `c2c` names the yarns and writes out the diagonal rows of corner to corner crochet,
+    // `bead-loom` snaps to Miyuki Delica beads and lists how many grams of each it takes
+    #[arg(long, value_enum, default_value_t = Craft::CrossStitch)]
+    craft: Craft,
+    // Width and height of a stitch as `w:h`, the chart cells get drawn in this proportion.
+    // Square for cross stitch, 7:5 for knitting and 13:16 on a bead loom by default
+    #[arg(long, value_parser = parse_aspect)]
+    pixel_aspect: Option<UVec2>,
+    // Stitches per inch of the fabric the pattern is stitched on, half the thread count of
+    // evenweave and linen, which are stitched over two threads
+    #[arg(long, default_value_t = DEFAULT_FABRIC_COUNT, value_parser = clap::value_parser!(u32).range(1..))]
+    fabric_count: u32,
+    // Cloth the pattern is stitched on
+    #[arg(long, value_enum, default_value_t = FabricType::Aida)]
+    fabric_type: FabricType,
+    // Color of the fabric as `#rrggbb`. The stitches of this color are left out of the chart and
+    // legend, and the unstitched cells show it
+    #[arg(long, value_parser = parse_hex, default_value = "#ffffff")]
+    fabric_color: [u8; 3],
+    // Bare fabric to leave around the design on every side for framing, in centimeters, added to
+    // the finished size for the size to cut the fabric to
+    #[arg(long, default_value_t = DEFAULT_FABRIC_MARGIN)]
+    fabric_margin: f64,
+    // Strands of floss the stitches are made with, for the skein estimates of the legend
+    #[arg(long, default_value_t = 2, value_parser = clap::value_parser!(u32).range(1..=6))]
+    strands: u32,
+    // Chart the parts at the size of the stitches on the fabric, over as many pages as it takes
+    #[arg(long)]
+    true_size: bool,
+    // Add crop marks and glue strips to the part pages along with a page showing how they go
+    // together, for taping them into one large chart
+    #[arg(long)]
+    assembly_marks: bool,
+    // Draw tick boxes by the legend entries and on the top left corner of every block of 10x10
+    // stitches of the part pages, to tick off the finished colors and blocks
+    #[arg(long)]
+    progress_boxes: bool,
+    // Draw everything larger with fewer stitches on every part page and the most distinct
+    // symbols, for stitchers with low vision
+    #[arg(long, conflicts_with = "true_size")]
+    large_print: bool,
+    // Add a page per color at the end, charting the whole design with only the stitches of that
+    // color filled in and the others grayed out, to plan parking and stitch a color at a time
+    #[arg(long)]
+    highlight_pages: bool,
+    // Stitches charted on each part page, 50x70 on A4 and as many as keep them the same size on
+    // other paper by default
+    #[arg(long, value_parser = parse_size)]
+    section_size: Option<UVec2>,
+    // Rows and columns every part page repeats from the one before it, to line the parts up
+    // without counting
+    #[arg(long, default_value_t = 0, conflicts_with = "assembly_marks")]
+    overlap: u32,
+    // Only chart one half of a symmetric design, `auto` detects the mirror axis
+    #[arg(long, value_enum)]
+    mirror: Option<Mirror>,
+    // Palette file of the flosses already owned, prints the nearest owned substitute of the others
+    #[arg(long)]
+    stash: Option<PathBuf>,
+    // Also write the symbol and color of every cell along with the page tiling as JSON
+    #[arg(long)]
+    grid_map: Option<PathBuf>,
+    // Also write the pattern as Open Cross Stitch XML, to edit it in other charting software
+    #[arg(long)]
+    oxs: Option<PathBuf>,
+    // Also write an image of the charted colors as they are, then as seen with protanopia,
+    // deuteranopia and tritanopia, to check the palette works for colorblind stitchers
+    #[arg(long)]
+    simulate_cvd: Option<PathBuf>,
+    // Grayscale mask of the subject, the cells under its black pixels are left unstitched
+    #[arg(long)]
+    mask: Option<PathBuf>,
+    // SVG drawing or image of the outlines to backstitch over the stitches, in the colors of its
+    // lines. Images get a pixel for every corner of the stitches
+    #[arg(long)]
+    backstitch: Option<PathBuf>,
+    // JSON list of French knots and lazy daisies as `{"stitch", "x", "y", "color"}`, with
+    // `french-knot` or `lazy-daisy` stitches at cells counted from 1 in `#rrggbb` colors
+    #[arg(long)]
+    specialty_stitches: Option<PathBuf>,
+    // JSON list of named stitches as `{"name", "x", "y"}` to highlight, counting from 1
+    #[arg(long)]
+    bookmarks: Option<PathBuf>,
+    // Most memory the color images of the document take, in MiB. Larger charts get their colors
+    // drawn at fewer dots per stitch, the grid and symbols stay sharp. The rest of the document
+    // isn't counted
+    #[arg(long, default_value_t = 1024, value_parser = clap::value_parser!(u64).range(1..))]
+    max_image_memory: u64,
+    // Draw the symbols as filled outlines instead of font text, for viewers and printers that
+    // mangle symbol fonts
+    #[arg(long)]
+    outline_symbols: bool,
+    // Draw the first 50 symbols as geometric marks, circles, squares, triangles and the like,
+    // hollow, filled, half filled or dotted, that look the same with any fonts and printers.
+    // Colors past them get the outlines of --outline-symbols
+    #[arg(long, conflicts_with = "outline_symbols")]
+    geometric_symbols: bool,
+
+    // How the overview and part pages chart the stitches, `symbols-only` leaves out the colors
+    // for black and white printers
+    #[arg(long, value_enum, default_value_t = ChartStyle::ColorSymbols)]
+    chart_style: ChartStyle,
+    // Lighten the colors of the overview and part pages to this opacity over white, under dark
+    // symbols, to save ink on large patterns. 0.3 keeps the colors recognizable
+    #[arg(long)]
+    ink_saver: Option<f64>,
+    // Directory the Noto Sans fonts are looked for in before the font directories of the system
+    #[arg(long)]
+    font_dir: Option<PathBuf>,
+    // Font files used instead of the Noto Sans ones of the font directories
+    #[arg(long)]
+    font_regular: Option<PathBuf>,
+    #[arg(long)]
+    font_bold: Option<PathBuf>,
+    #[arg(long)]
+    font_italic: Option<PathBuf>,
+    #[arg(long)]
+    font_symbols: Option<PathBuf>,
+    #[arg(long)]
+    font_symbols_2: Option<PathBuf>,
+    // JSON object of the symbol of every color as `{"#rrggbb": "A"}`. The colors in it keep their
+    // symbols, and the file is updated with the ones of this pattern, creating it if needed
+    #[arg(long)]
+    symbol_map: Option<PathBuf>,
+}
+
+// Who the pattern comes from, on the cover and the pages
+struct Branding {
+    // Name in the page headers, none with --no-branding
+    name: Option<String>,
+    author: Option<String>,
+    website: Option<String>,
+    copyright: Option<String>,
+}
+
+// How the document gets laid out and what goes in it, from the arguments
+struct PdfOptions {
+    title: String,
+    by: Option<String>,
+    branding: Branding,
+    watermark: Option<String>,
+    // Stitches a skein of floss makes, for cross stitch
+    skein_stitches: Option<f64>,
+    // Printer profile the cover and preview pages are soft proofed against
+    proof: Option<Transform<[u8; 3], [u8; 3]>>,
+    stash: Option<Vec<PaletteColor>>,
+    page_order: PageOrder,
+    page_size: PageSize,
+    orientation: Orientation,
+    chart_style: ChartStyle,
+    ink_saver: Option<f64>,
+    mirror: Option<Mirror>,
+    metric: Metric,
+    // Fabric count the parts are charted at the actual size of
+    true_size: Option<u32>,
+    fabric_type: FabricType,
+    fabric_count: u32,
+    fabric_margin: f64,
+    fabric_color: Rgb<u8>,
+    assembly_marks: bool,
+    progress_boxes: bool,
+    large_print: bool,
+    highlight_pages: bool,
+    section_size: Option<UVec2>,
+    overlap: u32,
+    craft: Craft,
+    // The colors come from a palette file, whose names the yarns keep
+    named_colors: bool,
+    pixel_aspect: DVec2,
+    // In bytes
+    max_image_memory: u64,
+    symbol_drawing: SymbolDrawing,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, clap::ValueEnum, serde::Serialize)]
+#[serde(rename_all = "kebab-case")]
+enum Mirror {
+    Auto,
+    // The right half mirrors the left one
+    LeftRight,
+    // The bottom half mirrors the top one
+    TopBottom,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, clap::ValueEnum, serde::Serialize)]
+#[serde(rename_all = "lowercase")]
+enum PageOrder {
+    Rows,
+    Spiral,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, clap::ValueEnum, serde::Serialize)]
+#[serde(rename_all = "lowercase")]
+enum PageSize {
+    A4,
+    Letter,
+    Legal,
+    A3,
+}
+
+impl PageSize {
+    fn portrait(self) -> (Mm, Mm) {
+        match self {
+            PageSize::A4 => (Mm(210.0), Mm(297.0)),
+            PageSize::Letter => (Mm(215.9), Mm(279.4)),
+            PageSize::Legal => (Mm(215.9), Mm(355.6)),
+            PageSize::A3 => (Mm(297.0), Mm(420.0)),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, clap::ValueEnum, serde::Serialize)]
+#[serde(rename_all = "lowercase")]
+enum Orientation {
+    Auto,
+    Portrait,
+    Landscape,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, clap::ValueEnum, serde::Serialize)]
+#[serde(rename_all = "kebab-case")]
+enum ChartStyle {
+    // Colored cells without symbols
+    ColorBlocks,
+    // Black symbols on white
+    SymbolsOnly,
+    ColorSymbols,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, clap::ValueEnum, serde::Serialize)]
+#[serde(rename_all = "kebab-case")]
+enum Craft {
+    CrossStitch,
+    Knitting,
+    C2c,
+    BeadLoom,
+}
+
+impl Craft {
+    // What the document is, for its subject
+    fn description(self) -> &'static str {
+        match self {
+            Craft::CrossStitch => "Cross-stitch pattern",
+            Craft::Knitting => "Knitting chart",
+            Craft::C2c => "Corner to corner crochet pattern",
+            Craft::BeadLoom => "Bead loom pattern",
+        }
+    }
+
+    // Keywords of the document info, before the hashes of the inputs
+    fn keywords(self) -> &'static [&'static str] {
+        match self {
+            Craft::CrossStitch => &["cross stitch", "pattern", "chart"],
+            Craft::Knitting => &["knitting", "colorwork", "chart"],
+            Craft::C2c => &["crochet", "c2c", "pattern"],
+            Craft::BeadLoom => &["beading", "loom", "pattern"],
+        }
+    }
+
+    // Width and height of a stitch when --pixel-aspect isn't given
+    fn pixel_aspect(self) -> UVec2 {
+        match self {
+            Craft::CrossStitch | Craft::C2c => UVec2::ONE,
+            Craft::Knitting => KNITTING_ASPECT,
+            Craft::BeadLoom => BEAD_LOOM_ASPECT,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, clap::ValueEnum, serde::Serialize)]
+#[serde(rename_all = "lowercase")]
+enum FabricType {
+    Aida,
+    Evenweave,
+    Linen,
+}
+
+impl FabricType {
+    // How the cloth is sold for `fabric_count` stitches per inch, by its thread count when it is
+    // stitched over two threads
+    fn description(self, fabric_count: u32) -> String {
+        match self {
+            FabricType::Aida => format!("Aida {fabric_count} ct"),
+            FabricType::Evenweave => format!("Evenweave {} ct", fabric_count * 2),
+            FabricType::Linen => format!("Linen {} ct", fabric_count * 2),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, clap::ValueEnum, serde::Serialize)]
+#[serde(rename_all = "lowercase")]
+enum Brand {
+    Dmc,
+    Anchor,
+    Both,
 }
 
 fn main() -> anyhow::Result<()> {
     let args = Args::parse();
 
-    let input = {
-        let bytes = fs::read(args.input)?;
-        ::image::load_from_memory(&bytes)?
+    if args.input.len() > 1
+        && (args.mask.is_some()
+            || args.bookmarks.is_some()
+            || args.backstitch.is_some()
+            || args.specialty_stitches.is_some()
+            || args.oxs.is_some()
+            || args.simulate_cvd.is_some())
+    {
+        anyhow::bail!(
+            "--mask, --bookmarks, --backstitch, --specialty-stitches, --oxs and --simulate-cvd \
+             only work with a single input"
+        );
+    }
+    if args.craft != Craft::CrossStitch && args.backstitch.is_some() {
+        anyhow::bail!("--backstitch only applies to cross stitch patterns");
+    }
+    if args.craft != Craft::CrossStitch && args.specialty_stitches.is_some() {
+        anyhow::bail!("--specialty-stitches only applies to cross stitch patterns");
+    }
+    if args.craft != Craft::CrossStitch && args.brand != Brand::Dmc {
+        anyhow::bail!("--brand only applies to the flosses of cross stitch patterns");
+    }
+    if args.fabric_margin < 0.0 {
+        anyhow::bail!("--fabric-margin can't be negative");
+    }
+    if args
+        .ink_saver
+        .is_some_and(|opacity| !(0.0..=1.0).contains(&opacity))
+    {
+        anyhow::bail!("--ink-saver takes an opacity between 0 and 1");
+    }
+    if args
+        .watermark
+        .as_deref()
+        .is_some_and(|watermark| watermark.trim().is_empty())
+    {
+        anyhow::bail!("--watermark needs some text");
+    }
+
+    let mut designs = Vec::with_capacity(args.input.len());
+    let mut input_hashes = Vec::with_capacity(args.input.len());
+    for path in &args.input {
+        let bytes = fs::read(path)?;
+        let (input, palette) = match load_pattern(path)? {
+            Some(pattern) => (pattern_image(&pattern).into(), Some(pattern.colors)),
+            None => (::image::load_from_memory(&bytes)?, None),
+        };
+        input_hashes.push(format!("{:x}", Sha256::digest(&bytes)));
+
+        // Transparent cells are left unstitched, the same as the ones pixelart-gen leaves out
+        let input = match &args.mask {
+            Some(path) => {
+                let mask = image::imageops::resize(
+                    &image::open(path)?.to_luma8(),
+                    input.width(),
+                    input.height(),
+                    image::imageops::FilterType::Nearest,
+                );
+                let mut input = input.to_rgba8();
+                for (pixel, mask) in input.pixels_mut().zip(mask.pixels()) {
+                    if mask.0[0] < 128 {
+                        pixel.0[3] = 0;
+                    }
+                }
+
+                input.into()
+            }
+            None => input,
+        };
+
+        if !args.force {
+            check_pattern_size(
+                UVec2::new(input.width(), input.height()),
+                args.max_stitches,
+                args.fabric_count,
+            )?;
+        }
+
+        let bookmarks = args
+            .bookmarks
+            .as_deref()
+            .map(|path| load_bookmarks(path, UVec2::new(input.width(), input.height())))
+            .transpose()?
+            .unwrap_or_default();
+        let backstitches = args
+            .backstitch
+            .as_deref()
+            .map(|path| load_backstitches(path, UVec2::new(input.width(), input.height())))
+            .transpose()?
+            .unwrap_or_default();
+        let specialty_stitches = args
+            .specialty_stitches
+            .as_deref()
+            .map(|path| load_specialty_stitches(path, UVec2::new(input.width(), input.height())))
+            .transpose()?
+            .unwrap_or_default();
+        // The designs of a bundle go by their file names
+        let title = if args.input.len() > 1 {
+            path.file_stem()
+                .map_or_else(String::new, |stem| stem.to_string_lossy().into_owned())
+        } else {
+            args.title.clone()
+        };
+
+        designs.push(Design {
+            title,
+            img: input,
+            bookmarks,
+            backstitches,
+            specialty_stitches,
+            palette,
+        });
+    }
+    let parameters = serde_json::to_string(&args)?;
+
+    let font_bytes = load_fonts(&args)?;
+
+    let symbol_map = match &args.symbol_map {
+        Some(path) if path.exists() => {
+            let map: HashMap<String, char> = serde_json::from_str(&fs::read_to_string(path)?)?;
+            map.into_iter()
+                .map(|(color, symbol)| (color.to_ascii_lowercase(), symbol))
+                .collect()
+        }
+        _ => HashMap::default(),
+    };
+
+    let proof = args
+        .proof
+        .as_ref()
+        .map(|path| -> anyhow::Result<_> {
+            Ok(Transform::new_proofing(
+                &Profile::new_srgb(),
+                PixelFormat::RGB_8,
+                &Profile::new_srgb(),
+                PixelFormat::RGB_8,
+                &Profile::new_file(path)?,
+                Intent::Perceptual,
+                Intent::RelativeColorimetric,
+                Flags::SOFT_PROOFING,
+            )?)
+        })
+        .transpose()?;
+
+    let floss_map = floss_map(match args.brand {
+        Brand::Anchor => anchor_palette()?,
+        Brand::Dmc if args.craft == Craft::BeadLoom && args.palette.is_none() => delica_palette()?,
+        Brand::Dmc | Brand::Both => load_palette(args.palette.as_deref())?,
+    })?;
+    let stash = args
+        .stash
+        .as_deref()
+        .map(|path| load_palette(Some(path)))
+        .transpose()?;
+    let conversions = if args.brand == Brand::Both {
+        dmc_to_anchor()?
+    } else {
+        HashMap::default()
+    };
+    // Only the DMC flosses have their names listed
+    let dmc_names = if args.craft == Craft::CrossStitch
+        && args.brand != Brand::Anchor
+        && args.palette.is_none()
+    {
+        dmc_names()?
+    } else {
+        HashMap::default()
+    };
+
+    let options = PdfOptions {
+        title: args.title.clone(),
+        by: args.by.clone(),
+        branding: Branding {
+            name: (!args.no_branding).then(|| args.branding.clone()),
+            author: args.author.clone(),
+            website: args.website.clone(),
+            copyright: args.copyright.clone(),
+        },
+        watermark: args.watermark.clone(),
+        skein_stitches: (args.craft == Craft::CrossStitch)
+            .then(|| skein_stitches(args.fabric_count, args.strands)),
+        proof,
+        stash,
+        page_order: args.page_order,
+        page_size: args.page_size,
+        orientation: args.orientation,
+        chart_style: args.chart_style,
+        ink_saver: args.ink_saver,
+        mirror: args.mirror,
+        metric: args.metric,
+        true_size: args.true_size.then_some(args.fabric_count),
+        fabric_type: args.fabric_type,
+        fabric_count: args.fabric_count,
+        fabric_margin: args.fabric_margin,
+        fabric_color: Rgb(args.fabric_color),
+        assembly_marks: args.assembly_marks,
+        progress_boxes: args.progress_boxes,
+        large_print: args.large_print,
+        highlight_pages: args.highlight_pages,
+        section_size: args.section_size,
+        overlap: args.overlap,
+        craft: args.craft,
+        named_colors: args.palette.is_some(),
+        pixel_aspect: args
+            .pixel_aspect
+            .unwrap_or(args.craft.pixel_aspect())
+            .as_dvec2(),
+        max_image_memory: args.max_image_memory * 1024 * 1024,
+        symbol_drawing: if args.geometric_symbols {
+            SymbolDrawing::Geometric
+        } else if args.outline_symbols {
+            SymbolDrawing::Outline
+        } else {
+            SymbolDrawing::Text
+        },
     };
+    let (doc, grid_maps, additions) = generate_pdf(
+        designs,
+        &font_bytes,
+        floss_map,
+        conversions,
+        dmc_names,
+        symbol_map.clone(),
+        &options,
+    )?;
+
+    // A list with one map per design for a bundle
+    if let Some(path) = &args.grid_map {
+        let writer = BufWriter::new(fs::File::create(path)?);
+        match grid_maps.as_slice() {
+            [grid_map] => serde_json::to_writer(writer, grid_map)?,
+            grid_maps => serde_json::to_writer(writer, grid_maps)?,
+        }
+    }
+
+    // Colors that aren't in this pattern stay in the map, for when they come back
+    if let Some(path) = &args.symbol_map {
+        let mut symbol_map = symbol_map.into_iter().collect::<BTreeMap<_, _>>();
+        for color in grid_maps.iter().flat_map(|grid_map| &grid_map.colors) {
+            symbol_map.insert(color.color.clone(), color.symbol);
+        }
+        fs::write(path, serde_json::to_string_pretty(&symbol_map)?)?;
+    }
+
+    if let Some(path) = &args.oxs {
+        fs::write(
+            path,
+            oxs(
+                &grid_maps[0],
+                &args.title,
+                args.by.as_deref(),
+                args.fabric_count,
+                Rgb(args.fabric_color),
+                args.brand,
+            ),
+        )?;
+    }
+
+    if let Some(path) = &args.simulate_cvd {
+        cvd_preview(&grid_maps[0])?.save(path)?;
+    }
 
-    generate_pdf(&input, args.title, args.by)
-        .save(&mut BufWriter::new(fs::File::create(args.output).unwrap()))?;
+    let branding = &options.branding;
+    let doc = match branding.author.as_ref().or(branding.name.as_ref()) {
+        Some(author) => doc.with_author(author),
+        None => doc,
+    };
+    // Written into the document info so a pattern can be traced back to how it was generated
+    let doc = doc
+        .with_creator(format!("pdfgen {}", env!("CARGO_PKG_VERSION")))
+        .with_subject(format!(
+            "{} made with {parameters}",
+            args.craft.description()
+        ))
+        .with_identifier(input_hashes.join(" "))
+        .with_keywords(
+            args.craft
+                .keywords()
+                .iter()
+                .map(|keyword| keyword.to_string())
+                .chain(
+                    input_hashes
+                        .iter()
+                        .map(|hash| format!("input-sha256:{hash}")),
+                )
+                .collect(),
+        );
+    let mut out = BufWriter::new(fs::File::create(args.output).unwrap());
+    if additions.check_boxes.is_empty() && additions.symbol_forms.is_empty() {
+        doc.save(&mut out)?;
+    } else {
+        additions.add_to(&doc.save_to_bytes()?)?.save_to(&mut out)?;
+    }
 
     Ok(())
 }
 
-fn generate_pdf(img: &DynamicImage, title: String, by: Option<String>) -> PdfDocumentReference {
-    let (doc, curr_page, curr_layer) =
-        PdfDocument::new(&title, PORTRAIT_SIZE.0, PORTRAIT_SIZE.1, "cover");
-    let curr_layer = doc.get_page(curr_page).get_layer(curr_layer);
+// One image of the document
+struct Design {
+    title: String,
+    img: DynamicImage,
+    bookmarks: Vec<Bookmark>,
+    backstitches: Vec<Backstitch>,
+    specialty_stitches: Vec<SpecialtyStitch>,
+    // Flosses and symbols of an imported chart, whose colors are used as they are
+    palette: Option<Vec<PatternColor>>,
+}
 
-    let fonts = [
-        (
-            doc.add_external_font(std::io::Cursor::new(REGULAR))
-                .unwrap(),
-            REGULAR,
-        ),
-        (
-            doc.add_external_font(std::io::Cursor::new(BOLD)).unwrap(),
-            BOLD,
-        ),
-        (
-            doc.add_external_font(std::io::Cursor::new(ITALIC)).unwrap(),
-            ITALIC,
-        ),
-        (
-            doc.add_external_font(std::io::Cursor::new(FONT_SYMBOLS))
-                .unwrap(),
-            FONT_SYMBOLS,
-        ),
-        (
-            doc.add_external_font(std::io::Cursor::new(FONT_SYMBOLS_2))
-                .unwrap(),
-            FONT_SYMBOLS_2,
-        ),
-    ];
+// A design snapped to the flosses along with how it gets charted
+struct Section {
+    title: String,
+    img: DynamicImage,
+    // What the printer makes of `img`
+    preview: DynamicImage,
+    // Part of `img` on the part pages, half of it when mirrored
+    charted: DynamicImage,
+    mirror: Option<Mirror>,
+    bookmarks: Vec<Bookmark>,
+    // Every part with where it is in the page grid and its first stitch
+    sub_images: Vec<(RgbImage, UVec2, UVec2)>,
+    // Color, stitch count and floss in legend order
+    colors: Vec<(Rgb<u8>, usize, String)>,
+    // Symbol of every color in legend order
+    symbols: Vec<char>,
+    // Backstitches in the colors of their flosses
+    backstitches: Vec<Backstitch>,
+    // Color, length in stitches and floss of the backstitches, listed after the colors
+    backstitch_colors: Vec<(Rgb<u8>, f64, String)>,
+    // Specialty stitches in the colors of their flosses
+    specialty_stitches: Vec<SpecialtyStitch>,
+    // Stitch, color, count and floss of the specialty stitches, listed under a heading of their
+    // own after the backstitches
+    specialty_colors: Vec<(SpecialtyKind, Rgb<u8>, usize, String)>,
+    legend_slots: Vec<LegendSlot>,
+    // Lines of the written rows on each of their pages, and whether they go on with the row of
+    // the line before
+    instructions: Vec<Vec<(String, bool)>>,
+}
+
+impl Section {
+    // The first legend page also has the summary, so it is there without any colors
+    fn legend_pages(&self) -> usize {
+        self.legend_slots.last().map_or(1, |slot| slot.page + 1)
+    }
+
+    // Charts split over several pages get a map of them before the parts
+    fn has_page_map(&self) -> bool {
+        self.sub_images.len() > 1
+    }
+
+    // Preview, overview, legend, page map, part and written row pages, then the assembly page
+    fn pages(&self, assembly_marks: bool, highlight_pages: bool) -> usize {
+        2 + self.legend_pages()
+            + self.has_page_map() as usize
+            + self.sub_images.len()
+            + self.instructions.len()
+            + assembly_marks as usize
+            + highlight_pages as usize * self.colors.len()
+    }
+
+    // Where a part of `stitches` goes on its page
+    fn part_placement(
+        &self,
+        stitches: UVec2,
+        page: (Mm, Mm),
+        page_stitches: UVec2,
+        true_size: Option<u32>,
+        assembly_marks: bool,
+    ) -> Placement {
+        let chart_size = UVec2::new(self.charted.width(), self.charted.height());
+
+        match true_size {
+            Some(fabric_count) => {
+                Placement::true_size(stitches, &true_size_area(page), fabric_count)
+            }
+            // Assembled pages need the stitches the same size on all of them
+            None if assembly_marks => {
+                Placement::new(page_stitches.min(chart_size), &part_area(page)).tile(stitches)
+            }
+            None => Placement::new(stitches, &part_area(page)),
+        }
+    }
+}
+
+// Part of a page the chart parts get
+fn part_area(page: (Mm, Mm)) -> ChartArea {
+    ChartArea {
+        left: 0.0,
+        right: page.0 .0,
+        top: 0.0,
+        bottom: page.1 .0 - PART_MARGINS - MINI_LEGEND_HEIGHT,
+        page_height: page.1 .0 - PART_MARGINS / 2.0,
+    }
+}
+
+// The larger part of a page the actual size parts get
+fn true_size_area(page: (Mm, Mm)) -> ChartArea {
+    ChartArea {
+        left: TRUE_SIZE_MARGIN,
+        right: page.0 .0 - TRUE_SIZE_MARGIN,
+        ..part_area(page)
+    }
+}
+
+// Stitches of the chart parts, PAGE_STITCHES being how many fit on A4 portrait. Other pages fit
+// as many more or fewer as keep the stitches about the same size, in whole blocks of GRID so every
+// part starts on a bold line
+fn part_stitches(page: (Mm, Mm)) -> UVec2 {
+    let a4 = part_area(PageSize::A4.portrait());
+    let area = part_area(page);
+    let scale = DVec2::new(
+        (area.right - area.left) / (a4.right - a4.left),
+        (area.bottom - area.top) / (a4.bottom - a4.top),
+    );
+
+    ((PAGE_STITCHES.as_dvec2() * scale).as_uvec2() / GRID * GRID).max(GRID)
+}
+
+// Roughly the bytes the images of `sections` take with at most `max_scale` dots per stitch, the
+// design being on the cover, preview and overview pages on top of the parts
+fn image_bytes(
+    sections: &[Section],
+    page: (Mm, Mm),
+    page_stitches: UVec2,
+    true_size: Option<u32>,
+    assembly_marks: bool,
+    highlight_pages: bool,
+    max_scale: u32,
+) -> u64 {
+    let bytes = |stitches: UVec2, placement: Placement| {
+        let scale = placement.capped(max_scale).scale as u64;
+        stitches.x as u64 * stitches.y as u64 * scale * scale * 3
+    };
+
+    sections
+        .iter()
+        .map(|section| {
+            let size = UVec2::new(section.img.width(), section.img.height());
+            let parts = section
+                .sub_images
+                .iter()
+                .map(|(sub_image, _, _)| {
+                    let stitches = UVec2::new(sub_image.width(), sub_image.height());
+                    let placement = section.part_placement(
+                        stitches,
+                        page,
+                        page_stitches,
+                        true_size,
+                        assembly_marks,
+                    );
+                    bytes(stitches, placement)
+                })
+                .sum::<u64>();
+
+            let pages = 3 + highlight_pages as u64 * section.colors.len() as u64;
+            pages * bytes(size, Placement::new(size, &part_area(page))) + parts
+        })
+        .sum()
+}
+
+// The flosses the colors get snapped to, along with their names
+struct Flosses {
+    colors: Vec<Rgb<u8>>,
+    index: PaletteIndex,
+    names: HashMap<Rgb<u8>, String>,
+}
+
+impl Flosses {
+    fn new(names: HashMap<Rgb<u8>, String>, metric: Metric) -> Self {
+        let colors = names.keys().copied().collect::<Vec<_>>();
+        let index = PaletteIndex::with_metric(
+            colors.iter().map(|color| {
+                let lab = palette::Lab::<palette::white_point::D65, f64>::adapt_from(
+                    palette::rgb::Srgb::new(color.0[0], color.0[1], color.0[2]).into_format(),
+                );
+                [lab.l, lab.a, lab.b]
+            }),
+            metric,
+        );
+
+        Flosses {
+            colors,
+            index,
+            names,
+        }
+    }
+
+    // Color of the floss closest to `color`
+    fn nearest(&self, color: [u8; 3]) -> Rgb<u8> {
+        let lab_color = palette::Lab::<palette::white_point::D65, f64>::adapt_from(
+            palette::rgb::Srgb::new(color[0], color[1], color[2]).into_format(),
+        );
+
+        self.colors[self
+            .index
+            .nearest([lab_color.l, lab_color.a, lab_color.b])
+            .unwrap()]
+    }
+}
+
+fn generate_pdf(
+    designs: Vec<Design>,
+    font_bytes: &[Vec<u8>],
+    floss_map: HashMap<Rgb<u8>, String>,
+    conversions: HashMap<String, String>,
+    dmc_names: HashMap<String, String>,
+    // Symbols of the colors by their hex code
+    mut symbol_map: HashMap<String, char>,
+    options: &PdfOptions,
+) -> anyhow::Result<(PdfDocumentReference, Vec<GridMap>, Additions)> {
+    let PdfOptions {
+        title,
+        by,
+        branding,
+        watermark,
+        ..
+    } = options;
+    // Every page but the sideways preview and overview pages of `auto`
+    let page = match options.orientation {
+        Orientation::Auto | Orientation::Portrait => options.page_size.portrait(),
+        Orientation::Landscape => {
+            let (width, height) = options.page_size.portrait();
+            (height, width)
+        }
+    };
+    // Large print pages are laid out on smaller paper, then drawn scaled up to fill the real one
+    let print_scale = if options.large_print {
+        LARGE_PRINT_SCALE
+    } else {
+        1.0
+    };
+    let (doc, curr_page, curr_layer) = PdfDocument::new(title, page.0, page.1, "cover");
+    doc.add_bookmark("Cover", curr_page);
+    let curr_layer = scaled_layer(doc.get_page(curr_page).get_layer(curr_layer), print_scale);
+    let page = (page.0 / print_scale, page.1 / print_scale);
+
+    // Only the glyphs that can show up in the document get embedded
+    let text_chars = (' '..='~')
+        .chain(title.chars())
+        .chain(designs.iter().flat_map(|design| design.title.chars()))
+        .chain(by.iter().flat_map(|by| by.chars()))
+        .chain(
+            [
+                &branding.name,
+                &branding.author,
+                &branding.website,
+                &branding.copyright,
+            ]
+            .into_iter()
+            .flatten()
+            .flat_map(|text| text.chars()),
+        )
+        .chain(watermark.iter().flat_map(|watermark| watermark.chars()))
+        .chain(floss_map.values().flat_map(|floss| floss.chars()))
+        .chain(conversions.values().flat_map(|floss| floss.chars()))
+        .chain(dmc_names.values().flat_map(|name| name.chars()))
+        .chain(
+            designs
+                .iter()
+                .flat_map(|design| design.palette.iter().flatten())
+                .flat_map(|color| color.floss.chars()),
+        )
+        // Directions of the written C2C rows
+        .chain(['↖', '↘'])
+        .collect::<HashSet<_>>();
+    let font_files = font_bytes
+        .iter()
+        .enumerate()
+        .map(|(idx, font_bytes)| {
+            if idx < TEXT_FONTS {
+                subset_font(font_bytes, &text_chars)
+            } else {
+                subset_font(font_bytes, &HashSet::from(SYMBOLS))
+            }
+        })
+        .collect::<Vec<_>>();
+    let fonts = font_files
+        .iter()
+        .map(|font_file| {
+            (
+                doc.add_external_font(std::io::Cursor::new(&font_file[..]))
+                    .unwrap(),
+                &font_file[..],
+            )
+        })
+        .collect::<Vec<_>>();
 
     let symbol_font_map = {
         let mut map: HashMap<_, _, RandomState> = HashMap::default();
@@ -121,89 +1159,146 @@ fn generate_pdf(img: &DynamicImage, title: String, by: Option<String>) -> PdfDoc
 
         map
     };
+    let symbol_styles = symbol_styles(&symbol_font_map, options.symbol_drawing);
+    // The geometric marks don't look like the glyphs they stand for
+    let symbol_shapes = if options.symbol_drawing == SymbolDrawing::Geometric {
+        HashMap::default()
+    } else {
+        SYMBOLS
+            .iter()
+            .map(|symbol| (*symbol, symbol_shape(*symbol, symbol_font_map[symbol].1)))
+            .collect()
+    };
 
-    let floss_map = load_dmc_colors();
-
-    // Set the pixels to the closest DMC colors
-    let img = {
-        let mut img = img.to_rgba8();
-        for color in img.pixels_mut() {
-            if color.0[3] == 0 {
-                color.0[0] = 255;
-                color.0[1] = 255;
-                color.0[2] = 255;
-                color.0[3] = 255;
-                continue;
-            }
-
-            let lab_color = palette::Lab::<palette::white_point::D65, f64>::adapt_from(
-                palette::rgb::Srgb::new(color.0[0], color.0[1], color.0[2]).into_format(),
-            );
-
-            let (_, selected_color) = floss_map
-                .keys()
-                .map(|color| {
-                    (
-                        palette::Lab::<palette::white_point::D65, f64>::adapt_from(
-                            palette::rgb::Srgb::new(color.0[0], color.0[1], color.0[2])
-                                .into_format(),
-                        ),
-                        color,
-                    )
-                })
-                .min_by_key(|(lab, _)| float_ord::FloatOrd(lab.distance(lab_color)))
-                .unwrap();
-
-            *color = image::Rgba([
-                selected_color.0[0],
-                selected_color.0[1],
-                selected_color.0[2],
-                255,
-            ]);
+    // Actual size parts can't get more stitches than fit on the page
+    let page_stitches = match (options.true_size, options.section_size) {
+        (Some(fabric_count), section_size) => {
+            let fit = true_size_stitches(&true_size_area(page), fabric_count);
+            section_size.map_or(fit, |section_size| section_size.min(fit))
         }
-
-        let img: DynamicImage = img.into();
-        &img.to_rgb8().into()
+        (None, Some(section_size)) => section_size.max(UVec2::ONE),
+        (None, None) => part_stitches(page),
     };
+    if options.overlap >= page_stitches.min_element() {
+        eprintln!("--overlap is as large as the parts, they only move on by one stitch");
+    }
 
-    let sub_images = sub_divide_images(img);
-    let mut colors: HashMap<_, _, RandomState> = HashMap::default();
+    let flosses = Flosses::new(floss_map, options.metric);
+    let mut sections = designs
+        .into_iter()
+        .map(|design| {
+            let section = prepare_section(
+                design,
+                &flosses,
+                page,
+                page_stitches,
+                &symbol_map,
+                &symbol_shapes,
+                options,
+            )?;
+            // The designs of a bundle share their symbols
+            for ((color, _, _), symbol) in section.colors.iter().zip(&section.symbols) {
+                symbol_map.insert(hex_color(*color), *symbol);
+            }
+            Ok(section)
+        })
+        .collect::<anyhow::Result<Vec<_>>>()?;
+    if matches!(options.craft, Craft::Knitting | Craft::C2c) {
+        name_yarns(&mut sections, options.named_colors);
+    }
+    if options.craft == Craft::C2c {
+        for section in &mut sections {
+            section.instructions = paginate_instructions(c2c_rows(section), page, fonts[0].1);
+        }
+    }
 
-    for color in img.to_rgb8().pixels() {
-        if color.0 == [255, 255, 255] {
-            continue;
+    // A bundle starts with the flosses of all of its designs together
+    let shopping_list = (sections.len() > 1).then(|| {
+        let mut totals: HashMap<Rgb<u8>, (usize, String)> = HashMap::default();
+        for (color, freq, floss) in sections.iter().flat_map(|section| &section.colors) {
+            totals.entry(*color).or_insert((0, floss.clone())).0 += freq;
         }
 
-        *colors.entry(*color).or_insert(0) += 1;
+        let mut colors = totals
+            .into_iter()
+            .map(|(color, (freq, floss))| (color, freq, floss))
+            .collect::<Vec<_>>();
+        match options.craft {
+            Craft::CrossStitch | Craft::BeadLoom => sort_legend(&mut colors),
+            // In the order the yarns are named in
+            Craft::Knitting | Craft::C2c => {
+                colors.sort_by_key(|(color, freq, _)| (Reverse(*freq), color.0))
+            }
+        }
+        colors
+    });
+    let shopping_slots = shopping_list
+        .as_ref()
+        .map(|colors| legend_slots(colors.len(), page.0 .0, page.1 .0));
+    let shopping_pages = shopping_slots
+        .as_ref()
+        .map_or(0, |slots| slots.last().map_or(1, |slot| slot.page + 1));
+    let total_pages = 1
+        + shopping_pages
+        + sections
+            .iter()
+            .map(|section| section.pages(options.assembly_marks, options.highlight_pages))
+            .sum::<usize>();
+
+    // The whole document is kept in memory until it is saved. Only the images are capped, they
+    // take most of it
+    let max_scale = (1..=MAX_SCALE)
+        .rev()
+        .find(|&scale| {
+            let bytes = image_bytes(
+                &sections,
+                page,
+                page_stitches,
+                options.true_size,
+                options.assembly_marks,
+                options.highlight_pages,
+                scale,
+            );
+            bytes <= options.max_image_memory
+        })
+        .unwrap_or(1);
+    if max_scale < MAX_SCALE {
+        eprintln!(
+            "Drawing the colors at {max_scale} dots per stitch at most to stay within --max-image-memory"
+        );
     }
-    let total_pages =
-        3 + if colors.len() <= 69 {
-            1
-        } else {
-            ((colors.len() as f64 - 69.0) / 75.0).ceil() as usize + 1
-        } + sub_images.len();
 
-    let mut colors = colors
-        .into_iter()
-        .map(|(color, freq)| (color, freq, floss_map[&color]))
-        .collect::<Vec<_>>();
-    colors.sort_by_key(|(_, _, floss)| *floss);
+    if let Some(stash) = &options.stash {
+        print_substitutions(
+            shopping_list.as_deref().unwrap_or(&sections[0].colors),
+            stash,
+            options.metric,
+        );
+    }
 
-    let color_symbol_map = colors
-        .clone()
-        .into_iter()
-        .enumerate()
-        .map(|(idx, (color, _, _))| (color, SYMBOLS[idx]))
-        .collect::<HashMap<_, _>>();
+    let pages = PdfPages {
+        doc: &doc,
+        options,
+        page,
+        print_scale,
+        total_pages,
+        fonts: &fonts,
+        symbol_font_map: &symbol_font_map,
+        symbol_styles: &symbol_styles,
+        conversions: &conversions,
+        dmc_names: &dmc_names,
+        page_stitches,
+        max_scale,
+    };
 
     // Add border
     const BORDER_MARGIN: Mm = Mm(5.0);
     curr_layer.add_shape(Line {
         points: printpdf::calculate_points_for_rect(
-            PORTRAIT_SIZE.0 - (BORDER_MARGIN * 2.0),
-            PORTRAIT_SIZE.1 - (BORDER_MARGIN * 2.0),
-            BORDER_MARGIN + ((PORTRAIT_SIZE.0 - (BORDER_MARGIN * 2.0)) / 2.0),
-            BORDER_MARGIN + ((PORTRAIT_SIZE.1 - (BORDER_MARGIN * 2.0)) / 2.0),
+            page.0 - (BORDER_MARGIN * 2.0),
+            page.1 - (BORDER_MARGIN * 2.0),
+            BORDER_MARGIN + ((page.0 - (BORDER_MARGIN * 2.0)) / 2.0),
+            BORDER_MARGIN + ((page.1 - (BORDER_MARGIN * 2.0)) / 2.0),
         ),
         is_closed: true,
         has_fill: false,
@@ -214,21 +1309,21 @@ fn generate_pdf(img: &DynamicImage, title: String, by: Option<String>) -> PdfDoc
     // Add title text
     render_centered_text(
         &curr_layer,
-        &title,
+        title,
         30.0,
-        (PORTRAIT_SIZE.0 / 2.0, PORTRAIT_SIZE.1 - Mm(30.0)),
+        (page.0 / 2.0, page.1 - Mm(30.0)),
         &fonts[1],
     );
 
     // Add the by line
     let top_offset;
-    if let Some(by) = &by {
+    if let Some(by) = by {
         top_offset = 45.0;
         render_centered_text(
             &curr_layer,
             by,
             30.0,
-            (PORTRAIT_SIZE.0 / 2.0, PORTRAIT_SIZE.1 - Mm(45.0)),
+            (page.0 / 2.0, page.1 - Mm(45.0)),
             &fonts[2],
         );
     } else {
@@ -237,506 +1332,2683 @@ fn generate_pdf(img: &DynamicImage, title: String, by: Option<String>) -> PdfDoc
             &curr_layer,
             "Original Pattern",
             24.0,
-            (PORTRAIT_SIZE.0 / 2.0, PORTRAIT_SIZE.1 - Mm(42.0)),
+            (page.0 / 2.0, page.1 - Mm(42.0)),
             &fonts[2],
         );
     }
 
     // Render Bottom Text
-    let bottom_offset = 245.0;
+    let bottom_offset = page.1 .0 - 52.0;
     render_centered_text(
         &curr_layer,
-        "Cross-Stitch Pattern",
+        if sections.len() > 1 {
+            "Cross-Stitch Pattern Bundle"
+        } else {
+            "Cross-Stitch Pattern"
+        },
         24.0,
-        (PORTRAIT_SIZE.0 / 2.0, PORTRAIT_SIZE.1 - Mm(250.0)),
-        &fonts[0],
-    );
-    render_centered_text(
-        &curr_layer,
-        "BY",
-        24.0,
-        (PORTRAIT_SIZE.0 / 2.0, PORTRAIT_SIZE.1 - Mm(260.0)),
-        &fonts[0],
-    );
-    render_centered_text(
-        &curr_layer,
-        "needlethreading",
-        24.0,
-        (PORTRAIT_SIZE.0 / 2.0, PORTRAIT_SIZE.1 - Mm(270.0)),
+        (page.0 / 2.0, Mm(47.0)),
         &fonts[0],
     );
+    // By the designer, or the branding without one
+    if let Some(name) = branding.author.as_ref().or(branding.name.as_ref()) {
+        render_centered_text(&curr_layer, "BY", 24.0, (page.0 / 2.0, Mm(37.0)), &fonts[0]);
+        render_centered_text(&curr_layer, name, 24.0, (page.0 / 2.0, Mm(27.0)), &fonts[0]);
+    }
+    if let Some(website) = &branding.website {
+        render_centered_text(
+            &curr_layer,
+            website,
+            12.0,
+            (page.0 / 2.0, Mm(20.0)),
+            &fonts[2],
+        );
+    }
 
     // Render Page idx
-    render_centered_text(
-        &curr_layer,
-        &format!("1 / {}", total_pages),
-        18.0,
-        (PORTRAIT_SIZE.0 / 2.0, PORTRAIT_SIZE.1 - Mm(285.0)),
-        &fonts[1],
+    pages.render_page_number(&curr_layer, 1, (page.0 / 2.0, Mm(12.0)));
+
+    // Adding the main image, or all of them side by side for a bundle
+    let cover_area = ChartArea {
+        left: BORDER_MARGIN.0,
+        right: (page.0 - BORDER_MARGIN).0,
+        top: top_offset,
+        bottom: bottom_offset,
+        page_height: page.1 .0,
+    };
+    let columns = (sections.len() as f64).sqrt().ceil() as usize;
+    let rows = sections.len().div_ceil(columns);
+    let cell = DVec2::new(
+        (cover_area.right - cover_area.left) / columns as f64,
+        (cover_area.bottom - cover_area.top) / rows as f64,
     );
+    for (idx, section) in sections.iter().enumerate() {
+        let (column, row) = ((idx % columns) as f64, (idx / columns) as f64);
+        render_image_centered(
+            curr_layer.clone(),
+            &section.preview,
+            options.pixel_aspect,
+            max_scale,
+            &ChartArea {
+                left: cover_area.left + cell.x * column,
+                right: cover_area.left + cell.x * (column + 1.0),
+                top: cover_area.top + cell.y * row,
+                bottom: cover_area.top + cell.y * (row + 1.0),
+                ..cover_area
+            },
+        );
+    }
+    if let Some(watermark) = watermark {
+        render_watermark(&doc, curr_page, page, print_scale, watermark, &fonts[1]);
+    }
+
+    if let (Some(colors), Some(slots)) = (&shopping_list, &shopping_slots) {
+        pages.render_shopping_list(colors, slots, sections.len());
+    }
+
+    let mut first_page = 2 + shopping_pages;
+    let mut grid_maps = Vec::with_capacity(sections.len());
+    let mut check_boxes = Vec::new();
+    for section in &sections {
+        grid_maps.push(render_section(
+            &pages,
+            section,
+            sections.len() > 1,
+            first_page,
+            options.progress_boxes.then_some(&mut check_boxes),
+        ));
+        first_page += section.pages(options.assembly_marks, options.highlight_pages);
+    }
+
+    if let Some(copyright) = &branding.copyright {
+        for page_idx in 0..total_pages {
+            let layer = scaled_layer(
+                doc.get_page(PdfPageIndex(page_idx)).add_layer("copyright"),
+                print_scale,
+            );
+            render_left_text(&layer, copyright, 8.0, (Mm(10.0), Mm(7.0)), &fonts[0]);
+        }
+    }
+
+    // The fields go on the paper, they aren't scaled with the layers
+    for check_box in &mut check_boxes {
+        check_box.center *= print_scale;
+        check_box.size *= print_scale;
+    }
+
+    // Every outline is drawn once, the cells of its symbol only place it
+    let symbol_forms = sections
+        .iter()
+        .flat_map(|section| &section.symbols)
+        .collect::<BTreeSet<_>>()
+        .into_iter()
+        .filter_map(|symbol| {
+            let style = &symbol_styles[symbol];
+            let outline = style.outline.as_ref()?;
+            Some([false, true].map(|halo| SymbolForm::new(*symbol, outline, style.bold, halo)))
+        })
+        .flatten()
+        .collect();
+
+    let additions = Additions {
+        check_boxes,
+        symbol_forms,
+    };
+    Ok((doc, grid_maps, additions))
+}
+
+// Snaps a design to the flosses and splits it into the parts of the part pages
+fn prepare_section(
+    design: Design,
+    flosses: &Flosses,
+    page: (Mm, Mm),
+    page_stitches: UVec2,
+    symbol_map: &HashMap<String, char>,
+    symbol_shapes: &HashMap<char, Vec<f64>>,
+    options: &PdfOptions,
+) -> anyhow::Result<Section> {
+    let fabric_color = options.fabric_color;
+    // The author of an imported chart already picked the flosses, the first of those sharing a
+    // color wins
+    let palette = design.palette.as_ref().map(|palette| {
+        palette
+            .iter()
+            .rev()
+            .map(|color| (Rgb(color.rgb), color))
+            .collect::<HashMap<_, _>>()
+    });
+
+    // Set the pixels to the closest DMC colors
+    let img = {
+        let mut img = design.img.to_rgba8();
+        for color in img.pixels_mut() {
+            if color.0[3] == 0 {
+                let [r, g, b] = fabric_color.0;
+                *color = Rgba([r, g, b, 255]);
+                continue;
+            }
+            if palette.is_some() {
+                continue;
+            }
+
+            let selected_color = flosses.nearest([color.0[0], color.0[1], color.0[2]]);
+
+            *color = image::Rgba([
+                selected_color.0[0],
+                selected_color.0[1],
+                selected_color.0[2],
+                255,
+            ]);
+        }
+
+        let img: DynamicImage = img.into();
+        DynamicImage::from(img.to_rgb8())
+    };
+
+    // The cover and preview pages show how the printer will reproduce the colors
+    let preview = options
+        .proof
+        .as_ref()
+        .map_or_else(|| img.clone(), |transform| soft_proof(&img, transform));
+
+    let mirror = match options.mirror {
+        Some(Mirror::Auto) => {
+            let detected = detect_mirror(&img.to_rgb8());
+            if detected.is_none() {
+                eprintln!("{} isn't symmetric, charting all of it", design.title);
+            }
+            detected
+        }
+        mirror => mirror,
+    };
+    // Only the half that gets mirrored is charted, the center row or column is part of it
+    let charted = match mirror {
+        Some(Mirror::LeftRight) => img.crop_imm(0, 0, img.width().div_ceil(2), img.height()),
+        Some(Mirror::TopBottom) => img.crop_imm(0, 0, img.width(), img.height().div_ceil(2)),
+        _ => img.clone(),
+    };
+
+    let mut sub_images = sub_divide_images(&charted, page_stitches, options.overlap);
+    if options.page_order == PageOrder::Spiral {
+        let sections = sub_images
+            .iter()
+            .fold(UVec2::ZERO, |sections, (_, offset, _)| {
+                sections.max(*offset + 1)
+            });
+        let order = spiral_order(sections);
+        sub_images.sort_by_key(|(_, offset, _)| order.iter().position(|o| o == offset));
+    }
+    let mut colors: HashMap<_, _, RandomState> = HashMap::default();
+
+    // Stitches in the color of the fabric are left out
+    for color in img.to_rgb8().pixels() {
+        if *color == fabric_color {
+            continue;
+        }
+
+        *colors.entry(*color).or_insert(0) += 1;
+    }
+
+    let backstitches = design
+        .backstitches
+        .iter()
+        .map(|backstitch| Backstitch {
+            color: flosses.nearest(backstitch.color.0),
+            ..*backstitch
+        })
+        .collect::<Vec<_>>();
+    let mut backstitch_colors: Vec<(Rgb<u8>, f64, String)> = vec![];
+    for backstitch in &backstitches {
+        match backstitch_colors
+            .iter_mut()
+            .find(|(color, _, _)| *color == backstitch.color)
+        {
+            Some((_, length, _)) => *length += backstitch.length(),
+            None => backstitch_colors.push((
+                backstitch.color,
+                backstitch.length(),
+                flosses.names[&backstitch.color].clone(),
+            )),
+        }
+    }
+    sort_legend(&mut backstitch_colors);
+
+    let specialty_stitches = design
+        .specialty_stitches
+        .iter()
+        .map(|specialty| SpecialtyStitch {
+            color: flosses.nearest(specialty.color.0),
+            ..*specialty
+        })
+        .collect::<Vec<_>>();
+    let mut specialty_colors: Vec<(SpecialtyKind, Rgb<u8>, usize, String)> = vec![];
+    for specialty in &specialty_stitches {
+        match specialty_colors
+            .iter_mut()
+            .find(|(kind, color, _, _)| *kind == specialty.kind && *color == specialty.color)
+        {
+            Some((_, _, count, _)) => *count += 1,
+            None => specialty_colors.push((
+                specialty.kind,
+                specialty.color,
+                1,
+                flosses.names[&specialty.color].clone(),
+            )),
+        }
+    }
+    specialty_colors.sort_by_key(|(kind, _, _, floss)| {
+        (
+            *kind,
+            floss.parse::<usize>().unwrap_or(usize::MAX),
+            floss.clone(),
+        )
+    });
+
+    // The specialty stitches get a heading
+    let legend_entries = colors.len()
+        + backstitch_colors.len()
+        + specialty_colors.len()
+        + !specialty_colors.is_empty() as usize;
+    let legend_slots = legend_slots(legend_entries, page.0 .0, page.1 .0);
+
+    let mut colors = colors
+        .into_iter()
+        .map(|(color, freq)| {
+            let floss = match &palette {
+                Some(palette) => palette[&color].floss.clone(),
+                None => flosses.names[&color].clone(),
+            };
+            (color, freq, floss)
+        })
+        .collect::<Vec<_>>();
+    sort_legend(&mut colors);
+    // The symbols of an imported chart win over the ones of the map
+    let reserved = symbol_map.values().copied().collect::<HashSet<_>>();
+    // Only the geometric marks get drawn without the symbol fonts
+    let available = match options.symbol_drawing {
+        SymbolDrawing::Geometric => &SYMBOLS[..GEOMETRIC_MARKS],
+        _ => &SYMBOLS[..],
+    };
+    let symbols = assign_symbols(
+        &colors,
+        available,
+        &reserved,
+        // The geometric marks are already told apart
+        options.large_print && options.symbol_drawing != SymbolDrawing::Geometric,
+        symbol_shapes,
+        |color| {
+            palette
+                .as_ref()
+                .and_then(|palette| palette.get(color))
+                .and_then(|color| color.symbol)
+                .or_else(|| symbol_map.get(&hex_color(*color)).copied())
+        },
+    )?;
+
+    if let Some(transform) = &options.proof {
+        warn_indistinguishable_colors(&colors, transform, options.metric);
+    }
+
+    Ok(Section {
+        title: design.title,
+        img,
+        preview,
+        charted,
+        mirror,
+        bookmarks: design.bookmarks,
+        sub_images,
+        colors,
+        symbols,
+        backstitches,
+        backstitch_colors,
+        specialty_stitches,
+        specialty_colors,
+        legend_slots,
+        instructions: Vec::default(),
+    })
+}
+
+// Gives every color the symbol `preferred` picks for it when it's one of the `available` ones and
+// no color before it took it already, and the first free symbol otherwise. The `reserved`
+// symbols are only given out once all the others are taken
+fn assign_symbols(
+    colors: &[(Rgb<u8>, usize, String)],
+    available: &[char],
+    reserved: &HashSet<char>,
+    distinct: bool,
+    symbol_shapes: &HashMap<char, Vec<f64>>,
+    preferred: impl Fn(&Rgb<u8>) -> Option<char>,
+) -> anyhow::Result<Vec<char>> {
+    let mut taken = HashSet::new();
+    let mut symbols = colors
+        .iter()
+        .map(|(color, _, floss)| {
+            let symbol = preferred(color)?;
+            if !available.contains(&symbol) {
+                eprintln!("The symbol {symbol} of {floss} isn't available, replacing it");
+                return None;
+            }
+
+            taken.insert(symbol).then_some(symbol)
+        })
+        .collect::<Vec<_>>();
+
+    // The other symbols only come after the distinct ones run out
+    let order = if distinct {
+        DISTINCT_SYMBOLS
+            .iter()
+            .chain(
+                available
+                    .iter()
+                    .filter(|symbol| !DISTINCT_SYMBOLS.contains(symbol)),
+            )
+            .copied()
+            .collect()
+    } else {
+        available.to_vec()
+    };
+    let mut free = order
+        .iter()
+        .filter(|symbol| !reserved.contains(*symbol))
+        .chain(order.iter().filter(|symbol| reserved.contains(*symbol)))
+        .filter(|symbol| !taken.contains(*symbol))
+        .copied()
+        .collect::<Vec<_>>();
+    if symbols.iter().filter(|symbol| symbol.is_none()).count() > free.len() {
+        anyhow::bail!(
+            "the chart has {} colors, more than the {} symbols there are to tell them apart",
+            colors.len(),
+            available.len()
+        );
+    }
+
+    // A color colorblind stitchers could take for others gets the symbol least like theirs out of
+    // the next few in line, any other color the next one
+    for idx in 0..symbols.len() {
+        if symbols[idx].is_some() {
+            continue;
+        }
+
+        let lookalikes = symbols
+            .iter()
+            .zip(colors)
+            .filter(|(_, (color, _, _))| confusable(*color, colors[idx].0))
+            .filter_map(|(symbol, _)| *symbol)
+            .collect::<Vec<_>>();
+        let unlikeness = |symbol: char| {
+            lookalikes
+                .iter()
+                .map(|other| shape_distance(symbol_shapes, symbol, *other))
+                .fold(f64::INFINITY, f64::min)
+        };
+        let pick = (1..free.len().min(CVD_SYMBOL_CHOICES)).fold(0, |best, candidate| {
+            if unlikeness(free[candidate]) > unlikeness(free[best]) {
+                candidate
+            } else {
+                best
+            }
+        });
+        symbols[idx] = Some(free.remove(pick));
+    }
+
+    Ok(symbols.into_iter().map(Option::unwrap).collect())
+}
+
+// Ink of a symbol over a grid on its em square, centered on its advance, to compare its shape
+// with the others
+fn symbol_shape(symbol: char, font_bytes: &[u8]) -> Vec<f64> {
+    const SIZE: i32 = 32;
+
+    let font = rusttype::Font::try_from_bytes(font_bytes).unwrap();
+    let scale = rusttype::Scale::uniform(SIZE as f32);
+    let glyph = font.glyph(symbol).scaled(scale);
+    let left = (SIZE as f32 - glyph.h_metrics().advance_width) / 2.0;
+    let glyph = glyph.positioned(rusttype::point(left, font.v_metrics(scale).ascent));
+
+    let mut shape = vec![0.0; (SIZE * SIZE) as usize];
+    if let Some(bounds) = glyph.pixel_bounding_box() {
+        glyph.draw(|x, y, coverage| {
+            let (x, y) = (bounds.min.x + x as i32, bounds.min.y + y as i32);
+            if (0..SIZE).contains(&x) && (0..SIZE).contains(&y) {
+                shape[(y * SIZE + x) as usize] += coverage as f64;
+            }
+        });
+    }
+
+    shape
+}
+
+// Share of the ink of two symbols that doesn't overlap, 0 for the same shape and 1 for shapes
+// that don't touch. Symbols without a shape all look the same
+fn shape_distance(symbol_shapes: &HashMap<char, Vec<f64>>, a: char, b: char) -> f64 {
+    let (Some(a), Some(b)) = (symbol_shapes.get(&a), symbol_shapes.get(&b)) else {
+        return 0.0;
+    };
+    let (apart, total) = a.iter().zip(b).fold((0.0, 0.0), |(apart, total), (a, b)| {
+        (apart + (a - b).abs(), total + a + b)
+    });
+
+    if total > 0.0 {
+        apart / total
+    } else {
+        0.0
+    }
+}
+
+// Image of an imported chart, with its unstitched cells transparent
+fn pattern_image(pattern: &Pattern) -> RgbaImage {
+    RgbaImage::from_fn(pattern.size.x, pattern.size.y, |x, y| {
+        match pattern.cells[(y * pattern.size.x + x) as usize] {
+            Some(idx) => {
+                let [r, g, b] = pattern.colors[idx].rgb;
+                Rgba([r, g, b, 255])
+            }
+            None => Rgba([0, 0, 0, 0]),
+        }
+    })
+}
+
+// Knitters call the yarn most of a piece is knit in the main color and the others contrast colors,
+// numbered from the most used one over all the designs. A floss number means nothing to them, the
+// names of a palette file follow the yarn
+fn name_yarns(sections: &mut [Section], named_colors: bool) {
+    let mut totals: HashMap<Rgb<u8>, usize> = HashMap::default();
+    for (color, freq, _) in sections.iter().flat_map(|section| &section.colors) {
+        *totals.entry(*color).or_default() += freq;
+    }
+    let mut totals = totals.into_iter().collect::<Vec<_>>();
+    totals.sort_by_key(|(color, freq)| (Reverse(*freq), color.0));
+    let order = totals
+        .iter()
+        .enumerate()
+        .map(|(idx, (color, _))| (*color, idx))
+        .collect::<HashMap<_, _>>();
+
+    for section in sections {
+        for (color, _, floss) in &mut section.colors {
+            let yarn = match order[color] {
+                0 => "MC".to_string(),
+                idx => format!("CC{idx}"),
+            };
+            *floss = if named_colors {
+                format!("{yarn} {floss}")
+            } else {
+                yarn
+            };
+        }
+        section.colors.sort_by_key(|(color, _, _)| order[color]);
+    }
+}
+
+// Corner to corner crochet works the tiles in diagonal rows from the bottom left corner, turning
+// at the end of each one. Every row as the runs of the same color in the order they are worked,
+// the even rows going up to the left
+fn c2c_rows(section: &Section) -> Vec<String> {
+    let img = section.img.to_rgb8();
+    let size = UVec2::new(img.width(), img.height());
+    let names = section
+        .colors
+        .iter()
+        .map(|(color, _, yarn)| (*color, yarn.as_str()))
+        .collect::<HashMap<_, _>>();
+
+    (0..size.x + size.y - 1)
+        .map(|diagonal| {
+            // From the bottom right end of the diagonal to its top left one
+            let mut tiles = (0..=diagonal)
+                .map(|step| UVec2::new(diagonal - step, step))
+                .filter(|tile| tile.cmplt(size).all())
+                .map(|tile| {
+                    let color = img.get_pixel(tile.x, size.y - 1 - tile.y);
+                    names.get(color).copied().unwrap_or("background")
+                })
+                .collect::<Vec<_>>();
+            let row = diagonal + 1;
+            let direction = if row % 2 == 0 {
+                '↖'
+            } else {
+                tiles.reverse();
+                '↘'
+            };
+
+            let mut runs: Vec<(usize, &str)> = vec![];
+            for yarn in tiles {
+                match runs.last_mut() {
+                    Some((count, last)) if *last == yarn => *count += 1,
+                    _ => runs.push((1, yarn)),
+                }
+            }
+            let runs = runs
+                .iter()
+                .map(|(count, yarn)| format!("{count} {yarn}"))
+                .collect::<Vec<_>>();
+
+            format!("Row {row} ({direction}): {}", runs.join(", "))
+        })
+        .collect()
+}
+
+// Wraps the written rows after their commas to the width of a page, and splits the lines into
+// pages
+fn paginate_instructions(
+    rows: Vec<String>,
+    page: (Mm, Mm),
+    font: &[u8],
+) -> Vec<Vec<(String, bool)>> {
+    let width = page.0 .0 - 20.0;
+    let mut lines = vec![];
+    for row in rows {
+        let mut line = String::new();
+        let mut continued = false;
+        for part in row.split_inclusive(", ") {
+            let available = if continued {
+                width - INSTRUCTION_INDENT
+            } else {
+                width
+            };
+            if !line.is_empty()
+                && text_width(&format!("{line}{part}"), INSTRUCTION_SIZE, font) > available
+            {
+                lines.push((std::mem::take(&mut line), continued));
+                continued = true;
+            }
+            line.push_str(part);
+        }
+        lines.push((line, continued));
+    }
+
+    lines
+        .chunks((((page.1 .0 - INSTRUCTION_MARGINS) / INSTRUCTION_LINE) as usize).max(1))
+        .map(|page| page.to_vec())
+        .collect()
+}
+
+// Name and count of a legend entry, with its Anchor equivalent or the grams of beads it takes, and
+// the size that fits them in a legend column
+fn legend_text(
+    floss: &str,
+    freq: usize,
+    conversions: &HashMap<String, String>,
+    craft: Craft,
+) -> (String, f64) {
+    match conversions.get(floss) {
+        Some(anchor) => (format!("{} / {} ({} ct)", floss, anchor, freq), 13.0),
+        None if craft == Craft::BeadLoom => (
+            format!(
+                "{} ({} ct, {:.1} g)",
+                floss,
+                freq,
+                freq as f64 / DELICAS_PER_GRAM
+            ),
+            13.0,
+        ),
+        None => (format!("{} ({} ct)", floss, freq), 16.0),
+    }
+}
+
+// Name of the floss and how many skeins of it the stitches take, as much floss as `crosses` full
+// crosses, whichever are known, along with the size that fits them below the legend entry
+fn legend_details(
+    floss: &str,
+    crosses: f64,
+    dmc_names: &HashMap<String, String>,
+    skein_stitches: Option<f64>,
+    font: &[u8],
+) -> Option<(String, f64)> {
+    let skeins = skein_stitches.map(|skein| format!("{:.1} skeins", crosses / skein));
+    let details = match (dmc_names.get(floss), skeins) {
+        (Some(name), Some(skeins)) => format!("{name}, {skeins}"),
+        (Some(name), None) => name.clone(),
+        (None, Some(skeins)) => skeins,
+        (None, None) => return None,
+    };
+    let width = text_width(&details, LEGEND_DETAILS_SIZE, font);
+
+    Some((
+        details,
+        LEGEND_DETAILS_SIZE * (LEGEND_DETAILS_WIDTH / width).min(1.0),
+    ))
+}
+
+// Full crosses a skein makes on fabric of `fabric_count` stitches per inch, stitched with
+// `strands` strands
+fn skein_stitches(fabric_count: u32, strands: u32) -> f64 {
+    let stitch = MMPI / fabric_count as f64;
+    SKEIN_LENGTH * SKEIN_STRANDS as f64 / (STITCH_THREAD * stitch * strands as f64)
+}
+
+// Numbered flosses in numeric order, named ones after them
+fn sort_legend<T>(colors: &mut [(Rgb<u8>, T, String)]) {
+    colors
+        .sort_by_key(|(_, _, floss)| (floss.parse::<usize>().unwrap_or(usize::MAX), floss.clone()));
+}
+
+// The document being drawn, along with what all of its pages share
+struct PdfPages<'a> {
+    doc: &'a PdfDocumentReference,
+    options: &'a PdfOptions,
+    // Every page but the sideways preview and overview pages, as laid out before the large print
+    // scaling
+    page: (Mm, Mm),
+    print_scale: f64,
+    total_pages: usize,
+    fonts: &'a [(IndirectFontRef, &'a [u8])],
+    symbol_font_map: &'a HashMap<char, (IndirectFontRef, &'a [u8])>,
+    symbol_styles: &'a HashMap<char, SymbolStyle>,
+    conversions: &'a HashMap<String, String>,
+    dmc_names: &'a HashMap<String, String>,
+    // Stitches charted on each part page
+    page_stitches: UVec2,
+    // Most dots per stitch the colors get drawn at
+    max_scale: u32,
+}
+
+impl PdfPages<'_> {
+    // Adds a page, turned on its side when `sideways`, with its layer scaled for large print and
+    // the watermark over it when `watermarked`
+    fn add_page(
+        &self,
+        name: &str,
+        sideways: bool,
+        watermarked: bool,
+    ) -> (PdfPageIndex, PdfLayerReference) {
+        let size = if sideways {
+            (self.page.1, self.page.0)
+        } else {
+            self.page
+        };
+        let (page_index, layer_index) =
+            self.doc
+                .add_page(size.0 * self.print_scale, size.1 * self.print_scale, name);
+        let layer = scaled_layer(
+            self.doc.get_page(page_index).get_layer(layer_index),
+            self.print_scale,
+        );
+        if let Some(watermark) = self.options.watermark.as_deref().filter(|_| watermarked) {
+            render_watermark(
+                self.doc,
+                page_index,
+                size,
+                self.print_scale,
+                watermark,
+                &self.fonts[1],
+            );
+        }
+
+        (page_index, layer)
+    }
+
+    // Adds a page of the legend or the shopping list, with the ruler under its header
+    fn add_list_page(
+        &self,
+        name: &str,
+        title: &str,
+        page_number: usize,
+    ) -> (PdfPageIndex, PdfLayerReference) {
+        let (page_index, layer) = self.add_page(name, false, false);
+        self.render_header(&layer, title);
+        ruler(
+            &layer,
+            (Mm(10.0), self.page.1 - Mm(18.0)),
+            (self.page.0 - Mm(10.0), self.page.1 - Mm(18.0)),
+        );
+        self.render_page_number(&layer, page_number, (self.page.0 / 2.0, Mm(12.0)));
+
+        (page_index, layer)
+    }
+
+    // Title at the top left of a page and the branding at its top right
+    fn render_header(&self, layer: &PdfLayerReference, title: &str) {
+        render_left_text(
+            layer,
+            title,
+            16.0,
+            (Mm(10.0), self.page.1 - Mm(15.0)),
+            &self.fonts[0],
+        );
+
+        if let Some(branding) = &self.options.branding.name {
+            render_right_text(
+                layer,
+                branding,
+                16.0,
+                (self.page.0 - Mm(10.0), self.page.1 - Mm(15.0)),
+                &self.fonts[1],
+            );
+        }
+    }
+
+    // The header of a sideways page, along its left edge
+    fn render_sideways_header(&self, layer: &PdfLayerReference, title: &str) {
+        render_ccw_rotated_start(layer, title, 24.0, (Mm(15.0), Mm(15.0)), &self.fonts[0]);
+
+        if let Some(branding) = &self.options.branding.name {
+            render_ccw_rotated_end(
+                layer,
+                branding,
+                24.0,
+                (Mm(15.0), self.page.0 - Mm(15.0)),
+                &self.fonts[1],
+            );
+        }
+    }
+
+    fn render_page_number(&self, layer: &PdfLayerReference, page_number: usize, center: (Mm, Mm)) {
+        render_centered_text(
+            layer,
+            &format!("{} / {}", page_number, self.total_pages),
+            18.0,
+            center,
+            &self.fonts[1],
+        );
+    }
+
+    // Draws the chart layout onto `layer`
+    fn chart<'b>(&'b self, layer: &'b PdfLayerReference) -> PdfChart<'b> {
+        PdfChart {
+            layer,
+            fonts: self.fonts,
+            symbol_font_map: self.symbol_font_map,
+            symbol_styles: self.symbol_styles,
+            style: self.options.chart_style,
+            tint: self.options.ink_saver,
+            stroke: None,
+            check_boxes: Vec::new(),
+        }
+    }
+
+    // Name and count of a legend entry along with its details below it, `crosses` being as much
+    // floss as that many full crosses
+    fn render_legend_text(
+        &self,
+        layer: &PdfLayerReference,
+        (text, size): (String, f64),
+        floss: &str,
+        crosses: f64,
+        (left, top): (Mm, Mm),
+    ) {
+        let regular = &self.fonts[0];
+        layer.use_text(text, size, Mm(32.0) + left, top - Mm(2.0), &regular.0);
+
+        if let Some((details, size)) = legend_details(
+            floss,
+            crosses,
+            self.dmc_names,
+            self.options.skein_stitches,
+            regular.1,
+        ) {
+            layer.use_text(details, size, Mm(32.0) + left, top - Mm(6.0), &regular.0);
+        }
+    }
+
+    // Every floss of a bundle with the stitches of all of its designs, laid out like the legend
+    fn render_shopping_list(
+        &self,
+        colors: &[(Rgb<u8>, usize, String)],
+        slots: &[LegendSlot],
+        designs: usize,
+    ) {
+        let (page, fonts) = (self.page, self.fonts);
+        let title = &self.options.title;
+        let (curr_page, mut layer) = self.add_list_page("shopping list", title, 2);
+        self.doc.add_bookmark("Shopping List", curr_page);
+        let mut page_idx = 0;
+
+        semi_underlined_text(
+            &layer,
+            &format!(
+                "Shopping list: {} Colors for {} Designs",
+                colors.len(),
+                designs
+            ),
+            0..13,
+            (Mm(10.0), page.1 - Mm(27.0)),
+            18.0,
+            &fonts[0],
+        );
+
+        ruler(
+            &layer,
+            (Mm(10.0), page.1 - Mm(53.0)),
+            (page.0 - Mm(10.0), page.1 - Mm(53.0)),
+        );
+
+        for ((color, freq, floss), slot) in colors.iter().zip(slots) {
+            if slot.page != page_idx {
+                page_idx = slot.page;
+                layer = self.add_list_page("shopping list", title, 2 + page_idx).1;
+            }
+
+            let position = slot.position(page.1 .0);
+            let (left, top) = (Mm(position.x), Mm(position.y));
+
+            render_swatch(&layer, color, left, top);
+
+            if slot.row == 0 && !self.conversions.is_empty() {
+                layer.use_text(
+                    "DMC / Anchor",
+                    10.0,
+                    Mm(32.0) + left,
+                    top + Mm(4.0),
+                    &fonts[0].0,
+                );
+            }
+
+            self.render_legend_text(
+                &layer,
+                legend_text(floss, *freq, self.conversions, self.options.craft),
+                floss,
+                *freq as f64,
+                (left, top),
+            );
+        }
+    }
+
+    // Colors charted on a part page in legend order, with their symbols and flosses, in the strip
+    // below the chart. The last entry counts the colors left when they don't all fit
+    fn render_mini_legend(
+        &self,
+        layer: &PdfLayerReference,
+        sub_image: &RgbImage,
+        colors: &[(Rgb<u8>, usize, String)],
+        symbols: &[char],
+    ) {
+        let font = &self.fonts[0];
+        let used = sub_image.pixels().collect::<HashSet<_>>();
+        let entries = colors
+            .iter()
+            .zip(symbols)
+            .filter(|((color, _, _), _)| used.contains(color))
+            .collect::<Vec<_>>();
+        let text_room = entries
+            .iter()
+            .map(|((_, _, floss), _)| text_width(floss, MINI_LEGEND_SIZE, font.1))
+            .fold(text_width("+99 more", MINI_LEGEND_SIZE, font.1), f64::max);
+        let entry_width = MINI_LEGEND_SWATCH + 1.0 + text_room + 3.0;
+        let columns = (((self.page.0 .0 - 20.0) / entry_width) as usize).max(1);
+        let room = columns * MINI_LEGEND_ROWS;
+        let shown = if entries.len() > room {
+            room - 1
+        } else {
+            entries.len()
+        };
+
+        // Left edge and middle of every entry
+        let position = |idx: usize| {
+            (
+                10.0 + (idx % columns) as f64 * entry_width,
+                PART_MARGINS / 2.0 + MINI_LEGEND_HEIGHT
+                    - MINI_LEGEND_ROW * ((idx / columns) as f64 + 0.5),
+            )
+        };
+
+        for (idx, ((color, _, floss), symbol)) in entries.iter().take(shown).enumerate() {
+            let (left, middle) = position(idx);
+            let center = (Mm(left + MINI_LEGEND_SWATCH / 2.0), Mm(middle));
+
+            layer.set_fill_color(printpdf::Color::Rgb(printpdf::Rgb {
+                r: color.0[0] as f64 / 255.0,
+                g: color.0[1] as f64 / 255.0,
+                b: color.0[2] as f64 / 255.0,
+                icc_profile: None,
+            }));
+            layer.add_shape(Line {
+                points: printpdf::calculate_points_for_rect(
+                    Mm(MINI_LEGEND_SWATCH),
+                    Mm(MINI_LEGEND_SWATCH),
+                    center.0,
+                    center.1,
+                ),
+                is_closed: true,
+                has_fill: true,
+                has_stroke: true,
+                is_clipping_path: false,
+            });
+
+            render_symbol(
+                layer,
+                **symbol,
+                MINI_LEGEND_SWATCH * 2.0,
+                (center.0 - Mm(0.375), center.1 - Mm(0.75)),
+                self.symbol_font_map.get(*symbol),
+                &self.symbol_styles[symbol],
+                symbol_ink(color),
+            );
+
+            layer.set_fill_color(printpdf::Color::Rgb(printpdf::Rgb {
+                r: 0.0,
+                g: 0.0,
+                b: 0.0,
+                icc_profile: None,
+            }));
+            render_left_text(
+                layer,
+                floss,
+                MINI_LEGEND_SIZE,
+                (Mm(left + MINI_LEGEND_SWATCH + 1.0), Mm(middle - 0.8)),
+                font,
+            );
+        }
+
+        if shown < entries.len() {
+            let (left, middle) = position(shown);
+            render_left_text(
+                layer,
+                &format!("+{} more", entries.len() - shown),
+                MINI_LEGEND_SIZE,
+                (Mm(left), Mm(middle - 0.8)),
+                font,
+            );
+        }
+    }
+}
+
+// Preview, overview, legend and part pages of one design, numbered from `first_page`. Tick boxes
+// get drawn and collected when `progress_boxes` is given
+fn render_section(
+    pages: &PdfPages,
+    section: &Section,
+    bundle: bool,
+    first_page: usize,
+    mut progress_boxes: Option<&mut Vec<CheckBox>>,
+) -> GridMap {
+    let section_pages = SectionPages::new(pages, section, bundle, first_page);
+
+    section_pages.render_preview();
+    section_pages.render_overview();
+    section_pages.render_legend(progress_boxes.as_deref_mut());
+
+    let mut grid_map = section_pages.grid_map();
+    // The outlines of the page map link to the part pages once they are there
+    let page_map = section_pages.render_page_map();
+    let part_pages = section_pages.render_parts(&mut grid_map, progress_boxes);
+    if let Some((map_page, outlines)) = page_map {
+        link_pages(
+            &pages.doc.get_page(map_page),
+            pages.print_scale,
+            &outlines,
+            &part_pages,
+        );
+    }
+
+    section_pages.render_written_rows();
+    if pages.options.assembly_marks {
+        section_pages.render_assembly(&grid_map.pages, &part_pages);
+    }
+    if pages.options.highlight_pages {
+        section_pages.render_highlights();
+    }
+
+    grid_map
+}
+
+// The pages of one design, numbered from `first_page`
+struct SectionPages<'a> {
+    pages: &'a PdfPages<'a>,
+    section: &'a Section,
+    // The outline entries of the designs of a bundle go under their titles
+    bundle: bool,
+    first_page: usize,
+    // Wide designs get the whole of the preview and overview pages turned sideways
+    sideways: bool,
+    color_symbol_map: HashMap<Rgb<u8>, char>,
+    overlays: ChartOverlays<'a>,
+}
+
+impl<'a> SectionPages<'a> {
+    fn new(pages: &'a PdfPages<'a>, section: &'a Section, bundle: bool, first_page: usize) -> Self {
+        let options = pages.options;
+        let img = &section.img;
+
+        SectionPages {
+            pages,
+            section,
+            bundle,
+            first_page,
+            sideways: options.orientation == Orientation::Auto
+                && (img.height() as f64 * options.pixel_aspect.y)
+                    < img.width() as f64 * options.pixel_aspect.x,
+            color_symbol_map: section
+                .colors
+                .iter()
+                .zip(&section.symbols)
+                .map(|((color, _, _), symbol)| (*color, *symbol))
+                .collect(),
+            overlays: ChartOverlays {
+                // Rows count from the bottom of the whole design, of which a mirrored chart is
+                // the top
+                rows: match options.craft {
+                    Craft::CrossStitch | Craft::C2c | Craft::BeadLoom => RowNumbers::Top,
+                    Craft::Knitting => RowNumbers::Knitting(img.height()),
+                },
+                backstitches: &section.backstitches,
+                specialty_stitches: &section.specialty_stitches,
+                bookmarks: &section.bookmarks,
+            },
+        }
+    }
+
+    // Entry of the document outline, under the title of the design in a bundle
+    fn outline(&self, name: &str, page_index: PdfPageIndex) {
+        if self.bundle {
+            self.pages
+                .doc
+                .add_bookmark(format!("{}: {name}", self.section.title), page_index);
+        } else {
+            self.pages.doc.add_bookmark(name, page_index);
+        }
+    }
+
+    // A mirrored chart has its middle on the edge of the charted half
+    fn design_size(&self) -> UVec2 {
+        UVec2::new(self.section.img.width(), self.section.img.height())
+    }
+
+    fn first_part_page(&self) -> usize {
+        self.first_page + 2 + self.section.legend_pages() + self.section.has_page_map() as usize
+    }
+
+    // Grid, symbols and overlays over the colors of `img`, the part of the design starting at
+    // the stitch `first`
+    fn render_chart(
+        &self,
+        layer: &PdfLayerReference,
+        img: &RgbImage,
+        first: UVec2,
+        placement: &Placement,
+    ) {
+        draw_chart(
+            &mut self.pages.chart(layer),
+            img,
+            first,
+            self.design_size(),
+            placement,
+            &self.color_symbol_map,
+            &self.overlays,
+        );
+    }
+
+    // The design as it will look once stitched
+    fn render_preview(&self) {
+        let (pages, page) = (self.pages, self.pages.page);
+        let (curr_page, layer) = pages.add_page("preview", self.sideways, true);
+        self.outline("Preview", curr_page);
+
+        let area = if self.sideways {
+            pages.render_page_number(&layer, self.first_page, (page.1 / 2.0, Mm(5.0)));
+            pages.render_sideways_header(&layer, &self.section.title);
+
+            ChartArea {
+                left: 10.0,
+                right: page.1 .0,
+                top: 0.0,
+                bottom: page.0 .0 - 10.0,
+                page_height: page.0 .0 - 5.0,
+            }
+        } else {
+            pages.render_page_number(&layer, self.first_page, (page.0 / 2.0, Mm(7.0)));
+            pages.render_header(&layer, &self.section.title);
+
+            ChartArea {
+                left: 0.0,
+                right: page.0 .0,
+                top: 10.0,
+                bottom: page.1 .0 - 10.0,
+                page_height: page.1 .0 - 5.0,
+            }
+        };
+
+        render_image_centered(
+            layer,
+            &self.section.preview,
+            pages.options.pixel_aspect,
+            pages.max_scale,
+            &area,
+        );
+    }
+
+    // The whole chart on one page, with the bookmarks listed below it
+    fn render_overview(&self) {
+        let (pages, page, options) = (self.pages, self.pages.page, self.pages.options);
+        let section = self.section;
+        let (curr_page, layer) = pages.add_page("preview", self.sideways, true);
+        self.outline("Overview", curr_page);
+
+        // Room left below the overview for the bookmark list
+        let callouts_height = if section.bookmarks.is_empty() {
+            0.0
+        } else {
+            BOOKMARK_LINE * section.bookmarks.len() as f64 + 18.0
+        };
+        let (area, callouts) = if self.sideways {
+            pages.render_page_number(&layer, self.first_page + 1, (page.1 / 2.0, Mm(5.0)));
+            pages.render_sideways_header(&layer, &section.title);
+
+            let area = ChartArea {
+                left: 10.0,
+                right: page.1 .0,
+                top: 0.0,
+                bottom: page.0 .0 - 10.0 - callouts_height,
+                page_height: page.0 .0 - 5.0,
+            };
+            let callouts = (Mm(25.0), page.0 - Mm(area.bottom));
+            (area, callouts)
+        } else {
+            pages.render_page_number(&layer, self.first_page + 1, (page.0 / 2.0, Mm(7.0)));
+            pages.render_header(&layer, &section.title);
+
+            let area = ChartArea {
+                left: 0.0,
+                right: page.0 .0,
+                top: 20.0,
+                bottom: page.1 .0 - callouts_height,
+                page_height: page.1 .0,
+            };
+            let callouts = (Mm(10.0), page.1 - Mm(area.bottom));
+            (area, callouts)
+        };
+
+        let placement = Placement::new(
+            UVec2::new(section.preview.width(), section.preview.height()),
+            &area,
+        )
+        .stretched(options.pixel_aspect);
+        if options.chart_style != ChartStyle::SymbolsOnly {
+            render_image(
+                layer.clone(),
+                &tint_image(&section.preview, options.ink_saver),
+                &placement,
+                pages.max_scale,
+            );
+        }
+
+        self.render_chart(&layer, &section.img.to_rgb8(), UVec2::ZERO, &placement);
+
+        render_bookmark_callouts(&layer, &section.bookmarks, callouts, &pages.fonts[0]);
+    }
+
+    // Size and cloth of the design, then every color with its symbol, floss and count, the
+    // backstitches and the specialty stitches, over as many pages as they take
+    fn render_legend(&self, mut progress_boxes: Option<&mut Vec<CheckBox>>) {
+        let (pages, page, options, fonts) = (
+            self.pages,
+            self.pages.page,
+            self.pages.options,
+            self.pages.fonts,
+        );
+        let Section {
+            title,
+            img,
+            colors,
+            symbols,
+            backstitch_colors,
+            specialty_colors,
+            legend_slots,
+            ..
+        } = self.section;
+        let (curr_page, mut layer) = pages.add_list_page("threads", title, self.first_page + 2);
+        self.outline("Legend", curr_page);
+
+        semi_underlined_text(
+            &layer,
+            &format!("Dimension: {}w x {}h", img.width(), img.height()),
+            0..9,
+            (Mm(10.0), page.1 - Mm(27.0)),
+            18.0,
+            &fonts[0],
+        );
+
+        // In centimeters
+        let finished_size = DVec2::new(img.width() as f64, img.height() as f64)
+            / options.fabric_count as f64
+            * MMPI
+            / 10.0;
+        semi_underlined_text(
+            &layer,
+            &format!(
+                "Finished Size: {:.2} cm x {:.2} cm",
+                finished_size.x, finished_size.y
+            ),
+            0..13,
+            (Mm(10.0), page.1 - Mm(37.0)),
+            18.0,
+            &fonts[0],
+        );
+
+        if options.craft == Craft::CrossStitch {
+            let cut_size = finished_size + options.fabric_margin * 2.0;
+            semi_underlined_text(
+                &layer,
+                &format!("Cut Size: {:.2} cm x {:.2} cm", cut_size.x, cut_size.y),
+                0..8,
+                (Mm(10.0), page.1 - Mm(47.0)),
+                18.0,
+                &fonts[0],
+            );
+        }
+
+        semi_underlined_text(
+            &layer,
+            &match options.craft {
+                Craft::CrossStitch => format!(
+                    "Cloth: {}",
+                    options.fabric_type.description(options.fabric_count)
+                ),
+                Craft::Knitting => "Rows: RS odd, WS even".to_string(),
+                Craft::C2c => "Start: bottom left corner".to_string(),
+                Craft::BeadLoom => "Beads: Miyuki Delica 11/0".to_string(),
+            },
+            0..5,
+            (page.0 / 2.0 + Mm(15.0), page.1 - Mm(27.0)),
+            18.0,
+            &fonts[0],
+        );
+
+        semi_underlined_text(
+            &layer,
+            &format!("No. of colors: {} Colors", colors.len()),
+            0..13,
+            (page.0 / 2.0 + Mm(15.0), page.1 - Mm(37.0)),
+            18.0,
+            &fonts[0],
+        );
+
+        ruler(
+            &layer,
+            (Mm(10.0), page.1 - Mm(53.0)),
+            (page.0 - Mm(10.0), page.1 - Mm(53.0)),
+        );
+
+        let mut page_idx = 0;
+
+        for (idx, slot) in legend_slots.iter().enumerate() {
+            if slot.page != page_idx {
+                page_idx = slot.page;
+                layer = pages
+                    .add_list_page("colors page", title, self.first_page + 2 + page_idx)
+                    .1;
+            }
+
+            let position = slot.position(page.1 .0);
+            let (left, top) = (Mm(position.x), Mm(position.y));
+
+            let heading =
+                !specialty_colors.is_empty() && idx == colors.len() + backstitch_colors.len();
+            if let Some(check_boxes) = progress_boxes.as_deref_mut().filter(|_| !heading) {
+                let center = DVec2::new(left.0 + 7.0, top.0);
+                render_tick_box(&layer, center, 4.0);
+                check_boxes.push(CheckBox::new(&layer, center, 4.0));
+            }
+
+            // The backstitches follow the colors, then the specialty stitches under their heading
+            let Some((color, freq, floss)) = colors.get(idx) else {
+                let (text, crosses, floss) = if let Some((color, length, floss)) =
+                    backstitch_colors.get(idx - colors.len())
+                {
+                    render_backstitch_swatch(&layer, color, left, top);
+                    (
+                        format!("{floss} backstitch"),
+                        length * BACKSTITCH_THREAD / STITCH_THREAD,
+                        floss,
+                    )
+                } else {
+                    let Some(specialty_idx) =
+                        (idx - colors.len() - backstitch_colors.len()).checked_sub(1)
+                    else {
+                        layer.use_text(
+                            "Specialty stitches",
+                            13.0,
+                            Mm(12.0) + left,
+                            top - Mm(2.0),
+                            &fonts[1].0,
+                        );
+                        continue;
+                    };
+
+                    let (kind, color, count, floss) = &specialty_colors[specialty_idx];
+                    render_specialty_marker(
+                        &layer,
+                        *kind,
+                        color,
+                        DVec2::new(12.0 + left.0, top.0 - 3.0),
+                        DVec2::splat(6.0),
+                    );
+                    let thread = match kind {
+                        SpecialtyKind::FrenchKnot => FRENCH_KNOT_THREAD,
+                        SpecialtyKind::LazyDaisy => LAZY_DAISY_THREAD,
+                    };
+                    (
+                        format!("{floss} {} ({count})", kind.name()),
+                        *count as f64 * thread / STITCH_THREAD,
+                        floss,
+                    )
+                };
+
+                pages.render_legend_text(&layer, (text, 13.0), floss, crosses, (left, top));
+                continue;
+            };
+
+            render_swatch(&layer, color, left, top);
+
+            render_symbol(
+                &layer,
+                symbols[idx],
+                12.0,
+                (Mm(14.25) + left, top - Mm(1.5)),
+                pages.symbol_font_map.get(&symbols[idx]),
+                &pages.symbol_styles[&symbols[idx]],
+                symbol_ink(color),
+            );
+
+            layer.set_fill_color(printpdf::Color::Rgb(printpdf::Rgb {
+                r: 0.0,
+                g: 0.0,
+                b: 0.0,
+                icc_profile: None,
+            }));
+
+            // Column header naming the two brands of the conversion
+            if slot.row == 0 && !pages.conversions.is_empty() {
+                layer.use_text(
+                    "DMC / Anchor",
+                    10.0,
+                    Mm(32.0) + left,
+                    top + Mm(4.0),
+                    &fonts[0].0,
+                );
+            }
+
+            pages.render_legend_text(
+                &layer,
+                legend_text(floss, *freq, pages.conversions, options.craft),
+                floss,
+                *freq as f64,
+                (left, top),
+            );
+        }
+    }
+
+    // Symbol and color of every cell, the part pages get listed as they are added
+    fn grid_map(&self) -> GridMap {
+        let section = self.section;
+        let legend_idx = section
+            .colors
+            .iter()
+            .enumerate()
+            .map(|(idx, (color, _, _))| (*color, idx))
+            .collect::<HashMap<_, _>>();
+
+        GridMap {
+            width: section.img.width(),
+            height: section.img.height(),
+            colors: section
+                .colors
+                .iter()
+                .map(|(color, freq, floss)| GridColor {
+                    symbol: self.color_symbol_map[color],
+                    floss: floss.clone(),
+                    color: hex_color(*color),
+                    stitches: *freq,
+                })
+                .collect(),
+            cells: section
+                .img
+                .to_rgb8()
+                .pixels()
+                .map(|color| legend_idx.get(color).copied())
+                .collect(),
+            pages: Vec::default(),
+            mirror: section.mirror,
+            bookmarks: section.bookmarks.clone(),
+        }
+    }
+
+    // Charts split over several pages get a map of them, along with the outlines of the parts
+    // on it
+    fn render_page_map(&self) -> Option<(PdfPageIndex, Vec<(DVec2, DVec2)>)> {
+        let (pages, page, section) = (self.pages, self.pages.page, self.section);
+        if !section.has_page_map() {
+            return None;
+        }
+
+        let first_part_page = self.first_part_page();
+        let (curr_page, layer) = pages.add_page("page map", false, true);
+        self.outline("Page Map", curr_page);
+
+        pages.render_header(&layer, &section.title);
+
+        render_centered_text(
+            &layer,
+            "Page Map",
+            24.0,
+            (page.0 / 2.0, page.1 - Mm(30.0)),
+            &pages.fonts[1],
+        );
+
+        render_centered_text(
+            &layer,
+            "Every part of the chart is on the page with its number.",
+            12.0,
+            (page.0 / 2.0, page.1 - Mm(40.0)),
+            &pages.fonts[0],
+        );
+
+        // Faded so the page numbers stand out
+        let placement = Placement::new(
+            UVec2::new(section.charted.width(), section.charted.height()),
+            &ChartArea {
+                left: 10.0,
+                right: page.0 .0 - 10.0,
+                top: 45.0,
+                bottom: page.1 .0 - 20.0,
+                page_height: page.1 .0,
+            },
+        )
+        .stretched(pages.options.pixel_aspect);
+        render_image(
+            layer.clone(),
+            &tint_image(&section.charted, Some(0.35)),
+            &placement,
+            pages.max_scale,
+        );
+
+        let part_pages = section
+            .sub_images
+            .iter()
+            .enumerate()
+            .map(|(idx, (sub_image, _, first))| {
+                (
+                    first_part_page + idx,
+                    *first,
+                    UVec2::new(sub_image.width(), sub_image.height()),
+                )
+            })
+            .collect::<Vec<_>>();
+        let outlines = draw_page_map(&mut pages.chart(&layer), &part_pages, &placement);
+
+        pages.render_page_number(&layer, first_part_page - 1, (page.0 / 2.0, Mm(12.0)));
+
+        Some((curr_page, outlines))
+    }
+
+    // A page for every part of the chart, listed in `grid_map` as they are added
+    fn render_parts(
+        &self,
+        grid_map: &mut GridMap,
+        mut progress_boxes: Option<&mut Vec<CheckBox>>,
+    ) -> Vec<PdfPageIndex> {
+        let (pages, page, options) = (self.pages, self.pages.page, self.pages.options);
+        let section = self.section;
+        let chart_size = UVec2::new(section.charted.width(), section.charted.height());
+        let first_part_page = self.first_part_page();
+        let mirror_instruction = section
+            .mirror
+            .map(|mirror| mirror_instruction(mirror, &section.img));
+
+        let mut part_pages = Vec::with_capacity(section.sub_images.len());
+        for (idx, &(ref sub_image, _, first)) in section.sub_images.iter().enumerate() {
+            let (curr_page, layer) = pages.add_page("threads", false, true);
+            part_pages.push(curr_page);
+            self.outline(&format!("Part {}", idx + 1), curr_page);
+
+            grid_map.pages.push(GridPage {
+                page: first_part_page + idx,
+                x: first.x,
+                y: first.y,
+                width: sub_image.width(),
+                height: sub_image.height(),
+            });
+
+            pages.render_header(&layer, &section.title);
+            pages.render_page_number(&layer, first_part_page + idx, (page.0 / 2.0, Mm(12.0)));
+
+            let stitches = UVec2::new(sub_image.width(), sub_image.height());
+            let placement = section
+                .part_placement(
+                    stitches,
+                    page,
+                    pages.page_stitches,
+                    options.true_size,
+                    options.assembly_marks,
+                )
+                .stretched(options.pixel_aspect);
+            if options.chart_style != ChartStyle::SymbolsOnly {
+                render_image(
+                    layer.clone(),
+                    &tint_image(&sub_image.clone().into(), options.ink_saver),
+                    &placement,
+                    pages.max_scale,
+                );
+            }
+
+            self.render_chart(&layer, sub_image, first, &placement);
+            pages.render_mini_legend(&layer, sub_image, &section.colors, &section.symbols);
+
+            if let Some(check_boxes) = progress_boxes.as_deref_mut() {
+                let mut chart = pages.chart(&layer);
+                draw_block_boxes(&mut chart, first, stitches, &placement);
+                check_boxes.append(&mut chart.check_boxes);
+            }
+
+            if options.true_size.is_some() || options.assembly_marks {
+                let mut chart = pages.chart(&layer);
+                draw_registration_marks(&mut chart, &placement);
+
+                if options.assembly_marks {
+                    let next = first + stitches;
+                    draw_glue_edges(
+                        &mut chart,
+                        &placement,
+                        next.x < chart_size.x,
+                        next.y < chart_size.y,
+                    );
+                }
+            }
+
+            if let Some(fabric_count) = options.true_size {
+                render_left_text(
+                    &layer,
+                    &format!("Actual size on {fabric_count} count fabric, print at 100% scale"),
+                    10.0,
+                    (Mm(10.0), page.1 - Mm(21.0)),
+                    &pages.fonts[2],
+                );
+            }
+
+            if let Some(instruction) = &mirror_instruction {
+                render_centered_text(
+                    &layer,
+                    instruction,
+                    12.0,
+                    (page.0 / 2.0, Mm(22.0)),
+                    &pages.fonts[2],
+                );
+            }
+        }
+
+        part_pages
+    }
+
+    // The rows written out, for the crafts that have them
+    fn render_written_rows(&self) {
+        let (pages, page, section) = (self.pages, self.pages.page, self.section);
+        let first_rows_page = self.first_part_page() + section.sub_images.len();
+
+        for (idx, lines) in section.instructions.iter().enumerate() {
+            let (curr_page, layer) = pages.add_page("rows", false, false);
+            if idx == 0 {
+                self.outline("Written Rows", curr_page);
+            }
+
+            pages.render_header(&layer, &section.title);
+
+            render_centered_text(
+                &layer,
+                "Written Rows",
+                24.0,
+                (page.0 / 2.0, page.1 - Mm(30.0)),
+                &pages.fonts[1],
+            );
+
+            for (line_idx, (line, continued)) in lines.iter().enumerate() {
+                let left = if *continued {
+                    10.0 + INSTRUCTION_INDENT
+                } else {
+                    10.0
+                };
+                render_left_text(
+                    &layer,
+                    line,
+                    INSTRUCTION_SIZE,
+                    (
+                        Mm(left),
+                        page.1 - Mm(40.0 + INSTRUCTION_LINE * line_idx as f64),
+                    ),
+                    &pages.fonts[0],
+                );
+            }
+
+            pages.render_page_number(&layer, first_rows_page + idx, (page.0 / 2.0, Mm(12.0)));
+        }
+    }
+
+    // How the part pages go together, each of `part_pages` linked from its outline
+    fn render_assembly(&self, grid_pages: &[GridPage], part_pages: &[PdfPageIndex]) {
+        let (pages, page, section) = (self.pages, self.pages.page, self.section);
+        let (curr_page, layer) = pages.add_page("assembly", false, false);
+        self.outline("Assembly", curr_page);
+
+        pages.render_header(&layer, &section.title);
+
+        render_centered_text(
+            &layer,
+            "Assembly",
+            24.0,
+            (page.0 / 2.0, page.1 - Mm(30.0)),
+            &pages.fonts[1],
+        );
+
+        for (idx, line) in [
+            "Trim each page along its marks on the sides without a hatched strip,",
+            "then lay it over the hatched strips of the pages above and left of it",
+            "so the borders of the charts meet.",
+        ]
+        .into_iter()
+        .enumerate()
+        {
+            render_centered_text(
+                &layer,
+                line,
+                12.0,
+                (page.0 / 2.0, page.1 - Mm(40.0 + 6.0 * idx as f64)),
+                &pages.fonts[0],
+            );
+        }
+
+        let grid_pages = grid_pages
+            .iter()
+            .map(|page| {
+                (
+                    page.page,
+                    UVec2::new(page.x, page.y),
+                    UVec2::new(page.width, page.height),
+                )
+            })
+            .collect::<Vec<_>>();
+        let outlines = draw_assembly_diagram(
+            &mut pages.chart(&layer),
+            UVec2::new(section.charted.width(), section.charted.height()),
+            &grid_pages,
+            &ChartArea {
+                left: 10.0,
+                right: page.0 .0 - 10.0,
+                top: 60.0,
+                bottom: page.1 .0 - 20.0,
+                page_height: page.1 .0,
+            },
+        );
+        link_pages(
+            &pages.doc.get_page(curr_page),
+            pages.print_scale,
+            &outlines,
+            part_pages,
+        );
+
+        pages.render_page_number(
+            &layer,
+            self.first_part_page() + section.sub_images.len() + section.instructions.len(),
+            (page.0 / 2.0, Mm(12.0)),
+        );
+    }
+
+    // A page per color charting the whole design with only its stitches filled in
+    fn render_highlights(&self) {
+        let (pages, page, options) = (self.pages, self.pages.page, self.pages.options);
+        let section = self.section;
+        let first_highlight_page = self.first_part_page()
+            + section.sub_images.len()
+            + section.instructions.len()
+            + options.assembly_marks as usize;
+        let img = section.img.to_rgb8();
+
+        for (idx, ((color, freq, floss), symbol)) in
+            section.colors.iter().zip(&section.symbols).enumerate()
+        {
+            let (curr_page, layer) = pages.add_page("highlight", false, true);
+            self.outline(&format!("Highlight {floss}"), curr_page);
+
+            pages.render_header(&layer, &section.title);
+
+            render_centered_text(
+                &layer,
+                floss,
+                24.0,
+                (page.0 / 2.0, page.1 - Mm(30.0)),
+                &pages.fonts[1],
+            );
+
+            render_centered_text(
+                &layer,
+                &format!("The {freq} stitches in this color, the other colors are grayed out."),
+                12.0,
+                (page.0 / 2.0, page.1 - Mm(40.0)),
+                &pages.fonts[0],
+            );
+
+            let placement = Placement::new(
+                self.design_size(),
+                &ChartArea {
+                    left: 10.0,
+                    right: page.0 .0 - 10.0,
+                    top: 45.0,
+                    bottom: page.1 .0 - 20.0,
+                    page_height: page.1 .0,
+                },
+            )
+            .stretched(options.pixel_aspect);
+            if options.chart_style != ChartStyle::SymbolsOnly {
+                render_image(
+                    layer.clone(),
+                    &tint_image(&highlight_image(&img, *color), options.ink_saver),
+                    &placement,
+                    pages.max_scale,
+                );
+            }
+
+            // Only the cells of the color get their symbol
+            draw_chart(
+                &mut pages.chart(&layer),
+                &img,
+                UVec2::ZERO,
+                self.design_size(),
+                &placement,
+                &HashMap::from_iter([(*color, *symbol)]),
+                &ChartOverlays::rows_only(self.overlays.rows),
+            );
 
-    // Adding the main image
-    render_image_centered(
-        curr_layer,
-        img,
-        BORDER_MARGIN.0,
-        (PORTRAIT_SIZE.0 - BORDER_MARGIN).0,
-        top_offset,
-        bottom_offset,
-        PORTRAIT_SIZE.1 .0,
-    );
+            pages.render_page_number(&layer, first_highlight_page + idx, (page.0 / 2.0, Mm(12.0)));
+        }
+    }
+}
 
-    if img.height() >= img.width() {
-        let (curr_page, curr_layer) = doc.add_page(PORTRAIT_SIZE.0, PORTRAIT_SIZE.1, "preview");
-        let layer = doc.get_page(curr_page).get_layer(curr_layer);
+// `img` with the stitches of any other color than `color` in a light gray
+fn highlight_image(img: &RgbImage, color: Rgb<u8>) -> DynamicImage {
+    let mut img = img.clone();
+    for pixel in img.pixels_mut() {
+        if *pixel != color {
+            *pixel = Rgb([HIGHLIGHT_GRAY; 3]);
+        }
+    }
 
-        // Render Page idx
-        render_centered_text(
-            &layer,
-            &format!("2 / {}", total_pages),
-            18.0,
-            (PORTRAIT_SIZE.0 / 2.0, PORTRAIT_SIZE.1 - Mm(290.0)),
-            &fonts[1],
-        );
+    img.into()
+}
 
-        render_left_text(
-            &layer,
-            &title,
-            16.0,
-            (Mm(10.0), PORTRAIT_SIZE.1 - Mm(15.0)),
-            &fonts[0],
-        );
+// Makes the outline of every part on `page` a link to the page of the part. Links are placed on
+// the paper, they aren't scaled with the layers
+fn link_pages(
+    page: &PdfPageReference,
+    print_scale: f64,
+    outlines: &[(DVec2, DVec2)],
+    part_pages: &[PdfPageIndex],
+) {
+    for ((bottom_left, top_right), part_page) in outlines.iter().zip(part_pages) {
+        let (bottom_left, top_right) = (*bottom_left * print_scale, *top_right * print_scale);
+        page.add_link_annotation(LinkAnnotation::new(
+            Rect::new(
+                Mm(bottom_left.x),
+                Mm(bottom_left.y),
+                Mm(top_right.x),
+                Mm(top_right.y),
+            ),
+            None,
+            None,
+            Actions::go_to(Destination::XYZ {
+                page: *part_page,
+                left: None,
+                top: None,
+                zoom: None,
+            }),
+            None,
+        ));
+    }
+}
 
-        render_right_text(
-            &layer,
-            "needlethreading",
-            16.0,
-            (PORTRAIT_SIZE.0 - Mm(10.0), PORTRAIT_SIZE.1 - Mm(15.0)),
-            &fonts[1],
-        );
+#[derive(Debug, serde::Serialize)]
+struct GridMap {
+    width: u32,
+    height: u32,
+    // Legend entries in legend order
+    colors: Vec<GridColor>,
+    // Legend index of every cell in row-major order, null for unstitched cells
+    cells: Vec<Option<usize>>,
+    // Part of the grid charted on each part page, in cells
+    pages: Vec<GridPage>,
+    // Set when only half of the grid is charted
+    mirror: Option<Mirror>,
+    bookmarks: Vec<Bookmark>,
+}
 
-        render_image_centered(
-            layer,
-            img,
-            0.0,
-            PORTRAIT_SIZE.0 .0,
-            10.0,
-            PORTRAIT_SIZE.1 .0 - 10.0,
-            PORTRAIT_SIZE.1 .0 - 5.0,
-        );
-    } else {
-        let (curr_page, curr_layer) = doc.add_page(PORTRAIT_SIZE.1, PORTRAIT_SIZE.0, "preview");
-        let layer = doc.get_page(curr_page).get_layer(curr_layer);
+#[derive(Debug, serde::Serialize)]
+struct GridColor {
+    symbol: char,
+    floss: String,
+    color: String,
+    stitches: usize,
+}
 
-        // Render Page idx
-        render_centered_text(
-            &layer,
-            &format!("2 / {}", total_pages),
-            18.0,
-            (PORTRAIT_SIZE.1 / 2.0, PORTRAIT_SIZE.0 - Mm(205.0)),
-            &fonts[1],
+// The colors of a grid map as they are on the top left, then as seen with every deficiency
+// clockwise, with the unstitched cells transparent
+fn cvd_preview(grid_map: &GridMap) -> anyhow::Result<RgbaImage> {
+    let colors = grid_map
+        .colors
+        .iter()
+        .map(|color| parse_hex(&color.color).map(Rgb))
+        .collect::<anyhow::Result<Vec<_>>>()?;
+    let views = [None]
+        .into_iter()
+        .chain(Deficiency::ALL.map(Some))
+        .collect::<Vec<_>>();
+    // A stitch wide gap between the views
+    let (width, height) = (grid_map.width + 1, grid_map.height + 1);
+
+    let mut img = RgbaImage::new(
+        (width * 2 - 1) * CVD_PREVIEW_SCALE,
+        (height * 2 - 1) * CVD_PREVIEW_SCALE,
+    );
+    for (view_idx, deficiency) in views.iter().enumerate() {
+        let corner = UVec2::new(
+            [0, 1, 0, 1][view_idx] * width,
+            [0, 0, 1, 1][view_idx] * height,
         );
+        for (cell_idx, cell) in grid_map.cells.iter().enumerate() {
+            let Some(color_idx) = cell else {
+                continue;
+            };
+            let color = colors[*color_idx];
+            let Rgb([r, g, b]) = deficiency.map_or(color, |deficiency| deficiency.simulate(color));
+            let cell = corner
+                + UVec2::new(
+                    cell_idx as u32 % grid_map.width,
+                    cell_idx as u32 / grid_map.width,
+                );
+
+            for y in 0..CVD_PREVIEW_SCALE {
+                for x in 0..CVD_PREVIEW_SCALE {
+                    img.put_pixel(
+                        cell.x * CVD_PREVIEW_SCALE + x,
+                        cell.y * CVD_PREVIEW_SCALE + y,
+                        Rgba([r, g, b, 255]),
+                    );
+                }
+            }
+        }
+    }
 
-        render_ccw_rotated_start(&layer, &title, 24.0, (Mm(15.0), Mm(15.0)), &fonts[0]);
+    Ok(img)
+}
 
-        render_ccw_rotated_end(
-            &layer,
-            "needlethreading",
-            24.0,
-            (Mm(15.0), PORTRAIT_SIZE.0 - Mm(15.0)),
-            &fonts[1],
-        );
+// Open Cross Stitch chart of a grid map, with the bare fabric as the first palette entry and the
+// flosses numbered the way charting software looks them up
+fn oxs(
+    map: &GridMap,
+    title: &str,
+    by: Option<&str>,
+    fabric_count: u32,
+    fabric_color: Rgb<u8>,
+    brand: Brand,
+) -> String {
+    let mut xml = String::from("<?xml version=\"1.0\" encoding=\"UTF-8\"?>\n<chart>\n");
+    xml.push_str(&format!(
+        "<format comments01=\"Written by pdfgen {}\" />\n",
+        env!("CARGO_PKG_VERSION")
+    ));
+    xml.push_str(&format!(
+        "<properties oxsversion=\"1.0\" software=\"pdfgen\" software_version=\"{}\" \
+         chartheight=\"{}\" chartwidth=\"{}\" charttitle=\"{}\" author=\"{}\" copyright=\"\" \
+         instructions=\"\" stitchesperinch=\"{fabric_count}\" stitchesperinch_y=\"{fabric_count}\" \
+         palettecount=\"{}\" />\n",
+        env!("CARGO_PKG_VERSION"),
+        map.height,
+        map.width,
+        xml_escape(title),
+        xml_escape(by.unwrap_or_default()),
+        map.colors.len(),
+    ));
 
-        render_image_centered(
-            layer,
-            img,
-            10.0,
-            PORTRAIT_SIZE.1 .0,
-            0.0,
-            PORTRAIT_SIZE.0 .0 - 10.0,
-            PORTRAIT_SIZE.0 .0 - 5.0,
-        );
+    xml.push_str("<palette>\n");
+    let fabric = hex_color(fabric_color)
+        .trim_start_matches('#')
+        .to_ascii_uppercase();
+    xml.push_str(&format!(
+        "<palette_item index=\"0\" number=\"cloth\" name=\"cloth\" color=\"{fabric}\" \
+         printcolor=\"{fabric}\" blendcolor=\"nil\" comments=\"aida\" strands=\"2\" symbol=\"0\" \
+         dashpattern=\"\" bsstrands=\"2\" bscolor=\"{fabric}\" />\n",
+    ));
+    for (idx, color) in map.colors.iter().enumerate() {
+        let number = match (brand, color.floss.parse::<usize>()) {
+            (Brand::Anchor, Ok(floss)) => format!("Anchor {floss}"),
+            (Brand::Dmc | Brand::Both, Ok(floss)) => format!("DMC {floss}"),
+            _ => color.floss.clone(),
+        };
+        let hex = color.color.trim_start_matches('#').to_ascii_uppercase();
+        xml.push_str(&format!(
+            "<palette_item index=\"{}\" number=\"{}\" name=\"{}\" color=\"{hex}\" \
+             printcolor=\"{hex}\" blendcolor=\"nil\" comments=\"\" strands=\"2\" symbol=\"{}\" \
+             dashpattern=\"\" bsstrands=\"2\" bscolor=\"{hex}\" />\n",
+            idx + 1,
+            xml_escape(&number),
+            xml_escape(&color.floss),
+            xml_escape(&color.symbol.to_string()),
+        ));
+    }
+    xml.push_str("</palette>\n");
+
+    xml.push_str("<fullstitches>\n");
+    for (idx, cell) in map.cells.iter().enumerate() {
+        if let Some(color) = cell {
+            xml.push_str(&format!(
+                "<stitch x=\"{}\" y=\"{}\" palindex=\"{}\" />\n",
+                idx as u32 % map.width,
+                idx as u32 / map.width,
+                color + 1
+            ));
+        }
     }
+    xml.push_str("</fullstitches>\n");
+    xml.push_str(
+        "<partstitches>\n</partstitches>\n<backstitches>\n</backstitches>\n\
+         <ornaments_inc_knots_and_beads>\n</ornaments_inc_knots_and_beads>\n\
+         <commentboxes>\n</commentboxes>\n</chart>\n",
+    );
 
-    if img.height() >= img.width() {
-        let (curr_page, curr_layer) = doc.add_page(PORTRAIT_SIZE.0, PORTRAIT_SIZE.1, "preview");
-        let layer = doc.get_page(curr_page).get_layer(curr_layer);
+    xml
+}
 
-        // Render Page idx
-        render_centered_text(
-            &layer,
-            &format!("3 / {}", total_pages),
-            18.0,
-            (PORTRAIT_SIZE.0 / 2.0, PORTRAIT_SIZE.1 - Mm(290.0)),
-            &fonts[1],
-        );
+fn hex_color(color: Rgb<u8>) -> String {
+    let [r, g, b] = color.0;
+    format!("#{r:02x}{g:02x}{b:02x}")
+}
 
-        render_left_text(
-            &layer,
-            &title,
-            16.0,
-            (Mm(10.0), PORTRAIT_SIZE.1 - Mm(15.0)),
-            &fonts[0],
-        );
+fn xml_escape(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
 
-        render_right_text(
-            &layer,
-            "needlethreading",
-            16.0,
-            (PORTRAIT_SIZE.0 - Mm(10.0), PORTRAIT_SIZE.1 - Mm(15.0)),
-            &fonts[1],
-        );
+#[derive(Debug, serde::Serialize)]
+struct GridPage {
+    page: usize,
+    x: u32,
+    y: u32,
+    width: u32,
+    height: u32,
+}
 
-        render_image_centered(
-            layer.clone(),
-            img,
-            0.0,
-            PORTRAIT_SIZE.0 .0,
-            20.0,
-            PORTRAIT_SIZE.1 .0,
-            PORTRAIT_SIZE.1 .0,
-        );
+// Mirror axis of `img` when nearly all of its stitches match their mirror image
+fn detect_mirror(img: &RgbImage) -> Option<Mirror> {
+    let matching = |mirrored: &dyn Fn(u32, u32) -> (u32, u32)| {
+        let matches = img
+            .enumerate_pixels()
+            .filter(|(x, y, color)| {
+                let (mx, my) = mirrored(*x, *y);
+                img.get_pixel(mx, my) == *color
+            })
+            .count();
 
-        draw_image_overlay(
-            &layer,
-            &img.to_rgb8(),
-            UVec2::ZERO,
-            0.0,
-            PORTRAIT_SIZE.0 .0,
-            20.0,
-            PORTRAIT_SIZE.1 .0,
-            PORTRAIT_SIZE.1 .0,
-            &fonts,
-            &color_symbol_map,
-            &symbol_font_map,
-        );
+        matches as f64 / (img.width() * img.height()) as f64
+    };
+
+    let left_right = matching(&|x, y| (img.width() - 1 - x, y));
+    let top_bottom = matching(&|x, y| (x, img.height() - 1 - y));
+
+    if left_right.max(top_bottom) < SYMMETRY_THRESHOLD {
+        None
+    } else if left_right >= top_bottom {
+        Some(Mirror::LeftRight)
     } else {
-        let (curr_page, curr_layer) = doc.add_page(PORTRAIT_SIZE.1, PORTRAIT_SIZE.0, "preview");
-        let layer = doc.get_page(curr_page).get_layer(curr_layer);
+        Some(Mirror::TopBottom)
+    }
+}
 
-        render_ccw_rotated_start(&layer, &title, 24.0, (Mm(15.0), Mm(15.0)), &fonts[0]);
+// Tells the stitcher where to mirror the charted half, 1-based like the chart's ruler
+fn mirror_instruction(mirror: Mirror, img: &DynamicImage) -> String {
+    let (side, line, size) = match mirror {
+        Mirror::LeftRight => ("right", "column", img.width()),
+        Mirror::TopBottom => ("bottom", "row", img.height()),
+        Mirror::Auto => unreachable!("the mirror axis is resolved before charting"),
+    };
 
-        render_ccw_rotated_end(
-            &layer,
-            "needlethreading",
-            24.0,
-            (Mm(15.0), PORTRAIT_SIZE.0 - Mm(15.0)),
-            &fonts[1],
-        );
+    if size % 2 == 1 {
+        format!(
+            "Half pattern: stitch the {side} half as a mirror image around {line} {}",
+            size / 2 + 1
+        )
+    } else {
+        format!(
+            "Half pattern: stitch the {side} half as a mirror image after {line} {}",
+            size / 2
+        )
+    }
+}
 
-        render_image_centered(
-            layer.clone(),
-            img,
-            10.0,
-            PORTRAIT_SIZE.1 .0,
-            0.0,
-            PORTRAIT_SIZE.0 .0 - 10.0,
-            PORTRAIT_SIZE.0 .0 - 5.0,
-        );
+// Reads the fonts of FONT_FILES, from the files given for them or else the first ones found in
+// the font directories. Only the text fonts with --geometric-symbols
+fn load_fonts(args: &Args) -> anyhow::Result<Vec<Vec<u8>>> {
+    let dirs = args
+        .font_dir
+        .iter()
+        .cloned()
+        .chain(system_font_dirs())
+        .collect::<Vec<_>>();
+    let overrides = [
+        &args.font_regular,
+        &args.font_bold,
+        &args.font_italic,
+        &args.font_symbols,
+        &args.font_symbols_2,
+    ];
 
-        draw_image_overlay(
-            &layer,
-            &img.to_rgb8(),
-            UVec2::ZERO,
-            10.0,
-            PORTRAIT_SIZE.1 .0,
-            0.0,
-            PORTRAIT_SIZE.0 .0 - 10.0,
-            PORTRAIT_SIZE.0 .0 - 5.0,
-            &fonts,
-            &color_symbol_map,
-            &symbol_font_map,
-        );
+    // The geometric marks don't need the symbol fonts
+    let count = if args.geometric_symbols {
+        TEXT_FONTS
+    } else {
+        FONT_FILES.len()
+    };
 
-        // Render Page idx
-        render_centered_text(
-            &layer,
-            &format!("3 / {}", total_pages),
-            18.0,
-            (PORTRAIT_SIZE.1 / 2.0, PORTRAIT_SIZE.0 - Mm(205.0)),
-            &fonts[1],
-        );
+    FONT_FILES
+        .iter()
+        .zip(overrides)
+        .take(count)
+        .map(|(name, path)| {
+            let path = match path {
+                Some(path) => path.clone(),
+                None => dirs
+                    .iter()
+                    .find_map(|dir| find_file(dir, name, FONT_SEARCH_DEPTH))
+                    .with_context(|| {
+                        format!(
+                            "couldn't find {name}, install the Noto Sans fonts or pass --font-dir"
+                        )
+                    })?,
+            };
+
+            let bytes = fs::read(&path)
+                .with_context(|| format!("failed to read the font {}", path.display()))?;
+            if rusttype::Font::try_from_bytes(&bytes).is_none() {
+                anyhow::bail!("{} isn't a font pdfgen can read", path.display());
+            }
+
+            Ok(bytes)
+        })
+        .collect()
+}
+
+// Where the fonts of the user and then the ones of the system get installed
+fn system_font_dirs() -> Vec<PathBuf> {
+    let var = |name| std::env::var_os(name).map(PathBuf::from);
+
+    if cfg!(target_os = "windows") {
+        [
+            var("LOCALAPPDATA").map(|dir| dir.join("Microsoft").join("Windows").join("Fonts")),
+            Some(
+                var("WINDIR")
+                    .unwrap_or_else(|| PathBuf::from("C:\\Windows"))
+                    .join("Fonts"),
+            ),
+        ]
+        .into_iter()
+        .flatten()
+        .collect()
+    } else if cfg!(target_os = "macos") {
+        [
+            var("HOME").map(|home| home.join("Library/Fonts")),
+            Some(PathBuf::from("/Library/Fonts")),
+            Some(PathBuf::from("/System/Library/Fonts")),
+        ]
+        .into_iter()
+        .flatten()
+        .collect()
+    } else {
+        [
+            var("XDG_DATA_HOME")
+                .or_else(|| var("HOME").map(|home| home.join(".local/share")))
+                .map(|dir| dir.join("fonts")),
+            var("HOME").map(|home| home.join(".fonts")),
+            Some(PathBuf::from("/usr/local/share/fonts")),
+            Some(PathBuf::from("/usr/share/fonts")),
+        ]
+        .into_iter()
+        .flatten()
+        .collect()
     }
+}
 
-    // Generate the color count page
-    let (curr_page, curr_layer) = doc.add_page(PORTRAIT_SIZE.0, PORTRAIT_SIZE.1, "threads");
-    let layer = doc.get_page(curr_page).get_layer(curr_layer);
+// The file called `name` in `dir` or its subdirectories down to `depth` levels
+fn find_file(dir: &Path, name: &str, depth: u32) -> Option<PathBuf> {
+    let mut subdirs = vec![];
+    for entry in fs::read_dir(dir).ok()?.flatten() {
+        let path = entry.path();
+        if path.is_dir() {
+            subdirs.push(path);
+        } else if entry.file_name() == name {
+            return Some(path);
+        }
+    }
 
-    render_left_text(
-        &layer,
-        &title,
-        16.0,
-        (Mm(10.0), PORTRAIT_SIZE.1 - Mm(15.0)),
-        &fonts[0],
-    );
+    if depth == 0 {
+        return None;
+    }
+    subdirs.sort();
+    subdirs
+        .iter()
+        .find_map(|subdir| find_file(subdir, name, depth - 1))
+}
 
-    render_right_text(
-        &layer,
-        "needlethreading",
-        16.0,
-        (PORTRAIT_SIZE.0 - Mm(10.0), PORTRAIT_SIZE.1 - Mm(15.0)),
-        &fonts[1],
-    );
+// Strips the outlines of every glyph but the ones of `chars`, keeps the whole font if that fails
+fn subset_font<'f>(font: &'f [u8], chars: &HashSet<char>) -> Cow<'f, [u8]> {
+    let parsed = rusttype::Font::try_from_bytes(font).unwrap();
+    let glyphs = chars
+        .iter()
+        .map(|c| parsed.glyph(*c).id().0)
+        .collect::<Vec<_>>();
 
-    ruler(
-        &layer,
-        (Mm(10.0), PORTRAIT_SIZE.1 - Mm(18.0)),
-        (PORTRAIT_SIZE.0 - Mm(10.0), PORTRAIT_SIZE.1 - Mm(18.0)),
-    );
+    match subsetter::subset(font, 0, subsetter::Profile::pdf(&glyphs)) {
+        Ok(subset) => Cow::Owned(subset),
+        Err(_) => Cow::Borrowed(font),
+    }
+}
 
-    semi_underlined_text(
-        &layer,
-        &format!("Dimension: {}w x {}h", img.width(), img.height()),
-        0..9,
-        (Mm(10.0), PORTRAIT_SIZE.1 - Mm(27.0)),
-        18.0,
-        &fonts[0],
-    );
+// How the symbols of the chart get drawn
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum SymbolDrawing {
+    Text,
+    // The outlines of their glyphs
+    Outline,
+    // Marks of their own, without the symbol fonts
+    Geometric,
+}
 
-    semi_underlined_text(
-        &layer,
-        &format!(
-            "Finished Size: {:.2} cm x {:.2} cm",
-            (img.width() as f64 / 8.0) * 2.54,
-            (img.height() as f64 / 8.0) * 2.54
-        ),
-        0..13,
-        (Mm(10.0), PORTRAIT_SIZE.1 - Mm(37.0)),
-        18.0,
-        &fonts[0],
-    );
+#[derive(Debug, Clone)]
+struct SymbolStyle {
+    scale: f64,
+    bold: bool,
+    // Drawn instead of the glyph of the font when present
+    outline: Option<SymbolOutline>,
+}
 
-    semi_underlined_text(
-        &layer,
-        "Cloth: Aida (16 t./inch)",
-        0..5,
-        (Mm(120.0), PORTRAIT_SIZE.1 - Mm(27.0)),
-        18.0,
-        &fonts[0],
-    );
+// Glyph of a symbol as paths, in ems from its origin on the baseline
+#[derive(Debug, Clone)]
+struct SymbolOutline {
+    // Every contour joined into one path so the nonzero rule still leaves the holes open, the
+    // joins going back and forth cancel out
+    fill: Vec<(Point, bool)>,
+    // Every contour on its own for stroking, where the joins would show
+    contours: Vec<Vec<(Point, bool)>>,
+    advance: f64,
+}
 
-    semi_underlined_text(
-        &layer,
-        &format!("No. of colors: {} Colors", colors.len()),
-        0..13,
-        (Mm(120.0), PORTRAIT_SIZE.1 - Mm(37.0)),
-        18.0,
-        &fonts[0],
-    );
+impl SymbolOutline {
+    fn new(symbol: char, font_bytes: &[u8]) -> Self {
+        let font = rusttype::Font::try_from_bytes(font_bytes).unwrap();
+        // Scales are in units of the height of the font, not of its em square
+        let v_metrics = font.v_metrics_unscaled();
+        let em = font.units_per_em() as f32 / (v_metrics.ascent - v_metrics.descent);
+        let glyph = font.glyph(symbol).scaled(rusttype::Scale::uniform(em));
 
-    ruler(
-        &layer,
-        (Mm(10.0), PORTRAIT_SIZE.1 - Mm(43.0)),
-        (PORTRAIT_SIZE.0 - Mm(10.0), PORTRAIT_SIZE.1 - Mm(43.0)),
-    );
+        let mut builder = OutlineBuilder::default();
+        glyph.build_outline(&mut builder);
 
-    // Render Page idx
-    render_centered_text(
-        &layer,
-        &format!("4 / {}", total_pages),
-        18.0,
-        (PORTRAIT_SIZE.0 / 2.0, PORTRAIT_SIZE.1 - Mm(285.0)),
-        &fonts[1],
-    );
+        Self::from_contours(builder.contours, glyph.h_metrics().advance_width as f64)
+    }
 
-    let mut top = Mm(50.0);
-    let mut page_idx = 0;
-    let mut row_idx = 0;
-    let mut col_idx = 0;
-    let mut layer = layer;
-
-    let regular = doc
-        .add_external_font(std::io::Cursor::new(REGULAR))
-        .unwrap();
-
-    for (idx, (color, freq, floss)) in colors.iter().enumerate() {
-        layer.set_fill_color(printpdf::Color::Rgb(printpdf::Rgb {
-            r: color.0[0] as f64 / 255.0,
-            g: color.0[1] as f64 / 255.0,
-            b: color.0[2] as f64 / 255.0,
-            icc_profile: None,
-        }));
+    // The `idx`th geometric mark, the shapes going round before their fills do so the first
+    // colors get the most distinct marks. None past the last one
+    fn geometric(idx: usize) -> Option<Self> {
+        let shapes = geometric_shapes();
+        let shape = &shapes[idx % shapes.len()];
+        let hole = shape
+            .iter()
+            .rev()
+            .map(|point| *point * GEOMETRIC_HOLE)
+            .collect::<Vec<_>>();
+        let inside = |keep: &dyn Fn(DVec2) -> bool| {
+            let points = shape.iter().map(|point| *point * GEOMETRIC_HOLE);
+            clip_polygon(&points.collect::<Vec<_>>(), keep)
+        };
+
+        let polygons = match idx / shapes.len() {
+            0 => vec![shape.clone(), hole],
+            1 => vec![shape.clone()],
+            2 => vec![shape.clone(), hole, inside(&|point| point.x <= 0.0)],
+            3 => vec![shape.clone(), hole, inside(&|point| point.y <= 0.0)],
+            4 => vec![
+                shape.clone(),
+                hole,
+                regular_polygon(16, GEOMETRIC_HOLE * 0.45, 0.0),
+            ],
+            _ => return None,
+        };
+
+        // Centered on the middle of where a glyph would be
+        let center = DVec2::new(GEOMETRIC_RADIUS, SYMBOL_MIDDLE);
+        let contours = polygons
+            .into_iter()
+            .map(|polygon| {
+                polygon
+                    .into_iter()
+                    .map(|point| {
+                        let point = center + point * GEOMETRIC_RADIUS;
+                        (
+                            Point {
+                                x: Pt(point.x),
+                                y: Pt(point.y),
+                            },
+                            false,
+                        )
+                    })
+                    .collect()
+            })
+            .collect();
 
-        if ((PORTRAIT_SIZE.1 - top) - Mm(10.0 * row_idx as f64)).0 - 3.5 < 20.0 {
-            row_idx = 0;
-            col_idx += 1;
+        Some(Self::from_contours(contours, 2.0 * GEOMETRIC_RADIUS))
+    }
+
+    fn from_contours(contours: Vec<Vec<(Point, bool)>>, advance: f64) -> Self {
+        let mut fill = vec![];
+        for contour in &contours {
+            fill.extend_from_slice(contour);
+            fill.push((contour[0].0, false));
+            fill.push((contours[0][0].0, false));
         }
 
-        if col_idx > 2 {
-            let (curr_page, curr_layer) =
-                doc.add_page(PORTRAIT_SIZE.0, PORTRAIT_SIZE.1, "colors page");
-            layer = doc.get_page(curr_page).get_layer(curr_layer);
+        SymbolOutline {
+            fill,
+            contours,
+            advance,
+        }
+    }
+}
 
-            render_left_text(
-                &layer,
-                &title,
-                16.0,
-                (Mm(10.0), PORTRAIT_SIZE.1 - Mm(15.0)),
-                &fonts[0],
-            );
+// The outline of a symbol in ems as a form XObject, drawn once for the document and placed in
+// every cell of the symbol. It paints in the fill and stroke colors of where it's placed
+struct SymbolForm {
+    name: String,
+    bbox: [f64; 4],
+    content: String,
+}
 
-            render_right_text(
-                &layer,
-                "needlethreading",
-                16.0,
-                (PORTRAIT_SIZE.0 - Mm(10.0), PORTRAIT_SIZE.1 - Mm(15.0)),
-                &fonts[1],
-            );
+impl SymbolForm {
+    // The form of the ink of `symbol`, or of the halo around it
+    fn new(symbol: char, outline: &SymbolOutline, bold: bool, halo: bool) -> Self {
+        let stroke = |thickness: f64| {
+            let contours = outline
+                .contours
+                .iter()
+                .map(|contour| path_operators(contour) + " S");
+            format!("{thickness} w ") + &contours.collect::<Vec<_>>().join(" ")
+        };
+        let content = if halo {
+            stroke(HALO_STROKE)
+        } else if bold {
+            path_operators(&outline.fill) + " f " + &stroke(BOLD_STROKE)
+        } else {
+            path_operators(&outline.fill) + " f"
+        };
 
-            ruler(
-                &layer,
-                (Mm(10.0), PORTRAIT_SIZE.1 - Mm(18.0)),
-                (PORTRAIT_SIZE.0 - Mm(10.0), PORTRAIT_SIZE.1 - Mm(18.0)),
-            );
+        // With room for the strokes around the outline
+        let margin = HALO_STROKE.max(BOLD_STROKE);
+        let (min, max) = outline.fill.iter().fold(
+            (DVec2::INFINITY, DVec2::NEG_INFINITY),
+            |(min, max), (point, _)| {
+                let point = DVec2::new(point.x.0, point.y.0);
+                (min.min(point), max.max(point))
+            },
+        );
+
+        SymbolForm {
+            name: Self::name(symbol, halo),
+            bbox: [
+                min.x - margin,
+                min.y - margin,
+                max.x + margin,
+                max.y + margin,
+            ],
+            content,
+        }
+    }
 
-            page_idx += 1;
+    fn name(symbol: char, halo: bool) -> String {
+        let part = if halo { "Halo" } else { "Ink" };
+        format!("Symbol{part}{:X}", symbol as u32)
+    }
+}
 
-            // Render Page idx
-            render_centered_text(
-                &layer,
-                &format!("{} / {}", 4 + page_idx, total_pages),
-                18.0,
-                (PORTRAIT_SIZE.0 / 2.0, PORTRAIT_SIZE.1 - Mm(285.0)),
-                &fonts[1],
+// `points` as a closed path in PDF operators, the flagged points being followed by the two
+// handles of a curve
+fn path_operators(points: &[(Point, bool)]) -> String {
+    let xy = |(point, _): &(Point, bool)| format!("{:.4} {:.4}", point.x.0, point.y.0);
+
+    let mut path = format!("{} m", xy(&points[0]));
+    let mut idx = 1;
+    while idx < points.len() {
+        if points[idx - 1].1 && points[idx].1 && idx + 2 < points.len() {
+            path += &format!(
+                " {} {} {} c",
+                xy(&points[idx]),
+                xy(&points[idx + 1]),
+                xy(&points[idx + 2])
             );
+            idx += 3;
+        } else {
+            path += &format!(" {} l", xy(&points[idx]));
+            idx += 1;
+        }
+    }
+
+    path + " h"
+}
 
-            top = Mm(25.0);
+// Counterclockwise outlines of the geometric marks, within the unit circle
+fn geometric_shapes() -> Vec<Vec<DVec2>> {
+    let plus = [
+        (0.3, 0.3),
+        (0.3, 1.0),
+        (-0.3, 1.0),
+        (-0.3, 0.3),
+        (-1.0, 0.3),
+        (-1.0, -0.3),
+        (-0.3, -0.3),
+        (-0.3, -1.0),
+        (0.3, -1.0),
+        (0.3, -0.3),
+        (1.0, -0.3),
+        (1.0, 0.3),
+    ]
+    .map(|(x, y)| DVec2::new(x, y) * 0.95)
+    .to_vec();
+    let saltire = plus
+        .iter()
+        .map(|point| DVec2::from_angle(std::f64::consts::FRAC_PI_4).rotate(*point))
+        .collect();
+    let star = (0..10)
+        .map(|idx| {
+            let radius = if idx % 2 == 0 { 1.0 } else { 0.45 };
+            DVec2::from_angle(std::f64::consts::FRAC_PI_2 + idx as f64 * std::f64::consts::PI / 5.0)
+                * radius
+        })
+        .collect();
+    let hourglass = [
+        (-0.8, -0.9),
+        (0.8, -0.9),
+        (0.15, 0.0),
+        (0.8, 0.9),
+        (-0.8, 0.9),
+        (-0.15, 0.0),
+    ]
+    .map(|(x, y)| DVec2::new(x, y))
+    .to_vec();
+
+    vec![
+        regular_polygon(32, 1.0, 0.0),
+        regular_polygon(4, 1.0, std::f64::consts::FRAC_PI_4),
+        regular_polygon(3, 1.0, std::f64::consts::FRAC_PI_2),
+        regular_polygon(3, 1.0, -std::f64::consts::FRAC_PI_2),
+        regular_polygon(4, 1.0, 0.0),
+        plus,
+        saltire,
+        star,
+        regular_polygon(6, 1.0, 0.0),
+        hourglass,
+    ]
+}
+
+// Counterclockwise corners of a regular polygon around the origin, the first one at `angle`
+fn regular_polygon(corners: usize, radius: f64, angle: f64) -> Vec<DVec2> {
+    (0..corners)
+        .map(|idx| {
+            DVec2::from_angle(angle + idx as f64 * std::f64::consts::TAU / corners as f64) * radius
+        })
+        .collect()
+}
 
-            row_idx = 0;
-            col_idx = 0;
+// Part of a convex or star shaped polygon on the side of a line through the origin that `keep`
+// accepts, the line being an axis
+fn clip_polygon(polygon: &[DVec2], keep: &dyn Fn(DVec2) -> bool) -> Vec<DVec2> {
+    let mut clipped = vec![];
+    for (idx, point) in polygon.iter().enumerate() {
+        let next = polygon[(idx + 1) % polygon.len()];
+        if keep(*point) {
+            clipped.push(*point);
+        }
+        if keep(*point) != keep(next) {
+            // Where the edge crosses whichever axis `keep` splits at
+            let t = if keep(DVec2::X) != keep(-DVec2::X) {
+                point.x / (point.x - next.x)
+            } else {
+                point.y / (point.y - next.y)
+            };
+            clipped.push(point.lerp(next, t));
         }
+    }
 
-        layer.add_shape(Line {
-            points: printpdf::calculate_points_for_rect(
-                Mm(6.0),
-                Mm(6.0),
-                Mm(15.0) + Mm(65.0 * col_idx as f64),
-                (PORTRAIT_SIZE.1 - top) - Mm(10.0 * row_idx as f64),
-            ),
-            is_closed: true,
-            has_fill: true,
-            has_stroke: true,
-            is_clipping_path: false,
-        });
+    clipped
+}
 
-        layer.add_shape(Line {
-            points: printpdf::calculate_points_for_rect(
-                Mm(10.0),
-                Mm(6.0),
-                Mm(25.0) + Mm(65.0 * col_idx as f64),
-                (PORTRAIT_SIZE.1 - top) - Mm(10.0 * row_idx as f64),
-            ),
-            is_closed: true,
-            has_fill: true,
-            has_stroke: true,
-            is_clipping_path: false,
-        });
+#[derive(Default)]
+struct OutlineBuilder {
+    contours: Vec<Vec<(Point, bool)>>,
+}
+
+impl OutlineBuilder {
+    fn push(&mut self, x: f32, y: f32) {
+        // Outlines come out with y pointing down
+        let point = Point {
+            x: Pt(x as f64),
+            y: Pt(-y as f64),
+        };
+        self.contours.last_mut().unwrap().push((point, false));
+    }
+}
+
+impl rusttype::OutlineBuilder for OutlineBuilder {
+    fn move_to(&mut self, x: f32, y: f32) {
+        self.contours.push(vec![]);
+        self.push(x, y);
+    }
+
+    fn line_to(&mut self, x: f32, y: f32) {
+        self.push(x, y);
+    }
+
+    fn quad_to(&mut self, x1: f32, y1: f32, x: f32, y: f32) {
+        // The same curve as a cubic one, its handles two thirds of the way to the quadratic one
+        let (from, _) = *self.contours.last().unwrap().last().unwrap();
+        let (x0, y0) = (from.x.0 as f32, -from.y.0 as f32);
+        self.curve_to(
+            x0 + (x1 - x0) * 2.0 / 3.0,
+            y0 + (y1 - y0) * 2.0 / 3.0,
+            x + (x1 - x) * 2.0 / 3.0,
+            y + (y1 - y) * 2.0 / 3.0,
+            x,
+            y,
+        );
+    }
 
-        let l = (0.2126 * (color.0[0] as f64 / 255.0).powf(2.2))
-            + (0.7152 * (color.0[1] as f64 / 255.0).powf(2.2))
-            + (0.0722 * (color.0[2] as f64 / 255.0).powf(2.2));
+    fn curve_to(&mut self, x1: f32, y1: f32, x2: f32, y2: f32, x: f32, y: f32) {
+        // The flag marks points followed by a handle
+        self.contours.last_mut().unwrap().last_mut().unwrap().1 = true;
+        self.push(x1, y1);
+        self.contours.last_mut().unwrap().last_mut().unwrap().1 = true;
+        self.push(x2, y2);
+        self.push(x, y);
+    }
+
+    fn close(&mut self) {}
+}
+
+// Share of an em square covered by the symbol
+fn ink_coverage(symbol: char, font_bytes: &[u8]) -> f64 {
+    const SIZE: f32 = 64.0;
+
+    let font = rusttype::Font::try_from_bytes(font_bytes).unwrap();
+    let glyph = font
+        .glyph(symbol)
+        .scaled(rusttype::Scale::uniform(SIZE))
+        .positioned(rusttype::point(0.0, 0.0));
+
+    let mut ink = 0.0;
+    glyph.draw(|_, _, coverage| ink += coverage as f64);
+
+    ink / (SIZE * SIZE) as f64
+}
+
+// Enlarges the symbols with much less ink than the others, and emboldens the ones that would
+// need to grow too much to fit in their cell
+fn symbol_styles(
+    symbol_font_map: &HashMap<char, (IndirectFontRef, &[u8])>,
+    symbol_drawing: SymbolDrawing,
+) -> HashMap<char, SymbolStyle> {
+    // The geometric marks all have about as much ink
+    if symbol_drawing == SymbolDrawing::Geometric {
+        return SYMBOLS[..GEOMETRIC_MARKS]
+            .iter()
+            .enumerate()
+            .map(|(idx, symbol)| {
+                let style = SymbolStyle {
+                    scale: 1.0,
+                    bold: false,
+                    outline: SymbolOutline::geometric(idx),
+                };
+                (*symbol, style)
+            })
+            .collect();
+    }
+
+    let coverages = SYMBOLS
+        .iter()
+        .map(|symbol| (*symbol, ink_coverage(*symbol, symbol_font_map[symbol].1)))
+        .collect::<Vec<_>>();
+
+    let mut sorted = coverages
+        .iter()
+        .map(|(_, coverage)| *coverage)
+        .collect::<Vec<_>>();
+    sorted.sort_by(f64::total_cmp);
+    let target = sorted[sorted.len() / 2] * MIN_RELATIVE_INK;
 
-        if l > 0.5f64.powf(2.2) {
-            layer.set_fill_color(printpdf::Color::Rgb(printpdf::Rgb {
-                r: 0.0,
-                g: 0.0,
-                b: 0.0,
-                icc_profile: None,
-            }));
-        } else {
-            layer.set_fill_color(printpdf::Color::Rgb(printpdf::Rgb {
-                r: 1.0,
-                g: 1.0,
-                b: 1.0,
-                icc_profile: None,
-            }));
-        }
+    coverages
+        .into_iter()
+        .map(|(symbol, coverage)| {
+            // Ink grows with the square of the size
+            let scale = (target / coverage.max(f64::EPSILON)).sqrt().max(1.0);
 
-        render_centered_text(
-            &layer,
-            &format!("{}", SYMBOLS[idx]),
-            12.0,
             (
-                Mm(14.25) + Mm(65.0 * col_idx as f64),
-                ((PORTRAIT_SIZE.1 - top) - Mm(1.5)) - Mm(10.0 * row_idx as f64),
-            ),
-            &symbol_font_map[&SYMBOLS[idx]],
-        );
+                symbol,
+                SymbolStyle {
+                    scale: scale.min(MAX_SYMBOL_SCALE),
+                    bold: scale > MAX_SYMBOL_SCALE,
+                    outline: (symbol_drawing != SymbolDrawing::Text)
+                        .then(|| SymbolOutline::new(symbol, symbol_font_map[&symbol].1)),
+                },
+            )
+        })
+        .collect()
+}
+
+// Black or white, whichever reads better on `color`, along with a halo of the other one when
+// even that doesn't stand out enough
+fn symbol_ink(color: &Rgb<u8>) -> (printpdf::Color, Option<printpdf::Color>) {
+    let l = (0.2126 * (color.0[0] as f64 / 255.0).powf(2.2))
+        + (0.7152 * (color.0[1] as f64 / 255.0).powf(2.2))
+        + (0.0722 * (color.0[2] as f64 / 255.0).powf(2.2));
+    let (v, contrast) = if l > 0.5f64.powf(2.2) {
+        (0.0, (l + 0.05) / 0.05)
+    } else {
+        (1.0, 1.05 / (l + 0.05))
+    };
 
-        layer.set_fill_color(printpdf::Color::Rgb(printpdf::Rgb {
-            r: 0.0,
-            g: 0.0,
-            b: 0.0,
+    let grey = |v| {
+        printpdf::Color::Rgb(printpdf::Rgb {
+            r: v,
+            g: v,
+            b: v,
             icc_profile: None,
-        }));
+        })
+    };
 
-        layer.use_text(
-            format!("{} ({} ct)", floss, freq),
-            16.0,
-            Mm(32.0) + Mm(65.0 * col_idx as f64),
-            ((PORTRAIT_SIZE.1 - top) - Mm(2.0)) - Mm(10.0 * row_idx as f64),
-            &regular,
-        );
+    (
+        grey(v),
+        (contrast < MIN_SYMBOL_CONTRAST).then(|| grey(1.0 - v)),
+    )
+}
 
-        row_idx += 1;
+// Draws `symbol` in the ink and halo `symbol_ink` picked for its color, leaving the ink as the
+// fill color
+fn render_symbol(
+    layer: &PdfLayerReference,
+    symbol: char,
+    size: f64,
+    center_position: (Mm, Mm),
+    font: Option<&(IndirectFontRef, &[u8])>,
+    style: &SymbolStyle,
+    (ink, halo): (printpdf::Color, Option<printpdf::Color>),
+) {
+    layer.set_fill_color(ink.clone());
+    let scaled_size = size * style.scale;
+    // Keep the middle of the symbol in place as it grows from its baseline
+    let drop: Mm = Pt((scaled_size - size) * SYMBOL_MIDDLE).into();
+    let position = (center_position.0, center_position.1 - drop);
+
+    if let Some(outline) = &style.outline {
+        render_symbol_outline(layer, symbol, outline, scaled_size, position, ink, halo);
+        return;
+    }
+    // Only the geometric marks go without a font
+    let font = font.unwrap();
+
+    // Stroked under the symbol so only its outer half shows
+    if let Some(halo) = halo {
+        layer.save_graphics_state();
+        layer.set_outline_color(halo);
+        layer.set_outline_thickness(scaled_size * HALO_STROKE);
+        layer.set_text_rendering_mode(TextRenderingMode::Stroke);
+        render_centered_text(layer, &symbol.to_string(), scaled_size, position, font);
+        layer.restore_graphics_state();
     }
 
-    // Generate pixel part pages
-    for (idx, (sub_image, offset)) in sub_images.into_iter().enumerate() {
-        let (curr_page, curr_layer) = doc.add_page(PORTRAIT_SIZE.0, PORTRAIT_SIZE.1, "threads");
-        let layer = doc.get_page(curr_page).get_layer(curr_layer);
+    if style.bold {
+        layer.save_graphics_state();
+        layer.set_outline_color(ink);
+        layer.set_outline_thickness(scaled_size * BOLD_STROKE);
+        layer.set_text_rendering_mode(TextRenderingMode::FillStroke);
+    }
 
-        render_left_text(
-            &layer,
-            &title,
-            16.0,
-            (Mm(10.0), PORTRAIT_SIZE.1 - Mm(15.0)),
-            &fonts[0],
-        );
+    render_centered_text(layer, &symbol.to_string(), scaled_size, position, font);
 
-        render_right_text(
-            &layer,
-            "needlethreading",
-            16.0,
-            (PORTRAIT_SIZE.0 - Mm(10.0), PORTRAIT_SIZE.1 - Mm(15.0)),
-            &fonts[1],
-        );
+    if style.bold {
+        layer.restore_graphics_state();
+    }
+}
 
-        // Render Page idx
-        render_centered_text(
-            &layer,
-            &format!("{} / {}", (4 + page_idx) + idx + 1, total_pages),
-            18.0,
-            (PORTRAIT_SIZE.0 / 2.0, PORTRAIT_SIZE.1 - Mm(285.0)),
-            &fonts[1],
-        );
+// Places the form of the outline where the text of the symbol would go, with the fill color
+// already set
+fn render_symbol_outline(
+    layer: &PdfLayerReference,
+    symbol: char,
+    outline: &SymbolOutline,
+    size: f64,
+    center_position: (Mm, Mm),
+    ink: printpdf::Color,
+    halo: Option<printpdf::Color>,
+) {
+    let place = |halo| {
+        let name = SymbolForm::name(symbol, halo);
+        layer.add_operation(lopdf::content::Operation::new(
+            "Do",
+            vec![lopdf::Object::Name(name.into_bytes())],
+        ));
+    };
 
-        render_image_centered(
-            layer.clone(),
-            &sub_image.clone().into(),
-            0.0,
-            PORTRAIT_SIZE.0 .0,
-            0.0,
-            PORTRAIT_SIZE.1 .0 - 40.0,
-            PORTRAIT_SIZE.1 .0 - 20.0,
-        );
+    layer.save_graphics_state();
+    layer.set_ctm(CurTransMat::Translate(
+        center_position.0.into_pt() - Pt(outline.advance * size / 2.0),
+        center_position.1.into_pt(),
+    ));
+    // Sizes are in ems from here on
+    layer.set_ctm(CurTransMat::Scale(size, size));
 
-        draw_image_overlay(
-            &layer,
-            &sub_image,
-            offset,
-            0.0,
-            PORTRAIT_SIZE.0 .0,
-            0.0,
-            PORTRAIT_SIZE.1 .0 - 40.0,
-            PORTRAIT_SIZE.1 .0 - 20.0,
-            &fonts,
-            &color_symbol_map,
-            &symbol_font_map,
-        );
+    // Stroked under the symbol so only its outer half shows
+    if let Some(halo) = halo {
+        layer.set_outline_color(halo);
+        place(true);
     }
 
-    doc
+    // The strokes of bold symbols are in the ink too
+    layer.set_outline_color(ink);
+    place(false);
+
+    layer.restore_graphics_state();
 }
 
 fn render_centered_text(
@@ -746,10 +4018,24 @@ fn render_centered_text(
     center_position: (Mm, Mm),
     font: &(IndirectFontRef, &[u8]),
 ) {
-    let width = {
-        let font = rusttype::Font::try_from_bytes(font.1).unwrap();
+    let width = text_width(text, size, font.1);
+
+    layer.begin_text_section();
+    layer.use_text(
+        text,
+        size,
+        center_position.0 - (Mm(width) / 2.0),
+        center_position.1,
+        &font.0,
+    );
+    layer.end_text_section();
+}
 
-        font.layout(
+// Width of `text` set in `font` at `size` points, in millimeters
+fn text_width(text: &str, size: f64, font: &[u8]) -> f64 {
+    let font = rusttype::Font::try_from_bytes(font).unwrap();
+    let width = font
+        .layout(
             text,
             rusttype::Scale {
                 x: size as f32,
@@ -759,18 +4045,9 @@ fn render_centered_text(
         )
         .last()
         .map(|g| g.position().x + g.unpositioned().h_metrics().advance_width)
-        .unwrap_or(0.0)
-    } / 2.1;
+        .unwrap_or(0.0);
 
-    layer.begin_text_section();
-    layer.use_text(
-        text,
-        size,
-        center_position.0 - (Mm(width as f64) / 2.0),
-        center_position.1,
-        &font.0,
-    );
-    layer.end_text_section();
+    width as f64 / 2.1
 }
 
 fn render_left_text(
@@ -792,27 +4069,13 @@ fn render_right_text(
     start_position: (Mm, Mm),
     font: &(IndirectFontRef, &[u8]),
 ) {
-    let width = {
-        let font = rusttype::Font::try_from_bytes(font.1).unwrap();
-
-        font.layout(
-            text,
-            rusttype::Scale {
-                x: size as f32,
-                y: size as f32,
-            },
-            rusttype::Point { x: 0.0, y: 0.0 },
-        )
-        .last()
-        .map(|g| g.position().x + g.unpositioned().h_metrics().advance_width)
-        .unwrap_or(0.0)
-    } / 2.1;
+    let width = text_width(text, size, font.1);
 
     layer.begin_text_section();
     layer.use_text(
         text,
         size,
-        start_position.0 - Mm(width as f64),
+        start_position.0 - Mm(width),
         start_position.1,
         &font.0,
     );
@@ -845,28 +4108,14 @@ fn render_ccw_rotated_end(
     start_position: (Mm, Mm),
     font: &(IndirectFontRef, &[u8]),
 ) {
-    let width = {
-        let font = rusttype::Font::try_from_bytes(font.1).unwrap();
-
-        font.layout(
-            text,
-            rusttype::Scale {
-                x: size as f32,
-                y: size as f32,
-            },
-            rusttype::Point { x: 0.0, y: 0.0 },
-        )
-        .last()
-        .map(|g| g.position().x + g.unpositioned().h_metrics().advance_width)
-        .unwrap_or(0.0)
-    } / 2.1;
+    let width = text_width(text, size, font.1);
 
     layer.begin_text_section();
     layer.set_font(&font.0, size);
     layer.set_text_cursor(Mm(0.0), Mm(0.0));
     layer.set_text_matrix(printpdf::TextMatrix::TranslateRotate(
         start_position.0.into_pt(),
-        (start_position.1 - Mm(width as f64)).into_pt(),
+        (start_position.1 - Mm(width)).into_pt(),
         90.0,
     ));
     layer.write_text(text, &font.0);
@@ -880,28 +4129,14 @@ fn render_ccw_rotated_centered(
     start_position: (Mm, Mm),
     font: &(IndirectFontRef, &[u8]),
 ) {
-    let width = {
-        let font = rusttype::Font::try_from_bytes(font.1).unwrap();
-
-        font.layout(
-            text,
-            rusttype::Scale {
-                x: size as f32,
-                y: size as f32,
-            },
-            rusttype::Point { x: 0.0, y: 0.0 },
-        )
-        .last()
-        .map(|g| g.position().x + g.unpositioned().h_metrics().advance_width)
-        .unwrap_or(0.0)
-    } / 2.1;
+    let width = text_width(text, size, font.1);
 
     layer.begin_text_section();
     layer.set_font(&font.0, size);
     layer.set_text_cursor(Mm(0.0), Mm(0.0));
     layer.set_text_matrix(printpdf::TextMatrix::TranslateRotate(
         start_position.0.into_pt(),
-        (start_position.1 - Mm(width as f64 / 2.0)).into_pt(),
+        (start_position.1 - Mm(width / 2.0)).into_pt(),
         90.0,
     ));
     layer.write_text(text, &font.0);
@@ -911,125 +4146,435 @@ fn render_ccw_rotated_centered(
 fn render_image_centered(
     layer: PdfLayerReference,
     img: &DynamicImage,
-    left: f64,
-    right: f64,
-    top: f64,
-    bottom: f64,
-    height: f64,
+    pixel_aspect: DVec2,
+    max_scale: u32,
+    area: &ChartArea,
 ) {
-    let (img, translate) = {
-        let size = DVec2 {
-            x: img.width() as f64,
-            y: img.height() as f64,
-        };
-        let screen_size = DVec2 {
-            x: right - (left + IMAGE_PADDING * 2.0),
-            y: bottom - (top + IMAGE_PADDING * 2.0),
-        } * DPMM;
-        let mut scale = (screen_size / size).min_element() as u32;
-
-        if scale > 58 {
-            scale = 58;
-        }
-
-        let img = img.resize(
-            img.width() * scale,
-            img.height() * scale,
-            image::imageops::FilterType::Nearest,
-        );
+    render_image(
+        layer,
+        img,
+        &Placement::new(UVec2::new(img.width(), img.height()), area).stretched(pixel_aspect),
+        max_scale,
+    );
+}
 
-        let translate = (screen_size - (size * scale as f64)) / 2.0;
+// Draws every stitch as a square of dots, at most `max_scale` of them a side, scaled to the size
+// of the stitches of the placement
+fn render_image(
+    layer: PdfLayerReference,
+    img: &DynamicImage,
+    placement: &Placement,
+    max_scale: u32,
+) {
+    let placement = placement.capped(max_scale);
+    let img = img.resize(
+        img.width() * placement.scale,
+        img.height() * placement.scale,
+        image::imageops::FilterType::Nearest,
+    );
+    // Side of the squares of dots the image has for its stitches, in mm
+    let dot_size = placement.scale as f64 * MMPI / placement.dpi;
 
-        (
-            img,
-            (
-                (translate.x / DPMM) + left + IMAGE_PADDING,
-                (translate.y / DPMM) + (height - bottom) + IMAGE_PADDING,
-            ),
-        )
-    };
     printpdf::Image::from_dynamic_image(&img).add_to_layer(
         layer,
         ImageTransform {
-            translate_x: Some(Mm(translate.0)),
-            translate_y: Some(Mm(translate.1)),
-            dpi: Some(DPI),
+            translate_x: Some(Mm(placement.origin.x)),
+            translate_y: Some(Mm(placement.origin.y)),
+            scale_x: Some(placement.stitch.x / dot_size),
+            scale_y: Some(placement.stitch.y / dot_size),
+            dpi: Some(placement.dpi),
             ..Default::default()
         },
     );
 }
 
-fn draw_image_overlay(
+// Squares of `color` at the start of a legend entry, the first one for its symbol. Leaves the fill
+// black
+fn render_swatch(layer: &PdfLayerReference, color: &Rgb<u8>, left: Mm, top: Mm) {
+    layer.set_fill_color(printpdf::Color::Rgb(printpdf::Rgb {
+        r: color.0[0] as f64 / 255.0,
+        g: color.0[1] as f64 / 255.0,
+        b: color.0[2] as f64 / 255.0,
+        icc_profile: None,
+    }));
+
+    layer.add_shape(Line {
+        points: printpdf::calculate_points_for_rect(Mm(6.0), Mm(6.0), Mm(15.0) + left, top),
+        is_closed: true,
+        has_fill: true,
+        has_stroke: true,
+        is_clipping_path: false,
+    });
+
+    layer.add_shape(Line {
+        points: printpdf::calculate_points_for_rect(Mm(10.0), Mm(6.0), Mm(25.0) + left, top),
+        is_closed: true,
+        has_fill: true,
+        has_stroke: true,
+        is_clipping_path: false,
+    });
+
+    layer.set_fill_color(printpdf::Color::Rgb(printpdf::Rgb {
+        r: 0.0,
+        g: 0.0,
+        b: 0.0,
+        icc_profile: None,
+    }));
+}
+
+// Line of `color` where the swatches of a color go, for a backstitch legend entry
+fn render_backstitch_swatch(layer: &PdfLayerReference, color: &Rgb<u8>, left: Mm, top: Mm) {
+    layer.save_graphics_state();
+    layer.set_outline_thickness(BACKSTITCH_STROKE * 1.5);
+    layer.set_outline_color(printpdf::Color::Rgb(printpdf::Rgb {
+        r: color.0[0] as f64 / 255.0,
+        g: color.0[1] as f64 / 255.0,
+        b: color.0[2] as f64 / 255.0,
+        icc_profile: None,
+    }));
+
+    layer.add_shape(Line {
+        points: vec![
+            (Point::new(Mm(12.0) + left, top), false),
+            (Point::new(Mm(30.0) + left, top), false),
+        ],
+        is_closed: false,
+        has_fill: false,
+        has_stroke: true,
+        is_clipping_path: false,
+    });
+
+    layer.restore_graphics_state();
+}
+
+// Marker of a specialty stitch in `color` with a black outline, filling the cell with the bottom
+// left corner `origin` and the size `cell` in millimeters. French knots are round dots, lazy
+// daisies upright petals
+fn render_specialty_marker(
     layer: &PdfLayerReference,
-    img: &RgbImage,
-    offset: UVec2,
-    left: f64,
-    right: f64,
-    top: f64,
-    bottom: f64,
-    height: f64,
-    fonts: &[(IndirectFontRef, &[u8])],
-    color_symbol_map: &HashMap<Rgb<u8>, char>,
-    symbol_font_map: &HashMap<char, (IndirectFontRef, &[u8])>,
+    kind: SpecialtyKind,
+    color: &Rgb<u8>,
+    origin: DVec2,
+    cell: DVec2,
 ) {
-    const GRID: UVec2 = UVec2 { x: 10, y: 10 };
-    let image_size = UVec2 {
-        x: img.width(),
-        y: img.height(),
-    };
+    let radius = match kind {
+        SpecialtyKind::FrenchKnot => DVec2::splat(0.3),
+        SpecialtyKind::LazyDaisy => DVec2::new(0.2, 0.42),
+    } * cell;
+    let center = origin + cell / 2.0;
+
+    layer.save_graphics_state();
+    layer.set_outline_thickness(0.5);
+    layer.set_outline_color(printpdf::Color::Rgb(printpdf::Rgb {
+        r: 0.0,
+        g: 0.0,
+        b: 0.0,
+        icc_profile: None,
+    }));
+    layer.set_fill_color(printpdf::Color::Rgb(printpdf::Rgb {
+        r: color.0[0] as f64 / 255.0,
+        g: color.0[1] as f64 / 255.0,
+        b: color.0[2] as f64 / 255.0,
+        icc_profile: None,
+    }));
+
+    layer.add_shape(Line {
+        points: (0..24)
+            .map(|idx| {
+                let point = center + DVec2::from_angle(idx as f64 * TAU / 24.0) * radius;
+                (Point::new(Mm(point.x), Mm(point.y)), false)
+            })
+            .collect(),
+        is_closed: true,
+        has_fill: true,
+        has_stroke: true,
+        is_clipping_path: false,
+    });
+
+    layer.restore_graphics_state();
+}
+
+// Scales everything drawn on `layer` from the laid out page up to the paper. Every layer has a
+// graphics state of its own
+fn scaled_layer(layer: PdfLayerReference, print_scale: f64) -> PdfLayerReference {
+    if print_scale != 1.0 {
+        layer.set_ctm(CurTransMat::Scale(print_scale, print_scale));
+    }
+    layer
+}
+
+// Text across the middle of a page, along its diagonal, in outlined gray letters on a layer of
+// its own over everything else on the page. The chart shows through the outlines without the
+// transparency some printers get wrong
+fn render_watermark(
+    doc: &PdfDocumentReference,
+    page_index: PdfPageIndex,
+    page: (Mm, Mm),
+    print_scale: f64,
+    text: &str,
+    font: &(IndirectFontRef, &[u8]),
+) {
+    let diagonal = DVec2::new(page.0 .0, page.1 .0);
+    let direction = diagonal.normalize();
+    let size = 100.0 * WATERMARK_SPAN * diagonal.length() / text_width(text, 100.0, font.1);
+    let width = text_width(text, size, font.1);
+    // Start of the baseline, so the middle of the letters is at the middle of the page
+    let start = diagonal / 2.0
+        - direction * width / 2.0
+        - direction.perp() * Mm::from(Pt(size * SYMBOL_MIDDLE)).0;
+
+    let layer = scaled_layer(doc.get_page(page_index).add_layer("watermark"), print_scale);
+    layer.save_graphics_state();
+    layer.set_outline_color(printpdf::Color::Rgb(printpdf::Rgb {
+        r: WATERMARK_GRAY,
+        g: WATERMARK_GRAY,
+        b: WATERMARK_GRAY,
+        icc_profile: None,
+    }));
+    layer.set_outline_thickness(1.0);
+    layer.set_text_rendering_mode(TextRenderingMode::Stroke);
+
+    layer.begin_text_section();
+    layer.set_font(&font.0, size);
+    layer.set_text_matrix(printpdf::TextMatrix::TranslateRotate(
+        Mm(start.x).into_pt(),
+        Mm(start.y).into_pt(),
+        direction.y.atan2(direction.x).to_degrees(),
+    ));
+    layer.write_text(text, &font.0);
+    layer.end_text_section();
+    layer.restore_graphics_state();
+}
+
+// White box of `size` millimeters with a black outline, centered on `center`, to tick off with a
+// pen. A check box field goes over it once the document is saved
+fn render_tick_box(layer: &PdfLayerReference, center: DVec2, size: f64) {
+    layer.save_graphics_state();
+    layer.set_outline_thickness(0.5);
+    layer.set_outline_color(printpdf::Color::Rgb(printpdf::Rgb {
+        r: 0.0,
+        g: 0.0,
+        b: 0.0,
+        icc_profile: None,
+    }));
+    layer.set_fill_color(printpdf::Color::Rgb(printpdf::Rgb {
+        r: 1.0,
+        g: 1.0,
+        b: 1.0,
+        icc_profile: None,
+    }));
+    layer.add_shape(Line {
+        points: printpdf::calculate_points_for_rect(Mm(size), Mm(size), Mm(center.x), Mm(center.y)),
+        is_closed: true,
+        has_fill: true,
+        has_stroke: true,
+        is_clipping_path: false,
+    });
+    layer.restore_graphics_state();
+}
+
+// Tick box of --progress-boxes, in millimeters on its page
+struct CheckBox {
+    page: PdfPageIndex,
+    center: DVec2,
+    size: f64,
+}
+
+impl CheckBox {
+    fn new(layer: &PdfLayerReference, center: DVec2, size: f64) -> Self {
+        CheckBox {
+            page: layer.page,
+            center,
+            size,
+        }
+    }
+}
+
+// What printpdf can't make, added to the document once it's saved
+struct Additions {
+    check_boxes: Vec<CheckBox>,
+    symbol_forms: Vec<SymbolForm>,
+}
+
+impl Additions {
+    fn add_to(&self, pdf: &[u8]) -> anyhow::Result<lopdf::Document> {
+        let mut doc = lopdf::Document::load_mem(pdf)?;
+        add_symbol_forms(&mut doc, &self.symbol_forms)?;
+        if !self.check_boxes.is_empty() {
+            add_check_boxes(&mut doc, &self.check_boxes)?;
+        }
+
+        Ok(doc)
+    }
+}
+
+// Adds the forms of the symbols to the saved document, in the resources of every page. printpdf
+// only places form XObjects, it doesn't make them
+fn add_symbol_forms(doc: &mut lopdf::Document, forms: &[SymbolForm]) -> anyhow::Result<()> {
+    let mut xobjects = lopdf::Dictionary::new();
+    for form in forms {
+        let stream = lopdf::Stream::new(
+            dictionary! {
+                "Type" => "XObject",
+                "Subtype" => "Form",
+                "BBox" => form.bbox.map(lopdf::Object::from).to_vec(),
+            },
+            form.content.clone().into_bytes(),
+        );
+        xobjects.set(form.name.clone(), doc.add_object(stream));
+    }
 
-    let (scaled_image_size, step_size, translate, x_extra, y_extra) = {
-        let size = image_size.as_dvec2();
-        let screen_size = DVec2 {
-            x: right - (left + IMAGE_PADDING * 2.0),
-            y: bottom - (top + IMAGE_PADDING * 2.0),
-        } * DPMM;
-        let mut scale = (screen_size / size).min_element() as u32;
+    for page in doc.get_pages().into_values() {
+        let resources = doc
+            .get_object(page)?
+            .as_dict()?
+            .get(b"Resources")?
+            .as_reference();
+        let resources = match resources {
+            Ok(resources) => doc.get_object_mut(resources)?.as_dict_mut()?,
+            // Or kept in the page itself
+            Err(_) => doc
+                .get_object_mut(page)?
+                .as_dict_mut()?
+                .get_mut(b"Resources")?
+                .as_dict_mut()?,
+        };
+        match resources.get_mut(b"XObject") {
+            // The images of printpdf are there, in the resources themselves
+            Ok(lopdf::Object::Dictionary(images)) => images.extend(&xobjects),
+            _ => resources.set("XObject", xobjects.clone()),
+        }
+    }
+
+    Ok(())
+}
+
+// Turns the tick boxes into the check box fields of a form, which PDF readers tick with a click
+// and save along with the document. printpdf has no form fields, so they get added to the saved
+// document
+fn add_check_boxes(doc: &mut lopdf::Document, check_boxes: &[CheckBox]) -> anyhow::Result<()> {
+    let pages = doc.get_pages();
+    let points = |mm: f64| mm * 72.0 / MMPI;
+
+    let mut fields = Vec::with_capacity(check_boxes.len());
+    for (idx, check_box) in check_boxes.iter().enumerate() {
+        let size = points(check_box.size);
+        let bottom_left = (check_box.center - check_box.size / 2.0)
+            .to_array()
+            .map(points);
+        let appearance = |content: Vec<u8>| {
+            let bounds = vec![0.into(), 0.into(), size.into(), size.into()];
+            lopdf::Stream::new(
+                dictionary! {
+                    "Type" => "XObject",
+                    "Subtype" => "Form",
+                    "BBox" => bounds,
+                },
+                content,
+            )
+        };
+        // A tick from the left across the bottom to the top right corner
+        let check_mark = format!(
+            "q {:.2} w 1 J 1 j {:.2} {:.2} m {:.2} {:.2} l {:.2} {:.2} l S Q",
+            size * CHECK_MARK_STROKE,
+            size * 0.2,
+            size * 0.5,
+            size * 0.42,
+            size * 0.22,
+            size * 0.82,
+            size * 0.8
+        );
+        let checked = doc.add_object(appearance(check_mark.into_bytes()));
+        let unchecked = doc.add_object(appearance(Vec::new()));
+
+        let page = *pages
+            .get(&(check_box.page.0 as u32 + 1))
+            .context("a tick box is on a page the document doesn't have")?;
+        let field = doc.add_object(dictionary! {
+            "Type" => "Annot",
+            "Subtype" => "Widget",
+            "FT" => "Btn",
+            "T" => lopdf::Object::string_literal(format!("tick box {}", idx + 1)),
+            "Rect" => vec![
+                bottom_left[0].into(),
+                bottom_left[1].into(),
+                (bottom_left[0] + size).into(),
+                (bottom_left[1] + size).into(),
+            ],
+            // Printed along with the page
+            "F" => 4,
+            "P" => page,
+            "V" => "Off",
+            "AS" => "Off",
+            "AP" => dictionary! {
+                "N" => dictionary! {
+                    "Yes" => checked,
+                    "Off" => unchecked,
+                },
+            },
+        });
 
-        if scale > 58 {
-            scale = 58;
+        let page = doc.get_object_mut(page)?.as_dict_mut()?;
+        match page.get_mut(b"Annots") {
+            Ok(lopdf::Object::Array(annotations)) => annotations.push(field.into()),
+            // Only the link annotations of printpdf are there, in the page itself
+            _ => page.set("Annots", vec![lopdf::Object::from(field)]),
         }
+        fields.push(field.into());
+    }
+
+    let catalog = doc.trailer.get(b"Root")?.as_reference()?;
+    doc.get_object_mut(catalog)?.as_dict_mut()?.set(
+        "AcroForm",
+        dictionary! {
+            "Fields" => fields,
+            "NeedAppearances" => false,
+        },
+    );
 
-        let translate = (screen_size - (size * scale as f64)) / 2.0;
+    Ok(())
+}
 
-        (
-            (size * scale as f64) / DPMM,
-            (GRID * scale).as_dvec2() / DPMM,
-            (
-                (translate.x / DPMM) + left + IMAGE_PADDING,
-                (translate.y / DPMM) + (height - bottom) + IMAGE_PADDING,
-            ),
-            ((image_size.x % GRID.x) * scale) as f64 / DPMM,
-            ((image_size.y % GRID.y) * scale) as f64 / DPMM,
-        )
-    };
+// Draws the chart layout onto a PDF layer
+struct PdfChart<'a> {
+    layer: &'a PdfLayerReference,
+    fonts: &'a [(IndirectFontRef, &'a [u8])],
+    symbol_font_map: &'a HashMap<char, (IndirectFontRef, &'a [u8])>,
+    symbol_styles: &'a HashMap<char, SymbolStyle>,
+    style: ChartStyle,
+    // Opacity the colors under the symbols are drawn with
+    tint: Option<f64>,
+    // Only changed in the PDF when the next line needs another one
+    stroke: Option<Stroke>,
+    // Tick boxes drawn so far, to make into check box fields
+    check_boxes: Vec<CheckBox>,
+}
 
-    layer.set_outline_thickness(0.1);
-    layer.set_outline_color(printpdf::Color::Rgb(printpdf::Rgb {
-        r: 0.388,
-        g: 0.388,
-        b: 0.388,
-        icc_profile: None,
-    }));
+impl ChartBackend for PdfChart<'_> {
+    fn line(&mut self, from: DVec2, to: DVec2, stroke: Stroke) {
+        if self.stroke != Some(stroke) {
+            self.stroke = Some(stroke);
+
+            let (thickness, [r, g, b]) = match stroke {
+                Stroke::Fine => (0.1, [0.388; 3]),
+                Stroke::Bold => (1.0, [0.0; 3]),
+                Stroke::Center => (1.8, [0.0; 3]),
+                Stroke::Backstitch(color) => (BACKSTITCH_STROKE, color.0.map(|v| v as f64 / 255.0)),
+            };
+            self.layer.set_outline_thickness(thickness);
+            self.layer
+                .set_outline_color(printpdf::Color::Rgb(printpdf::Rgb {
+                    r,
+                    g,
+                    b,
+                    icc_profile: None,
+                }));
+        }
 
-    let inner_step_size = step_size / GRID.as_dvec2();
-    for i in 0..image_size.x {
-        layer.add_shape(Line {
+        self.layer.add_shape(Line {
             points: vec![
-                (
-                    Point::new(
-                        Mm(translate.0 + inner_step_size.x * i as f64),
-                        Mm(translate.1),
-                    ),
-                    true,
-                ),
-                (
-                    Point::new(
-                        Mm(translate.0 + inner_step_size.x * i as f64),
-                        Mm(translate.1 + scaled_image_size.y),
-                    ),
-                    true,
-                ),
+                (Point::new(Mm(from.x), Mm(from.y)), true),
+                (Point::new(Mm(to.x), Mm(to.y)), true),
             ],
             is_closed: false,
             has_fill: false,
@@ -1038,262 +4583,134 @@ fn draw_image_overlay(
         });
     }
 
-    for i in 0..image_size.y {
-        layer.add_shape(Line {
-            points: vec![
-                (
-                    Point::new(
-                        Mm(translate.0),
-                        Mm(translate.1 + inner_step_size.y * i as f64),
-                    ),
-                    true,
-                ),
-                (
-                    Point::new(
-                        Mm(translate.0 + scaled_image_size.x),
-                        Mm(translate.1 + inner_step_size.y * i as f64),
-                    ),
-                    true,
-                ),
-            ],
-            is_closed: false,
-            has_fill: false,
-            has_stroke: true,
-            is_clipping_path: false,
-        });
+    fn label(&mut self, text: &str, size: f64, at: DVec2, vertical: bool) {
+        if vertical {
+            render_ccw_rotated_centered(
+                self.layer,
+                text,
+                size,
+                (Mm(at.x), Mm(at.y)),
+                &self.fonts[1],
+            );
+        } else {
+            render_centered_text(self.layer, text, size, (Mm(at.x), Mm(at.y)), &self.fonts[1]);
+        }
     }
 
-    let sections = image_size / GRID;
-
-    layer.set_outline_thickness(1.0);
-    layer.set_outline_color(printpdf::Color::Rgb(printpdf::Rgb {
-        r: 0.0,
-        g: 0.0,
-        b: 0.0,
-        icc_profile: None,
-    }));
+    fn highlight(&mut self, number: usize, origin: DVec2, cell: DVec2) {
+        let color = printpdf::Color::Rgb(printpdf::Rgb {
+            r: BOOKMARK_COLOR[0],
+            g: BOOKMARK_COLOR[1],
+            b: BOOKMARK_COLOR[2],
+            icc_profile: None,
+        });
 
-    for i in 1..=sections.x {
-        layer.add_shape(Line {
-            points: vec![
-                (
-                    Point::new(Mm(translate.0 + step_size.x * i as f64), Mm(translate.1)),
-                    true,
-                ),
-                (
-                    Point::new(
-                        Mm(translate.0 + step_size.x * i as f64),
-                        Mm(translate.1 + scaled_image_size.y),
-                    ),
-                    true,
-                ),
-            ],
-            is_closed: false,
+        self.layer.save_graphics_state();
+        self.layer.set_outline_thickness(1.5);
+        self.layer.set_outline_color(color.clone());
+        self.layer.set_fill_color(color);
+        self.layer.add_shape(Line {
+            points: printpdf::calculate_points_for_rect(
+                Mm(cell.x),
+                Mm(cell.y),
+                Mm(origin.x + cell.x / 2.0),
+                Mm(origin.y + cell.y / 2.0),
+            ),
+            is_closed: true,
             has_fill: false,
             has_stroke: true,
             is_clipping_path: false,
         });
-
-        render_centered_text(
-            &layer,
-            &format!("{}", 10 * i + offset.x * OUTPUT_STITCH_SIZE.x),
-            8.0,
-            (
-                Mm(translate.0 + step_size.x * i as f64),
-                Mm(translate.1 + scaled_image_size.y) + Mm(1.0),
-            ),
-            &fonts[1],
+        render_left_text(
+            self.layer,
+            &number.to_string(),
+            6.0,
+            (Mm(origin.x + cell.x), Mm(origin.y + cell.y)),
+            &self.fonts[1],
         );
+        self.layer.restore_graphics_state();
     }
 
-    let rem = image_size % GRID;
-    if rem.x != 0 {
-        let extra = if offset.x * OUTPUT_STITCH_SIZE.x > 99 {
-            4.0
-        } else {
-            2.0
-        };
-        render_centered_text(
-            &layer,
-            &format!("{}", offset.x * OUTPUT_STITCH_SIZE.x + image_size.x),
-            8.0,
-            (
-                Mm((translate.0 + step_size.x * (sections.x as f64 + 1.0)).min(
-                    translate.0 + scaled_image_size.x + if x_extra < extra { extra } else { 0.0 },
-                )),
-                Mm(translate.1 + scaled_image_size.y) + Mm(1.0),
-            ),
-            &fonts[1],
-        );
+    fn specialty(&mut self, stitch: &SpecialtyStitch, origin: DVec2, cell: DVec2) {
+        render_specialty_marker(self.layer, stitch.kind, &stitch.color, origin, cell);
     }
 
-    for i in 0..sections.y {
-        layer.add_shape(Line {
-            points: vec![
-                (
-                    Point::new(
-                        Mm(translate.0),
-                        Mm(translate.1 + step_size.y * i as f64 + y_extra),
-                    ),
-                    true,
-                ),
-                (
-                    Point::new(
-                        Mm(translate.0 + scaled_image_size.x),
-                        Mm(translate.1 + step_size.y * i as f64 + y_extra),
-                    ),
-                    true,
-                ),
-            ],
-            is_closed: false,
-            has_fill: false,
-            has_stroke: true,
+    fn tick_box(&mut self, center: DVec2, size: f64) {
+        render_tick_box(self.layer, center, size);
+        self.check_boxes
+            .push(CheckBox::new(self.layer, center, size));
+    }
+
+    fn arrow(&mut self, tip: DVec2, direction: DVec2, length: f64) {
+        let base = tip - direction * length;
+        let side = direction.perp() * length / 2.0;
+
+        self.layer
+            .set_fill_color(printpdf::Color::Rgb(printpdf::Rgb {
+                r: 0.0,
+                g: 0.0,
+                b: 0.0,
+                icc_profile: None,
+            }));
+        self.layer.add_shape(Line {
+            points: [tip, base + side, base - side]
+                .into_iter()
+                .map(|point| (Point::new(Mm(point.x), Mm(point.y)), false))
+                .collect(),
+            is_closed: true,
+            has_fill: true,
+            has_stroke: false,
             is_clipping_path: false,
         });
-
-        render_ccw_rotated_centered(
-            layer,
-            &format!(
-                "{}",
-                10 * (sections.y - i) + offset.y * OUTPUT_STITCH_SIZE.y
-            ),
-            8.0,
-            (
-                Mm(translate.0 - 1.0),
-                Mm(translate.1 + step_size.y * i as f64 + y_extra),
-            ),
-            &fonts[1],
-        );
     }
 
-    let rem = image_size % GRID;
-    if rem.y != 0 {
-        let extra = if image_size.y > 99 { 4.0 } else { 2.0 };
-        render_ccw_rotated_centered(
-            &layer,
-            &format!("{}", offset.y * OUTPUT_STITCH_SIZE.y + image_size.y),
-            8.0,
+    fn symbol(&mut self, symbol: char, color: Rgb<u8>, origin: DVec2, cell: DVec2) {
+        let ink = match self.style {
+            ChartStyle::ColorBlocks => return,
+            // As on white fabric
+            ChartStyle::SymbolsOnly => symbol_ink(&Rgb([255, 255, 255])),
+            ChartStyle::ColorSymbols => symbol_ink(&tint(color, self.tint)),
+        };
+
+        render_symbol(
+            self.layer,
+            symbol,
+            cell.y * 2.0,
             (
-                Mm(translate.0 - 1.0),
-                Mm((translate.1 - (step_size.y - y_extra))
-                    .max(translate.1 - if y_extra < extra { extra } else { 0.0 })),
+                Mm(origin.x + cell.x * 0.43211062),
+                Mm(origin.y + cell.y * (1.0 - 0.720184367)),
             ),
-            &fonts[1],
+            self.symbol_font_map.get(&symbol),
+            &self.symbol_styles[&symbol],
+            ink,
         );
     }
+}
 
-    // Add thick lines around the border
-    layer.add_shape(Line {
-        points: vec![
-            (Point::new(Mm(translate.0), Mm(translate.1)), true),
-            (
-                Point::new(Mm(translate.0), Mm(translate.1 + scaled_image_size.y)),
-                true,
-            ),
-        ],
-        is_closed: false,
-        has_fill: false,
-        has_stroke: true,
-        is_clipping_path: false,
-    });
-    layer.add_shape(Line {
-        points: vec![
-            (
-                Point::new(Mm(translate.0 + scaled_image_size.x), Mm(translate.1)),
-                true,
-            ),
-            (
-                Point::new(
-                    Mm(translate.0 + scaled_image_size.x),
-                    Mm(translate.1 + scaled_image_size.y),
-                ),
-                true,
-            ),
-        ],
-        is_closed: false,
-        has_fill: false,
-        has_stroke: true,
-        is_clipping_path: false,
-    });
-    layer.add_shape(Line {
-        points: vec![
-            (Point::new(Mm(translate.0), Mm(translate.1)), true),
-            (
-                Point::new(Mm(translate.0 + scaled_image_size.x), Mm(translate.1)),
-                true,
-            ),
-        ],
-        is_closed: false,
-        has_fill: false,
-        has_stroke: true,
-        is_clipping_path: false,
-    });
-    layer.add_shape(Line {
-        points: vec![
-            (
-                Point::new(Mm(translate.0), Mm(translate.1 + scaled_image_size.y)),
-                true,
+// Numbered list of the bookmarks below `top_left`
+fn render_bookmark_callouts(
+    layer: &PdfLayerReference,
+    bookmarks: &[Bookmark],
+    top_left: (Mm, Mm),
+    font: &(IndirectFontRef, &[u8]),
+) {
+    for (idx, bookmark) in bookmarks.iter().enumerate() {
+        render_left_text(
+            layer,
+            &format!(
+                "{}. {} (column {}, row {})",
+                idx + 1,
+                bookmark.name,
+                bookmark.x,
+                bookmark.y
             ),
+            10.0,
             (
-                Point::new(
-                    Mm(translate.0 + scaled_image_size.x),
-                    Mm(translate.1 + scaled_image_size.y),
-                ),
-                true,
+                top_left.0,
+                top_left.1 - Mm(BOOKMARK_LINE * (idx + 1) as f64),
             ),
-        ],
-        is_closed: false,
-        has_fill: false,
-        has_stroke: true,
-        is_clipping_path: false,
-    });
-
-    // Generate color markers
-    for y in 0..image_size.y {
-        for x in 0..image_size.x {
-            let color = img.get_pixel(x, y);
-
-            if color.0 == [255, 255, 255] {
-                continue;
-            }
-
-            let l = (0.2126 * (color.0[0] as f64 / 255.0).powf(2.2))
-                + (0.7152 * (color.0[1] as f64 / 255.0).powf(2.2))
-                + (0.0722 * (color.0[2] as f64 / 255.0).powf(2.2));
-
-            if l > 0.5f64.powf(2.2) {
-                layer.set_fill_color(printpdf::Color::Rgb(printpdf::Rgb {
-                    r: 0.0,
-                    g: 0.0,
-                    b: 0.0,
-                    icc_profile: None,
-                }));
-            } else {
-                layer.set_fill_color(printpdf::Color::Rgb(printpdf::Rgb {
-                    r: 1.0,
-                    g: 1.0,
-                    b: 1.0,
-                    icc_profile: None,
-                }));
-            }
-
-            render_centered_text(
-                &layer,
-                &format!("{}", color_symbol_map[color]),
-                inner_step_size.y * 2.0,
-                (
-                    Mm(translate.0
-                        + inner_step_size.x * x as f64
-                        + (inner_step_size.x * 0.43211062)),
-                    PORTRAIT_SIZE.1
-                        - Mm(top
-                            + translate.1
-                            + inner_step_size.y * y as f64
-                            + (inner_step_size.y * 0.720184367)),
-                ),
-                &symbol_font_map[&color_symbol_map[color]],
-            );
-        }
+            font,
+        );
     }
 }
 
@@ -1375,69 +4792,259 @@ fn semi_underlined_text(
     layer.end_text_section();
 }
 
-fn load_dmc_colors() -> HashMap<Rgb<u8>, usize> {
-    #[derive(serde::Deserialize)]
-    struct DmcColor {
-        floss: Option<usize>,
-        red: u8,
-        green: u8,
-        blue: u8,
+fn soft_proof(img: &DynamicImage, transform: &Transform<[u8; 3], [u8; 3]>) -> DynamicImage {
+    let mut img = img.to_rgb8();
+    let pixels = img.pixels().map(|pixel| pixel.0).collect::<Vec<_>>();
+    let mut proofed = vec![[0; 3]; pixels.len()];
+
+    transform.transform_pixels(&pixels, &mut proofed);
+    for (pixel, proofed) in img.pixels_mut().zip(proofed) {
+        pixel.0 = proofed;
     }
 
-    let colors: Vec<DmcColor> =
-        serde_json::from_str(include_str!("../../dmc_colors.json")).unwrap();
+    img.into()
+}
 
-    colors
+// `color` at `opacity` over white
+fn tint(color: Rgb<u8>, opacity: Option<f64>) -> Rgb<u8> {
+    let Some(opacity) = opacity else {
+        return color;
+    };
+
+    Rgb(color
+        .0
+        .map(|v| (255.0 - (255.0 - v as f64) * opacity).round() as u8))
+}
+
+fn tint_image(img: &DynamicImage, opacity: Option<f64>) -> DynamicImage {
+    let mut img = img.to_rgb8();
+    for pixel in img.pixels_mut() {
+        *pixel = tint(*pixel, opacity);
+    }
+
+    img.into()
+}
+
+fn warn_indistinguishable_colors(
+    colors: &[(Rgb<u8>, usize, String)],
+    transform: &Transform<[u8; 3], [u8; 3]>,
+    metric: Metric,
+) {
+    // Roughly the smallest difference the eye can tell apart
+    const JUST_NOTICEABLE_DIFFERENCE: f64 = 2.3;
+
+    let pixels = colors
+        .iter()
+        .map(|(color, _, _)| color.0)
+        .collect::<Vec<_>>();
+    let mut proofed = vec![[0; 3]; pixels.len()];
+    transform.transform_pixels(&pixels, &mut proofed);
+
+    let proofed = proofed
         .into_iter()
-        .filter_map(
-            |DmcColor {
-                 floss,
-                 red,
-                 green,
-                 blue,
-             }| floss.map(|floss| (Rgb::from([red, green, blue]), floss)),
-        )
-        .collect()
+        .map(|color| {
+            let lab = palette::Lab::<palette::white_point::D65, f64>::adapt_from(
+                palette::rgb::Srgb::new(color[0], color[1], color[2]).into_format(),
+            );
+            [lab.l, lab.a, lab.b]
+        })
+        .collect::<Vec<_>>();
+
+    for i in 0..colors.len() {
+        for j in (i + 1)..colors.len() {
+            let distance = metric.distance(proofed[i], proofed[j]);
+            if distance < JUST_NOTICEABLE_DIFFERENCE {
+                eprintln!(
+                    "Warning: {} and {} will print indistinguishably (ΔE {distance:.2})",
+                    colors[i].2, colors[j].2
+                );
+            }
+        }
+    }
+}
+
+fn print_substitutions(
+    colors: &[(Rgb<u8>, usize, String)],
+    stash: &[PaletteColor],
+    metric: Metric,
+) {
+    let to_lab = |color: [u8; 3]| {
+        let lab = palette::Lab::<palette::white_point::D65, f64>::adapt_from(
+            palette::rgb::Srgb::new(color[0], color[1], color[2]).into_format(),
+        );
+        [lab.l, lab.a, lab.b]
+    };
+    let stash_index =
+        PaletteIndex::with_metric(stash.iter().map(|color| to_lab(color.rgb)), metric);
+
+    let missing = colors
+        .iter()
+        .filter(|(color, _, floss)| {
+            !stash
+                .iter()
+                .any(|owned| owned.rgb == color.0 || owned.name.as_ref() == Some(floss))
+        })
+        .collect::<Vec<_>>();
+
+    if missing.is_empty() {
+        println!("All {} flosses are in the stash", colors.len());
+        return;
+    }
+
+    println!("Missing from the stash, with the nearest owned floss:");
+    println!(
+        "{:<10} {:<10} {:>6} {:>10}",
+        "Floss", "Owned", "ΔE", "Stitches"
+    );
+    for (color, freq, floss) in missing {
+        let lab = to_lab(color.0);
+        match stash_index.nearest(lab) {
+            Some(idx) => {
+                let owned = &stash[idx];
+                let [r, g, b] = owned.rgb;
+                let name = owned
+                    .name
+                    .clone()
+                    .unwrap_or_else(|| format!("#{r:02x}{g:02x}{b:02x}"));
+
+                println!(
+                    "{floss:<10} {name:<10} {:>6.2} {freq:>10}",
+                    metric.distance(lab, to_lab(owned.rgb))
+                );
+            }
+            None => println!("{floss:<10} {:<10} {:>6} {freq:>10}", "-", "-"),
+        }
+    }
+}
+
+fn floss_map(colors: Vec<PaletteColor>) -> anyhow::Result<HashMap<Rgb<u8>, String>> {
+    // Colors without a floss number or name can't be listed in the legend
+    let floss_map = colors
+        .into_iter()
+        .filter_map(|PaletteColor { name, rgb }| name.map(|name| (Rgb::from(rgb), name)))
+        .collect::<HashMap<_, _>>();
+    if floss_map.is_empty() {
+        anyhow::bail!(
+            "none of the palette colors have a name or floss number to list in the legend"
+        );
+    }
+
+    Ok(floss_map)
 }
 
-fn sub_divide_images(img: &DynamicImage) -> Vec<(RgbImage, UVec2)> {
+fn sub_divide_images(
+    img: &DynamicImage,
+    page_stitches: UVec2,
+    overlap: u32,
+) -> Vec<(RgbImage, UVec2, UVec2)> {
     let img = img.to_rgb8();
-    let mut images = Vec::default();
 
-    for j in 0..((img.height() / OUTPUT_STITCH_SIZE.y)
-        + if img.height() % OUTPUT_STITCH_SIZE.y != 0 {
-            1
-        } else {
-            0
-        })
-    {
-        for i in 0..((img.width() / OUTPUT_STITCH_SIZE.x)
-            + if img.width() % OUTPUT_STITCH_SIZE.x != 0 {
-                1
-            } else {
-                0
-            })
-        {
-            images.push((
-                img.view(
-                    i * OUTPUT_STITCH_SIZE.x,
-                    j * OUTPUT_STITCH_SIZE.y,
-                    if (i * OUTPUT_STITCH_SIZE.x + OUTPUT_STITCH_SIZE.x) > img.width() {
-                        img.width() % OUTPUT_STITCH_SIZE.x
-                    } else {
-                        OUTPUT_STITCH_SIZE.x
-                    },
-                    if (j * OUTPUT_STITCH_SIZE.y + OUTPUT_STITCH_SIZE.y) > img.height() {
-                        img.height() % OUTPUT_STITCH_SIZE.y
-                    } else {
-                        OUTPUT_STITCH_SIZE.y
-                    },
-                )
-                .to_image(),
-                UVec2 { x: i, y: j },
-            ));
+    paginate(
+        UVec2::new(img.width(), img.height()),
+        page_stitches,
+        overlap,
+    )
+    .into_iter()
+    .map(|(page, first, size)| {
+        (
+            img.view(first.x, first.y, size.x, size.y).to_image(),
+            page,
+            first,
+        )
+    })
+    .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn fits_whole_blocks_on_the_part_pages() {
+        assert_eq!(part_stitches(PageSize::A4.portrait()), PAGE_STITCHES);
+        for page_size in [PageSize::Letter, PageSize::Legal, PageSize::A3] {
+            let (width, height) = page_size.portrait();
+            for page in [(width, height), (height, width)] {
+                let stitches = part_stitches(page);
+                assert_eq!(stitches % GRID, UVec2::ZERO, "{page_size:?} {stitches}");
+            }
         }
+        assert_eq!(
+            part_stitches(PageSize::Letter.portrait()),
+            UVec2::new(50, 60)
+        );
     }
 
-    images
+    #[test]
+    fn lists_only_the_named_palette_colors() {
+        let named = PaletteColor {
+            name: Some("310".to_string()),
+            rgb: [0, 0, 0],
+        };
+        let unnamed = PaletteColor {
+            name: None,
+            rgb: [255, 255, 255],
+        };
+
+        assert_eq!(
+            floss_map(vec![named, unnamed.clone()]).unwrap(),
+            HashMap::from([(Rgb([0, 0, 0]), "310".to_string())])
+        );
+        assert!(floss_map(vec![unnamed]).is_err());
+    }
+
+    #[test]
+    fn fits_whole_blocks_on_large_print_pages() {
+        let (width, height) = PageSize::A4.portrait();
+        let page = (width / LARGE_PRINT_SCALE, height / LARGE_PRINT_SCALE);
+
+        // Shrunk by LARGE_PRINT_SCALE, the page fits a few stitches more than 30x40
+        assert_eq!(part_stitches(page), UVec2::new(30, 40));
+    }
+
+    #[test]
+    fn gives_out_only_the_available_symbols() {
+        let colors = (0..3)
+            .map(|idx| (Rgb([idx, idx, idx]), 1, idx.to_string()))
+            .collect::<Vec<_>>();
+        let assign = |available: &[char]| {
+            assign_symbols(
+                &colors,
+                available,
+                &HashSet::new(),
+                false,
+                &HashMap::default(),
+                // Past the available ones
+                |_| Some('Z'),
+            )
+        };
+
+        assert_eq!(assign(&SYMBOLS[..3]).unwrap(), ['A', 'B', 'C']);
+        assert!(assign(&SYMBOLS[..2]).is_err());
+    }
+
+    #[test]
+    fn draws_the_symbol_outlines_as_forms() {
+        let point = |x, y, handle| (Point { x: Pt(x), y: Pt(y) }, handle);
+        let path = [
+            point(0.0, 0.0, true),
+            point(0.0, 1.0, true),
+            point(1.0, 1.0, false),
+            point(1.0, 0.0, false),
+            point(0.5, -1.0, false),
+        ];
+        assert_eq!(
+            path_operators(&path),
+            "0.0000 0.0000 m 0.0000 1.0000 1.0000 1.0000 1.0000 0.0000 c 0.5000 -1.0000 l h"
+        );
+
+        // A hollow circle, its hole a contour of its own
+        let outline = SymbolOutline::geometric(0).unwrap();
+        let ink = SymbolForm::new('A', &outline, false, false);
+        let halo = SymbolForm::new('A', &outline, false, true);
+        assert_ne!(ink.name, halo.name);
+        assert!(ink.content.ends_with(" f"));
+        assert_eq!(halo.content.matches(" S").count(), 2);
+        assert!(ink.bbox[0] < 0.0 && ink.bbox[2] > 2.0 * GEOMETRIC_RADIUS);
+    }
 }
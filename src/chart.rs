@@ -0,0 +1,985 @@
+use std::{collections::HashMap, fs, path::Path};
+
+use anyhow::bail;
+
+use glam::{DVec2, IVec2, UVec2};
+use image::{Rgb, RgbImage};
+
+use crate::{backstitch::Backstitch, specialty::SpecialtyStitch};
+
+// Largest part of the chart that fits on one page, in stitches
+pub const PAGE_STITCHES: UVec2 = UVec2 { x: 50, y: 70 };
+
+// Stitches between two bold grid lines
+pub const GRID: UVec2 = UVec2 { x: 10, y: 10 };
+
+pub const MMPI: f64 = 25.4;
+
+pub const DPI: f64 = 300.0;
+
+pub const DPMM: f64 = DPI / MMPI;
+
+pub const IMAGE_PADDING: f64 = 5.0;
+
+// Largest a stitch gets, in dots
+pub const MAX_SCALE: u32 = 58;
+
+// Size of the grid coordinates, in points
+const LABEL_SIZE: f64 = 8.0;
+
+// Distance of the knitting row numbers from the side of the chart, in millimeters
+const ROW_LABEL_OFFSET: f64 = 3.0;
+
+// Length of the arrows pointing at the middle of a chart and how far they stay from its edge, in
+// millimeters
+const CENTER_ARROW: f64 = 2.5;
+const CENTER_ARROW_GAP: f64 = 0.5;
+
+// How far registration marks reach out from the corners of a chart, in millimeters
+const REGISTRATION_MARK: f64 = 4.0;
+
+// Width of the strips the next page gets glued over and the space between their hatches, in
+// millimeters
+const GLUE_STRIP: f64 = 4.0;
+const GLUE_HATCH: f64 = 1.5;
+
+// Size of the tick boxes of the blocks, relative to a stitch
+const TICK_BOX: f64 = 0.45;
+
+// Size of the page numbers of the assembly diagram, in points
+const DIAGRAM_LABEL_SIZE: f64 = 14.0;
+
+// Layout of the legend in millimeters, the first page starts lower to leave room for the summary
+const LEGEND_FIRST_TOP: f64 = 60.0;
+const LEGEND_TOP: f64 = 25.0;
+const LEGEND_BOTTOM: f64 = 20.0;
+const LEGEND_ROW: f64 = 10.0;
+const LEGEND_COLUMN: f64 = 65.0;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Stroke {
+    // Line between two stitches
+    Fine,
+    // Every GRID stitches and around the chart
+    Bold,
+    // Through the middle of the whole design
+    Center,
+    // Backstitch in the color of its floss
+    Backstitch(Rgb<u8>),
+}
+
+/// How the rows of a chart are numbered.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RowNumbers {
+    // Every GRID rows from the top, along the left side
+    Top,
+    // Every row from the bottom of a chart this many rows high, as knitted. The odd right side
+    // rows are worked from the right and numbered there, the even wrong side rows on the left
+    Knitting(u32),
+}
+
+/// Draws what the layout decides. Positions are in millimeters from the bottom left of the page.
+pub trait ChartBackend {
+    fn line(&mut self, from: DVec2, to: DVec2, stroke: Stroke);
+
+    /// Grid coordinate centered on `at`, rotated counter clockwise when `vertical`.
+    fn label(&mut self, text: &str, size: f64, at: DVec2, vertical: bool);
+
+    /// Symbol of the stitch in the cell with the bottom left corner `origin`.
+    fn symbol(&mut self, symbol: char, color: Rgb<u8>, origin: DVec2, cell: DVec2);
+
+    /// Marker of a specialty stitch in the cell with the bottom left corner `origin`.
+    fn specialty(&mut self, stitch: &SpecialtyStitch, origin: DVec2, cell: DVec2);
+
+    /// Marks the cell of a bookmark, `number` being its place in the bookmark list from 1.
+    fn highlight(&mut self, number: usize, origin: DVec2, cell: DVec2);
+
+    /// Empty square `size` millimeters large centered on `center`, to tick off by hand.
+    fn tick_box(&mut self, center: DVec2, size: f64);
+
+    /// Filled triangle with its tip at `tip`, pointing along the unit vector `direction`.
+    fn arrow(&mut self, tip: DVec2, direction: DVec2, length: f64);
+}
+
+/// Named stitch to point out to the stitcher, in chart coordinates starting from 1.
+#[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
+pub struct Bookmark {
+    pub name: String,
+    pub x: u32,
+    pub y: u32,
+}
+
+/// Loads a JSON list of bookmarks, checking they are all on a chart of `size` stitches.
+pub fn load_bookmarks(path: &Path, size: UVec2) -> anyhow::Result<Vec<Bookmark>> {
+    let bookmarks: Vec<Bookmark> = serde_json::from_slice(&fs::read(path)?)?;
+
+    for bookmark in &bookmarks {
+        if bookmark.x == 0 || bookmark.y == 0 || bookmark.x > size.x || bookmark.y > size.y {
+            bail!(
+                "the bookmark {:?} at {}, {} is outside of the {}x{} chart",
+                bookmark.name,
+                bookmark.x,
+                bookmark.y,
+                size.x,
+                size.y
+            );
+        }
+    }
+
+    Ok(bookmarks)
+}
+
+/// Part of a page an image gets centered in. `left` and `right` are from the left edge of the
+/// page, `top` and `bottom` from its top edge, all in millimeters.
+#[derive(Debug, Clone, Copy)]
+pub struct ChartArea {
+    pub left: f64,
+    pub right: f64,
+    pub top: f64,
+    pub bottom: f64,
+    pub page_height: f64,
+}
+
+/// Where an image of stitches ends up in a `ChartArea`.
+#[derive(Debug, Clone, Copy)]
+pub struct Placement {
+    // Dots per stitch at `dpi`
+    pub scale: u32,
+    pub dpi: f64,
+    // Bottom left corner of the image
+    pub origin: DVec2,
+    pub stitch: DVec2,
+    pub size: DVec2,
+}
+
+impl Placement {
+    /// Largest whole number of dots per stitch fitting the area, centered in it.
+    pub fn new(stitches: UVec2, area: &ChartArea) -> Self {
+        let size = stitches.as_dvec2();
+        let screen_size = DVec2 {
+            x: area.right - (area.left + IMAGE_PADDING * 2.0),
+            y: area.bottom - (area.top + IMAGE_PADDING * 2.0),
+        } * DPMM;
+        let scale = ((screen_size / size).min_element() as u32).min(MAX_SCALE);
+        let translate = (screen_size - (size * scale as f64)) / 2.0;
+
+        Placement {
+            scale,
+            dpi: DPI,
+            origin: DVec2 {
+                x: (translate.x / DPMM) + area.left + IMAGE_PADDING,
+                y: (translate.y / DPMM) + (area.page_height - area.bottom) + IMAGE_PADDING,
+            },
+            stitch: DVec2::splat(scale as f64 / DPMM),
+            size: (size * scale as f64) / DPMM,
+        }
+    }
+
+    /// Stitches the size they are on fabric of `fabric_count` stitches per inch, in the top left
+    /// of the area so the pages of a chart line up.
+    pub fn true_size(stitches: UVec2, area: &ChartArea, fabric_count: u32) -> Self {
+        // As close to DPI as whole dots per stitch get
+        let scale = (DPI / fabric_count as f64).ceil() as u32;
+        let stitch = MMPI / fabric_count as f64;
+        let size = stitches.as_dvec2() * stitch;
+
+        Placement {
+            scale,
+            dpi: (fabric_count * scale) as f64,
+            origin: DVec2 {
+                x: area.left + IMAGE_PADDING,
+                y: (area.page_height - area.top) - IMAGE_PADDING - size.y,
+            },
+            stitch: DVec2::splat(stitch),
+            size,
+        }
+    }
+
+    /// Same placement with at most `max_scale` dots per stitch, at a lower DPI so the image keeps
+    /// its size.
+    pub fn capped(&self, max_scale: u32) -> Self {
+        if self.scale <= max_scale {
+            return *self;
+        }
+
+        let scale = max_scale.max(1);
+        Placement {
+            scale,
+            dpi: self.dpi * scale as f64 / self.scale as f64,
+            ..*self
+        }
+    }
+
+    /// Placement of a part of the chart at the same scale, sharing the top left corner so the
+    /// stitches of every page are the same size.
+    pub fn tile(&self, stitches: UVec2) -> Self {
+        let size = stitches.as_dvec2() * self.stitch;
+
+        Placement {
+            origin: DVec2::new(self.origin.x, self.origin.y + self.size.y - size.y),
+            size,
+            ..*self
+        }
+    }
+
+    /// Same placement with stitches of `aspect` width to height, narrowed along one side and
+    /// centered where the square stitches were.
+    pub fn stretched(&self, aspect: DVec2) -> Self {
+        let stitch = self.stitch * aspect / aspect.max_element();
+        let size = self.size / self.stitch * stitch;
+
+        Placement {
+            origin: self.origin + (self.size - size) / 2.0,
+            stitch,
+            size,
+            ..*self
+        }
+    }
+}
+
+/// Most stitches a page fits at their size on fabric of `fabric_count` stitches per inch.
+pub fn true_size_stitches(area: &ChartArea, fabric_count: u32) -> UVec2 {
+    let stitch = MMPI / fabric_count as f64;
+    let available = DVec2 {
+        x: area.right - (area.left + IMAGE_PADDING * 2.0),
+        y: area.bottom - (area.top + IMAGE_PADDING * 2.0),
+    };
+
+    (available / stitch).floor().as_uvec2().max(UVec2::ONE)
+}
+
+/// How the rows of a chart get numbered and what gets drawn over its symbols, all in the
+/// coordinates of the whole design.
+#[derive(Debug, Clone, Copy)]
+pub struct ChartOverlays<'a> {
+    pub rows: RowNumbers,
+    pub backstitches: &'a [Backstitch],
+    pub specialty_stitches: &'a [SpecialtyStitch],
+    pub bookmarks: &'a [Bookmark],
+}
+
+impl ChartOverlays<'_> {
+    /// Numbered rows without anything over the symbols.
+    pub fn rows_only(rows: RowNumbers) -> Self {
+        ChartOverlays {
+            rows,
+            backstitches: &[],
+            specialty_stitches: &[],
+            bookmarks: &[],
+        }
+    }
+}
+
+/// Draws the grid, its coordinates, the symbol of every stitch of `img` and the `overlays` on
+/// it, `img` being the part of the chart starting at the stitch `first`. The middle of the whole
+/// design, of `design_size` stitches, gets a heavier line with arrows pointing at it from the
+/// bottom and right edges when it crosses the part.
+pub fn draw_chart(
+    backend: &mut impl ChartBackend,
+    img: &RgbImage,
+    first: UVec2,
+    design_size: UVec2,
+    placement: &Placement,
+    color_symbol_map: &HashMap<Rgb<u8>, char>,
+    overlays: &ChartOverlays,
+) {
+    let image_size = UVec2::new(img.width(), img.height());
+    let Placement {
+        origin,
+        stitch,
+        size,
+        ..
+    } = *placement;
+    // Stitches past the last bold line of the whole design at the end of the part
+    let rem = (first + image_size) % GRID;
+    let extra_size = rem.as_dvec2() * stitch;
+
+    for i in 0..image_size.x {
+        let x = origin.x + stitch.x * i as f64;
+        backend.line(
+            DVec2::new(x, origin.y),
+            DVec2::new(x, origin.y + size.y),
+            Stroke::Fine,
+        );
+    }
+
+    for i in 0..image_size.y {
+        let y = origin.y + stitch.y * i as f64;
+        backend.line(
+            DVec2::new(origin.x, y),
+            DVec2::new(origin.x + size.x, y),
+            Stroke::Fine,
+        );
+    }
+
+    for i in grid_lines(first.x, image_size.x, GRID.x) {
+        let x = origin.x + stitch.x * i as f64;
+        backend.line(
+            DVec2::new(x, origin.y),
+            DVec2::new(x, origin.y + size.y),
+            Stroke::Bold,
+        );
+        backend.label(
+            &format!("{}", first.x + i),
+            LABEL_SIZE,
+            DVec2::new(x, origin.y + size.y + 1.0),
+            false,
+        );
+    }
+
+    if rem.x != 0 {
+        let extra = if first.x > 99 { 4.0 } else { 2.0 };
+        backend.label(
+            &format!("{}", first.x + image_size.x),
+            LABEL_SIZE,
+            DVec2::new(
+                (origin.x + size.x + (GRID.x - rem.x) as f64 * stitch.x)
+                    .min(origin.x + size.x + if extra_size.x < extra { extra } else { 0.0 }),
+                origin.y + size.y + 1.0,
+            ),
+            false,
+        );
+    }
+
+    let cell_origin = |x: u32, y: u32| {
+        DVec2::new(
+            origin.x + stitch.x * x as f64,
+            origin.y + size.y - stitch.y * (y + 1) as f64,
+        )
+    };
+
+    match overlays.rows {
+        RowNumbers::Top => {
+            for i in grid_lines(first.y, image_size.y, GRID.y) {
+                let y = origin.y + size.y - stitch.y * i as f64;
+                backend.line(
+                    DVec2::new(origin.x, y),
+                    DVec2::new(origin.x + size.x, y),
+                    Stroke::Bold,
+                );
+                backend.label(
+                    &format!("{}", first.y + i),
+                    LABEL_SIZE,
+                    DVec2::new(origin.x - 1.0, y),
+                    true,
+                );
+            }
+        }
+        RowNumbers::Knitting(total) => {
+            for y in 0..image_size.y {
+                let row = total - (first.y + y);
+                // Above every GRID rows counting from the bottom
+                if y > 0 && row.is_multiple_of(GRID.y) {
+                    let top = origin.y + size.y - stitch.y * y as f64;
+                    backend.line(
+                        DVec2::new(origin.x, top),
+                        DVec2::new(origin.x + size.x, top),
+                        Stroke::Bold,
+                    );
+                }
+
+                let x = if row % 2 == 1 {
+                    origin.x + size.x + ROW_LABEL_OFFSET
+                } else {
+                    origin.x - ROW_LABEL_OFFSET
+                };
+                backend.label(
+                    &row.to_string(),
+                    LABEL_SIZE,
+                    DVec2::new(x, cell_origin(0, y).y + stitch.y / 2.0 - 1.0),
+                    false,
+                );
+            }
+
+            for (side, x) in [
+                ("RS", origin.x + size.x + ROW_LABEL_OFFSET),
+                ("WS", origin.x - ROW_LABEL_OFFSET),
+            ] {
+                backend.label(side, LABEL_SIZE, DVec2::new(x, origin.y - 4.0), false);
+            }
+        }
+    }
+
+    if rem.y != 0 && overlays.rows == RowNumbers::Top {
+        let extra = if image_size.y > 99 { 4.0 } else { 2.0 };
+        backend.label(
+            &format!("{}", first.y + image_size.y),
+            LABEL_SIZE,
+            DVec2::new(
+                origin.x - 1.0,
+                (origin.y - (GRID.y - rem.y) as f64 * stitch.y)
+                    .max(origin.y - if extra_size.y < extra { extra } else { 0.0 }),
+            ),
+            true,
+        );
+    }
+
+    // Through the middle of the center stitch when there is one, on the grid line otherwise
+    let center = design_size.as_dvec2() / 2.0 - first.as_dvec2();
+    // Knitting row numbers are on the right too
+    let right_gap = match overlays.rows {
+        RowNumbers::Top => CENTER_ARROW_GAP,
+        RowNumbers::Knitting(_) => ROW_LABEL_OFFSET * 2.0 + CENTER_ARROW_GAP,
+    };
+    if center.x >= 0.0 && center.x <= image_size.x as f64 {
+        let x = origin.x + stitch.x * center.x;
+        backend.line(
+            DVec2::new(x, origin.y),
+            DVec2::new(x, origin.y + size.y),
+            Stroke::Center,
+        );
+        backend.arrow(
+            DVec2::new(x, origin.y - CENTER_ARROW_GAP),
+            DVec2::Y,
+            CENTER_ARROW,
+        );
+    }
+    if center.y >= 0.0 && center.y <= image_size.y as f64 {
+        let y = origin.y + size.y - stitch.y * center.y;
+        backend.line(
+            DVec2::new(origin.x, y),
+            DVec2::new(origin.x + size.x, y),
+            Stroke::Center,
+        );
+        backend.arrow(
+            DVec2::new(origin.x + size.x + right_gap, y),
+            -DVec2::X,
+            CENTER_ARROW,
+        );
+    }
+
+    // Thick lines around the border
+    let corners = [
+        origin,
+        origin + DVec2::new(0.0, size.y),
+        origin + size,
+        origin + DVec2::new(size.x, 0.0),
+    ];
+    for (from, to) in [(0, 1), (3, 2), (0, 3), (1, 2)] {
+        backend.line(corners[from], corners[to], Stroke::Bold);
+    }
+
+    for y in 0..image_size.y {
+        for x in 0..image_size.x {
+            let color = img.get_pixel(x, y);
+
+            // Only the bare fabric has no symbol
+            let Some(symbol) = color_symbol_map.get(color) else {
+                continue;
+            };
+
+            backend.symbol(*symbol, *color, cell_origin(x, y), stitch);
+        }
+    }
+
+    // Corners of the stitches from the top left of the part
+    let corner = |at: DVec2| {
+        DVec2::new(
+            origin.x + stitch.x * at.x,
+            origin.y + size.y - stitch.y * at.y,
+        )
+    };
+    for backstitch in overlays.backstitches {
+        let (from, to) = (
+            backstitch.from.as_dvec2() - first.as_dvec2(),
+            backstitch.to.as_dvec2() - first.as_dvec2(),
+        );
+        if let Some((from, to)) = clip_line(from, to, image_size.as_dvec2()) {
+            backend.line(
+                corner(from),
+                corner(to),
+                Stroke::Backstitch(backstitch.color),
+            );
+        }
+    }
+
+    for specialty in overlays.specialty_stitches {
+        if specialty.cell.cmpge(first).all() && (specialty.cell - first).cmplt(image_size).all() {
+            let cell = specialty.cell - first;
+            backend.specialty(specialty, cell_origin(cell.x, cell.y), stitch);
+        }
+    }
+
+    for (idx, bookmark) in overlays.bookmarks.iter().enumerate() {
+        let cell = UVec2::new(bookmark.x - 1, bookmark.y - 1);
+        if cell.cmpge(first).all() && (cell - first).cmplt(image_size).all() {
+            let cell = cell - first;
+            backend.highlight(idx + 1, cell_origin(cell.x, cell.y), stitch);
+        }
+    }
+}
+
+// Offsets from the start of a part beginning at the stitch `first` and `len` stitches long of the
+// bold lines of the whole design, so the lines of every part line up
+fn grid_lines(first: u32, len: u32, grid: u32) -> impl Iterator<Item = u32> {
+    (grid - first % grid..=len).step_by(grid as usize)
+}
+
+// Part of the line from `from` to `to` within the rectangle from zero to `size`, lines along its
+// edges included
+fn clip_line(from: DVec2, to: DVec2, size: DVec2) -> Option<(DVec2, DVec2)> {
+    let delta = to - from;
+    let (mut start, mut end) = (0.0f64, 1.0f64);
+
+    for (towards, room) in [
+        (-delta.x, from.x),
+        (delta.x, size.x - from.x),
+        (-delta.y, from.y),
+        (delta.y, size.y - from.y),
+    ] {
+        if towards == 0.0 {
+            if room < 0.0 {
+                return None;
+            }
+        } else if towards < 0.0 {
+            start = start.max(room / towards);
+        } else {
+            end = end.min(room / towards);
+        }
+    }
+
+    (start < end).then(|| (from + delta * start, from + delta * end))
+}
+
+/// Puts a tick box where the bold lines meet at the top left corner of every block of GRID
+/// stitches of a part of `stitches` starting at the stitch `first`, charted at `placement`. The
+/// blocks cut short by the edges of the part get one too.
+pub fn draw_block_boxes(
+    backend: &mut impl ChartBackend,
+    first: UVec2,
+    stitches: UVec2,
+    placement: &Placement,
+) {
+    let size = placement.stitch.min_element() * TICK_BOX;
+    // The edges of the part and the bold lines inside it
+    let starts = |first: u32, len: u32, grid: u32| {
+        std::iter::once(0).chain(grid_lines(first, len - 1, grid))
+    };
+
+    for y in starts(first.y, stitches.y, GRID.y) {
+        for x in starts(first.x, stitches.x, GRID.x) {
+            let corner = DVec2::new(
+                placement.origin.x + x as f64 * placement.stitch.x,
+                placement.origin.y + placement.size.y - y as f64 * placement.stitch.y,
+            );
+            backend.tick_box(corner, size);
+        }
+    }
+}
+
+/// Marks the corners of a chart outside of it, lining them up with the marks of the next pages
+/// puts the printouts together.
+pub fn draw_registration_marks(backend: &mut impl ChartBackend, placement: &Placement) {
+    let Placement { origin, size, .. } = *placement;
+
+    for corner in [DVec2::ZERO, DVec2::X, DVec2::Y, DVec2::ONE] {
+        let at = origin + size * corner;
+        // Away from the chart
+        let out = corner * 2.0 - 1.0;
+
+        backend.line(
+            at,
+            at + DVec2::new(out.x * REGISTRATION_MARK, 0.0),
+            Stroke::Bold,
+        );
+        backend.line(
+            at,
+            at + DVec2::new(0.0, out.y * REGISTRATION_MARK),
+            Stroke::Bold,
+        );
+    }
+}
+
+/// Hatches the strips along the right and bottom edges of a chart that the pages next to it get
+/// glued over.
+pub fn draw_glue_edges(
+    backend: &mut impl ChartBackend,
+    placement: &Placement,
+    right: bool,
+    bottom: bool,
+) {
+    let Placement { origin, size, .. } = *placement;
+
+    if right {
+        let x = origin.x + size.x;
+        for i in 0..=(size.y / GLUE_HATCH) as u32 {
+            let y = origin.y + size.y - GLUE_HATCH * i as f64;
+            // Cut short at the bottom of the strip
+            let length = GLUE_STRIP.min(y - origin.y);
+            backend.line(
+                DVec2::new(x, y),
+                DVec2::new(x + length, y - length),
+                Stroke::Fine,
+            );
+        }
+        backend.line(
+            DVec2::new(x + GLUE_STRIP, origin.y),
+            DVec2::new(x + GLUE_STRIP, origin.y + size.y),
+            Stroke::Fine,
+        );
+    }
+
+    if bottom {
+        let y = origin.y;
+        for i in 0..=(size.x / GLUE_HATCH) as u32 {
+            let x = origin.x + GLUE_HATCH * i as f64;
+            let length = GLUE_STRIP.min(origin.x + size.x - x);
+            backend.line(
+                DVec2::new(x, y),
+                DVec2::new(x + length, y - length),
+                Stroke::Fine,
+            );
+        }
+        backend.line(
+            DVec2::new(origin.x, y - GLUE_STRIP),
+            DVec2::new(origin.x + size.x, y - GLUE_STRIP),
+            Stroke::Fine,
+        );
+    }
+}
+
+/// Outlines every page of a chart of `size` stitches where it goes in the assembled chart, fit
+/// in `area`. `pages` are the page numbers along with the first stitch and size of their part.
+/// Returns the bottom left and top right corner of every outline.
+pub fn draw_assembly_diagram(
+    backend: &mut impl ChartBackend,
+    size: UVec2,
+    pages: &[(usize, UVec2, UVec2)],
+    area: &ChartArea,
+) -> Vec<(DVec2, DVec2)> {
+    let available = DVec2 {
+        x: area.right - (area.left + IMAGE_PADDING * 2.0),
+        y: area.bottom - (area.top + IMAGE_PADDING * 2.0),
+    };
+    let stitch = (available / size.as_dvec2()).min_element();
+    let extent = size.as_dvec2() * stitch;
+    let origin = DVec2 {
+        x: area.left + IMAGE_PADDING + (available.x - extent.x) / 2.0,
+        y: (area.page_height - area.bottom) + IMAGE_PADDING + (available.y - extent.y) / 2.0,
+    };
+
+    outline_pages(backend, pages, origin, extent, DVec2::splat(stitch))
+}
+
+/// Outlines and numbers every page over the whole design drawn at `placement`, for a map of
+/// which page charts which part. `pages` and what is returned are as for `draw_assembly_diagram`.
+pub fn draw_page_map(
+    backend: &mut impl ChartBackend,
+    pages: &[(usize, UVec2, UVec2)],
+    placement: &Placement,
+) -> Vec<(DVec2, DVec2)> {
+    outline_pages(
+        backend,
+        pages,
+        placement.origin,
+        placement.size,
+        placement.stitch,
+    )
+}
+
+// Outline and number of every page, on a chart `extent` large with its bottom left corner at
+// `origin` and stitches of `stitch`
+fn outline_pages(
+    backend: &mut impl ChartBackend,
+    pages: &[(usize, UVec2, UVec2)],
+    origin: DVec2,
+    extent: DVec2,
+    stitch: DVec2,
+) -> Vec<(DVec2, DVec2)> {
+    let mut outlines = Vec::with_capacity(pages.len());
+    for (number, first, stitches) in pages {
+        let bottom_left = DVec2::new(
+            origin.x + first.x as f64 * stitch.x,
+            origin.y + extent.y - (first.y + stitches.y) as f64 * stitch.y,
+        );
+        let top_right = bottom_left + stitches.as_dvec2() * stitch;
+        let corners = [
+            bottom_left,
+            DVec2::new(bottom_left.x, top_right.y),
+            top_right,
+            DVec2::new(top_right.x, bottom_left.y),
+        ];
+        for (from, to) in [(0, 1), (1, 2), (2, 3), (3, 0)] {
+            backend.line(corners[from], corners[to], Stroke::Bold);
+        }
+
+        backend.label(
+            &number.to_string(),
+            DIAGRAM_LABEL_SIZE,
+            (bottom_left + top_right) / 2.0,
+            false,
+        );
+        outlines.push((bottom_left, top_right));
+    }
+
+    outlines
+}
+
+/// Splits a chart of `size` stitches into pages of `page_stitches`, every page after the first
+/// of a row or column repeating the last `overlap` stitches of the one before it. Returns where
+/// each page is in the page grid along with its first stitch and its size in stitches, row by row.
+pub fn paginate(size: UVec2, page_stitches: UVec2, overlap: u32) -> Vec<(UVec2, UVec2, UVec2)> {
+    let step = page_stitches
+        .saturating_sub(UVec2::splat(overlap))
+        .max(UVec2::ONE);
+    let pages = ((size.saturating_sub(UVec2::splat(overlap)) + step - 1) / step).max(UVec2::ONE);
+
+    (0..pages.y)
+        .flat_map(|j| (0..pages.x).map(move |i| UVec2::new(i, j)))
+        .map(|page| {
+            let first = page * step;
+            (page, first, (size - first).min(page_stitches))
+        })
+        .collect()
+}
+
+/// Pages of a grid of `sections` starting at the center and working outwards.
+pub fn spiral_order(sections: UVec2) -> Vec<UVec2> {
+    const DIRECTIONS: [IVec2; 4] = [
+        IVec2::new(1, 0),
+        IVec2::new(0, 1),
+        IVec2::new(-1, 0),
+        IVec2::new(0, -1),
+    ];
+
+    let total = (sections.x * sections.y) as usize;
+    let mut order = Vec::with_capacity(total);
+    let mut coord = ((sections.as_ivec2() - 1) / 2).max(IVec2::ZERO);
+    let mut step_len = 1;
+    let mut direction = 0;
+
+    // Walk outwards in a square spiral, skipping the steps that fall outside the grid
+    while order.len() < total {
+        for _ in 0..2 {
+            for _ in 0..step_len {
+                if coord.cmpge(IVec2::ZERO).all() && coord.cmplt(sections.as_ivec2()).all() {
+                    order.push(coord.as_uvec2());
+                }
+                coord += DIRECTIONS[direction];
+            }
+            direction = (direction + 1) % DIRECTIONS.len();
+        }
+        step_len += 1;
+    }
+
+    order
+}
+
+/// Where a legend entry goes, counting legend pages from the first one.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct LegendSlot {
+    pub page: usize,
+    pub column: u32,
+    pub row: u32,
+}
+
+impl LegendSlot {
+    /// Left of the column and top of the row of the entry, with the top of the page at
+    /// `page_height`.
+    pub fn position(&self, page_height: f64) -> DVec2 {
+        let top = if self.page == 0 {
+            LEGEND_FIRST_TOP
+        } else {
+            LEGEND_TOP
+        };
+
+        DVec2 {
+            x: LEGEND_COLUMN * self.column as f64,
+            y: (page_height - top) - LEGEND_ROW * self.row as f64,
+        }
+    }
+}
+
+/// Lays `count` legend entries out in as many columns as fit across the page, starting a new page
+/// once the columns are full.
+pub fn legend_slots(count: usize, page_width: f64, page_height: f64) -> Vec<LegendSlot> {
+    let columns = ((page_width / LEGEND_COLUMN) as u32).max(1);
+    let mut slots = Vec::with_capacity(count);
+    let mut slot = LegendSlot {
+        page: 0,
+        column: 0,
+        row: 0,
+    };
+
+    for _ in 0..count {
+        if slot.position(page_height).y - 3.5 < LEGEND_BOTTOM {
+            slot.row = 0;
+            slot.column += 1;
+        }
+
+        if slot.column >= columns {
+            slot = LegendSlot {
+                page: slot.page + 1,
+                column: 0,
+                row: 0,
+            };
+        }
+
+        slots.push(slot);
+        slot.row += 1;
+    }
+
+    slots
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // The bold lines, labels and tick boxes drawn, everything else is ignored
+    #[derive(Default)]
+    struct Recorder {
+        bold: Vec<(DVec2, DVec2)>,
+        labels: Vec<(String, bool)>,
+        tick_boxes: Vec<DVec2>,
+    }
+
+    impl ChartBackend for Recorder {
+        fn line(&mut self, from: DVec2, to: DVec2, stroke: Stroke) {
+            if stroke == Stroke::Bold {
+                self.bold.push((from, to));
+            }
+        }
+
+        fn label(&mut self, text: &str, _: f64, _: DVec2, vertical: bool) {
+            self.labels.push((text.to_string(), vertical));
+        }
+
+        fn symbol(&mut self, _: char, _: Rgb<u8>, _: DVec2, _: DVec2) {}
+
+        fn specialty(&mut self, _: &SpecialtyStitch, _: DVec2, _: DVec2) {}
+
+        fn highlight(&mut self, _: usize, _: DVec2, _: DVec2) {}
+
+        fn tick_box(&mut self, center: DVec2, _: f64) {
+            self.tick_boxes.push(center);
+        }
+
+        fn arrow(&mut self, _: DVec2, _: DVec2, _: f64) {}
+    }
+
+    // A millimeter per stitch with the bottom left corner of the part at the origin
+    fn placement(stitches: UVec2) -> Placement {
+        Placement {
+            scale: 1,
+            dpi: DPI,
+            origin: DVec2::ZERO,
+            stitch: DVec2::ONE,
+            size: stitches.as_dvec2(),
+        }
+    }
+
+    #[test]
+    fn lines_up_the_grid_of_parts_starting_off_it() {
+        let stitches = UVec2::new(20, 25);
+        let mut recorder = Recorder::default();
+        draw_chart(
+            &mut recorder,
+            &RgbImage::new(stitches.x, stitches.y),
+            UVec2::new(37, 119),
+            UVec2::new(100, 200),
+            &placement(stitches),
+            &HashMap::new(),
+            &ChartOverlays::rows_only(RowNumbers::Top),
+        );
+
+        let mut columns = recorder
+            .bold
+            .iter()
+            .filter(|(from, to)| from.x == to.x)
+            .map(|(from, _)| from.x)
+            .collect::<Vec<_>>();
+        columns.sort_by(f64::total_cmp);
+        columns.dedup();
+        // Columns 40 and 50 of the design, between the edges of the part
+        assert_eq!(columns, [0.0, 3.0, 13.0, 20.0]);
+
+        let mut rows = recorder
+            .bold
+            .iter()
+            .filter(|(from, to)| from.y == to.y)
+            .map(|(from, _)| from.y)
+            .collect::<Vec<_>>();
+        rows.sort_by(f64::total_cmp);
+        rows.dedup();
+        // Rows 140, 130 and 120 of the design counting up from the bottom of the part
+        assert_eq!(rows, [0.0, 4.0, 14.0, 24.0, 25.0]);
+
+        let labels = |vertical: bool| {
+            recorder
+                .labels
+                .iter()
+                .filter(|label| label.1 == vertical)
+                .map(|label| label.0.as_str())
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(labels(false), ["40", "50", "57"]);
+        assert_eq!(labels(true), ["120", "130", "140", "144"]);
+    }
+
+    #[test]
+    fn keeps_overlapping_parts_on_one_grid() {
+        let size = UVec2::new(120, 30);
+        let parts = paginate(size, UVec2::new(45, 30), 3);
+        assert_eq!(
+            parts.iter().map(|part| part.1.x).collect::<Vec<_>>(),
+            [0, 42, 84]
+        );
+
+        let columns = parts
+            .iter()
+            .map(|&(_, first, stitches)| {
+                let mut recorder = Recorder::default();
+                draw_chart(
+                    &mut recorder,
+                    &RgbImage::new(stitches.x, stitches.y),
+                    first,
+                    size,
+                    &placement(stitches),
+                    &HashMap::new(),
+                    &ChartOverlays::rows_only(RowNumbers::Top),
+                );
+                recorder
+                    .labels
+                    .into_iter()
+                    .filter(|label| !label.1)
+                    .map(|label| label.0)
+                    .collect::<Vec<_>>()
+            })
+            .collect::<Vec<_>>();
+
+        // Every bold line is on a tenth column of the design, only the ends of the parts aren't
+        assert_eq!(
+            columns,
+            [
+                vec!["10", "20", "30", "40", "45"],
+                vec!["50", "60", "70", "80", "87"],
+                vec!["90", "100", "110", "120"],
+            ]
+        );
+    }
+
+    #[test]
+    fn puts_tick_boxes_on_the_grid_of_the_design() {
+        let stitches = UVec2::new(20, 12);
+        let mut recorder = Recorder::default();
+        draw_block_boxes(
+            &mut recorder,
+            UVec2::new(45, 30),
+            stitches,
+            &placement(stitches),
+        );
+
+        assert_eq!(
+            recorder.tick_boxes,
+            [
+                DVec2::new(0.0, 12.0),
+                DVec2::new(5.0, 12.0),
+                DVec2::new(15.0, 12.0),
+                DVec2::new(0.0, 2.0),
+                DVec2::new(5.0, 2.0),
+                DVec2::new(15.0, 2.0),
+            ]
+        );
+    }
+}
@@ -0,0 +1,84 @@
+use std::{collections::VecDeque, fs, io::BufWriter, path::Path};
+
+use glam::UVec2;
+
+use crate::{color::Color, solver::SuperPixel};
+
+/// State of a run, enough to continue it from where it was saved.
+#[derive(serde::Serialize, serde::Deserialize)]
+pub struct Checkpoint {
+    // Hash of the input the run was started on
+    pub input_hash: String,
+    pub iteration: usize,
+    pub t: f64,
+    pub t_initial: f64,
+    pub k: usize,
+    pub k_max: usize,
+    pub super_pixels: Vec<SuperPixelState>,
+    pub palette: Vec<(Color, f64)>,
+    pub clusters: Vec<UVec2>,
+    pub owners: Vec<Option<u8>>,
+    pub locked: Vec<bool>,
+    // Missing from older checkpoints, whose runs start over on settling
+    #[serde(default)]
+    pub convergence: Convergence,
+}
+
+/// How far a run got towards settling its palette at the current temperature.
+#[derive(Default, serde::Serialize, serde::Deserialize)]
+pub struct Convergence {
+    pub settled: bool,
+    pub running_average: f64,
+    pub prev_changes: VecDeque<f64>,
+    pub running_variance_avg: f64,
+    pub prev_variances: VecDeque<f64>,
+    pub variance_check_passed_count: usize,
+}
+
+#[derive(serde::Serialize, serde::Deserialize)]
+pub struct SuperPixelState {
+    coord: UVec2,
+    palette_color: Color,
+    probability: f64,
+    conditional_probability: Vec<f64>,
+    sp_color: Color,
+}
+
+impl SuperPixelState {
+    pub fn new(sp: &SuperPixel) -> Self {
+        SuperPixelState {
+            coord: sp.coord,
+            palette_color: sp.palette_color,
+            probability: sp.probability,
+            conditional_probability: sp.conditional_probability.clone(),
+            sp_color: sp.sp_color,
+        }
+    }
+
+    pub fn restore(self, sp: &mut SuperPixel) {
+        sp.coord = self.coord;
+        sp.palette_color = self.palette_color;
+        sp.probability = self.probability;
+        sp.conditional_probability = self.conditional_probability;
+        sp.sp_color = self.sp_color;
+    }
+}
+
+impl Checkpoint {
+    pub fn load(path: &Path) -> anyhow::Result<Self> {
+        Ok(serde_json::from_reader(fs::File::open(path)?)?)
+    }
+
+    /// Writes to a temporary file first so an interrupted save keeps the previous checkpoint.
+    pub fn save(&self, path: &Path) -> anyhow::Result<()> {
+        let mut tmp_path = path.as_os_str().to_owned();
+        tmp_path.push(".tmp");
+
+        let mut writer = BufWriter::new(fs::File::create(&tmp_path)?);
+        serde_json::to_writer(&mut writer, self)?;
+        writer.into_inner()?.sync_all()?;
+        fs::rename(&tmp_path, path)?;
+
+        Ok(())
+    }
+}
@@ -0,0 +1,183 @@
+use std::collections::HashMap;
+
+use ::image::{Rgb, RgbImage};
+use glam::UVec2;
+
+use pixelart_gen::{color::Color, metric::Metric};
+
+/// Gives every stitch the color most of the stitches within `radius` of it have, when more than
+/// half of them share a color other than its own. Only the stitches of the same frame and not
+/// left unstitched count, and unstitched pixels stay as they are. Returns how many stitches
+/// changed.
+pub fn majority_filter(
+    output: &mut RgbImage,
+    unstitched: &[bool],
+    frame_height: u32,
+    radius: u32,
+) -> usize {
+    let size = UVec2::new(output.width(), output.height());
+    let original = output.clone();
+    let mut changed = 0;
+
+    for idx in (0..unstitched.len()).filter(|idx| !unstitched[*idx]) {
+        let cell = UVec2::new(idx as u32 % size.x, idx as u32 / size.x);
+        let frame_top = cell.y / frame_height * frame_height;
+        let min = cell
+            .saturating_sub(UVec2::splat(radius))
+            .max(UVec2::new(0, frame_top));
+        let max = (cell + radius + 1).min(UVec2::new(size.x, frame_top + frame_height));
+
+        let mut counts = HashMap::<Rgb<u8>, usize>::new();
+        let mut total = 0;
+        for y in min.y..max.y {
+            for x in min.x..max.x {
+                if !unstitched[(y * size.x + x) as usize] {
+                    *counts.entry(*original.get_pixel(x, y)).or_default() += 1;
+                    total += 1;
+                }
+            }
+        }
+
+        let color = *original.get_pixel(cell.x, cell.y);
+        let majority = counts
+            .into_iter()
+            .filter(|(other, count)| *other != color && 2 * count > total)
+            .map(|(other, _)| other)
+            .next();
+        if let Some(majority) = majority {
+            output.put_pixel(cell.x, cell.y, majority);
+            changed += 1;
+        }
+    }
+
+    changed
+}
+
+/// What merging the small regions cost.
+#[derive(Debug)]
+pub struct ConfettiReport {
+    // Regions merged into a neighbor
+    pub regions: usize,
+    // Stitches that ended up with another color
+    pub stitches: usize,
+    // Mean difference between the old and the new color of these stitches
+    pub mean_delta_e: f64,
+}
+
+/// Recolors the stitches of every region of fewer than `min_size` stitches with the color of
+/// the neighboring region closest to its own, until no such region has a neighbor left.
+/// Regions are 4-connected and don't reach across the frames stacked in `output`, which are
+/// `frame_height` stitches high. Unstitched pixels are neither merged nor merged into.
+pub fn remove_confetti(
+    output: &mut RgbImage,
+    unstitched: &[bool],
+    frame_height: u32,
+    min_size: usize,
+    metric: Metric,
+) -> ConfettiReport {
+    let size = UVec2::new(output.width(), output.height());
+    let original = output.clone();
+    let mut merged = 0;
+
+    loop {
+        let regions = regions(output, unstitched, frame_height);
+        let mut recolored = 0;
+        for region in regions.iter().filter(|region| region.len() < min_size) {
+            let color = *output.get_pixel(region[0].x, region[0].y);
+            let lab = Color::from_srgb(color.0).to_array();
+
+            // The color right next to the region that is the nearest to its own
+            let neighbor = region
+                .iter()
+                .flat_map(|cell| neighbors(*cell, size, frame_height))
+                .filter(|cell| !unstitched[(cell.y * size.x + cell.x) as usize])
+                .map(|cell| *output.get_pixel(cell.x, cell.y))
+                .filter(|neighbor| *neighbor != color)
+                .map(|neighbor| {
+                    let distance = metric.distance(lab, Color::from_srgb(neighbor.0).to_array());
+                    (neighbor, distance)
+                })
+                .min_by(|(_, a), (_, b)| a.total_cmp(b));
+            let Some((neighbor, _)) = neighbor else {
+                continue;
+            };
+
+            for cell in region {
+                output.put_pixel(cell.x, cell.y, neighbor);
+            }
+            recolored += 1;
+        }
+
+        if recolored == 0 {
+            break;
+        }
+        merged += recolored;
+    }
+
+    let delta_es = original
+        .pixels()
+        .zip(output.pixels())
+        .filter(|(old, new)| old != new)
+        .map(|(old, new)| {
+            metric.distance(
+                Color::from_srgb(old.0).to_array(),
+                Color::from_srgb(new.0).to_array(),
+            )
+        })
+        .collect::<Vec<_>>();
+
+    ConfettiReport {
+        regions: merged,
+        stitches: delta_es.len(),
+        mean_delta_e: delta_es.iter().sum::<f64>() / delta_es.len().max(1) as f64,
+    }
+}
+
+// Stitched cells of the same color connected through their sides, every region as a list of
+// its cells
+fn regions(output: &RgbImage, unstitched: &[bool], frame_height: u32) -> Vec<Vec<UVec2>> {
+    let size = UVec2::new(output.width(), output.height());
+    let mut seen = unstitched.to_vec();
+    let mut regions = vec![];
+
+    for idx in 0..seen.len() {
+        if seen[idx] {
+            continue;
+        }
+
+        let start = UVec2::new(idx as u32 % size.x, idx as u32 / size.x);
+        let color = *output.get_pixel(start.x, start.y);
+        let mut region = vec![];
+        let mut stack = vec![start];
+        seen[idx] = true;
+        while let Some(cell) = stack.pop() {
+            region.push(cell);
+            for neighbor in neighbors(cell, size, frame_height) {
+                let neighbor_idx = (neighbor.y * size.x + neighbor.x) as usize;
+                if !seen[neighbor_idx] && *output.get_pixel(neighbor.x, neighbor.y) == color {
+                    seen[neighbor_idx] = true;
+                    stack.push(neighbor);
+                }
+            }
+        }
+
+        regions.push(region);
+    }
+
+    regions
+}
+
+// Cells sharing a side with `cell` in the same frame
+fn neighbors(cell: UVec2, size: UVec2, frame_height: u32) -> impl Iterator<Item = UVec2> {
+    let frame_top = cell.y / frame_height * frame_height;
+    let frame_bottom = frame_top + frame_height;
+
+    [
+        (cell.x > 0).then(|| cell - UVec2::X),
+        (cell.x + 1 < size.x).then(|| cell + UVec2::X),
+        (cell.y > frame_top).then(|| cell - UVec2::Y),
+        (cell.y + 1 < frame_bottom).then(|| cell + UVec2::Y),
+    ]
+    .into_iter()
+    .flatten()
+}
@@ -1,13 +1,14 @@
 use std::{
     iter::Sum,
-    ops::{Add, AddAssign, Div, DivAssign, Mul},
+    ops::{Add, AddAssign, Div, DivAssign, Mul, Sub},
 };
 
 use glam::{DVec2, DVec3, UVec2};
+use palette::{Clamp, FromColor};
 
-use crate::{image::LabImage, SuperPixel};
+use crate::{image::LabImage, metric::Metric, solver::SuperPixel};
 
-#[derive(Debug, Clone, Copy, PartialEq)]
+#[derive(Debug, Clone, Copy, PartialEq, serde::Serialize, serde::Deserialize)]
 #[repr(transparent)]
 pub struct Color(DVec3);
 
@@ -22,16 +23,38 @@ impl Color {
         [self.0.x, self.0.y, self.0.z]
     }
 
+    pub fn from_srgb(rgb: [u8; 3]) -> Self {
+        let color: palette::rgb::Srgb<f64> =
+            palette::rgb::Srgb::new(rgb[0], rgb[1], rgb[2]).into_format();
+        let lab: palette::Lab<palette::white_point::D65, f64> = palette::Lab::from_color(color);
+        Color::new(lab.l, lab.a, lab.b)
+    }
+
+    pub fn to_srgb(&self) -> [u8; 3] {
+        let lab = palette::Lab::<palette::white_point::D65, f64>::new(self.l(), self.a(), self.b());
+        let rgb = palette::Srgb::from_color(lab).clamp().into_format::<u8>();
+        [rgb.red, rgb.green, rgb.blue]
+    }
+
+    /// Straight line distance to `rhs` in Lab.
     pub fn distance(&self, rhs: Color) -> f64 {
         self.0.distance(rhs.0)
     }
 
+    /// Difference with `rhs` using `metric`, `self` being the reference.
+    pub fn difference(&self, rhs: Color, metric: Metric) -> f64 {
+        match metric {
+            Metric::Euclidean => self.distance(rhs),
+            metric => metric.distance(self.to_array(), rhs.to_array()),
+        }
+    }
+
     pub fn average_from(img: &LabImage, in_size: UVec2) -> Color {
-        img.pixels.iter().map(|color| *color).sum::<Color>() / (in_size.x * in_size.y) as f64
+        img.pixels.iter().copied().sum::<Color>() / (in_size.x * in_size.y) as f64
     }
 
     pub fn condit_prob(&self, probability: f64, sp: &SuperPixel, t: f64) -> f64 {
-        probability * std::f64::consts::E.powf(-1.0 * sp.sp_color.distance(*self) / t)
+        probability * std::f64::consts::E.powf(-sp.sp_color.difference(*self, sp.metric) / t)
     }
 
     pub fn perturb(&mut self, delta: DVec2) {
@@ -61,6 +84,14 @@ impl Add for Color {
     }
 }
 
+impl Sub for Color {
+    type Output = Color;
+
+    fn sub(self, rhs: Self) -> Self::Output {
+        Color(self.0 - rhs.0)
+    }
+}
+
 impl AddAssign<Color> for Color {
     fn add_assign(&mut self, rhs: Color) {
         self.0 += rhs.0;
@@ -0,0 +1,78 @@
+use image::Rgb;
+
+use crate::color::Color;
+
+// Colors closer than this ΔE76 once simulated are taken for each other
+const CONFUSABLE_DISTANCE: f64 = 12.0;
+
+/// The common color vision deficiencies, each missing one kind of cone.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Deficiency {
+    Protanopia,
+    Deuteranopia,
+    Tritanopia,
+}
+
+impl Deficiency {
+    pub const ALL: [Deficiency; 3] = [
+        Deficiency::Protanopia,
+        Deficiency::Deuteranopia,
+        Deficiency::Tritanopia,
+    ];
+
+    // Rows of the matrices of Machado, Oliveira and Fernandes (2009) at full severity, which
+    // work on linear RGB
+    fn matrix(self) -> [[f64; 3]; 3] {
+        match self {
+            Deficiency::Protanopia => [
+                [0.152286, 1.052583, -0.204868],
+                [0.114503, 0.786281, 0.099216],
+                [-0.003882, -0.048116, 1.051998],
+            ],
+            Deficiency::Deuteranopia => [
+                [0.367322, 0.860646, -0.227968],
+                [0.280085, 0.672501, 0.047413],
+                [-0.011820, 0.042940, 0.968881],
+            ],
+            Deficiency::Tritanopia => [
+                [1.255528, -0.076749, -0.178779],
+                [-0.078411, 0.930809, 0.147602],
+                [0.004733, 0.691367, 0.303900],
+            ],
+        }
+    }
+
+    /// How `color` looks to someone with this deficiency.
+    pub fn simulate(self, color: Rgb<u8>) -> Rgb<u8> {
+        let linear = palette::Srgb::new(color.0[0], color.0[1], color.0[2])
+            .into_format::<f64>()
+            .into_linear();
+        let channels = [linear.red, linear.green, linear.blue];
+        let [red, green, blue] = self.matrix().map(|row| {
+            row.iter()
+                .zip(channels)
+                .map(|(weight, channel)| weight * channel)
+                .sum::<f64>()
+                .clamp(0.0, 1.0)
+        });
+        let rgb: palette::Srgb<u8> =
+            palette::Srgb::from_linear(palette::LinSrgb::new(red, green, blue));
+
+        Rgb([rgb.red, rgb.green, rgb.blue])
+    }
+}
+
+/// Whether `a` and `b` look alike to people with one of the common deficiencies.
+pub fn confusable(a: Rgb<u8>, b: Rgb<u8>) -> bool {
+    Deficiency::ALL.iter().any(|deficiency| {
+        let a = Color::from_srgb(deficiency.simulate(a).0).to_array();
+        let b = Color::from_srgb(deficiency.simulate(b).0).to_array();
+        let distance = a
+            .iter()
+            .zip(b)
+            .map(|(a, b)| (a - b).powi(2))
+            .sum::<f64>()
+            .sqrt();
+        distance < CONFUSABLE_DISTANCE
+    })
+}
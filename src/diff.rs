@@ -0,0 +1,68 @@
+use std::{fs, io::BufWriter, path::PathBuf};
+
+use clap::Parser;
+
+use pixelart_gen::pattern_diff::{changes, diff_image, GridMap, Stitch};
+
+// Changed cells listed in the terminal, the rest only go to -o
+const LISTED_CHANGES: usize = 20;
+
+/// Compares the grid maps `pdfgen --grid-map` wrote for two versions of a pattern, as
+/// `pixelart-gen diff old.json new.json`.
+#[derive(Debug, Parser)]
+#[command(bin_name = "pixelart-gen diff")]
+pub struct DiffArgs {
+    // Grid map of the published pattern, as written by `pdfgen --grid-map`
+    old: PathBuf,
+    // Grid map of the updated pattern
+    new: PathBuf,
+    // Write the changed cells as JSON
+    #[arg(short)]
+    output: Option<PathBuf>,
+    // Draw the updated pattern with the changed cells outlined and the others faded
+    #[arg(long)]
+    image: Option<PathBuf>,
+    // Pixels per cell of --image
+    #[arg(long, default_value_t = 8, value_parser = clap::value_parser!(u32).range(3..))]
+    scale: u32,
+}
+
+/// Lists the cells whose floss changed, counting from 1 like the chart.
+pub fn run(args: DiffArgs) -> anyhow::Result<()> {
+    let old = GridMap::load(&args.old)?;
+    let new = GridMap::load(&args.new)?;
+    let changes = changes(&old, &new)?;
+
+    println!(
+        "{} of {} cells changed",
+        changes.len(),
+        old.width * old.height
+    );
+    for change in changes.iter().take(LISTED_CHANGES) {
+        let name = |stitch: &Option<Stitch>| {
+            stitch.as_ref().map_or("unstitched".to_string(), |stitch| {
+                format!("{} ({})", stitch.floss, stitch.symbol)
+            })
+        };
+        println!(
+            "  {}, {}: {} -> {}",
+            change.x,
+            change.y,
+            name(&change.old),
+            name(&change.new)
+        );
+    }
+    if changes.len() > LISTED_CHANGES {
+        println!("  and {} more", changes.len() - LISTED_CHANGES);
+    }
+
+    if let Some(path) = &args.output {
+        serde_json::to_writer(BufWriter::new(fs::File::create(path)?), &changes)?;
+    }
+
+    if let Some(path) = &args.image {
+        diff_image(&new, &changes, args.scale)?.save(path)?;
+    }
+
+    Ok(())
+}
@@ -0,0 +1,105 @@
+use pixelart_gen::{checkpoint::Checkpoint, solver::Solver};
+
+// Most colors `--fg-colors auto` goes up to without an explicit maximum
+const AUTO_MAX_COLORS: u8 = 32;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, serde::Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ColorCount {
+    Fixed(u8),
+    // Keep adding colors up to this many while they still pay off
+    Auto(u8),
+}
+
+impl ColorCount {
+    /// Most colors the palette can get.
+    pub fn max(self) -> usize {
+        match self {
+            ColorCount::Fixed(count) | ColorCount::Auto(count) => count as usize,
+        }
+    }
+}
+
+/// Parses color counts written as `12`, `auto` or `auto:24`.
+pub fn parse_color_count(s: &str) -> Result<ColorCount, String> {
+    let parse = |v: &str| match v.trim().parse::<u8>() {
+        Ok(0) => Err("the color count can't be zero".to_string()),
+        Ok(v) => Ok(v),
+        Err(err) => Err(format!("invalid color count {s:?}: {err}")),
+    };
+
+    match s.trim().split_once(':') {
+        Some(("auto", max)) => Ok(ColorCount::Auto(parse(max)?)),
+        None if s.trim() == "auto" => Ok(ColorCount::Auto(AUTO_MAX_COLORS)),
+        _ => parse(s).map(ColorCount::Fixed),
+    }
+}
+
+/// Follows how much every expansion of the palette lowers the reconstruction error, and stops the
+/// palette at the last size whose expansion lowered it by at least `min_gain`.
+pub struct ElbowSearch {
+    min_gain: f64,
+    // Sizes the palette went through, with the state right after it got there
+    states: Vec<(usize, Checkpoint)>,
+    // Reconstruction error once the palette converged, for every size but the current one
+    errors: Vec<f64>,
+}
+
+impl ElbowSearch {
+    pub fn new(solver: &Solver, min_gain: f64) -> Self {
+        ElbowSearch {
+            min_gain,
+            states: vec![(solver.k(), solver.checkpoint(String::new()))],
+            errors: vec![],
+        }
+    }
+
+    /// Checks the palette after an iteration. Once an expansion doesn't pay off, the solver goes
+    /// back to the palette before it, which is then final, and its size is returned.
+    pub fn update(&mut self, solver: &mut Solver) -> Option<usize> {
+        let (k, _) = self.states.last().unwrap();
+        if solver.k() == *k {
+            return None;
+        }
+
+        // The palette converged at the previous size right before the expansion, and the super
+        // pixels still use its colors
+        let error = solver.reconstruction_error();
+        if let Some(previous) = self.errors.last() {
+            if previous - error < self.min_gain * previous {
+                let (k, state) = self.states.swap_remove(self.states.len() - 2);
+                solver.restore(state);
+                solver.freeze_color_count();
+                return Some(k);
+            }
+        }
+
+        self.errors.push(error);
+        self.states
+            .push((solver.k(), solver.checkpoint(String::new())));
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_color_counts() {
+        assert_eq!(parse_color_count("8"), Ok(ColorCount::Fixed(8)));
+        assert_eq!(parse_color_count(" 12 "), Ok(ColorCount::Fixed(12)));
+        assert_eq!(
+            parse_color_count("auto"),
+            Ok(ColorCount::Auto(AUTO_MAX_COLORS))
+        );
+        assert_eq!(parse_color_count("auto:24"), Ok(ColorCount::Auto(24)));
+    }
+
+    #[test]
+    fn rejects_invalid_color_counts() {
+        for invalid in ["0", "auto:0", "auto:", "auto:many", "many", "-3", "256", ""] {
+            assert!(parse_color_count(invalid).is_err(), "{invalid:?} parsed");
+        }
+    }
+}
@@ -0,0 +1,551 @@
+use std::io::{self, Write};
+
+use glam::{IVec2, UVec2};
+
+// Longest move of a Tajima record and of a PEC long form move, in tenths of a millimeter
+const DST_MAX_MOVE: i32 = 121;
+const PEC_MAX_MOVE: i32 = 2047;
+// Flags of the last byte of a Tajima record
+const DST_JUMP: u8 = 0x80;
+const DST_COLOR_CHANGE: u8 = 0xc0;
+const DST_END: u8 = 0xf3;
+// Flags of the PEC long form moves, the first jump moves to the start and the others trim
+const PEC_JUMP: u16 = 0x1000;
+const PEC_TRIM: u16 = 0x2000;
+const PEC_ICON: UVec2 = UVec2 { x: 48, y: 38 };
+
+// Brother thread chart the PEC colors are numbered in, from 1
+const PEC_THREADS: [[u8; 3]; 64] = [
+    [0x1a, 0x0a, 0x94],
+    [0x0f, 0x75, 0xff],
+    [0x00, 0x93, 0x4c],
+    [0xba, 0xbd, 0xfe],
+    [0xec, 0x00, 0x00],
+    [0xe4, 0x99, 0x5a],
+    [0xcc, 0x48, 0xab],
+    [0xfd, 0xc4, 0xfa],
+    [0xdd, 0x84, 0xcd],
+    [0x6b, 0xd3, 0x8a],
+    [0xe4, 0xa9, 0x45],
+    [0xff, 0xbd, 0x42],
+    [0xff, 0xe6, 0x00],
+    [0x6c, 0xd9, 0x00],
+    [0xc1, 0xa9, 0x41],
+    [0xb5, 0xad, 0x97],
+    [0xba, 0x9c, 0x5f],
+    [0xfa, 0xf5, 0x9e],
+    [0x80, 0x80, 0x80],
+    [0x00, 0x00, 0x00],
+    [0x00, 0x1c, 0xdf],
+    [0xdf, 0x00, 0xb8],
+    [0x62, 0x62, 0x62],
+    [0x69, 0x26, 0x0d],
+    [0xff, 0x00, 0x60],
+    [0xbf, 0x82, 0x00],
+    [0xf3, 0x91, 0x78],
+    [0xff, 0x68, 0x05],
+    [0xf0, 0xf0, 0xf0],
+    [0xc8, 0x32, 0xcd],
+    [0xb0, 0xbf, 0x9b],
+    [0x65, 0xbf, 0xeb],
+    [0xff, 0xba, 0x04],
+    [0xff, 0xf0, 0x6c],
+    [0xfe, 0xca, 0x15],
+    [0xf3, 0x81, 0x01],
+    [0x37, 0xa9, 0x23],
+    [0x23, 0x46, 0x5f],
+    [0xa6, 0xa6, 0x95],
+    [0xce, 0xbf, 0xa6],
+    [0x96, 0xaa, 0x02],
+    [0xff, 0xe3, 0xc6],
+    [0xff, 0x99, 0xd7],
+    [0x00, 0x70, 0x04],
+    [0xed, 0xcc, 0xfb],
+    [0xc0, 0x89, 0xd8],
+    [0xe7, 0xd9, 0xb4],
+    [0xe9, 0x0e, 0x86],
+    [0xcf, 0x68, 0x29],
+    [0x40, 0x86, 0x15],
+    [0xdb, 0x17, 0x97],
+    [0xff, 0xa7, 0x04],
+    [0xb9, 0xff, 0xff],
+    [0x22, 0x89, 0x27],
+    [0xb6, 0x12, 0xcd],
+    [0x00, 0xaa, 0x00],
+    [0xfe, 0xa9, 0xdc],
+    [0xfe, 0xd5, 0x10],
+    [0x00, 0x97, 0xdf],
+    [0xff, 0xff, 0x84],
+    [0xcf, 0xe7, 0x74],
+    [0xff, 0xc8, 0x64],
+    [0xff, 0xc8, 0xc8],
+    [0xff, 0xc8, 0xc8],
+];
+
+/// What the machine does next. Positions are in tenths of a millimeter from the center of the
+/// design, with y going down.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Command {
+    // Sews up to here
+    Stitch(IVec2),
+    // Moves here without sewing
+    Jump(IVec2),
+    // Stops for the next thread
+    ColorChange,
+}
+
+/// Cross stitches of a row major grid of `size` cells, `cells` having the thread of every
+/// stitched cell out of `threads` and every cell being `cell` tenths of a millimeter a side. The
+/// threads are sewn one after the other, every cross from its bottom left corner so the crosses
+/// of a row follow on from each other, and the machine jumps to the ones that don't.
+pub fn cross_stitches(
+    cells: &[Option<usize>],
+    size: UVec2,
+    threads: usize,
+    cell: i32,
+) -> Vec<Command> {
+    let center = size.as_ivec2() * cell / 2;
+    let mut commands = vec![];
+
+    for thread in 0..threads {
+        if thread > 0 {
+            commands.push(Command::ColorChange);
+        }
+
+        let mut needle = None;
+        for (idx, _) in cells
+            .iter()
+            .enumerate()
+            .filter(|(_, cell)| **cell == Some(thread))
+        {
+            let top_left =
+                IVec2::new(idx as i32 % size.x as i32, idx as i32 / size.x as i32) * cell - center;
+            let bottom_left = top_left + IVec2::new(0, cell);
+            if needle != Some(bottom_left) {
+                commands.push(Command::Jump(bottom_left));
+                commands.push(Command::Stitch(bottom_left));
+            }
+
+            let bottom_right = top_left + IVec2::splat(cell);
+            commands.extend([
+                Command::Stitch(top_left + IVec2::new(cell, 0)),
+                Command::Stitch(top_left),
+                Command::Stitch(bottom_right),
+            ]);
+            needle = Some(bottom_right);
+        }
+    }
+
+    commands
+}
+
+/// Writes `commands` as a Tajima DST file, the thread colors being up to the machine.
+pub fn write_dst(out: &mut impl Write, name: &str, commands: &[Command]) -> io::Result<()> {
+    let mut records = vec![];
+    let mut needle = IVec2::ZERO;
+    let (mut min, mut max) = (IVec2::ZERO, IVec2::ZERO);
+    let mut color_changes = 0;
+
+    for command in commands {
+        match *command {
+            Command::Stitch(to) | Command::Jump(to) => {
+                let moves = split_move(needle, to, DST_MAX_MOVE);
+                for (idx, step) in moves.iter().enumerate() {
+                    // Moves too long for a record get there in jumps
+                    let jump = matches!(command, Command::Jump(_)) || idx + 1 < moves.len();
+                    records.extend(dst_record(*step, if jump { DST_JUMP } else { 0 }));
+                }
+                needle = to;
+                min = min.min(to);
+                max = max.max(to);
+            }
+            Command::ColorChange => {
+                records.extend(dst_record(IVec2::ZERO, DST_COLOR_CHANGE));
+                color_changes += 1;
+            }
+        }
+    }
+    records.extend([0, 0, DST_END]);
+
+    // The header has y going up
+    let mut header = format!(
+        "LA:{:<16.16}\rST:{:>7}\rCO:{:>3}\r+X:{:>5}\r-X:{:>5}\r+Y:{:>5}\r-Y:{:>5}\r",
+        name,
+        records.len() / 3,
+        color_changes,
+        max.x,
+        -min.x,
+        -min.y,
+        max.y,
+    );
+    for (label, value) in [("AX", needle.x), ("AY", -needle.y), ("MX", 0), ("MY", 0)] {
+        let sign = if value < 0 { '-' } else { '+' };
+        header.push_str(&format!("{label}:{sign}{:>5}\r", value.abs()));
+    }
+    header.push_str("PD:******\r\x1a");
+
+    let mut header = header.into_bytes();
+    header.resize(512, b' ');
+    out.write_all(&header)?;
+    out.write_all(&records)
+}
+
+/// Writes `commands` as a Brother PES file made of only the PEC part the machines read, with
+/// every block of `commands` up to a color change sewn in the Brother thread closest to its
+/// color in `threads`.
+pub fn write_pes(
+    out: &mut impl Write,
+    name: &str,
+    commands: &[Command],
+    threads: &[[u8; 3]],
+) -> io::Result<()> {
+    let mut pec = format!("LA:{:<16.16}\r", name).into_bytes();
+    pec.extend_from_slice(&[b' '; 12]);
+    pec.extend_from_slice(&[0xff, 0x00, (PEC_ICON.x / 8) as u8, PEC_ICON.y as u8]);
+    pec.extend_from_slice(b"    \x64 \x00 \x00   ");
+    pec.push(threads.len().saturating_sub(1) as u8);
+    pec.extend(threads.iter().map(|color| nearest_pec_thread(*color)));
+    pec.resize(512, b' ');
+
+    let (min, max) = commands
+        .iter()
+        .filter_map(|command| match command {
+            Command::Stitch(at) | Command::Jump(at) => Some(*at),
+            Command::ColorChange => None,
+        })
+        .fold((IVec2::ZERO, IVec2::ZERO), |(min, max), at| {
+            (min.min(at), max.max(at))
+        });
+    let size = max - min;
+
+    let block_start = pec.len();
+    // Two zeros, then room for the length of the block
+    pec.extend_from_slice(&[0; 5]);
+    pec.extend_from_slice(&[0x31, 0xff, 0xf0]);
+    pec.extend_from_slice(&(size.x as u16).to_le_bytes());
+    pec.extend_from_slice(&(size.y as u16).to_le_bytes());
+    pec.extend_from_slice(&0x1e0u16.to_le_bytes());
+    pec.extend_from_slice(&0x1b0u16.to_le_bytes());
+    pec.extend_from_slice(&(0x9000 | (-min.x & 0xfff) as u16).to_be_bytes());
+    pec.extend_from_slice(&(0x9000 | (-min.y & 0xfff) as u16).to_be_bytes());
+    pec_stitches(&mut pec, commands);
+    let block_len = (pec.len() - block_start) as u32;
+    pec[block_start + 2..block_start + 5].copy_from_slice(&block_len.to_le_bytes()[..3]);
+
+    // Thumbnails of the whole design, then of what every thread sews
+    pec.extend(pec_icon(commands, None, min, size));
+    for thread in 0..threads.len() {
+        pec.extend(pec_icon(commands, Some(thread), min, size));
+    }
+
+    let mut header = b"#PES0001".to_vec();
+    // The PEC part follows right after the header
+    header.extend_from_slice(&22u32.to_le_bytes());
+    header.resize(22, 0);
+    out.write_all(&header)?;
+    out.write_all(&pec)
+}
+
+// Relative moves from `from` to `to`, spread evenly so none is longer than `max` along an axis
+fn split_move(from: IVec2, to: IVec2, max: i32) -> Vec<IVec2> {
+    let delta = to - from;
+    let steps = ((delta.abs().max_element() + max - 1) / max).max(1);
+
+    (1..=steps)
+        .map(|step| delta * step / steps - delta * (step - 1) / steps)
+        .collect()
+}
+
+// Tajima records spell out moves as sums of ±1, ±3, ±9, ±27 and ±81
+fn dst_record(delta: IVec2, flags: u8) -> [u8; 3] {
+    let mut bytes = [0u8; 3];
+    let (mut x, mut y) = (delta.x, -delta.y);
+    // Weight of every digit along with the byte and bits of its positive and negative flags
+    let digits = [
+        (81, 2, 0x04, 0x08, 0x20, 0x10),
+        (27, 1, 0x04, 0x08, 0x20, 0x10),
+        (9, 0, 0x04, 0x08, 0x20, 0x10),
+        (3, 1, 0x01, 0x02, 0x80, 0x40),
+        (1, 0, 0x01, 0x02, 0x80, 0x40),
+    ];
+    for (weight, byte, x_plus, x_minus, y_plus, y_minus) in digits {
+        let half = weight / 2;
+        if x > half {
+            bytes[byte] |= x_plus;
+            x -= weight;
+        } else if x < -half {
+            bytes[byte] |= x_minus;
+            x += weight;
+        }
+        if y > half {
+            bytes[byte] |= y_plus;
+            y -= weight;
+        } else if y < -half {
+            bytes[byte] |= y_minus;
+            y += weight;
+        }
+    }
+    bytes[2] |= 0x03 | flags;
+
+    bytes
+}
+
+fn pec_stitches(pec: &mut Vec<u8>, commands: &[Command]) {
+    let mut needle = IVec2::ZERO;
+    let mut jumping = true;
+    let mut first = true;
+    // The color change commands alternate between two codes
+    let mut color_two = true;
+
+    for command in commands {
+        match *command {
+            Command::Stitch(to) => {
+                if jumping {
+                    pec.extend_from_slice(&[0, 0]);
+                    jumping = false;
+                }
+                for step in split_move(needle, to, PEC_MAX_MOVE) {
+                    if step.cmpgt(IVec2::splat(-64)).all() && step.cmplt(IVec2::splat(63)).all() {
+                        pec.extend_from_slice(&[(step.x & 0x7f) as u8, (step.y & 0x7f) as u8]);
+                    } else {
+                        pec.extend(pec_long_move(step, 0));
+                    }
+                }
+                needle = to;
+            }
+            Command::Jump(to) => {
+                jumping = true;
+                for step in split_move(needle, to, PEC_MAX_MOVE) {
+                    pec.extend(pec_long_move(step, if first { PEC_JUMP } else { PEC_TRIM }));
+                }
+                needle = to;
+            }
+            Command::ColorChange => {
+                if jumping {
+                    pec.extend_from_slice(&[0, 0]);
+                    jumping = false;
+                }
+                pec.extend_from_slice(&[0xfe, 0xb0, if color_two { 2 } else { 1 }]);
+                color_two = !color_two;
+            }
+        }
+        first = false;
+    }
+
+    if jumping {
+        pec.extend_from_slice(&[0, 0]);
+    }
+    pec.push(0xff);
+}
+
+// 12 bit move of a single axis with the long form bit and `flags`
+fn pec_long_move(step: IVec2, flags: u16) -> [u8; 4] {
+    let [x, y] =
+        [step.x, step.y].map(|value| (0x8000 | flags | (value & 0xfff) as u16).to_be_bytes());
+
+    [x[0], x[1], y[0], y[1]]
+}
+
+fn nearest_pec_thread(color: [u8; 3]) -> u8 {
+    let distance = |thread: &[u8; 3]| {
+        color
+            .iter()
+            .zip(thread)
+            .map(|(a, b)| (*a as i32 - *b as i32).pow(2))
+            .sum::<i32>()
+    };
+
+    (PEC_THREADS
+        .iter()
+        .enumerate()
+        .min_by_key(|(_, thread)| distance(thread))
+        .map_or(0, |(idx, _)| idx)
+        + 1) as u8
+}
+
+// Monochrome thumbnail with a frame and the stitches of `thread`, or of all of them, scaled into it
+fn pec_icon(commands: &[Command], thread: Option<usize>, min: IVec2, size: IVec2) -> Vec<u8> {
+    let stride = (PEC_ICON.x / 8) as usize;
+    let mut icon = vec![0u8; stride * PEC_ICON.y as usize];
+    let mut set = |x: u32, y: u32| icon[y as usize * stride + x as usize / 8] |= 1 << (x % 8);
+
+    for x in 0..PEC_ICON.x {
+        set(x, 0);
+        set(x, PEC_ICON.y - 1);
+    }
+    for y in 0..PEC_ICON.y {
+        set(0, y);
+        set(PEC_ICON.x - 1, y);
+    }
+
+    // Inside the frame with a margin of 3 pixels
+    let inner = PEC_ICON.as_ivec2() - 8;
+    let mut current = 0;
+    for command in commands {
+        match command {
+            Command::ColorChange => current += 1,
+            Command::Stitch(at) if thread.is_none_or(|thread| thread == current) => {
+                let pixel = (*at - min) * inner / size.max(IVec2::ONE) + 4;
+                set(pixel.x as u32, pixel.y as u32);
+            }
+            _ => {}
+        }
+    }
+
+    icon
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // A jump long enough to take several records, a stitch in place, a stitch back and one after
+    // a color change
+    const COMMANDS: [Command; 5] = [
+        Command::Jump(IVec2::new(-300, 0)),
+        Command::Stitch(IVec2::new(-300, 0)),
+        Command::Stitch(IVec2::new(0, 0)),
+        Command::ColorChange,
+        Command::Stitch(IVec2::new(10, 20)),
+    ];
+
+    // Move of a Tajima record with y going down, along with its jump and color change flags
+    fn decode_dst_record(record: &[u8]) -> (IVec2, bool, bool) {
+        let bit = |byte: usize, bit: u8| (record[byte] >> bit & 1) as i32;
+        let mut delta = IVec2::ZERO;
+        for (byte, [x_plus, x_minus, y_plus, y_minus], weights) in [
+            (0, [0, 1, 7, 6], [1, 9]),
+            (1, [0, 1, 7, 6], [3, 27]),
+            (2, [2, 3, 5, 4], [0, 81]),
+        ] {
+            delta.x += weights[0] * (bit(byte, x_plus) - bit(byte, x_minus));
+            delta.y -= weights[0] * (bit(byte, y_plus) - bit(byte, y_minus));
+            // The second digit of a byte is two bits up
+            if byte < 2 {
+                delta.x += weights[1] * (bit(byte, x_plus + 2) - bit(byte, x_minus + 2));
+                delta.y -= weights[1] * (bit(byte, y_plus - 2) - bit(byte, y_minus - 2));
+            } else {
+                delta.x += weights[1] * (bit(byte, x_plus) - bit(byte, x_minus));
+                delta.y -= weights[1] * (bit(byte, y_plus) - bit(byte, y_minus));
+                delta.x -= weights[0];
+            }
+        }
+
+        (delta, record[2] & 0x80 != 0, record[2] & 0x40 != 0)
+    }
+
+    #[test]
+    fn writes_dst_records() {
+        let mut dst = vec![];
+        write_dst(&mut dst, "test", &COMMANDS).unwrap();
+
+        let (header, records) = dst.split_at(512);
+        let header = String::from_utf8_lossy(header);
+        assert!(header.starts_with("LA:test            \rST:     10\rCO:  1\r"));
+        assert!(header.contains("+X:   10\r-X:  300\r+Y:    0\r-Y:   20\r"));
+        assert!(header.contains("AX:+   10\rAY:-   20\r"));
+
+        let records = records.chunks(3).collect::<Vec<_>>();
+        assert_eq!(records.len(), 10);
+        assert_eq!(records[9], [0, 0, DST_END]);
+
+        let mut needle = IVec2::ZERO;
+        let mut positions = vec![];
+        for record in &records[..9] {
+            let (delta, jump, color_change) = decode_dst_record(record);
+            assert!(delta.abs().max_element() <= DST_MAX_MOVE);
+            needle += delta;
+            positions.push((needle, jump, color_change));
+        }
+        assert_eq!(
+            positions,
+            [
+                (IVec2::new(-100, 0), true, false),
+                (IVec2::new(-200, 0), true, false),
+                (IVec2::new(-300, 0), true, false),
+                (IVec2::new(-300, 0), false, false),
+                (IVec2::new(-200, 0), true, false),
+                (IVec2::new(-100, 0), true, false),
+                (IVec2::new(0, 0), false, false),
+                (IVec2::new(0, 0), true, true),
+                (IVec2::new(10, 20), false, false),
+            ]
+        );
+    }
+
+    // Reads a 7 bit short or 12 bit long form move of one axis
+    fn decode_pec_move(pec: &[u8], at: &mut usize) -> i32 {
+        let byte = pec[*at];
+        if byte & 0x80 == 0 {
+            *at += 1;
+            // Sign extended from 7 bits
+            ((byte as i32) << 25) >> 25
+        } else {
+            let value = ((byte as i32 & 0x0f) << 8) | pec[*at + 1] as i32;
+            *at += 2;
+            (value << 20) >> 20
+        }
+    }
+
+    #[test]
+    fn writes_pes_stitches() {
+        let threads = [[0, 0, 0], [0xec, 0x00, 0x00]];
+        let mut pes = vec![];
+        write_pes(&mut pes, "test", &COMMANDS, &threads).unwrap();
+
+        assert_eq!(&pes[..8], b"#PES0001");
+        assert_eq!(u32::from_le_bytes(pes[8..12].try_into().unwrap()), 22);
+        let pec = &pes[22..];
+        assert!(pec.starts_with(b"LA:test            \r"));
+        // The last thread index, then black and red out of the Brother chart
+        assert_eq!(pec[48..51], [1, 20, 5]);
+
+        let block_len = u32::from_le_bytes([pec[514], pec[515], pec[516], 0]) as usize;
+        let icon_len = (PEC_ICON.x / 8 * PEC_ICON.y) as usize;
+        assert_eq!(pec.len(), 512 + block_len + icon_len * (threads.len() + 1));
+        assert_eq!(pec[512 + block_len - 1], 0xff);
+
+        // Every move after the 20 bytes of the block header, adding up to where the needle is
+        let mut at = 512 + 20;
+        let mut needle = IVec2::ZERO;
+        let mut color_changes = 0;
+        while pec[at] != 0xff {
+            if pec[at..at + 2] == [0xfe, 0xb0] {
+                color_changes += 1;
+                at += 3;
+                continue;
+            }
+            needle.x += decode_pec_move(pec, &mut at);
+            needle.y += decode_pec_move(pec, &mut at);
+        }
+        assert_eq!(at, 512 + block_len - 1);
+        assert_eq!(needle, IVec2::new(10, 20));
+        assert_eq!(color_changes, 1);
+    }
+
+    #[test]
+    fn stitches_crosses_from_the_bottom_left() {
+        let commands = cross_stitches(&[Some(0), Some(0), None, Some(1)], UVec2::new(2, 2), 2, 10);
+
+        assert_eq!(
+            commands,
+            [
+                Command::Jump(IVec2::new(-10, 0)),
+                Command::Stitch(IVec2::new(-10, 0)),
+                Command::Stitch(IVec2::new(0, -10)),
+                Command::Stitch(IVec2::new(-10, -10)),
+                Command::Stitch(IVec2::new(0, 0)),
+                // The next cross starts where the last one ended
+                Command::Stitch(IVec2::new(10, -10)),
+                Command::Stitch(IVec2::new(0, -10)),
+                Command::Stitch(IVec2::new(10, 0)),
+                Command::ColorChange,
+                Command::Jump(IVec2::new(0, 10)),
+                Command::Stitch(IVec2::new(0, 10)),
+                Command::Stitch(IVec2::new(10, 0)),
+                Command::Stitch(IVec2::new(0, 0)),
+                Command::Stitch(IVec2::new(10, 10)),
+            ]
+        );
+    }
+}
@@ -0,0 +1,226 @@
+use std::sync::mpsc;
+
+use glam::UVec2;
+use wgpu::util::DeviceExt;
+
+use crate::{image::LabImage, solver::SuperPixel};
+
+// Has to match the workgroup size of the shader
+const WORKGROUP_SIZE: u32 = 16;
+
+#[repr(C)]
+#[derive(Debug, Clone, Copy, bytemuck::Pod, bytemuck::Zeroable)]
+struct GpuSuperPixel {
+    color: [f32; 4],
+    coord: [u32; 2],
+    padding: [u32; 2],
+}
+
+#[repr(C)]
+#[derive(Debug, Clone, Copy, bytemuck::Pod, bytemuck::Zeroable)]
+struct Params {
+    in_size: [u32; 2],
+    out_size: [u32; 2],
+    spatial_scale: f32,
+    // Super pixel rows of every stacked animation frame
+    frame_height: u32,
+    padding: [u32; 2],
+}
+
+/// Assigns the input pixels to super pixels with a compute shader.
+pub struct GpuLabeler {
+    device: wgpu::Device,
+    queue: wgpu::Queue,
+    pipeline: wgpu::ComputePipeline,
+    bind_group: wgpu::BindGroup,
+    params: wgpu::Buffer,
+    super_pixels: wgpu::Buffer,
+    labels: wgpu::Buffer,
+    staging: wgpu::Buffer,
+    in_size: UVec2,
+    out_size: UVec2,
+    frames: u32,
+}
+
+impl GpuLabeler {
+    /// `None` when there is no adapter or the input doesn't fit in its buffers.
+    pub fn new(img: &LabImage, out_size: UVec2, frames: u32) -> Option<Self> {
+        pollster::block_on(Self::new_async(img, out_size, frames))
+    }
+
+    async fn new_async(img: &LabImage, out_size: UVec2, frames: u32) -> Option<Self> {
+        let instance = wgpu::Instance::new(wgpu::InstanceDescriptor::default());
+        let adapter = instance
+            .request_adapter(&wgpu::RequestAdapterOptions {
+                power_preference: wgpu::PowerPreference::HighPerformance,
+                ..Default::default()
+            })
+            .await?;
+        let (device, queue) = adapter
+            .request_device(
+                &wgpu::DeviceDescriptor {
+                    label: None,
+                    features: wgpu::Features::empty(),
+                    limits: adapter.limits(),
+                },
+                None,
+            )
+            .await
+            .ok()?;
+
+        let image = img
+            .pixels
+            .iter()
+            .flat_map(|color| color.to_array().map(|v| v as f32))
+            .collect::<Vec<_>>();
+        let image_size = std::mem::size_of_val(image.as_slice()) as u64;
+        if image_size > device.limits().max_storage_buffer_binding_size as u64 {
+            return None;
+        }
+
+        let sp_count = (out_size.x * out_size.y) as u64;
+        let labels_size = img.pixels.len() as u64 * std::mem::size_of::<u32>() as u64;
+
+        let params = device.create_buffer(&wgpu::BufferDescriptor {
+            label: Some("params"),
+            size: std::mem::size_of::<Params>() as u64,
+            usage: wgpu::BufferUsages::UNIFORM | wgpu::BufferUsages::COPY_DST,
+            mapped_at_creation: false,
+        });
+        let image = device.create_buffer_init(&wgpu::util::BufferInitDescriptor {
+            label: Some("image"),
+            contents: bytemuck::cast_slice(&image),
+            usage: wgpu::BufferUsages::STORAGE,
+        });
+        let super_pixels = device.create_buffer(&wgpu::BufferDescriptor {
+            label: Some("super pixels"),
+            size: sp_count * std::mem::size_of::<GpuSuperPixel>() as u64,
+            usage: wgpu::BufferUsages::STORAGE | wgpu::BufferUsages::COPY_DST,
+            mapped_at_creation: false,
+        });
+        let labels = device.create_buffer(&wgpu::BufferDescriptor {
+            label: Some("labels"),
+            size: labels_size,
+            usage: wgpu::BufferUsages::STORAGE | wgpu::BufferUsages::COPY_SRC,
+            mapped_at_creation: false,
+        });
+        let staging = device.create_buffer(&wgpu::BufferDescriptor {
+            label: Some("labels staging"),
+            size: labels_size,
+            usage: wgpu::BufferUsages::MAP_READ | wgpu::BufferUsages::COPY_DST,
+            mapped_at_creation: false,
+        });
+
+        let shader = device.create_shader_module(wgpu::ShaderModuleDescriptor {
+            label: Some("sp_refine"),
+            source: wgpu::ShaderSource::Wgsl(include_str!("sp_refine.wgsl").into()),
+        });
+        let pipeline = device.create_compute_pipeline(&wgpu::ComputePipelineDescriptor {
+            label: Some("sp_refine"),
+            layout: None,
+            module: &shader,
+            entry_point: "main",
+        });
+        let bind_group = device.create_bind_group(&wgpu::BindGroupDescriptor {
+            label: None,
+            layout: &pipeline.get_bind_group_layout(0),
+            entries: &[
+                wgpu::BindGroupEntry {
+                    binding: 0,
+                    resource: params.as_entire_binding(),
+                },
+                wgpu::BindGroupEntry {
+                    binding: 1,
+                    resource: image.as_entire_binding(),
+                },
+                wgpu::BindGroupEntry {
+                    binding: 2,
+                    resource: super_pixels.as_entire_binding(),
+                },
+                wgpu::BindGroupEntry {
+                    binding: 3,
+                    resource: labels.as_entire_binding(),
+                },
+            ],
+        });
+
+        Some(GpuLabeler {
+            device,
+            queue,
+            pipeline,
+            bind_group,
+            params,
+            super_pixels,
+            labels,
+            staging,
+            in_size: img.size,
+            out_size,
+            frames,
+        })
+    }
+
+    /// Same as `assign_labels` on the CPU, returns false if the labels couldn't be read back.
+    pub fn assign_labels(&self, super_pixels: &[SuperPixel], labels: &mut [u32]) -> bool {
+        let Some(first) = super_pixels.first() else {
+            return false;
+        };
+
+        let params = Params {
+            in_size: self.in_size.to_array(),
+            out_size: self.out_size.to_array(),
+            spatial_scale: (first.spatial_weight * (first.n / first.m).powf(0.5)) as f32,
+            frame_height: self.out_size.y / self.frames,
+            padding: [0; 2],
+        };
+        let gpu_super_pixels = super_pixels
+            .iter()
+            .map(|sp| {
+                let [l, a, b] = sp.palette_color.to_array();
+                GpuSuperPixel {
+                    color: [l as f32, a as f32, b as f32, 0.0],
+                    coord: sp.coord.to_array(),
+                    padding: [0; 2],
+                }
+            })
+            .collect::<Vec<_>>();
+        self.queue
+            .write_buffer(&self.params, 0, bytemuck::bytes_of(&params));
+        self.queue.write_buffer(
+            &self.super_pixels,
+            0,
+            bytemuck::cast_slice(&gpu_super_pixels),
+        );
+
+        let mut encoder = self
+            .device
+            .create_command_encoder(&wgpu::CommandEncoderDescriptor { label: None });
+        {
+            let mut pass = encoder.begin_compute_pass(&wgpu::ComputePassDescriptor { label: None });
+            pass.set_pipeline(&self.pipeline);
+            pass.set_bind_group(0, &self.bind_group, &[]);
+            pass.dispatch_workgroups(
+                (self.in_size.x + WORKGROUP_SIZE - 1) / WORKGROUP_SIZE,
+                (self.in_size.y + WORKGROUP_SIZE - 1) / WORKGROUP_SIZE,
+                1,
+            );
+        }
+        encoder.copy_buffer_to_buffer(&self.labels, 0, &self.staging, 0, self.staging.size());
+        self.queue.submit(Some(encoder.finish()));
+
+        let slice = self.staging.slice(..);
+        let (sender, receiver) = mpsc::channel();
+        slice.map_async(wgpu::MapMode::Read, move |result| {
+            let _ = sender.send(result);
+        });
+        self.device.poll(wgpu::Maintain::Wait);
+
+        if !matches!(receiver.recv(), Ok(Ok(()))) {
+            return false;
+        }
+
+        labels.copy_from_slice(bytemuck::cast_slice(&slice.get_mapped_range()));
+        self.staging.unmap();
+
+        true
+    }
+}
@@ -1,7 +1,12 @@
-use std::ops::{Index, IndexMut};
+use std::{
+    io::Cursor,
+    ops::{Index, IndexMut},
+};
 
-use glam::UVec2;
+use glam::{DMat3, DVec2, DVec3, UVec2};
+use image::{codecs::gif::GifDecoder, AnimationDecoder, Frame, ImageFormat};
 use palette::FromColor;
+use rayon::prelude::{IntoParallelIterator, ParallelIterator};
 
 use crate::color::Color;
 
@@ -12,7 +17,147 @@ pub struct LabImage {
     pub size: UVec2,
 }
 
+/// Region of an image, in pixels.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, serde::Serialize)]
+pub struct Crop {
+    pub offset: UVec2,
+    pub size: UVec2,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, clap::ValueEnum, serde::Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum Gravity {
+    Center,
+    North,
+    South,
+    East,
+    West,
+    NorthEast,
+    NorthWest,
+    SouthEast,
+    SouthWest,
+}
+
+impl Gravity {
+    // Where the kept region sits in the image, from (0, 0) at the top left to (1, 1)
+    fn anchor(self) -> DVec2 {
+        match self {
+            Gravity::Center => DVec2::new(0.5, 0.5),
+            Gravity::North => DVec2::new(0.5, 0.0),
+            Gravity::South => DVec2::new(0.5, 1.0),
+            Gravity::East => DVec2::new(1.0, 0.5),
+            Gravity::West => DVec2::new(0.0, 0.5),
+            Gravity::NorthEast => DVec2::new(1.0, 0.0),
+            Gravity::NorthWest => DVec2::new(0.0, 0.0),
+            Gravity::SouthEast => DVec2::new(1.0, 1.0),
+            Gravity::SouthWest => DVec2::new(0.0, 1.0),
+        }
+    }
+}
+
+impl Crop {
+    /// Largest region of an image of `size` with the given aspect ratio.
+    pub fn from_aspect(size: UVec2, aspect: UVec2, gravity: Gravity) -> Self {
+        let scale = (size.as_dvec2() / aspect.as_dvec2()).min_element();
+        let crop_size = (aspect.as_dvec2() * scale)
+            .floor()
+            .as_uvec2()
+            .clamp(UVec2::ONE, size);
+
+        Crop {
+            offset: ((size - crop_size).as_dvec2() * gravity.anchor())
+                .round()
+                .as_uvec2(),
+            size: crop_size,
+        }
+    }
+
+    /// The same region in a copy of the image downscaled by `factor`.
+    pub fn scaled_down(self, factor: u32) -> Self {
+        Crop {
+            offset: self.offset / factor,
+            size: (self.size / factor).max(UVec2::ONE),
+        }
+    }
+
+    /// A region of this region, relative to its top left corner.
+    pub fn inner(self, crop: Crop) -> Self {
+        Crop {
+            offset: self.offset + crop.offset,
+            size: crop.size,
+        }
+    }
+}
+
+/// Parses regions written as `x,y,w,h`.
+pub fn parse_crop(s: &str) -> Result<Crop, String> {
+    let values = s
+        .split(',')
+        .map(|v| v.trim().parse::<u32>())
+        .collect::<Result<Vec<_>, _>>()
+        .map_err(|err| format!("invalid crop {s:?}: {err}"))?;
+
+    let [x, y, w, h] = values[..] else {
+        return Err(format!("expected a crop like 0,0,640,480, got {s:?}"));
+    };
+    if w == 0 || h == 0 {
+        return Err("the crop can't be empty".to_string());
+    }
+
+    Ok(Crop {
+        offset: UVec2::new(x, y),
+        size: UVec2::new(w, h),
+    })
+}
+
+/// Parses aspect ratios written as `w:h`.
+pub fn parse_aspect(s: &str) -> Result<UVec2, String> {
+    let (w, h) = s
+        .split_once(':')
+        .ok_or_else(|| format!("expected an aspect ratio like 4:5, got {s:?}"))?;
+    let parse = |v: &str| match v.trim().parse::<u32>() {
+        Ok(0) => Err("the aspect ratio can't be zero".to_string()),
+        Ok(v) => Ok(v),
+        Err(err) => Err(format!("invalid aspect ratio {s:?}: {err}")),
+    };
+
+    Ok(UVec2::new(parse(w)?, parse(h)?))
+}
+
 impl LabImage {
+    /// Converts the part of `img` inside `crop`, or all of it.
+    pub fn new(img: &image::DynamicImage, crop: Option<Crop>) -> anyhow::Result<Self> {
+        let Some(crop) = crop else {
+            return Ok(img.clone().into());
+        };
+
+        if (crop.offset + crop.size)
+            .cmpgt(UVec2::new(img.width(), img.height()))
+            .any()
+        {
+            anyhow::bail!(
+                "the crop {crop:?} goes past the {}x{} image",
+                img.width(),
+                img.height()
+            );
+        }
+
+        Ok(img
+            .crop_imm(crop.offset.x, crop.offset.y, crop.size.x, crop.size.y)
+            .into())
+    }
+
+    /// Images of the same size one below the other, the first one at the top.
+    pub fn stack(images: Vec<LabImage>) -> Self {
+        let size = images.first().map_or(UVec2::ZERO, |img| img.size);
+        let count = images.len() as u32;
+
+        LabImage {
+            pixels: images.into_iter().flat_map(|img| img.pixels).collect(),
+            size: UVec2::new(size.x, size.y * count),
+        }
+    }
+
     fn coord_to_idx(&self, coord: UVec2) -> usize {
         (coord.x + self.size.x * coord.y) as usize
     }
@@ -33,6 +178,60 @@ impl LabImage {
 
         Ok(pca)
     }
+
+    /// Principal component of the pixels weighted by `weights`, along with the variance along it.
+    pub fn weighted_principal_component(&self, weights: &[f64]) -> (DVec3, f64) {
+        let total = weights.iter().sum::<f64>();
+        let pixels = self
+            .pixels
+            .iter()
+            .map(|pixel| DVec3::from_array(pixel.to_array()));
+        let mean = pixels
+            .clone()
+            .zip(weights)
+            .fold(DVec3::ZERO, |mean, (pixel, weight)| mean + pixel * *weight)
+            / total;
+        let covariance = pixels
+            .zip(weights)
+            .fold(DMat3::ZERO, |covariance, (pixel, weight)| {
+                let d = pixel - mean;
+                covariance + DMat3::from_cols(d * d.x, d * d.y, d * d.z) * *weight
+            })
+            * (1.0 / total);
+
+        // Power iteration, the covariance is only 3x3 so it settles quickly
+        let mut component = DVec3::ONE.normalize();
+        for _ in 0..100 {
+            let next = covariance * component;
+            if next.length_squared() == 0.0 {
+                break;
+            }
+            component = next.normalize();
+        }
+
+        (component, component.dot(covariance * component))
+    }
+
+    /// Strength of the edge at every pixel, as half the color distance between the pixels on
+    /// either side of it along the axis where it is the largest.
+    pub fn edge_strength(&self) -> Vec<f64> {
+        (0..self.size.x * self.size.y)
+            .into_par_iter()
+            .map(|idx| {
+                let coord = UVec2::new(idx % self.size.x, idx / self.size.x);
+                let (min, max) = (
+                    coord.saturating_sub(UVec2::ONE),
+                    (coord + 1).min(self.size - 1),
+                );
+                let horizontal =
+                    self[UVec2::new(min.x, coord.y)].distance(self[UVec2::new(max.x, coord.y)]);
+                let vertical =
+                    self[UVec2::new(coord.x, min.y)].distance(self[UVec2::new(coord.x, max.y)]);
+
+                horizontal.max(vertical) / 2.0
+            })
+            .collect()
+    }
 }
 
 impl From<image::DynamicImage> for LabImage {
@@ -72,3 +271,58 @@ impl IndexMut<UVec2> for LabImage {
         &mut self.pixels[idx]
     }
 }
+
+/// Frames of an animated GIF, none for any other image including a GIF of a single frame.
+pub fn load_frames(bytes: &[u8]) -> anyhow::Result<Vec<Frame>> {
+    if image::guess_format(bytes)? != ImageFormat::Gif {
+        return Ok(Vec::default());
+    }
+
+    let frames = GifDecoder::new(Cursor::new(bytes))?
+        .into_frames()
+        .collect_frames()?;
+    Ok(if frames.len() > 1 {
+        frames
+    } else {
+        Vec::default()
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_crops() {
+        assert_eq!(
+            parse_crop("0,0,640,480"),
+            Ok(Crop {
+                offset: UVec2::ZERO,
+                size: UVec2::new(640, 480),
+            })
+        );
+        assert_eq!(
+            parse_crop(" 10, 20 ,30,40 "),
+            Ok(Crop {
+                offset: UVec2::new(10, 20),
+                size: UVec2::new(30, 40),
+            })
+        );
+    }
+
+    #[test]
+    fn rejects_invalid_crops() {
+        for invalid in [
+            "",
+            "0,0,640",
+            "0,0,640,480,1",
+            "0,0,0,480",
+            "0,0,640,0",
+            "-1,0,640,480",
+            "0;0;640;480",
+            "x,y,w,h",
+        ] {
+            assert!(parse_crop(invalid).is_err(), "{invalid:?} parsed");
+        }
+    }
+}
@@ -0,0 +1,23 @@
+pub mod aseprite;
+pub mod background;
+pub mod backstitch;
+pub mod chart;
+pub mod checkpoint;
+pub mod color;
+pub mod cvd;
+pub mod embroidery;
+#[cfg(feature = "gpu")]
+pub mod gpu;
+pub mod image;
+pub mod metric;
+pub mod palette_file;
+pub mod palette_index;
+pub mod pattern_diff;
+pub mod pattern_file;
+pub mod pattern_size;
+pub mod pixel_grid;
+pub mod presets;
+pub mod profile;
+pub mod regions;
+pub mod solver;
+pub mod specialty;
@@ -1,589 +1,1712 @@
-#![feature(get_many_mut)]
-
-mod color;
-mod image;
+mod acceptance;
+mod cleanup;
+mod diff;
+mod elbow;
+mod palette_editor;
+mod palette_report;
+mod progress;
+mod quality;
+#[cfg(feature = "ffmpeg")]
+mod video;
 
 use std::{
-    collections::{hash_map::RandomState, VecDeque},
+    collections::HashMap,
     fs,
-    path::PathBuf,
+    io::BufWriter,
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        mpsc,
+    },
+    thread,
 };
 
-use ::image::{Rgb, RgbImage};
+use ::image::{
+    codecs::gif::{GifEncoder, Repeat},
+    Delay, DynamicImage, Frame, GrayImage, Rgb, RgbImage, Rgba, RgbaImage,
+};
+use acceptance::{mean_delta_e, AcceptanceReport};
 use clap::Parser;
-use color::Color;
-use glam::{DVec2, DVec3, IVec2, UVec2};
-use image::LabImage;
-use palette::{chromatic_adaptation::AdaptFrom, color_difference::EuclideanDistance};
+use cleanup::{majority_filter, remove_confetti, ConfettiReport};
+use elbow::{parse_color_count, ColorCount, ElbowSearch};
+use glam::{DVec3, IVec2, UVec2};
+use palette::{chromatic_adaptation::AdaptFrom, Clamp, FromColor};
+use palette_editor::edit_palette;
+use palette_report::palette_report;
+use pixelart_gen::{
+    aseprite::write_aseprite,
+    background::detect_background,
+    checkpoint::Checkpoint,
+    color::Color,
+    embroidery::{cross_stitches, write_dst, write_pes},
+    image::{load_frames, parse_aspect, parse_crop, Crop, Gravity, LabImage},
+    metric::Metric,
+    palette_file::{load_palette, save_palette, PaletteColor},
+    palette_index::PaletteIndex,
+    pattern_file::{save_project, Project, ProjectColor},
+    pattern_size::{check_pattern_size, parse_size, DEFAULT_FABRIC_COUNT},
+    pixel_grid::PixelGrid,
+    presets::Preset,
+    profile::{track_allocations, Profiler, Stage, TrackingAllocator},
+    regions::{load_region_mask, parse_region_budget},
+    solver::{Grid, Masks, Schedule, Smoothing, Solver, SolverConfig, SuperPixel, SuperpixelMode},
+};
+use progress::{ConvergenceLog, Progress, ProgressFormat};
+use quality::QualityReport;
+use rand::{Rng, SeedableRng};
 use rayon::prelude::{
-    IndexedParallelIterator, IntoParallelIterator, IntoParallelRefMutIterator, ParallelIterator,
+    IndexedParallelIterator, IntoParallelIterator, IntoParallelRefIterator,
+    IntoParallelRefMutIterator, ParallelIterator,
 };
-use tracing::info;
+use sha2::{Digest, Sha256};
 
 const ALPHA: f64 = 0.7;
 const T_FINAL: f64 = 1.0;
 const EPSILON_PALETTE: f64 = 1.0;
 const EPSILON_CLUSTER: f64 = 0.25;
-
-#[derive(Debug, Parser)]
+const SPATIAL_WEIGHT: f64 = 45.0;
+const DELTA_SCALE: f64 = 1.5;
+const SMOOTH_POSITION: f64 = 0.4;
+const SMOOTH_COLOR: f64 = 0.5;
+const BACKGROUND_TOLERANCE: f64 = 10.0;
+// Share of the error an expansion of the palette has to take away for `-c auto` to keep it
+const ELBOW_GAIN: f64 = 0.1;
+// How much darker the grid lines of the upscaled preview are than the stitches they cross
+const PREVIEW_GRID_SHADE: f64 = 0.15;
+// Size the longest side of the results on the contact sheet of a sweep gets upscaled towards, and
+// the gap between them, in pixels
+const SWEEP_CELL: u32 = 256;
+const SWEEP_GAP: u32 = 16;
+// Saturation multiplier when snapping to thread colors, which tend to look duller than the input
+const SATURATION_BOOST: f64 = 1.1;
+
+#[global_allocator]
+static ALLOCATOR: TrackingAllocator = TrackingAllocator;
+
+// Set on Ctrl-C, the run stops after the current iteration and saves what it has
+static INTERRUPTED: AtomicBool = AtomicBool::new(false);
+
+#[derive(Debug, Parser, serde::Serialize)]
 pub struct Args {
     // Path to the input image
     #[arg(short)]
     input: PathBuf,
-    // Path to the output image
+    // Path to the output image, or the output directory when a name template is given
     #[arg(short)]
     output: String,
+    // How the output gets encoded
+    #[arg(long, value_enum, default_value_t = OutputFormat::Auto)]
+    output_format: OutputFormat,
     // Max size of the greater sized side in the output
-    #[arg(short)]
-    max_side_size: u16,
-    // Total color count in the output
-    #[arg(short)]
-    color_count: u8,
+    #[arg(
+        short,
+        required_unless_present_any = ["width", "pixel_art", "preset"],
+        conflicts_with_all = ["width", "height"]
+    )]
+    max_side_size: Option<u16>,
+    // Treat the input as upscaled pixel art, every pixel of the art becomes one stitch and only
+    // its colors get reduced
+    #[arg(long, conflicts_with_all = ["max_side_size", "width", "crop", "crop_aspect", "superpixel_factor", "pyramid"])]
+    pixel_art: bool,
+    // Exact width of the output, along with --height
+    #[arg(long, requires = "height", value_parser = clap::value_parser!(u32).range(1..))]
+    width: Option<u32>,
+    // Exact height of the output, along with --width
+    #[arg(long, requires = "width", value_parser = clap::value_parser!(u32).range(1..))]
+    height: Option<u32>,
+    // How the aspect ratio of the input is reconciled with --width and --height
+    #[arg(long, value_enum, default_value_t = Fit::Contain)]
+    fit: Fit,
+    // Only use this region of the input, as `x,y,w,h` in pixels
+    #[arg(long, value_parser = parse_crop, conflicts_with = "crop_aspect")]
+    crop: Option<Crop>,
+    // Width and height of a stitch as `w:h`, for the non square cells of knitting charts or some
+    // retro screens. The output gets as many stitches as keep the input in proportion
+    #[arg(long, value_parser = parse_aspect, default_value = "1:1")]
+    pixel_aspect: UVec2,
+    // Crop the input to this aspect ratio, as `w:h`
+    #[arg(long, value_parser = parse_aspect)]
+    crop_aspect: Option<UVec2>,
+    // Part of the input --crop-aspect keeps
+    #[arg(long, value_enum, default_value_t = Gravity::Center, requires = "crop_aspect")]
+    gravity: Gravity,
+    // Total color count in the output, or of the foreground when the background has --bg-colors.
+    // `auto` or `auto:<max>` keeps adding colors while they still lower the error enough
+    #[arg(
+        short,
+        long = "fg-colors",
+        alias = "color-count",
+        value_parser = parse_color_count,
+        required_unless_present_any = ["fixed_palette", "preset", "color_counts"]
+    )]
+    color_count: Option<ColorCount>,
+    // Share of the error an expansion of the palette has to take away for `-c auto` to keep it
+    #[arg(long, default_value_t = ELBOW_GAIN)]
+    elbow_gain: f64,
+    // Optimize for each of these color counts in one run, as `8,12,16`, and save the results side
+    // by side from the fewest colors to the most instead of a single output
+    #[arg(
+        long,
+        value_delimiter = ',',
+        value_parser = clap::value_parser!(u8).range(1..),
+        conflicts_with_all = ["color_count", "fixed_palette", "preset", "region_budget", "interactive", "resume", "pyramid"]
+    )]
+    color_counts: Vec<u8>,
+    // Palette file (JSON, GPL or ASE) whose colors get used as they are, instead of optimizing a
+    // palette of --fg-colors colors
+    #[arg(long, conflicts_with_all = ["color_count", "pyramid"])]
+    fixed_palette: Option<PathBuf>,
+    // Colors of a retro console, all of them unless -c picks fewer, and its screen as the
+    // default output size
+    #[arg(long, value_enum, conflicts_with_all = ["palette", "fixed_palette", "no_thread_snap"])]
+    preset: Option<Preset>,
+    // Palette file (JSON, GPL or ASE) to snap colors to instead of the built-in DMC colors
+    #[arg(long)]
+    palette: Option<PathBuf>,
+    // Output the optimized palette as is instead of snapping it to thread colors
+    #[arg(long, conflicts_with = "palette")]
+    no_thread_snap: bool,
+    // Color difference used by the optimization and the thread snap
+    #[arg(long, value_enum, default_value_t = Metric::Euclidean)]
+    metric: Metric,
+    // Multiplier of the a and b channels of the final colors, 1.1 by default when snapping to
+    // thread colors and 1 otherwise. 1 keeps the colors accurate
+    #[arg(long)]
+    saturation: Option<f64>,
+    // Multiplier of the lightness of the final colors
+    #[arg(long, default_value_t = 1.0)]
+    lightness: f64,
+    // Temperature multiplier applied each time the palette converges
+    #[arg(long, default_value_t = ALPHA)]
+    alpha: f64,
+    // Temperature at which the optimization stops
+    #[arg(long, default_value_t = T_FINAL)]
+    t_final: f64,
+    // How the temperature gets lowered each time the palette converges
+    #[arg(long, value_enum, default_value_t = Schedule::Geometric)]
+    schedule: Schedule,
+    // Stop after this many iterations even if the temperature is still above --t-final
+    #[arg(long, value_parser = clap::value_parser!(u32).range(1..))]
+    max_iterations: Option<u32>,
+    // Total palette change below which the palette counts as converged
+    #[arg(long, default_value_t = EPSILON_PALETTE)]
+    epsilon_palette: f64,
+    // Distance between the two colors of a cluster above which it gets split
+    #[arg(long, default_value_t = EPSILON_CLUSTER)]
+    epsilon_cluster: f64,
+    // Weight of the spatial distance against the color distance when assigning pixels
+    #[arg(long, default_value_t = SPATIAL_WEIGHT)]
+    spatial_weight: f64,
+    // Weight of the strongest edge of the input between a pixel and a super pixel when assigning
+    // pixels, against the color distance. Keeps thin high contrast details like whiskers or text
+    // from getting averaged away
+    #[arg(long, default_value_t = 0.0)]
+    edge_weight: f64,
+    // Multiplier of the principal component used to perturb new palette colors
+    #[arg(long, default_value_t = DELTA_SCALE)]
+    delta_scale: f64,
+    // Output file name template, supports {stem}, {colors}, {width}, {height} and {ext}
+    #[arg(long)]
+    name_template: Option<String>,
+    // Super pixels per output pixel along each side, aggregated into the output at the end
+    #[arg(long, default_value_t = 1, value_parser = clap::value_parser!(u32).range(1..))]
+    superpixel_factor: u32,
+    // Don't report progress
+    #[arg(long)]
+    quiet: bool,
+    // Progress output format, `json` prints one object per iteration for frontends
+    #[arg(long, value_enum, default_value_t = ProgressFormat::Bar)]
+    progress_format: ProgressFormat,
+    // Same as --progress-format json
+    #[arg(long, conflicts_with = "progress_format")]
+    json_progress: bool,
+    // File or named pipe to write JSON progress to instead of stderr, which keeps stdout free for
+    // the acceptance report
+    #[arg(long)]
+    progress_output: Option<PathBuf>,
+    // Largest stitch grid generated without --force
+    #[arg(long, value_parser = parse_size, default_value = "500x500")]
+    max_stitches: UVec2,
+    // Generate patterns above --max-stitches
+    #[arg(long)]
+    force: bool,
+    // Grayscale mask where every gray level marks a region of the image
+    #[arg(long)]
+    region_mask: Option<PathBuf>,
+    // Colors reserved for a region of the mask as `<mask value>:<color count>`, can be repeated
+    #[arg(long, value_parser = parse_region_budget, requires = "region_mask")]
+    region_budget: Vec<(u8, usize)>,
+    // Grayscale mask of the subject, black pixels are background left unstitched
+    #[arg(long)]
+    mask: Option<PathBuf>,
+    // Leave the background unstitched, found by flood filling from the border of the input
+    #[arg(long, conflicts_with = "mask")]
+    remove_background: bool,
+    // Color distance to the average border color below which --remove-background floods a pixel
+    #[arg(long, default_value_t = BACKGROUND_TOLERANCE, requires = "remove_background")]
+    background_tolerance: f64,
+    // Stitch the background with its own palette of this many colors instead of leaving it bare
+    #[arg(long, value_parser = clap::value_parser!(u8).range(1..))]
+    bg_colors: Option<u8>,
+    // Grayscale map of how much each part of the input matters, brighter parts get more colors
+    #[arg(long)]
+    importance: Option<PathBuf>,
+    // Once done, give every stitch the color more than half of the stitches within this radius
+    // share, to smooth jagged edges between the colors
+    #[arg(long, value_parser = clap::value_parser!(u32).range(1..))]
+    cleanup: Option<u32>,
+    // Merge regions of fewer stitches than this into the neighboring color closest to theirs once
+    // done, since lone stitches are tedious to stitch
+    #[arg(long, value_parser = clap::value_parser!(u32).range(2..))]
+    min_region_size: Option<u32>,
+    // Also save the output every N iterations, otherwise it is only saved once done
+    #[arg(long, value_parser = clap::value_parser!(u32).range(1..))]
+    save_interval: Option<u32>,
+    // Lattice the super pixels start on, `hex` gives rounder cells that suit organic subjects
+    // better. The stitches stay square
+    #[arg(long, value_enum, default_value_t = Grid::Square, conflicts_with = "pixel_art")]
+    grid: Grid,
+    // How the pixels get assigned to the super pixels, `slic` keeps every super pixel in one piece
+    #[arg(long, value_enum, default_value_t = SuperpixelMode::Unconstrained)]
+    superpixel: SuperpixelMode,
+    // Filter used to smooth the super pixel colors
+    #[arg(long, value_enum, default_value_t = Smoothing::Bilateral)]
+    smoothing: Smoothing,
+    // How far every super pixel moves towards the average position of its neighbours each
+    // iteration, from 0 to 1. 0 turns the position smoothing off
+    #[arg(long, default_value_t = SMOOTH_POSITION)]
+    smooth_position: f64,
+    // How much of the --smoothing filtered color goes into the color of every super pixel each
+    // iteration, from 0 to 1. 0 turns the color smoothing off
+    #[arg(long, default_value_t = SMOOTH_COLOR)]
+    smooth_color: f64,
+    // Periodically save the state of the run to this file
+    #[arg(long)]
+    checkpoint: Option<PathBuf>,
+    // Iterations between two checkpoints
+    #[arg(long, default_value_t = 100, value_parser = clap::value_parser!(u32).range(1..), requires = "checkpoint")]
+    checkpoint_interval: u32,
+    // Continue a run from a checkpoint made with the same input and options
+    #[arg(long)]
+    resume: Option<PathBuf>,
+    // Lock, delete or nudge palette colors from the terminal once the full palette first converges
+    #[arg(long)]
+    interactive: bool,
+    // First converge on a copy of the input downscaled by this factor and start from its result
+    #[arg(long, value_parser = clap::value_parser!(u32).range(2..))]
+    pyramid: Option<u32>,
+    // Write t, k, the total change, its variance, the colors in use and the elapsed time of every
+    // iteration to this CSV file
+    #[arg(long)]
+    log_csv: Option<PathBuf>,
+    // Time each stage of every iteration along with its peak memory, written as folded stacks
+    #[arg(long)]
+    profile: Option<PathBuf>,
+    // Also draw which super pixel every input pixel ended up in, each in a random color with its
+    // center marked, at the size of the input
+    #[arg(long)]
+    debug_superpixels: Option<PathBuf>,
+    // Also write a copy of the output upscaled with nearest neighbour, to view or share
+    #[arg(long)]
+    preview: Option<PathBuf>,
+    // Pixels per stitch of --preview
+    #[arg(long, default_value_t = 8, value_parser = clap::value_parser!(u32).range(1..), requires = "preview")]
+    preview_scale: u32,
+    // Draw a faint line between the stitches of --preview
+    #[arg(long, requires = "preview")]
+    preview_grid: bool,
+    // Also write the colors of the result, most used first, as a JSON, GPL, ASE or hex palette
+    #[arg(long)]
+    export_palette: Option<PathBuf>,
+    // Also write the pattern as machine cross stitches, as a Tajima .dst or Brother .pes file
+    // depending on the extension. The threads change in the order of --export-palette
+    #[arg(long)]
+    embroidery: Option<PathBuf>,
+    // Crosses per inch of --embroidery
+    #[arg(long, default_value_t = DEFAULT_FABRIC_COUNT, value_parser = clap::value_parser!(u32).range(1..), requires = "embroidery")]
+    embroidery_count: u32,
+    // Also write the stitches, their threads and these settings as a .pxproj project, which
+    // pdfgen charts as it is instead of matching the colors of the output again
+    #[arg(long)]
+    project: Option<PathBuf>,
+    // Also write the quality metrics printed at the end as JSON
+    #[arg(long)]
+    quality_report: Option<PathBuf>,
+    // Fail once done when the mean ΔE between the stitches and the input isn't below this
+    #[arg(long)]
+    require_delta_e_below: Option<f64>,
+    // Fail once done when the pattern has fewer colors than this, like when several palette
+    // colors snap to the same thread
+    #[arg(long, value_parser = clap::value_parser!(u32).range(1..))]
+    require_colors: Option<u32>,
+    #[cfg(feature = "ffmpeg")]
+    #[command(subcommand)]
+    command: Option<Command>,
+}
+
+#[cfg(feature = "ffmpeg")]
+#[derive(Debug, clap::Subcommand, serde::Serialize)]
+#[serde(rename_all = "lowercase")]
+enum Command {
+    // Read the input as a video clip and pack the frames into a sprite sheet, with their
+    // positions and durations in a JSON file next to it
+    Video(video::VideoArgs),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, clap::ValueEnum, serde::Serialize)]
+#[serde(rename_all = "kebab-case")]
+enum OutputFormat {
+    // From the extension of the output path
+    Auto,
+    // 8-bit PNG with the colors of the pattern as its palette, whatever the extension
+    IndexedPng,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, clap::ValueEnum, serde::Serialize)]
+#[serde(rename_all = "lowercase")]
+enum Fit {
+    // Largest size with the aspect ratio of the input fitting in the requested one
+    Contain,
+    // Crop the input to the requested aspect ratio
+    Cover,
+    // Distort the input to the requested size
+    Stretch,
 }
 
 fn main() -> anyhow::Result<()> {
+    // Comparing two patterns takes none of the arguments of a run, so it gets parsed on its own
+    if std::env::args_os().nth(1).is_some_and(|arg| arg == "diff") {
+        return diff::run(diff::DiffArgs::parse_from(std::env::args_os().skip(1)));
+    }
     let args = Args::parse();
+    // Counting every allocation slows the run down, only the --profile report needs it
+    if args.profile.is_some() {
+        track_allocations();
+    }
 
-    let input: LabImage = {
-        let bytes = fs::read(args.input)?;
-        ::image::load_from_memory(&bytes)?.into()
-    };
-
-    println!("{:?}", input[UVec2::new(0, 0)]);
+    #[cfg(feature = "ffmpeg")]
+    let video_args = args.command.as_ref().map(|command| match command {
+        Command::Video(video_args) => video_args,
+    });
 
-    let out_size = if input.size.x >= input.size.y {
-        UVec2 {
-            x: args.max_side_size as u32,
-            y: ((args.max_side_size as f64 / input.size.x as f64) * (input.size.y as f64)).ceil()
-                as u32,
+    let (decoded, frames, input_hash) = {
+        let bytes = fs::read(&args.input)?;
+        #[cfg(feature = "ffmpeg")]
+        let frames = match video_args {
+            Some(video_args) => video::extract_frames(&args.input, video_args.fps)?,
+            None => load_frames(&bytes)?,
+        };
+        #[cfg(not(feature = "ffmpeg"))]
+        let frames = load_frames(&bytes)?;
+        let decoded = match frames.first() {
+            Some(frame) => DynamicImage::ImageRgba8(frame.buffer().clone()),
+            None => ::image::load_from_memory(&bytes)?,
+        };
+
+        (decoded, frames, format!("{:x}", Sha256::digest(&bytes)))
+    };
+    if !frames.is_empty()
+        && (args.pixel_art
+            || args.pyramid.is_some()
+            || args.mask.is_some()
+            || args.region_mask.is_some()
+            || args.importance.is_some()
+            || args.embroidery.is_some()
+            || args.project.is_some())
+    {
+        anyhow::bail!(
+            "animated inputs don't work with --pixel-art, --pyramid, --mask, --region-mask, --importance, --embroidery or --project"
+        );
+    }
+    for (name, value) in [
+        ("--smooth-position", args.smooth_position),
+        ("--smooth-color", args.smooth_color),
+    ] {
+        if !(0.0..=1.0).contains(&value) {
+            anyhow::bail!("{name} has to be between 0 and 1");
         }
+    }
+    // The coarse pass would settle the size of the palette before the search gets to look at it
+    if matches!(args.color_count, Some(ColorCount::Auto(_))) && args.pyramid.is_some() {
+        anyhow::bail!("-c auto doesn't work with --pyramid");
+    }
+    let full_size = UVec2::new(decoded.width(), decoded.height());
+    // The art is brought back to one pixel per cell, which is also the size of the output
+    let pixel_grid = args
+        .pixel_art
+        .then(|| PixelGrid::detect(&decoded.to_rgb8()));
+    let decoded = match pixel_grid {
+        Some(grid) => ::image::DynamicImage::ImageRgb8(grid.sample(&decoded.to_rgb8())),
+        None => decoded,
+    };
+    let crop = match (args.crop, args.crop_aspect) {
+        (Some(crop), _) => Some(crop),
+        (None, Some(aspect)) => Some(Crop::from_aspect(full_size, aspect, args.gravity)),
+        (None, None) => None,
+    };
+    let (out_size, fit_crop) = match pixel_grid {
+        Some(grid) => (grid.cells(), None),
+        None => output_size(crop.map_or(full_size, |crop| crop.size), &args),
+    };
+    // --fit cover crops what is left after --crop
+    let crop = match (crop, fit_crop) {
+        (Some(crop), Some(fit_crop)) => Some(crop.inner(fit_crop)),
+        (crop, fit_crop) => crop.or(fit_crop),
+    };
+
+    let coarse_input = args
+        .pyramid
+        .map(|factor| {
+            LabImage::new(
+                &decoded.resize_exact(
+                    (decoded.width() / factor).max(1),
+                    (decoded.height() / factor).max(1),
+                    ::image::imageops::FilterType::Triangle,
+                ),
+                crop.map(|crop| crop.scaled_down(factor)),
+            )
+        })
+        .transpose()?;
+    // The frames of an animation are optimized together, stacked from top to bottom, so they all
+    // share one palette
+    let input = if frames.is_empty() {
+        LabImage::new(&decoded, crop)?
     } else {
-        UVec2 {
-            x: ((args.max_side_size as f64 / input.size.y as f64) * (input.size.x as f64)).ceil()
-                as u32,
-            y: args.max_side_size as u32,
-        }
+        LabImage::stack(
+            frames
+                .iter()
+                .map(|frame| LabImage::new(&DynamicImage::ImageRgba8(frame.buffer().clone()), crop))
+                .collect::<anyhow::Result<Vec<_>>>()?,
+        )
     };
+    let delays = frames.iter().map(Frame::delay).collect::<Vec<_>>();
+    // Frames of a video go side by side in a sprite sheet of this many columns
+    #[cfg(feature = "ffmpeg")]
+    let sprite_sheet = video_args.map(|video_args| video_args.columns(delays.len()));
+    #[cfg(not(feature = "ffmpeg"))]
+    let sprite_sheet = None;
+    drop(decoded);
+    drop(frames);
+
+    // Written into the output so a file can be traced back to how it was generated
+    let metadata = [
+        (
+            "Software",
+            format!("pixelart-gen {}", env!("CARGO_PKG_VERSION")),
+        ),
+        ("Parameters", serde_json::to_string(&args)?),
+        ("Input SHA-256", input_hash.clone()),
+    ];
+
+    if !args.force {
+        check_pattern_size(out_size, args.max_stitches, DEFAULT_FABRIC_COUNT)?;
+    }
 
-    println!("In Size: {:?}, Out Size: {out_size}", input.size);
+    let fixed_palette = match (&args.fixed_palette, args.preset) {
+        (Some(path), _) => Some(load_palette(Some(path))?),
+        (None, Some(preset)) if args.color_count.is_none() => Some(preset.colors()),
+        _ => None,
+    }
+    .map(|colors| {
+        colors
+            .iter()
+            .map(|color| Color::from_srgb(color.rgb))
+            .collect::<Vec<_>>()
+    });
+    // The run stops by every count of a sweep on its way to the highest one
+    let mut sweep = args
+        .color_counts
+        .iter()
+        .map(|count| *count as usize)
+        .collect::<Vec<_>>();
+    sweep.sort_unstable();
+    sweep.dedup();
+    // Clap makes sure there is one or the other
+    let color_count = match (&fixed_palette, sweep.first()) {
+        (Some(palette), _) => palette.len(),
+        (None, Some(count)) => *count,
+        (None, None) => args.color_count.unwrap().max(),
+    };
 
-    let pca = input.pca()?;
-    let component = pca.components().axis_iter(ndarray::Axis(0)).next().unwrap();
-    let component = component.as_slice().unwrap();
+    let reserved_colors = args
+        .region_budget
+        .iter()
+        .map(|(_, budget)| budget)
+        .sum::<usize>();
+    if reserved_colors >= color_count {
+        anyhow::bail!(
+            "the region budgets reserve {reserved_colors} colors, which leaves none of the {color_count} colors for the rest of the image"
+        );
+    }
+    // Masks are loaded at the size of the whole input, then cropped like it
+    let load_mask = |path: &Path| {
+        let mask = load_region_mask(path, full_size)?;
+        anyhow::Ok(match (crop, pixel_grid) {
+            (Some(Crop { offset, size }), _) => {
+                ::image::imageops::crop_imm(&mask, offset.x, offset.y, size.x, size.y).to_image()
+            }
+            (None, Some(grid)) => grid.sample(&mask),
+            (None, None) => mask,
+        })
+    };
+    let region_mask = args.region_mask.as_deref().map(load_mask).transpose()?;
+    let background_mask = match &args.mask {
+        Some(path) => Some(load_mask(path)?),
+        None if args.remove_background => {
+            Some(detect_background(&input, args.background_tolerance))
+        }
+        None => None,
+    };
+    let importance = args.importance.as_deref().map(load_mask).transpose()?;
 
-    let delta = DVec3 {
-        x: component[0],
-        y: component[1],
-        z: component[2],
-    } * 1.5;
-    let mut t = 1.1 * pca.explained_variance().first().unwrap();
-    // let mut t = 35.0;
-    let mut k = 1;
+    ctrlc::set_handler(|| {
+        // A second Ctrl-C stops right away
+        if INTERRUPTED.swap(true, Ordering::SeqCst) {
+            std::process::exit(130);
+        }
+    })?;
 
-    let init_color = dbg!(Color::average_from(&input, input.size));
-    let mut super_pixels = Vec::with_capacity((out_size.x * out_size.y) as usize);
+    let mut progress = Progress::new(
+        args.quiet,
+        if args.json_progress {
+            ProgressFormat::Json
+        } else {
+            args.progress_format
+        },
+        args.progress_output.as_deref(),
+    )?;
+    if let Some(grid) = pixel_grid {
+        progress.println(format!(
+            "Pixel grid: {}x{} pixels per cell, offset by {}",
+            grid.scale.x, grid.scale.y, grid.offset
+        ));
+    }
+    progress.println(format!("In Size: {:?}, Out Size: {out_size}", input.size));
 
-    for y in (0..out_size.y).map(|y| (y * input.size.y) / out_size.y) {
-        for x in (0..out_size.x).map(|x| (x * input.size.x) / out_size.x) {
-            super_pixels.push(SuperPixel::new(
-                &input,
-                UVec2 { x, y },
-                init_color,
+    let output_path = match &args.name_template {
+        Some(template) => Path::new(&args.output)
+            .join(expand_name_template(
+                template,
+                &args.input,
                 out_size,
+                color_count,
+            ))
+            .to_string_lossy()
+            .into_owned(),
+        None => args.output.clone(),
+    };
+
+    let out_size = out_size * UVec2::new(1, delays.len().max(1) as u32);
+    let sp_size = out_size * args.superpixel_factor;
+    let solver_config = SolverConfig {
+        alpha: args.alpha,
+        t_final: args.t_final,
+        schedule: args.schedule,
+        max_iterations: args.max_iterations.map(|max| max as usize),
+        epsilon_palette: args.epsilon_palette,
+        epsilon_cluster: args.epsilon_cluster,
+        spatial_weight: args.spatial_weight,
+        pixel_aspect: args.pixel_aspect,
+        edge_weight: args.edge_weight,
+        delta_scale: args.delta_scale,
+        grid: args.grid,
+        superpixel: args.superpixel,
+        smoothing: args.smoothing,
+        smooth_position: args.smooth_position,
+        smooth_color: args.smooth_color,
+        region_budget: args.region_budget.clone(),
+        pinned: args.pixel_art,
+        metric: args.metric,
+        frames: delays.len().max(1) as u32,
+    };
+    // The side passes leave the region budgets to the main one
+    let side_config = SolverConfig {
+        region_budget: Vec::default(),
+        ..solver_config.clone()
+    };
+    let sweep_config = solver_config.clone();
+    let masks = Masks {
+        region: region_mask.as_ref(),
+        background: background_mask.as_ref(),
+        importance: importance.as_ref(),
+    };
+    let mut solver = match fixed_palette {
+        Some(palette) => Solver::with_palette(&input, sp_size, palette, solver_config, masks)?,
+        None => Solver::new(&input, sp_size, color_count, solver_config, masks)?,
+    };
+
+    match &coarse_input {
+        Some(coarse) if coarse.size.cmpge(sp_size).all() => {
+            let resize_mask = |mask: &GrayImage| {
+                ::image::imageops::resize(
+                    mask,
+                    coarse.size.x,
+                    coarse.size.y,
+                    ::image::imageops::FilterType::Nearest,
+                )
+            };
+            let coarse_mask = background_mask.as_ref().map(resize_mask);
+            let coarse_importance = importance.as_ref().map(resize_mask);
+            let mut coarse_solver = Solver::new(
+                coarse,
+                sp_size,
+                color_count,
+                side_config.clone(),
+                Masks {
+                    region: None,
+                    background: coarse_mask.as_ref(),
+                    importance: coarse_importance.as_ref(),
+                },
+            )?;
+            side_pass(&mut coarse_solver, true);
+            if coarse_solver.k() < color_count {
+                anyhow::bail!(
+                    "the coarse pass cooled down before completing its palette, try a lower --t-final"
+                );
+            }
+
+            solver.start_from(&coarse_solver);
+            progress.println(format!(
+                "Coarse pass done, continuing at full size from t: {:.3}",
+                solver.t()
             ));
         }
+        Some(_) => progress
+            .println("The downscaled input is smaller than the output, skipping the coarse pass"),
+        None => {}
     }
 
-    let mut clusters = vec![UVec2 { x: 0, y: 1 }];
-    let mut palette = vec![(init_color, 0.5), (init_color, 0.5)];
-    palette[1].0.perturb(delta.truncate());
+    // Colors of the background super pixels along with the palette they come from
+    let background_colors = match (args.bg_colors, &background_mask) {
+        (Some(bg_colors), Some(mask))
+            if solver.super_pixels().iter().any(SuperPixel::is_background) =>
+        {
+            progress.println(format!(
+                "Optimizing the {bg_colors} colors of the background"
+            ));
+
+            let mut foreground_mask = mask.clone();
+            ::image::imageops::invert(&mut foreground_mask);
+            let mut background_solver = Solver::new(
+                &input,
+                sp_size,
+                bg_colors as usize,
+                side_config,
+                Masks {
+                    background: Some(&foreground_mask),
+                    ..Masks::default()
+                },
+            )?;
+            side_pass(&mut background_solver, false);
+
+            Some((
+                background_solver
+                    .super_pixels()
+                    .iter()
+                    .map(SuperPixel::palette_color)
+                    .collect::<Vec<_>>(),
+                background_solver.palette().to_vec(),
+            ))
+        }
+        (Some(_), Some(_)) => {
+            progress.println("There is no background to give its own colors to");
+            None
+        }
+        (Some(_), None) => {
+            anyhow::bail!(
+                "--bg-colors needs --mask or --remove-background to tell the background apart"
+            )
+        }
+        (None, _) => None,
+    };
+    let unstitched = if background_colors.is_some() {
+        vec![false; (out_size.x * out_size.y) as usize]
+    } else {
+        unstitched_cells(solver.super_pixels(), sp_size, args.superpixel_factor)
+    };
 
-    let dmc_colors = load_dmc_colors();
-    let lab_dmc_colors = dmc_colors
+    let thread_colors = match args.preset {
+        Some(preset) => preset.colors(),
+        None => load_palette(args.palette.as_deref())?,
+    };
+    let dmc_colors = thread_colors
         .iter()
-        .map(|color| palette::Lab::<palette::white_point::D65, _>::adapt_from(*color))
-        .collect::<Vec<_>>();
-    let colors: dashmap::DashSet<Rgb<u8>, RandomState> = dashmap::DashSet::default();
-    let mut output = RgbImage::new(out_size.x, out_size.y);
-    let mut running_average = 0.0;
-    let mut prev_changes = VecDeque::with_capacity(100);
-    let mut running_variance_avg = 0.0;
-    let mut prev_variances = VecDeque::with_capacity(100);
-    let mut variance_check_passed_count = 0;
+        .map(|color| palette::rgb::Rgb::new(color.rgb[0], color.rgb[1], color.rgb[2]).into_format())
+        .collect::<Vec<palette::rgb::Srgb<f64>>>();
+    let dmc_index = PaletteIndex::with_metric(
+        dmc_colors.iter().map(|color| {
+            let lab = palette::Lab::<palette::white_point::D65, _>::adapt_from(*color);
+            [lab.l, lab.a, lab.b]
+        }),
+        args.metric,
+    );
+    // Applied to the final colors right before they get snapped to thread colors
+    let saturation = args
+        .saturation
+        .unwrap_or(if args.no_thread_snap || args.preset.is_some() {
+            1.0
+        } else {
+            SATURATION_BOOST
+        });
+    let adjustment = DVec3::new(args.lightness, saturation, saturation);
+    let render_output =
+        |super_pixels: &[SuperPixel], palette_colors: &[(Color, f64)], output: &mut RgbImage| {
+            // The two palettes get merged before snapping to the thread colors
+            let colors = super_pixels
+                .iter()
+                .enumerate()
+                .map(|(idx, sp)| match &background_colors {
+                    Some((colors, _)) if sp.is_background() => colors[idx],
+                    _ => sp.palette_color(),
+                })
+                .collect::<Vec<_>>();
+            let palette_colors = palette_colors
+                .iter()
+                .chain(background_colors.iter().flat_map(|(_, palette)| palette))
+                .map(|(color, _)| *color)
+                .collect::<Vec<_>>();
+
+            let pixels = aggregate_super_pixels(
+                colors,
+                &palette_colors,
+                sp_size,
+                args.superpixel_factor,
+                args.metric,
+            )
+            .into_par_iter()
+            .map(|color| color * adjustment)
+            .map(|color| {
+                palette::Lab::<palette::white_point::D65, _>::new(color.l(), color.a(), color.b())
+            })
+            .map(|color| {
+                if args.no_thread_snap {
+                    return palette::rgb::Srgb::from_color(color).clamp();
+                }
 
-    let mut i = 0;
+                dmc_colors[dmc_index.nearest([color.l, color.a, color.b]).unwrap()]
+            })
+            .map(|color: palette::rgb::Srgb<f64>| {
+                let color = color.into_format::<u8>();
+                Rgb::from([color.red, color.green, color.blue])
+            });
 
-    while t > T_FINAL {
-        let start = std::time::Instant::now();
+            pixels
+                .zip(unstitched.par_iter())
+                .map(|(color, unstitched)| {
+                    // Bare fabric, same as how pdfgen reads white
+                    if *unstitched {
+                        Rgb([255, 255, 255])
+                    } else {
+                        color
+                    }
+                })
+                .zip(output.par_iter_mut().chunks(3))
+                .for_each(|(color, mut pixel)| {
+                    *pixel[0] = color.0[0];
+                    *pixel[1] = color.0[1];
+                    *pixel[2] = color.0[2];
+                });
+        };
 
-        sp_refine(&mut super_pixels, input.size, out_size);
+    let mut output = RgbImage::new(out_size.x, out_size.y);
+    // Previews are encoded and written on their own thread so the optimization never waits on disk
+    let previews = args.save_interval.map(|_| {
+        let (sender, receiver) = mpsc::sync_channel::<RgbImage>(1);
+        let path = output_path.clone();
+        let metadata = metadata.clone();
+        let unstitched = unstitched.clone();
+        let delays = delays.clone();
+        let output_format = args.output_format;
+        let writer = thread::spawn(move || -> anyhow::Result<()> {
+            for preview in receiver {
+                save_output(
+                    &preview,
+                    &unstitched,
+                    &delays,
+                    sprite_sheet,
+                    output_format,
+                    &path,
+                    &metadata,
+                )?;
+            }
 
-        associate(&mut super_pixels, &mut palette, &clusters, k, t);
+            Ok(())
+        });
 
-        let total_change = palette_refine(&mut super_pixels, &mut palette);
+        (sender, writer)
+    });
+    let mut palette_edited = false;
+    // The shader only knows the Euclidean distance, square stitches on the square grid and
+    // nothing about the edges
+    #[cfg(feature = "gpu")]
+    if args.metric == Metric::Euclidean
+        && args.edge_weight == 0.0
+        && args.grid == Grid::Square
+        && args.pixel_aspect.x == args.pixel_aspect.y
+        && !solver.use_gpu()
+    {
+        progress.println("No usable GPU found, refining on the CPU");
+    }
 
-        if prev_changes.len() == 100 {
-            running_average -= prev_changes.pop_front().unwrap();
+    if let Some(path) = &args.resume {
+        let checkpoint = Checkpoint::load(path)?;
+        let iteration = checkpoint.iteration;
+        if checkpoint.input_hash != input_hash || !solver.restore(checkpoint) {
+            anyhow::bail!(
+                "the checkpoint {} was made from a different input or output size",
+                path.display()
+            );
         }
 
-        prev_changes.push_back(total_change);
-        running_average += total_change;
+        progress.println(format!(
+            "Resuming from iteration {iteration}, k: {}, t: {:.3}",
+            solver.k(),
+            solver.t()
+        ));
+    }
 
-        let mean = running_average / 100.0;
-        let variance = prev_changes
-            .iter()
-            .map(|change| (mean - change).powi(2))
-            .sum::<f64>()
-            .sqrt()
-            / 100.0;
+    let mut sweep_states = vec![];
+    let mut elbow = match args.color_count {
+        Some(ColorCount::Auto(_)) => Some(ElbowSearch::new(&solver, args.elbow_gain)),
+        _ => None,
+    };
+    let mut profiler = Profiler::new(args.profile.is_some());
+    let mut convergence_log = args
+        .log_csv
+        .as_deref()
+        .map(ConvergenceLog::new)
+        .transpose()?;
+    while !solver.is_done() {
+        let (i, settled) = {
+            let report = solver.step_profiled(&mut profiler);
+            (report.iteration, report.settled)
+        };
+
+        if settled && args.interactive && !palette_edited {
+            palette_edited = true;
+            progress.suspend(|| solver.edit_palette(edit_palette))?;
+        }
 
-        if prev_variances.len() == 100 {
-            running_variance_avg -= prev_variances.pop_front().unwrap();
+        if let (Some(interval), Some((sender, _))) = (args.save_interval, &previews) {
+            if i % interval as usize == 0 {
+                profiler.stage(Stage::Snapping, || {
+                    render_output(solver.super_pixels(), solver.palette(), &mut output)
+                });
+                // Skip this preview if the previous one is still being written
+                let _ = sender.try_send(output.clone());
+            }
         }
 
-        prev_variances.push_back(variance);
-        running_variance_avg += variance;
+        let interrupted = INTERRUPTED.load(Ordering::SeqCst);
 
-        if ((running_variance_avg / 100.0) - variance).abs() < 0.001 {
-            variance_check_passed_count += 1;
-            println!("Trigger due to variance");
-        } else {
-            variance_check_passed_count = 0;
+        if let Some(path) = &args.checkpoint {
+            if interrupted || (i + 1) % args.checkpoint_interval as usize == 0 {
+                let checkpoint = solver.checkpoint(input_hash.clone());
+                profiler.stage(Stage::Saving, || checkpoint.save(path))?;
+            }
         }
 
-        if total_change < EPSILON_PALETTE || variance_check_passed_count > 100 {
-            variance_check_passed_count = 0;
-            t *= ALPHA;
-            if k < args.color_count as usize {
-                expand(
-                    &mut clusters,
-                    &mut palette,
-                    &mut k,
-                    args.color_count as usize,
-                    delta.truncate(),
-                );
+        if let Some(log) = &mut convergence_log {
+            log.log(&solver.report(), solver.used_colors())?;
+        }
+        progress.update(solver.report())?;
+
+        // The state at each count of a sweep is set aside to finish cooling down on its own
+        if let Some(next) = sweep.get(sweep_states.len() + 1) {
+            if solver.k() >= sweep[sweep_states.len()] {
+                sweep_states.push(solver.checkpoint(input_hash.clone()));
+                solver.set_color_count(*next);
             }
         }
 
-        colors.clear();
+        if let Some(search) = &mut elbow {
+            if let Some(k) = search.update(&mut solver) {
+                progress.println(format!(
+                    "More than {k} colors barely lower the error, settling on {k}"
+                ));
+                elbow = None;
+            }
+        }
 
-        let pixels = super_pixels
-            .par_iter_mut()
-            .map(|sp| sp.palette_color * DVec3::new(1.0, 1.1, 1.1))
-            .map(|color| {
-                palette::Lab::<palette::white_point::D65, _>::new(color.l(), color.a(), color.b())
-            })
-            .map(|color| {
-                let mut min_distance = f64::MAX;
-                let mut min_color = dmc_colors[0];
-
-                for (dmc_color, lab_dmc_color) in dmc_colors.iter().zip(lab_dmc_colors.iter()) {
-                    let distance = lab_dmc_color.distance_squared(color);
-                    if distance < min_distance {
-                        min_color = *dmc_color;
-                        min_distance = distance;
-                    }
-                }
+        if interrupted {
+            progress.println("Interrupted, saving the result so far");
+            break;
+        }
+    }
 
-                min_color
-            })
-            .map(|color: palette::rgb::Srgb<f64>| {
-                let color = color.into_format::<u8>();
-                colors.insert(Rgb::from([color.red, color.green, color.blue]));
-                Rgb::from([color.red, color.green, color.blue])
-            });
+    progress.finish();
+    if solver.t() > args.t_final && !INTERRUPTED.load(Ordering::SeqCst) {
+        progress.println(format!(
+            "Stopped at the maximum of {} iterations, t: {:.3}",
+            args.max_iterations.unwrap_or_default(),
+            solver.t()
+        ));
+    }
+    if elbow.is_some() && solver.k() >= color_count {
+        progress.println(format!(
+            "Every color up to the maximum still lowered the error, using {}",
+            solver.k()
+        ));
+    }
+    if let Some(log) = convergence_log {
+        log.finish()?;
+    }
 
-        pixels
-            .zip(output.par_iter_mut().chunks(3))
-            .for_each(|(color, mut pixel)| {
-                *pixel[0] = color.0[0];
-                *pixel[1] = color.0[1];
-                *pixel[2] = color.0[2];
-            });
+    if let Some((sender, writer)) = previews {
+        drop(sender);
+        writer.join().unwrap()?;
+    }
 
-        output.save(&args.output)?;
+    profiler.stage(Stage::Snapping, || {
+        render_output(solver.super_pixels(), solver.palette(), &mut output)
+    });
+    let frame_height = out_size.y / delays.len().max(1) as u32;
+    // The majority filter goes first, it can leave confetti of its own behind
+    let clean_up = |output: &mut RgbImage| -> (Option<usize>, Option<ConfettiReport>) {
+        let smoothed = args
+            .cleanup
+            .map(|radius| majority_filter(output, &unstitched, frame_height, radius));
+        let confetti = args.min_region_size.map(|min_size| {
+            remove_confetti(
+                output,
+                &unstitched,
+                frame_height,
+                min_size as usize,
+                args.metric,
+            )
+        });
 
-        println!(
-            "{i}: Total Change: {total_change:.3}, k: {k}, t: {t:.3}, time_delta: {:?}, color_count: {:?}, variance: {variance:.4}, avg. variance: {:.4} variance count: {variance_check_passed_count}\n",
-            start.elapsed(), colors.len(), running_variance_avg / 100.0
-        );
-        i += 1;
+        (smoothed, confetti)
+    };
+    let (smoothed, confetti) = clean_up(&mut output);
+    if let Some(smoothed) = smoothed {
+        progress.println(format!(
+            "The cleanup recolored {smoothed} stitches to the majority around them"
+        ));
     }
+    if let (Some(confetti), Some(min_size)) = (confetti, args.min_region_size) {
+        progress.println(format!(
+            "Merged {} regions of fewer than {min_size} stitches, recoloring {} stitches by {:.2} ΔE on average",
+            confetti.regions, confetti.stitches, confetti.mean_delta_e
+        ));
+    }
+    if sweep.is_empty() {
+        profiler.stage(Stage::Saving, || {
+            save_output(
+                &output,
+                &unstitched,
+                &delays,
+                sprite_sheet,
+                args.output_format,
+                &output_path,
+                &metadata,
+            )
+        })?;
+    } else {
+        let mut results = Vec::with_capacity(sweep_states.len() + 1);
+        for checkpoint in sweep_states {
+            let mut sweep_solver =
+                Solver::new(&input, sp_size, color_count, sweep_config.clone(), masks)?;
+            sweep_solver.restore(checkpoint);
+            side_pass(&mut sweep_solver, false);
+
+            let mut result = RgbImage::new(out_size.x, out_size.y);
+            render_output(
+                sweep_solver.super_pixels(),
+                sweep_solver.palette(),
+                &mut result,
+            );
+            clean_up(&mut result);
+            results.push((sweep_solver.k(), result));
+        }
+        results.push((solver.k(), output.clone()));
 
-    Ok(())
-}
+        let counts = results
+            .iter()
+            .map(|(k, _)| k.to_string())
+            .collect::<Vec<_>>();
+        progress.println(format!(
+            "Contact sheet of {} colors, left to right and top to bottom",
+            counts.join(", ")
+        ));
+        let results = results
+            .into_iter()
+            .map(|(_, result)| result)
+            .collect::<Vec<_>>();
+        let (sheet, sheet_unstitched) = contact_sheet(&results, &unstitched);
+        profiler.stage(Stage::Saving, || {
+            save_output(
+                &sheet,
+                &sheet_unstitched,
+                &[],
+                None,
+                args.output_format,
+                &output_path,
+                &metadata,
+            )
+        })?;
+    }
 
-#[derive(Debug)]
-pub struct SuperPixel<'s> {
-    img: &'s LabImage,
-    coord: UVec2,
-    palette_color: Color,
-    probability: f64,
-    pixels: dashmap::DashSet<UVec2>,
-    conditional_probability: Vec<f64>,
-    sp_color: Color,
-    original_coord: UVec2,
-    original_color: Color,
-    n: f64,
-    m: f64,
-}
+    if let Some(path) = &args.debug_superpixels {
+        draw_super_pixels(input.size, solver.labels(), solver.super_pixels()).save(path)?;
+    }
 
-impl<'s> SuperPixel<'s> {
-    pub fn new<'i: 's>(img: &'i LabImage, coord: UVec2, color: Color, out_size: UVec2) -> Self {
-        SuperPixel {
-            img,
-            coord,
-            palette_color: color,
-            probability: 1.0 / (out_size.x * out_size.y) as f64,
-            pixels: dashmap::DashSet::default(),
-            conditional_probability: vec![0.5, 0.5],
-            sp_color: Color::BLACK,
-            original_coord: coord,
-            original_color: img[coord],
-            n: (out_size.x * out_size.y) as f64,
-            m: (img.size.x * img.size.y) as f64,
+    if let Some(path) = &args.preview {
+        upscale_preview(&output, &unstitched, args.preview_scale, args.preview_grid).save(path)?;
+    }
+
+    if let Some(path) = &args.profile {
+        profiler.write_folded(path)?;
+        progress.println("Time and peak memory of each stage:");
+        for line in profiler.summary() {
+            progress.println(line);
         }
     }
 
-    pub fn cost(&self, coord: UVec2) -> f64 {
-        let c_diff = self.img[coord].distance(self.palette_color);
-        let spatial_diff = self.coord.as_dvec2().distance(coord.as_dvec2());
+    let report = palette_report(&output, &unstitched, args.metric);
+    progress.println("Palette report, least informative colors first:");
+    for entry in &report {
+        let [r, g, b] = entry.color.0;
+        progress.println(format!(
+            "#{r:02x}{g:02x}{b:02x} usage: {:>6.2}%, closest ΔE: {:>6.2}, score: {:.4}, entropy: {:.4} bits",
+            entry.usage * 100.0,
+            entry.distinctiveness,
+            entry.score(),
+            entry.information,
+        ));
+    }
 
-        c_diff + 45.0 * (self.n / self.m).powf(0.5) * spatial_diff
+    let quality = QualityReport::new(&input, &output, &unstitched);
+    progress.println(format!(
+        "CIEDE2000 to the input: mean {:.2}, median {:.2}, 90th percentile {:.2}, 99th percentile {:.2}, max {:.2}",
+        quality.mean_delta_e,
+        quality.median_delta_e,
+        quality.p90_delta_e,
+        quality.p99_delta_e,
+        quality.max_delta_e,
+    ));
+    if let Some(ssim) = quality.ssim {
+        progress.println(format!("SSIM of the lightness: {ssim:.4}"));
+    }
+    if let Some(path) = &args.quality_report {
+        serde_json::to_writer_pretty(BufWriter::new(fs::File::create(path)?), &quality)?;
     }
 
-    pub fn normalize_probs(
-        &mut self,
-        palette: &Vec<(Color, f64)>,
-        clusters: &Vec<UVec2>,
-        k: usize,
-    ) {
-        let denom: f64 = self.conditional_probability.iter().sum();
-        let mut hi = self
-            .conditional_probability
+    let mut by_usage = report.iter().collect::<Vec<_>>();
+    by_usage.sort_by(|a, b| b.usage.total_cmp(&a.usage));
+    let threads = by_usage
+        .iter()
+        .map(|entry| entry.color.0)
+        .collect::<Vec<_>>();
+    // Snapped colors are named after their thread
+    let thread_name = |rgb: [u8; 3]| {
+        thread_colors
             .iter()
-            .map(|f| *f)
-            .reduce(f64::max)
-            .unwrap();
+            .find(|color| !args.no_thread_snap && color.rgb == rgb)
+            .and_then(|color| color.name.clone())
+    };
+    // Index into `threads` of every stitch
+    let cells = output
+        .pixels()
+        .zip(&unstitched)
+        .map(|(pixel, unstitched)| {
+            (!unstitched).then(|| {
+                threads
+                    .iter()
+                    .position(|thread| *thread == pixel.0)
+                    .unwrap()
+            })
+        })
+        .collect::<Vec<_>>();
 
-        for (i, probability) in self.conditional_probability.iter_mut().enumerate() {
-            if *probability == hi {
-                self.palette_color = palette[i].0;
-            }
+    if let Some(path) = &args.export_palette {
+        let colors = threads
+            .iter()
+            .map(|rgb| PaletteColor {
+                name: thread_name(*rgb),
+                rgb: *rgb,
+            })
+            .collect::<Vec<_>>();
+        save_palette(path, &colors)?;
+    }
 
-            *probability /= denom;
+    if let Some(path) = &args.project {
+        let colors = threads
+            .iter()
+            .enumerate()
+            .map(|(idx, rgb)| ProjectColor {
+                rgb: *rgb,
+                floss: thread_name(*rgb),
+                stitches: cells.iter().filter(|cell| **cell == Some(idx)).count(),
+            })
+            .collect();
+        save_project(
+            path,
+            &Project::new(
+                UVec2::new(output.width(), output.height()),
+                colors,
+                cells.clone(),
+                serde_json::to_value(&args)?,
+            ),
+        )?;
+    }
+
+    if let Some(path) = &args.embroidery {
+        let commands = cross_stitches(
+            &cells,
+            UVec2::new(output.width(), output.height()),
+            threads.len(),
+            (254.0 / args.embroidery_count as f64).round() as i32,
+        );
+
+        let name = path.file_stem().unwrap_or_default().to_string_lossy();
+        let mut out = BufWriter::new(fs::File::create(path)?);
+        let extension = path
+            .extension()
+            .and_then(|ext| ext.to_str())
+            .map(|ext| ext.to_ascii_lowercase());
+        match extension.as_deref() {
+            Some("dst") => write_dst(&mut out, &name, &commands)?,
+            Some("pes") => write_pes(&mut out, &name, &commands, &threads)?,
+            _ => anyhow::bail!("unsupported embroidery format, expected a .dst or .pes file"),
         }
+    }
 
-        hi = -1.0;
-        for i in 0..k {
-            let cluster = clusters[i];
-            let mut prob = 0.0;
-            let mut color = Color::BLACK;
+    // Printed on stdout for the pipelines running with the gates, the exit status tells them
+    // whether it passed
+    if args.require_delta_e_below.is_some() || args.require_colors.is_some() {
+        let acceptance = AcceptanceReport::new(
+            mean_delta_e(
+                solver.super_pixels(),
+                sp_size,
+                args.superpixel_factor,
+                &output,
+                &unstitched,
+                args.metric,
+            ),
+            report.len(),
+            args.require_delta_e_below,
+            args.require_colors,
+        );
+        println!("{}", serde_json::to_string(&acceptance)?);
+        if !acceptance.passed {
+            anyhow::bail!(
+                "the pattern failed its quality gates: {}",
+                acceptance.failures.join(", ")
+            );
+        }
+    }
 
-            for ci in cluster.to_array() {
-                let cur = palette[ci as usize];
-                color += cur.0;
-                prob += cur.1;
-            }
+    Ok(())
+}
 
-            color /= cluster.to_array().len() as f64;
+// Size of the output, along with the part of the input it covers when the input has to be cropped
+fn output_size(in_size: UVec2, args: &Args) -> (UVec2, Option<Crop>) {
+    // Size of the input in stitches of the same proportions, up to a scale
+    let aspect = args.pixel_aspect.as_dvec2();
+    let shape = in_size.as_dvec2() / aspect;
+    let size = match (args.width, args.height, args.preset) {
+        (Some(width), Some(height), _) => UVec2::new(width, height),
+        // Fits the screen of the console unless -m is given
+        (_, _, Some(preset)) if args.max_side_size.is_none() => preset.screen_size(),
+        _ => {
+            // clap requires -m when neither --width and --height nor --preset are given
+            let max_side_size = args.max_side_size.unwrap() as f64;
+            let out_size = if shape.x >= shape.y {
+                UVec2 {
+                    x: max_side_size as u32,
+                    y: ((max_side_size / shape.x) * shape.y).ceil() as u32,
+                }
+            } else {
+                UVec2 {
+                    x: ((max_side_size / shape.y) * shape.x).ceil() as u32,
+                    y: max_side_size as u32,
+                }
+            };
 
-            if prob > hi {
-                hi = prob;
-                // self.palette_color = color;
-            }
+            return (out_size, None);
         }
-    }
+    };
 
-    pub fn update_position(&mut self) {
-        if self.pixels.len() == 0 {
-            info!("super pixel without pixels failure");
-            self.coord = self.original_coord;
-        } else {
-            self.coord = self.pixels.iter().map(|v| *v).sum::<UVec2>() / self.pixels.len() as u32;
+    match args.fit {
+        Fit::Contain => {
+            let scale = (size.as_dvec2() / shape).min_element();
+            let out_size = (shape * scale).round().as_uvec2();
+
+            (out_size.clamp(UVec2::ONE, size), None)
         }
+        Fit::Cover => {
+            let scale = (size.as_dvec2() / shape).max_element();
+            let crop_size = (size.as_dvec2() / scale * aspect)
+                .round()
+                .as_uvec2()
+                .clamp(UVec2::ONE, in_size);
+
+            (
+                size,
+                Some(Crop {
+                    offset: (in_size - crop_size) / 2,
+                    size: crop_size,
+                }),
+            )
+        }
+        Fit::Stretch => (size, None),
     }
+}
 
-    pub fn update_sp_color(&mut self) {
-        if self.pixels.len() == 0 {
-            self.sp_color = self.original_color;
-        } else {
-            self.sp_color = self
-                .pixels
-                .iter()
-                .map(|coord| self.img[*coord])
-                .sum::<Color>()
-                / self.pixels.len() as f64;
+// Output pixels where most of the super pixels are background
+fn unstitched_cells(super_pixels: &[SuperPixel], sp_size: UVec2, factor: u32) -> Vec<bool> {
+    let out_size = sp_size / factor;
+
+    (0..(out_size.x * out_size.y))
+        .map(|idx| {
+            let coord = UVec2 {
+                x: idx % out_size.x,
+                y: idx / out_size.x,
+            } * factor;
+
+            let background = (coord.y..(coord.y + factor))
+                .flat_map(|y| (coord.x..(coord.x + factor)).map(move |x| (x, y)))
+                .filter(|(x, y)| super_pixels[(x + y * sp_size.x) as usize].is_background())
+                .count();
+            background * 2 > (factor * factor) as usize
+        })
+        .collect()
+}
+
+// Runs a solver without the checkpoints, previews and palette editing of the main loop, until
+// the final temperature or, with `until_complete`, until its complete palette converges
+fn side_pass(solver: &mut Solver, until_complete: bool) {
+    while !solver.is_done() {
+        if solver.step().settled && until_complete {
+            break;
         }
     }
 }
 
-fn sp_refine(super_pixels: &mut Vec<SuperPixel>, in_size: UVec2, out_size: UVec2) {
-    super_pixels
-        .into_par_iter()
-        .for_each(|sp| sp.pixels.clear());
+// Averages the palette colors of the super pixels into output pixels
+fn aggregate_super_pixels(
+    colors: Vec<Color>,
+    palette: &[Color],
+    sp_size: UVec2,
+    factor: u32,
+    metric: Metric,
+) -> Vec<Color> {
+    if factor == 1 {
+        return colors;
+    }
 
-    (0..(in_size.x * in_size.y))
+    let out_size = sp_size / factor;
+    (0..(out_size.x * out_size.y))
         .into_par_iter()
-        .for_each(|idx| {
+        .map(|idx| {
             let coord = UVec2 {
-                x: idx % in_size.x,
-                y: idx / in_size.x,
-            };
-            let sp_coord = (coord * out_size) / in_size;
-            const D_COORDS: [IVec2; 9] = [
-                IVec2::new(-1, -1),
-                IVec2::new(-1, 0),
-                IVec2::new(-1, 1),
-                IVec2::new(0, -1),
-                IVec2::new(0, 0),
-                IVec2::new(0, 1),
-                IVec2::new(1, -1),
-                IVec2::new(1, 0),
-                IVec2::new(1, 1),
-            ];
-
-            let mut best_cost = f64::MAX;
-            let mut best_coord = UVec2::ZERO;
-            for d_coord in D_COORDS {
-                let n_coord = sp_coord.as_ivec2() + d_coord;
-                if n_coord.x >= 0
-                    && n_coord.y >= 0
-                    && n_coord.x < out_size.x as i32
-                    && n_coord.y < out_size.y as i32
-                {
-                    let n_coord = n_coord.as_uvec2();
-                    let new_cost =
-                        super_pixels[(n_coord.x + n_coord.y * out_size.x) as usize].cost(coord);
-                    if new_cost < best_cost {
-                        best_cost = new_cost;
-                        best_coord = n_coord;
-                    }
+                x: idx % out_size.x,
+                y: idx / out_size.x,
+            } * factor;
+
+            let mut color = Color::BLACK;
+            for y in coord.y..(coord.y + factor) {
+                for x in coord.x..(coord.x + factor) {
+                    color += colors[(x + y * sp_size.x) as usize];
                 }
             }
+            color /= (factor * factor) as f64;
 
-            super_pixels[(best_coord.x + best_coord.y * out_size.x) as usize]
-                .pixels
-                .insert(coord);
-        });
-
-    super_pixels.into_par_iter().for_each(|sp| {
-        sp.update_position();
-        sp.update_sp_color();
-    });
-
-    // Laplacian smoothing
-    let mut new_coords = (0..(out_size.x * out_size.y))
-        .map(|_| UVec2::ZERO)
-        .collect::<Vec<_>>();
+            // Snap the average back to the palette so the output keeps its color count
+            *palette
+                .iter()
+                .min_by_key(|palette_color| {
+                    float_ord::FloatOrd(palette_color.difference(color, metric))
+                })
+                .unwrap()
+        })
+        .collect()
+}
 
-    for j in 0..out_size.y {
-        for i in 0..out_size.x {
-            const D_COORDS: [IVec2; 4] = [
-                IVec2::new(0, 1),
-                IVec2::new(0, -1),
-                IVec2::new(-1, 0),
-                IVec2::new(1, 0),
-            ];
-            let sp = &super_pixels[(i + j * out_size.x) as usize];
-            let mut n = 0;
-            let mut new = UVec2::ZERO;
-
-            for coord in D_COORDS {
-                let n_coord = IVec2::new(i as i32, j as i32) + coord;
-                if n_coord.x >= 0
-                    && n_coord.y >= 0
-                    && n_coord.x < out_size.x as i32
-                    && n_coord.y < out_size.y as i32
-                {
-                    let n_coord = n_coord.as_uvec2();
-                    n += 1;
-
-                    new += super_pixels[(n_coord.x + n_coord.y * out_size.x) as usize].coord;
-                }
-            }
+fn expand_name_template(
+    template: &str,
+    input: &Path,
+    out_size: UVec2,
+    color_count: usize,
+) -> String {
+    let stem = input
+        .file_stem()
+        .map(|stem| stem.to_string_lossy())
+        .unwrap_or_default();
+
+    template
+        .replace("{stem}", &stem)
+        .replace("{colors}", &color_count.to_string())
+        .replace("{width}", &out_size.x.to_string())
+        .replace("{height}", &out_size.y.to_string())
+        .replace("{ext}", "png")
+}
 
-            let mut new = new.as_dvec2();
-            new /= n as f64;
+// Unstitched pixels are transparent in PNGs and GIFs and white everywhere else. `delays` are how
+// long each of the frames stacked in `output` shows, there is no animation when it is empty. The
+// frames are laid out in a sprite sheet of `sprite_sheet` columns instead of animated when given
+fn save_output(
+    output: &RgbImage,
+    unstitched: &[bool],
+    delays: &[Delay],
+    sprite_sheet: Option<u32>,
+    format: OutputFormat,
+    path: &str,
+    metadata: &[(&str, String)],
+) -> anyhow::Result<()> {
+    if let Some(columns) = sprite_sheet {
+        return save_sprite_sheet(output, unstitched, delays, columns, format, path, metadata);
+    }
 
-            new_coords[(i + j * out_size.x) as usize] =
-                (0.4 * new + 0.6 * sp.coord.as_dvec2()).as_uvec2();
+    let extension = Path::new(path)
+        .extension()
+        .and_then(|ext| ext.to_str())
+        .map(|ext| ext.to_ascii_lowercase());
+
+    match extension.as_deref() {
+        _ if format == OutputFormat::IndexedPng => {}
+        Some("png") => {}
+        Some("ase" | "aseprite") => return save_aseprite(output, unstitched, delays, path),
+        Some("gif") if !delays.is_empty() => return save_gif(output, unstitched, delays, path),
+        _ if !delays.is_empty() => anyhow::bail!("animations can only be saved as GIF or PNG"),
+        // Only PNG has somewhere to put the metadata, everything else is saved as is
+        _ => {
+            output.save(path)?;
+            return Ok(());
         }
     }
 
-    // Bilateral Filter Approximation
-    let mut new_colors = (0..(out_size.x * out_size.y))
-        .map(|_| Color::BLACK)
-        .collect::<Vec<_>>();
-    for j in 0..out_size.y {
-        for i in 0..out_size.x {
-            const D_COORDS: [IVec2; 9] = [
-                IVec2::new(-1, -1),
-                IVec2::new(-1, 0),
-                IVec2::new(-1, 1),
-                IVec2::new(0, -1),
-                IVec2::new(0, 0),
-                IVec2::new(0, 1),
-                IVec2::new(1, -1),
-                IVec2::new(1, 0),
-                IVec2::new(1, 1),
-            ];
-            let sp = &super_pixels[(i + j * out_size.x) as usize];
-            let mut n = 0.0;
-            let mut avg_color = Color::BLACK;
-
-            for coord in D_COORDS {
-                let n_coord = IVec2::new(i as i32, j as i32) + coord;
-                if n_coord.x >= 0
-                    && n_coord.y >= 0
-                    && n_coord.x < out_size.x as i32
-                    && n_coord.y < out_size.y as i32
-                {
-                    let n_coord = n_coord.as_uvec2();
-
-                    let next = super_pixels[(n_coord.x + n_coord.y * out_size.x) as usize].sp_color;
-                    let weight =
-                        std::f64::consts::E.powf(-1.0 * (sp.sp_color.l() - next.l()).abs());
-
-                    avg_color += next * weight;
-
-                    n += weight;
-                }
-            }
-
-            avg_color /= n;
-
-            new_colors[(i + j * out_size.x) as usize] = sp.sp_color * 0.5 + avg_color * 0.5;
+    let frame_count = delays.len().max(1) as u32;
+    let mut encoder = png::Encoder::new(
+        BufWriter::new(fs::File::create(path)?),
+        output.width(),
+        output.height() / frame_count,
+    );
+    let has_alpha = unstitched.contains(&true);
+    encoder.set_depth(png::BitDepth::Eight);
+    let data = if format == OutputFormat::IndexedPng {
+        let (palette, indices) = index_colors(output, unstitched)?;
+        encoder.set_color(png::ColorType::Indexed);
+        encoder.set_palette(palette.concat());
+        // Only the first entry, which unstitched pixels use, is transparent
+        if has_alpha {
+            encoder.set_trns(vec![0]);
         }
+        indices
+    } else if has_alpha {
+        encoder.set_color(png::ColorType::Rgba);
+        output
+            .pixels()
+            .zip(unstitched)
+            .flat_map(|(pixel, unstitched)| {
+                let [r, g, b] = pixel.0;
+                [r, g, b, if *unstitched { 0 } else { 255 }]
+            })
+            .collect::<Vec<_>>()
+    } else {
+        encoder.set_color(png::ColorType::Rgb);
+        output.as_raw().clone()
+    };
+    if !delays.is_empty() {
+        encoder.set_animated(frame_count, 0)?;
     }
-
-    for (i, sp) in super_pixels.iter_mut().enumerate() {
-        sp.coord = new_coords[i];
-        sp.sp_color = new_colors[i];
+    for (keyword, text) in metadata {
+        encoder.add_text_chunk(keyword.to_string(), text.clone())?;
     }
-}
 
-fn associate(
-    super_pixels: &mut Vec<SuperPixel>,
-    palettes: &mut Vec<(Color, f64)>,
-    clusters: &Vec<UVec2>,
-    k: usize,
-    t: f64,
-) {
-    super_pixels.into_par_iter().for_each(|sp| {
-        sp.conditional_probability.resize(palettes.len(), 0.0);
-        for (i, palette) in palettes.iter().enumerate() {
-            sp.conditional_probability[i] = palette.0.condit_prob(palette.1, sp, t);
+    let mut writer = encoder.write_header()?;
+    for (idx, frame) in data.chunks(data.len() / frame_count as usize).enumerate() {
+        if let Some(delay) = delays.get(idx) {
+            let (numer, denom) = delay.numer_denom_ms();
+            writer.set_frame_delay((numer as f64 / denom as f64).round() as u16, 1000)?;
         }
-        sp.normalize_probs(palettes, clusters, k);
-    });
-
-    palettes
-        .into_par_iter()
-        .enumerate()
-        .for_each(|(i, palette)| {
-            palette.1 = 0.0;
+        writer.write_image_data(frame)?;
+    }
+    writer.finish()?;
 
-            for sp in super_pixels.iter() {
-                palette.1 += sp.conditional_probability[i] * sp.probability;
-            }
-        });
+    Ok(())
 }
 
-fn palette_refine(super_pixels: &mut Vec<SuperPixel>, palettes: &mut Vec<(Color, f64)>) -> f64 {
-    palettes
-        .into_par_iter()
-        .enumerate()
-        .map(|(i, palette)| {
-            let mut new_color = Color::BLACK;
-
-            for sp in super_pixels.iter() {
-                new_color +=
-                    (sp.sp_color * sp.conditional_probability[i] * sp.probability) / palette.1;
+// Palette of the distinct colors of `output` in the order they first show up, after a
+// transparent entry for the unstitched pixels if there are any, along with the index of every pixel
+fn index_colors(output: &RgbImage, unstitched: &[bool]) -> anyhow::Result<(Vec<[u8; 3]>, Vec<u8>)> {
+    let mut colors = vec![];
+    if unstitched.contains(&true) {
+        colors.push(Rgb([0, 0, 0]));
+    }
+    let mut indices = HashMap::new();
+
+    let data = output
+        .pixels()
+        .zip(unstitched)
+        .map(|(pixel, unstitched)| {
+            if *unstitched {
+                return Ok(0);
             }
 
-            let distance = palette.0.distance(new_color);
-            palette.0 = new_color;
-            distance
+            let idx = *indices.entry(*pixel).or_insert_with(|| {
+                colors.push(*pixel);
+                colors.len() - 1
+            });
+            u8::try_from(idx).map_err(|_| anyhow::anyhow!("indexed images hold 256 colors at most"))
         })
-        .sum()
+        .collect::<anyhow::Result<Vec<_>>>()?;
+
+    Ok((colors.iter().map(|color| color.0).collect(), data))
 }
 
-fn expand(
-    clusters: &mut Vec<UVec2>,
-    palettes: &mut Vec<(Color, f64)>,
-    k: &mut usize,
-    k_max: usize,
-    delta: DVec2,
-) {
-    for i in 0..(*k).min(k_max) {
-        let [c1, c2] = palettes
-            .get_many_mut([clusters[i].x as usize, clusters[i].y as usize])
-            .unwrap();
+// Indexed with the colors of the pattern, so it can be edited in Aseprite right away
+fn save_aseprite(
+    output: &RgbImage,
+    unstitched: &[bool],
+    delays: &[Delay],
+    path: &str,
+) -> anyhow::Result<()> {
+    let frame_count = delays.len().max(1) as u32;
+    let size = UVec2::new(output.width(), output.height() / frame_count);
+    if size.max_element() > u16::MAX as u32 {
+        anyhow::bail!("Aseprite sprites are {} pixels a side at most", u16::MAX);
+    }
 
-        if c1.0.distance(c2.0) > EPSILON_CLUSTER {
-            *k += 1;
+    let (palette, indices) = index_colors(output, unstitched)?;
+    let frames = indices
+        .chunks((size.x * size.y) as usize)
+        .enumerate()
+        .map(|(idx, pixels)| {
+            let duration = delays.get(idx).map_or(100, |delay| {
+                let (numer, denom) = delay.numer_denom_ms();
+                (numer as f64 / denom as f64).round() as u16
+            });
+            (pixels, duration)
+        })
+        .collect::<Vec<_>>();
 
-            c1.1 /= 2.0;
-            c2.1 /= 2.0;
+    write_aseprite(
+        &mut BufWriter::new(fs::File::create(path)?),
+        size,
+        &palette,
+        unstitched.contains(&true).then_some(0),
+        &frames,
+    )?;
 
-            let c1 = *c1;
-            let c2 = *c2;
+    Ok(())
+}
 
-            palettes.push(c1);
-            palettes.push(c2);
+// Every stitch as a square of `scale` pixels, darkened a little along the top and left edges with
+// `grid`, and unstitched pixels transparent
+fn upscale_preview(output: &RgbImage, unstitched: &[bool], scale: u32, grid: bool) -> RgbaImage {
+    RgbaImage::from_fn(output.width() * scale, output.height() * scale, |x, y| {
+        let (cell_x, cell_y) = (x / scale, y / scale);
+        if unstitched[(cell_y * output.width() + cell_x) as usize] {
+            return Rgba([0, 0, 0, 0]);
+        }
 
-            clusters.push(UVec2::new(clusters[i][1], (palettes.len() - 1) as u32));
-            clusters[i] = UVec2::new(clusters[i][0], (palettes.len() - 2) as u32);
+        let Rgb(mut rgb) = *output.get_pixel(cell_x, cell_y);
+        if grid && scale > 1 && (x % scale == 0 || y % scale == 0) {
+            rgb = rgb.map(|c| (c as f64 * (1.0 - PREVIEW_GRID_SHADE)) as u8);
+        }
+        let [r, g, b] = rgb;
+        Rgba([r, g, b, 255])
+    })
+}
 
-            assert!(
-                (palettes[clusters[i].x as usize].1 - palettes[clusters[i].y as usize].1).abs()
-                    < EPSILON_CLUSTER
-            );
-            assert!(
-                (palettes[clusters.last().unwrap().x as usize].1
-                    - palettes[clusters.last().unwrap().y as usize].1)
-                    .abs()
-                    < EPSILON_CLUSTER
-            );
+// Every input pixel in the color of its super pixel, random but the same from run to run, with
+// the centers of the super pixels marked by a black cross
+fn draw_super_pixels(size: UVec2, labels: &[u32], super_pixels: &[SuperPixel]) -> RgbImage {
+    let mut rng = rand_pcg::Pcg32::seed_from_u64(0);
+    let colors = super_pixels
+        .iter()
+        .map(|_| Rgb(rng.gen::<[u8; 3]>()))
+        .collect::<Vec<_>>();
+
+    let mut img = RgbImage::from_fn(size.x, size.y, |x, y| {
+        colors[labels[(y * size.x + x) as usize] as usize]
+    });
+    for sp in super_pixels {
+        let center = sp.coord().as_ivec2();
+        for offset in [IVec2::ZERO, IVec2::X, -IVec2::X, IVec2::Y, -IVec2::Y] {
+            let mark = center + offset;
+            if mark.cmpge(IVec2::ZERO).all() && mark.cmplt(size.as_ivec2()).all() {
+                img.put_pixel(mark.x as u32, mark.y as u32, Rgb([0, 0, 0]));
+            }
         }
     }
 
-    if *k >= k_max {
-        let mut new_palette = Vec::default();
-        let mut new_clusters = Vec::default();
-
-        for i in 0..(*k) {
-            let c1 = palettes[clusters[i].x as usize];
-            let c2 = palettes[clusters[i].y as usize];
-            let new_color = (c1.0 + c2.0) / 2.0;
+    img
+}
 
-            new_palette.push((new_color, c1.1 + c2.1));
-            new_clusters.push(UVec2 { x: i as u32, y: 0 });
+// Results of a sweep in a grid with gaps between them, upscaled by a whole factor towards
+// SWEEP_CELL pixels a side. The unstitched pixels of the results stay unstitched on the sheet,
+// saved like any other output
+fn contact_sheet(results: &[RgbImage], unstitched: &[bool]) -> (RgbImage, Vec<bool>) {
+    let size = UVec2::new(results[0].width(), results[0].height());
+    let scale = (SWEEP_CELL / size.max_element()).max(1);
+    let columns = (results.len() as f64).sqrt().ceil() as u32;
+    let rows = (results.len() as u32).div_ceil(columns);
+    let cell = size * scale + SWEEP_GAP;
+
+    let mut sheet = RgbImage::from_pixel(
+        cell.x * columns + SWEEP_GAP,
+        cell.y * rows + SWEEP_GAP,
+        Rgb([255, 255, 255]),
+    );
+    let mut sheet_unstitched = vec![false; (sheet.width() * sheet.height()) as usize];
+    for (idx, result) in results.iter().enumerate() {
+        let at = UVec2::new(idx as u32 % columns, idx as u32 / columns) * cell + SWEEP_GAP;
+        for y in 0..size.y * scale {
+            for x in 0..size.x * scale {
+                let (cell_x, cell_y) = (x / scale, y / scale);
+                let (sheet_x, sheet_y) = (at.x + x, at.y + y);
+                sheet.put_pixel(sheet_x, sheet_y, *result.get_pixel(cell_x, cell_y));
+                sheet_unstitched[(sheet_y * sheet.width() + sheet_x) as usize] =
+                    unstitched[(cell_y * size.x + cell_x) as usize];
+            }
         }
+    }
 
-        *palettes = new_palette;
-        *clusters = new_clusters;
-    } else {
-        for i in 0..(*k) {
-            let c = &mut palettes[clusters[i].y as usize];
-            c.0.perturb(delta);
-        }
+    (sheet, sheet_unstitched)
+}
+
+fn save_gif(
+    output: &RgbImage,
+    unstitched: &[bool],
+    delays: &[Delay],
+    path: &str,
+) -> anyhow::Result<()> {
+    let frame_height = output.height() / delays.len() as u32;
+    let mut encoder = GifEncoder::new(BufWriter::new(fs::File::create(path)?));
+    encoder.set_repeat(Repeat::Infinite)?;
+
+    for (idx, delay) in delays.iter().enumerate() {
+        let top = idx as u32 * frame_height;
+        let frame = RgbaImage::from_fn(output.width(), frame_height, |x, y| {
+            let [r, g, b] = output.get_pixel(x, top + y).0;
+            let unstitched = unstitched[((top + y) * output.width() + x) as usize];
+            Rgba([r, g, b, if unstitched { 0 } else { 255 }])
+        });
+        encoder.encode_frame(Frame::from_parts(frame, 0, 0, *delay))?;
     }
+
+    Ok(())
 }
 
-fn load_dmc_colors() -> Vec<palette::rgb::Srgb<f64>> {
-    #[derive(serde::Deserialize)]
-    struct DmcColor {
-        red: u8,
-        green: u8,
-        blue: u8,
+#[derive(serde::Serialize)]
+struct SpriteSheet {
+    image: String,
+    width: u32,
+    height: u32,
+    frames: Vec<SpriteSheetFrame>,
+}
+
+#[derive(serde::Serialize)]
+struct SpriteSheetFrame {
+    x: u32,
+    y: u32,
+    width: u32,
+    height: u32,
+    duration_ms: u32,
+}
+
+// Lays the frames stacked in `output` out in rows of `columns`, and writes where each of them is
+// along with how long it shows into a JSON file next to the image
+fn save_sprite_sheet(
+    output: &RgbImage,
+    unstitched: &[bool],
+    delays: &[Delay],
+    columns: u32,
+    format: OutputFormat,
+    path: &str,
+    metadata: &[(&str, String)],
+) -> anyhow::Result<()> {
+    let frame_count = delays.len().max(1) as u32;
+    let frame_size = UVec2::new(output.width(), output.height() / frame_count);
+    let columns = columns.min(frame_count);
+    let rows = frame_count.div_ceil(columns);
+    let frame_pos = |idx: u32| UVec2::new(idx % columns, idx / columns) * frame_size;
+
+    let mut sheet = RgbImage::from_pixel(
+        frame_size.x * columns,
+        frame_size.y * rows,
+        Rgb([255, 255, 255]),
+    );
+    // The cells no frame goes in are left empty
+    let mut sheet_unstitched = vec![true; (sheet.width() * sheet.height()) as usize];
+    for (idx, (pixel, unstitched)) in output.pixels().zip(unstitched).enumerate() {
+        let (x, y) = (idx as u32 % output.width(), idx as u32 / output.width());
+        let at = frame_pos(y / frame_size.y) + UVec2::new(x, y % frame_size.y);
+        sheet.put_pixel(at.x, at.y, *pixel);
+        sheet_unstitched[(at.y * sheet.width() + at.x) as usize] = *unstitched;
     }
+    save_output(&sheet, &sheet_unstitched, &[], None, format, path, metadata)?;
 
-    let colors: Vec<DmcColor> = serde_json::from_str(include_str!("../dmc_colors.json")).unwrap();
+    let frames = delays
+        .iter()
+        .enumerate()
+        .map(|(idx, delay)| {
+            let pos = frame_pos(idx as u32);
+            let (numer, denom) = delay.numer_denom_ms();
+            SpriteSheetFrame {
+                x: pos.x,
+                y: pos.y,
+                width: frame_size.x,
+                height: frame_size.y,
+                duration_ms: (numer as f64 / denom as f64).round() as u32,
+            }
+        })
+        .collect();
+    let path = Path::new(path);
+    let json = SpriteSheet {
+        image: path
+            .file_name()
+            .map_or(String::new(), |name| name.to_string_lossy().into_owned()),
+        width: sheet.width(),
+        height: sheet.height(),
+        frames,
+    };
+    serde_json::to_writer_pretty(
+        BufWriter::new(fs::File::create(path.with_extension("json"))?),
+        &json,
+    )?;
 
-    colors
-        .into_iter()
-        .map(|DmcColor { red, green, blue }| palette::rgb::Rgb::new(red, green, blue).into_format())
-        .collect()
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn writes_the_metadata_into_png_text_chunks() {
+        let path =
+            std::env::temp_dir().join(format!("pixelart-gen-metadata-{}.png", std::process::id()));
+        let output = RgbImage::from_pixel(2, 2, Rgb([10, 20, 30]));
+        let metadata = [
+            ("Software", "pixelart-gen 0.0.0".to_string()),
+            ("Parameters", "{\"fg_colors\":12}".to_string()),
+        ];
+        save_output(
+            &output,
+            &[false; 4],
+            &[],
+            None,
+            OutputFormat::Auto,
+            path.to_str().unwrap(),
+            &metadata,
+        )
+        .unwrap();
+
+        let reader = png::Decoder::new(fs::File::open(&path).unwrap())
+            .read_info()
+            .unwrap();
+        let chunks = reader
+            .info()
+            .uncompressed_latin1_text
+            .iter()
+            .map(|chunk| (chunk.keyword.as_str(), chunk.text.clone()))
+            .collect::<Vec<_>>();
+        fs::remove_file(&path).unwrap();
+
+        assert_eq!(chunks, metadata);
+    }
 }
@@ -0,0 +1,111 @@
+use std::io::{self, BufRead};
+
+use pixelart_gen::color::Color;
+
+const HELP: &str = "Commands:
+  list                      show the palette
+  lock <n>...               keep colors from moving
+  unlock <n>...             let colors move again
+  delete <n>...             remove colors from the palette
+  nudge <n> <l> <a> <b>     move a color by the given Lab offset
+  continue                  resume the optimization";
+
+/// Lets the user lock, delete or nudge palette colors from the terminal, returns once they
+/// continue or stdin closes.
+pub fn edit_palette(
+    palette: &mut Vec<(Color, f64)>,
+    locked: &mut Vec<bool>,
+    owners: &mut Vec<Option<u8>>,
+) -> io::Result<()> {
+    eprintln!("The palette converged, edit it before the optimization continues.");
+    eprintln!("{HELP}");
+    print_palette(palette, locked);
+
+    let stdin = io::stdin();
+    for line in stdin.lock().lines() {
+        let line = line?;
+        let mut words = line.split_whitespace();
+        let Some(command) = words.next() else {
+            continue;
+        };
+        let args = words.collect::<Vec<_>>();
+
+        let result = match command {
+            "list" => Ok(()),
+            "lock" | "unlock" => parse_indices(&args, palette.len()).map(|indices| {
+                for idx in indices {
+                    locked[idx] = command == "lock";
+                }
+            }),
+            "delete" => parse_indices(&args, palette.len()).and_then(|mut indices| {
+                if indices.len() >= palette.len() {
+                    return Err("can't delete every color".to_string());
+                }
+
+                // Back to front so the remaining indices stay valid
+                indices.sort_unstable();
+                indices.dedup();
+                for idx in indices.into_iter().rev() {
+                    palette.remove(idx);
+                    locked.remove(idx);
+                    if !owners.is_empty() {
+                        owners.remove(idx);
+                    }
+                }
+
+                Ok(())
+            }),
+            "nudge" => parse_nudge(&args, palette.len()).map(|(idx, offset)| {
+                palette[idx].0 += offset;
+            }),
+            "continue" | "c" => break,
+            _ => Err(format!("unknown command {command:?}\n{HELP}")),
+        };
+
+        match result {
+            Ok(()) => print_palette(palette, locked),
+            Err(err) => eprintln!("{err}"),
+        }
+    }
+
+    Ok(())
+}
+
+fn print_palette(palette: &[(Color, f64)], locked: &[bool]) {
+    for (idx, ((color, probability), locked)) in palette.iter().zip(locked).enumerate() {
+        let [r, g, b] = color.to_srgb();
+        eprintln!(
+            "{idx:>3} \x1b[48;2;{r};{g};{b}m      \x1b[0m #{r:02x}{g:02x}{b:02x} {:>6.2}%{}",
+            probability * 100.0,
+            if *locked { " locked" } else { "" }
+        );
+    }
+}
+
+fn parse_indices(args: &[&str], len: usize) -> Result<Vec<usize>, String> {
+    if args.is_empty() {
+        return Err("expected at least one color index".to_string());
+    }
+
+    args.iter()
+        .map(|arg| match arg.parse::<usize>() {
+            Ok(idx) if idx < len => Ok(idx),
+            _ => Err(format!("{arg:?} isn't a color index below {len}")),
+        })
+        .collect()
+}
+
+fn parse_nudge(args: &[&str], len: usize) -> Result<(usize, Color), String> {
+    let [idx, l, a, b] = args else {
+        return Err("expected nudge <n> <l> <a> <b>".to_string());
+    };
+
+    let idx = parse_indices(&[idx], len)?[0];
+    let component = |value: &str| {
+        value
+            .parse::<f64>()
+            .map_err(|err| format!("invalid offset {value:?}: {err}"))
+    };
+
+    Ok((idx, Color::new(component(l)?, component(a)?, component(b)?)))
+}
@@ -0,0 +1,474 @@
+use std::{collections::HashMap, fs, path::Path};
+
+use anyhow::{bail, Context};
+use palette::{Clamp, FromColor};
+
+const DMC_COLORS: &str = include_str!("../dmc_colors.json");
+// DMC to Anchor conversion chart, Anchor colors are taken from their DMC equivalent
+const ANCHOR_COLORS: &str = include_str!("../anchor_colors.json");
+// Names DMC gives its flosses
+const DMC_NAMES: &str = include_str!("../dmc_names.json");
+// Miyuki Delica 11/0 bead colors, as close as a flat color gets to their finish
+const DELICA_COLORS: &str = include_str!("../delica_colors.json");
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PaletteColor {
+    // Floss number or color name, if the palette has one
+    pub name: Option<String>,
+    pub rgb: [u8; 3],
+}
+
+/// Loads the palette at `path`, or the built-in DMC palette when no path is given.
+pub fn load_palette(path: Option<&Path>) -> anyhow::Result<Vec<PaletteColor>> {
+    let Some(path) = path else {
+        return parse_json(DMC_COLORS);
+    };
+
+    let extension = path
+        .extension()
+        .and_then(|ext| ext.to_str())
+        .map(|ext| ext.to_ascii_lowercase());
+
+    let colors = match extension.as_deref() {
+        Some("json") => parse_json(&fs::read_to_string(path)?),
+        Some("gpl") => parse_gpl(&fs::read_to_string(path)?),
+        Some("ase") => parse_ase(&fs::read(path)?),
+        _ => bail!("unsupported palette format, expected a .json, .gpl or .ase file"),
+    }
+    .with_context(|| format!("failed to load palette {}", path.display()))?;
+
+    if colors.is_empty() {
+        bail!("palette {} has no colors", path.display());
+    }
+
+    Ok(colors)
+}
+
+/// Writes `colors` to `path` as JSON, GPL, ASE or a Lospec style list of hex colors, depending on
+/// its extension. Every format but the hex list keeps the names, which can be read back with
+/// `load_palette`.
+pub fn save_palette(path: &Path, colors: &[PaletteColor]) -> anyhow::Result<()> {
+    let extension = path
+        .extension()
+        .and_then(|ext| ext.to_str())
+        .map(|ext| ext.to_ascii_lowercase());
+
+    let bytes = match extension.as_deref() {
+        Some("json") => to_json(colors)?.into_bytes(),
+        Some("gpl") => to_gpl(colors).into_bytes(),
+        Some("ase") => to_ase(colors),
+        Some("hex") => to_hex(colors).into_bytes(),
+        _ => bail!("unsupported palette format, expected a .json, .gpl, .ase or .hex file"),
+    };
+
+    fs::write(path, bytes).with_context(|| format!("failed to save palette {}", path.display()))
+}
+
+/// Anchor floss colors, named by their Anchor number.
+pub fn anchor_palette() -> anyhow::Result<Vec<PaletteColor>> {
+    let conversions = dmc_to_anchor()?;
+    let mut seen = std::collections::HashSet::new();
+
+    // Several DMC colors share an Anchor equivalent, the first one wins
+    Ok(parse_json(DMC_COLORS)?
+        .into_iter()
+        .filter_map(|color| {
+            let anchor = conversions.get(color.name.as_ref()?)?;
+            seen.insert(anchor.clone()).then(|| PaletteColor {
+                name: Some(anchor.clone()),
+                rgb: color.rgb,
+            })
+        })
+        .collect())
+}
+
+/// Miyuki Delica 11/0 bead colors, named by their DB code.
+pub fn delica_palette() -> anyhow::Result<Vec<PaletteColor>> {
+    parse_json(DELICA_COLORS)
+}
+
+/// Maps DMC floss numbers to their closest Anchor floss number.
+pub fn dmc_to_anchor() -> anyhow::Result<HashMap<String, String>> {
+    #[derive(serde::Deserialize)]
+    struct Conversion {
+        dmc: usize,
+        anchor: usize,
+    }
+
+    let conversions: Vec<Conversion> = serde_json::from_str(ANCHOR_COLORS)?;
+
+    Ok(conversions
+        .into_iter()
+        .map(|Conversion { dmc, anchor }| (dmc.to_string(), anchor.to_string()))
+        .collect())
+}
+
+/// Maps DMC floss numbers to the names of their colors.
+pub fn dmc_names() -> anyhow::Result<HashMap<String, String>> {
+    #[derive(serde::Deserialize)]
+    struct FlossName {
+        floss: usize,
+        name: String,
+    }
+
+    let names: Vec<FlossName> = serde_json::from_str(DMC_NAMES)?;
+
+    Ok(names
+        .into_iter()
+        .map(|FlossName { floss, name }| (floss.to_string(), name))
+        .collect())
+}
+
+fn parse_json(json: &str) -> anyhow::Result<Vec<PaletteColor>> {
+    #[derive(serde::Deserialize)]
+    struct JsonColor {
+        floss: Option<usize>,
+        name: Option<String>,
+        red: u8,
+        green: u8,
+        blue: u8,
+    }
+
+    let colors: Vec<JsonColor> = serde_json::from_str(json)?;
+
+    Ok(colors
+        .into_iter()
+        .map(|color| PaletteColor {
+            name: color
+                .name
+                .or_else(|| color.floss.map(|floss| floss.to_string())),
+            rgb: [color.red, color.green, color.blue],
+        })
+        .collect())
+}
+
+fn to_json(colors: &[PaletteColor]) -> anyhow::Result<String> {
+    #[derive(serde::Serialize)]
+    struct JsonColor<'c> {
+        name: Option<&'c str>,
+        hex: String,
+        red: u8,
+        green: u8,
+        blue: u8,
+    }
+
+    let colors = colors
+        .iter()
+        .map(|color| {
+            let [red, green, blue] = color.rgb;
+            JsonColor {
+                name: color.name.as_deref(),
+                hex: format!("#{red:02x}{green:02x}{blue:02x}"),
+                red,
+                green,
+                blue,
+            }
+        })
+        .collect::<Vec<_>>();
+
+    Ok(serde_json::to_string_pretty(&colors)?)
+}
+
+fn to_gpl(colors: &[PaletteColor]) -> String {
+    let mut gpl = String::from("GIMP Palette\nName: pixelart-gen\n#\n");
+    for color in colors {
+        let [r, g, b] = color.rgb;
+        let name = color.name.as_deref().unwrap_or("Untitled");
+        gpl += &format!("{r:3} {g:3} {b:3}\t{name}\n");
+    }
+
+    gpl
+}
+
+fn to_hex(colors: &[PaletteColor]) -> String {
+    colors
+        .iter()
+        .map(|color| {
+            let [r, g, b] = color.rgb;
+            format!("{r:02x}{g:02x}{b:02x}\n")
+        })
+        .collect()
+}
+
+fn parse_gpl(gpl: &str) -> anyhow::Result<Vec<PaletteColor>> {
+    let mut lines = gpl.lines();
+
+    if lines.next().map(str::trim) != Some("GIMP Palette") {
+        bail!("missing the GIMP Palette header");
+    }
+
+    let mut colors = Vec::default();
+    for line in lines {
+        let line = line.trim();
+        if line.is_empty()
+            || line.starts_with('#')
+            || line.starts_with("Name:")
+            || line.starts_with("Columns:")
+        {
+            continue;
+        }
+
+        let mut parts = line.split_whitespace();
+        let mut channel = || -> anyhow::Result<u8> {
+            parts
+                .next()
+                .context("expected three color channels")?
+                .parse()
+                .with_context(|| format!("invalid color line {line:?}"))
+        };
+        let rgb = [channel()?, channel()?, channel()?];
+        let name = parts.collect::<Vec<_>>().join(" ");
+
+        colors.push(PaletteColor {
+            name: (!name.is_empty() && name != "Untitled").then_some(name),
+            rgb,
+        });
+    }
+
+    Ok(colors)
+}
+
+fn to_ase(colors: &[PaletteColor]) -> Vec<u8> {
+    const COLOR_ENTRY: u16 = 0x0001;
+    // Neither global nor spot
+    const NORMAL_COLOR: u16 = 2;
+
+    let mut ase = b"ASEF".to_vec();
+    // Version 1.0
+    ase.extend_from_slice(&[0, 1, 0, 0]);
+    ase.extend_from_slice(&(colors.len() as u32).to_be_bytes());
+
+    for color in colors {
+        let [r, g, b] = color.rgb;
+        // Unnamed colors go by their hex code
+        let name = color
+            .name
+            .clone()
+            .unwrap_or_else(|| format!("#{r:02x}{g:02x}{b:02x}"));
+        let name = name.encode_utf16().chain([0]).collect::<Vec<_>>();
+
+        let mut block = (name.len() as u16).to_be_bytes().to_vec();
+        block.extend(name.iter().flat_map(|c| c.to_be_bytes()));
+        block.extend_from_slice(b"RGB ");
+        for channel in color.rgb {
+            block.extend_from_slice(&(channel as f32 / 255.0).to_be_bytes());
+        }
+        block.extend_from_slice(&NORMAL_COLOR.to_be_bytes());
+
+        ase.extend_from_slice(&COLOR_ENTRY.to_be_bytes());
+        ase.extend_from_slice(&(block.len() as u32).to_be_bytes());
+        ase.extend(block);
+    }
+
+    ase
+}
+
+fn parse_ase(bytes: &[u8]) -> anyhow::Result<Vec<PaletteColor>> {
+    struct Reader<'b>(&'b [u8]);
+
+    impl<'b> Reader<'b> {
+        fn take(&mut self, len: usize) -> anyhow::Result<&'b [u8]> {
+            if self.0.len() < len {
+                bail!("unexpected end of file");
+            }
+            let (head, tail) = self.0.split_at(len);
+            self.0 = tail;
+            Ok(head)
+        }
+
+        fn u16(&mut self) -> anyhow::Result<u16> {
+            Ok(u16::from_be_bytes(self.take(2)?.try_into()?))
+        }
+
+        fn u32(&mut self) -> anyhow::Result<u32> {
+            Ok(u32::from_be_bytes(self.take(4)?.try_into()?))
+        }
+
+        fn f32(&mut self) -> anyhow::Result<f32> {
+            Ok(f32::from_be_bytes(self.take(4)?.try_into()?))
+        }
+    }
+
+    const COLOR_ENTRY: u16 = 0x0001;
+
+    let mut reader = Reader(bytes);
+    if reader.take(4)? != b"ASEF" {
+        bail!("missing the ASEF signature");
+    }
+    // Version
+    reader.take(4)?;
+
+    let mut colors = Vec::default();
+    for _ in 0..reader.u32()? {
+        let block_type = reader.u16()?;
+        let block_len = reader.u32()? as usize;
+        let mut block = Reader(reader.take(block_len)?);
+
+        // Group start and end blocks carry nothing we need
+        if block_type != COLOR_ENTRY {
+            continue;
+        }
+
+        let name_len = block.u16()? as usize;
+        let name = (0..name_len)
+            .map(|_| block.u16())
+            .collect::<anyhow::Result<Vec<_>>>()?;
+        let name = String::from_utf16(&name)?
+            .trim_end_matches('\0')
+            .to_string();
+
+        let model = block.take(4)?;
+        let rgb: palette::Srgb<f32> = match model {
+            b"RGB " => palette::Srgb::new(block.f32()?, block.f32()?, block.f32()?),
+            b"Gray" => {
+                let gray = block.f32()?;
+                palette::Srgb::new(gray, gray, gray)
+            }
+            b"CMYK" => {
+                let [c, m, y, k] = [block.f32()?, block.f32()?, block.f32()?, block.f32()?];
+                palette::Srgb::new(
+                    (1.0 - c) * (1.0 - k),
+                    (1.0 - m) * (1.0 - k),
+                    (1.0 - y) * (1.0 - k),
+                )
+            }
+            b"LAB " => palette::Srgb::from_color(palette::Lab::new(
+                block.f32()? * 100.0,
+                block.f32()?,
+                block.f32()?,
+            )),
+            _ => bail!(
+                "unsupported color model {:?}",
+                String::from_utf8_lossy(model)
+            ),
+        };
+        let rgb = rgb.clamp().into_format::<u8>();
+
+        colors.push(PaletteColor {
+            name: (!name.is_empty()).then_some(name),
+            rgb: [rgb.red, rgb.green, rgb.blue],
+        });
+    }
+
+    Ok(colors)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_gpl() {
+        let gpl = "GIMP Palette\nName: test\nColumns: 4\n#\n  0   0   0\t310\n174 207 225 Light Blue\n255 128   1\tUntitled\n";
+
+        assert_eq!(
+            parse_gpl(gpl).unwrap(),
+            [
+                PaletteColor {
+                    name: Some("310".to_string()),
+                    rgb: [0, 0, 0],
+                },
+                PaletteColor {
+                    name: Some("Light Blue".to_string()),
+                    rgb: [174, 207, 225],
+                },
+                PaletteColor {
+                    name: None,
+                    rgb: [255, 128, 1],
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn parses_json() {
+        let json = r#"[
+            {"floss": 310, "red": 0, "green": 0, "blue": 0},
+            {"name": "Ecru", "floss": null, "red": 240, "green": 227, "blue": 205},
+            {"red": 255, "green": 128, "blue": 1}
+        ]"#;
+
+        assert_eq!(
+            parse_json(json).unwrap(),
+            [
+                PaletteColor {
+                    name: Some("310".to_string()),
+                    rgb: [0, 0, 0],
+                },
+                PaletteColor {
+                    name: Some("Ecru".to_string()),
+                    rgb: [240, 227, 205],
+                },
+                PaletteColor {
+                    name: None,
+                    rgb: [255, 128, 1],
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn rejects_invalid_palettes() {
+        assert!(parse_gpl("310 0 0 0\n").is_err());
+        assert!(parse_gpl("GIMP Palette\n0 0\n").is_err());
+        assert!(parse_gpl("GIMP Palette\n0 0 256\n").is_err());
+        assert!(parse_ase(b"ASEF").is_err());
+        // One color entry promised, with a block longer than the file
+        assert!(parse_ase(b"ASEF\0\x01\0\0\0\0\0\x01\0\x01\0\0\0\x40\0\x02").is_err());
+        assert!(parse_json("[{\"red\": 0}]").is_err());
+    }
+
+    #[test]
+    fn loads_the_dmc_palette() {
+        let dmc = load_palette(None).unwrap();
+
+        assert_eq!(dmc.len(), 482);
+        assert!(dmc
+            .iter()
+            .any(|color| color.name.as_deref() == Some("310") && color.rgb == [0, 0, 0]));
+    }
+
+    fn colors() -> Vec<PaletteColor> {
+        vec![
+            PaletteColor {
+                name: Some("310".to_string()),
+                rgb: [0, 0, 0],
+            },
+            PaletteColor {
+                name: Some("Light Blue".to_string()),
+                rgb: [174, 207, 225],
+            },
+            PaletteColor {
+                name: None,
+                rgb: [255, 128, 1],
+            },
+        ]
+    }
+
+    #[test]
+    fn round_trips_json() {
+        assert_eq!(parse_json(&to_json(&colors()).unwrap()).unwrap(), colors());
+    }
+
+    #[test]
+    fn round_trips_gpl() {
+        let gpl = to_gpl(&colors());
+
+        assert!(gpl.contains("255 128   1\tUntitled\n"));
+        assert_eq!(parse_gpl(&gpl).unwrap(), colors());
+    }
+
+    #[test]
+    fn round_trips_ase() {
+        let mut expected = colors();
+        // Unnamed colors come back named by their hex code
+        expected[2].name = Some("#ff8001".to_string());
+
+        assert_eq!(parse_ase(&to_ase(&colors())).unwrap(), expected);
+    }
+
+    #[test]
+    fn writes_hex_lines() {
+        assert_eq!(to_hex(&colors()), "000000\naecfe1\nff8001\n");
+    }
+}
@@ -0,0 +1,142 @@
+use crate::metric::Metric;
+
+/// Nearest neighbour lookup over a fixed set of Lab colors, backed by a k-d tree.
+pub struct PaletteIndex {
+    // Colors along with their index in the original order, laid out so the middle of every
+    // range is the node splitting it
+    nodes: Vec<([f64; 3], usize)>,
+    metric: Metric,
+}
+
+impl PaletteIndex {
+    pub fn new(colors: impl IntoIterator<Item = [f64; 3]>) -> Self {
+        Self::with_metric(colors, Metric::Euclidean)
+    }
+
+    /// Index comparing colors with `metric`. The tree only prunes Euclidean searches, the other
+    /// metrics go through every color.
+    pub fn with_metric(colors: impl IntoIterator<Item = [f64; 3]>, metric: Metric) -> Self {
+        let mut nodes = colors
+            .into_iter()
+            .enumerate()
+            .map(|(idx, color)| (color, idx))
+            .collect::<Vec<_>>();
+        build(&mut nodes, 0);
+
+        PaletteIndex { nodes, metric }
+    }
+
+    /// Index of the color closest to `color`, `None` when the index is empty.
+    pub fn nearest(&self, color: [f64; 3]) -> Option<usize> {
+        if self.metric != Metric::Euclidean {
+            return self
+                .nodes
+                .iter()
+                .min_by(|a, b| {
+                    let a = self.metric.distance(color, a.0);
+                    a.total_cmp(&self.metric.distance(color, b.0))
+                })
+                .map(|(_, idx)| *idx);
+        }
+
+        let mut best = None;
+        search(&self.nodes, 0, color, &mut best);
+
+        best.map(|(_, idx)| idx)
+    }
+}
+
+fn build(nodes: &mut [([f64; 3], usize)], axis: usize) {
+    if nodes.len() <= 1 {
+        return;
+    }
+
+    let mid = nodes.len() / 2;
+    nodes.select_nth_unstable_by(mid, |a, b| a.0[axis].total_cmp(&b.0[axis]));
+
+    let (left, right) = nodes.split_at_mut(mid);
+    build(left, (axis + 1) % 3);
+    build(&mut right[1..], (axis + 1) % 3);
+}
+
+fn search(
+    nodes: &[([f64; 3], usize)],
+    axis: usize,
+    color: [f64; 3],
+    best: &mut Option<(f64, usize)>,
+) {
+    if nodes.is_empty() {
+        return;
+    }
+
+    let mid = nodes.len() / 2;
+    let (node, idx) = nodes[mid];
+    let distance = (0..3).map(|i| (node[i] - color[i]).powi(2)).sum::<f64>();
+    if best.is_none_or(|(best_distance, _)| distance < best_distance) {
+        *best = Some((distance, idx));
+    }
+
+    let offset = color[axis] - node[axis];
+    let (near, far) = if offset < 0.0 {
+        (&nodes[..mid], &nodes[mid + 1..])
+    } else {
+        (&nodes[mid + 1..], &nodes[..mid])
+    };
+
+    search(near, (axis + 1) % 3, color, best);
+    // The far side can only hold a closer color if the splitting plane is closer than the best one
+    if best.is_none_or(|(best_distance, _)| offset * offset < best_distance) {
+        search(far, (axis + 1) % 3, color, best);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use rand::{Rng, SeedableRng};
+
+    use super::*;
+
+    fn random_colors(rng: &mut impl Rng, count: usize) -> Vec<[f64; 3]> {
+        (0..count)
+            .map(|_| {
+                [
+                    rng.gen_range(0.0..100.0),
+                    rng.gen_range(-128.0..128.0),
+                    rng.gen_range(-128.0..128.0),
+                ]
+            })
+            .collect()
+    }
+
+    #[test]
+    fn finds_the_same_colors_as_a_linear_scan() {
+        let mut rng = rand_pcg::Pcg32::seed_from_u64(0);
+
+        for metric in [Metric::Euclidean, Metric::Cie94, Metric::Ciede2000] {
+            for count in [1, 2, 3, 10, 500] {
+                let colors = random_colors(&mut rng, count);
+                let index = PaletteIndex::with_metric(colors.iter().copied(), metric);
+
+                for color in random_colors(&mut rng, 200) {
+                    let nearest = index.nearest(color).unwrap();
+                    // Compared by distance, as equally close colors may be found in any order
+                    let best = colors
+                        .iter()
+                        .map(|other| metric.distance(color, *other))
+                        .fold(f64::INFINITY, f64::min);
+                    assert_eq!(
+                        metric.distance(color, colors[nearest]),
+                        best,
+                        "{metric:?} found {:?} for {color:?}",
+                        colors[nearest]
+                    );
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn finds_nothing_in_an_empty_index() {
+        assert_eq!(PaletteIndex::new([]).nearest([50.0, 0.0, 0.0]), None);
+    }
+}
@@ -0,0 +1,72 @@
+use std::collections::HashMap;
+
+use ::image::{Rgb, RgbImage};
+use palette::FromColor;
+
+use pixelart_gen::metric::Metric;
+
+pub struct PaletteEntryReport {
+    pub color: Rgb<u8>,
+    // Share of the output pixels using this color
+    pub usage: f64,
+    // Difference with the closest other color of the palette, with the metric of the run
+    pub distinctiveness: f64,
+    // Bits this color contributes to the entropy of the output
+    pub information: f64,
+}
+
+impl PaletteEntryReport {
+    pub fn score(&self) -> f64 {
+        self.usage * self.distinctiveness
+    }
+}
+
+/// Reports every color stitched in `output`, least informative first, telling the colors apart
+/// with `metric`.
+pub fn palette_report(
+    output: &RgbImage,
+    unstitched: &[bool],
+    metric: Metric,
+) -> Vec<PaletteEntryReport> {
+    let mut counts: HashMap<Rgb<u8>, usize> = HashMap::default();
+    for (pixel, _) in output
+        .pixels()
+        .zip(unstitched)
+        .filter(|(_, unstitched)| !**unstitched)
+    {
+        *counts.entry(*pixel).or_insert(0) += 1;
+    }
+
+    let total = counts.values().sum::<usize>() as f64;
+    let labs = counts
+        .keys()
+        .map(|color| {
+            let rgb = palette::Srgb::new(color.0[0], color.0[1], color.0[2]).into_format::<f64>();
+            let lab = palette::Lab::<palette::white_point::D65, f64>::from_color(rgb);
+            (*color, [lab.l, lab.a, lab.b])
+        })
+        .collect::<Vec<_>>();
+
+    let mut report = labs
+        .iter()
+        .map(|(color, lab)| {
+            let usage = counts[color] as f64 / total;
+            let distinctiveness = labs
+                .iter()
+                .filter(|(other, _)| other != color)
+                .map(|(_, other)| metric.distance(*lab, *other))
+                .reduce(f64::min)
+                .unwrap_or(0.0);
+
+            PaletteEntryReport {
+                color: *color,
+                usage,
+                distinctiveness,
+                information: -usage * usage.log2(),
+            }
+        })
+        .collect::<Vec<_>>();
+
+    report.sort_by(|a, b| a.score().total_cmp(&b.score()));
+    report
+}
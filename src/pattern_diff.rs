@@ -0,0 +1,137 @@
+use std::{fs, path::Path};
+
+use image::{Rgb, RgbImage};
+
+use crate::pattern_file::parse_hex;
+
+// Share of white mixed into the unchanged cells of the diff image
+const FADE: f32 = 0.75;
+const HIGHLIGHT: Rgb<u8> = Rgb([230, 25, 75]);
+
+/// The parts of a `pdfgen --grid-map` file a diff needs.
+#[derive(Debug, serde::Deserialize)]
+pub struct GridMap {
+    pub width: u32,
+    pub height: u32,
+    pub colors: Vec<GridColor>,
+    pub cells: Vec<Option<usize>>,
+}
+
+#[derive(Debug, serde::Deserialize)]
+pub struct GridColor {
+    pub symbol: char,
+    pub floss: String,
+    pub color: String,
+}
+
+#[derive(Debug, serde::Serialize)]
+pub struct Change<'a> {
+    // Counting from 1 like the chart
+    pub x: u32,
+    pub y: u32,
+    // Null for unstitched cells
+    pub old: Option<Stitch<'a>>,
+    pub new: Option<Stitch<'a>>,
+}
+
+#[derive(Debug, serde::Serialize)]
+pub struct Stitch<'a> {
+    pub symbol: char,
+    pub floss: &'a str,
+}
+
+impl GridMap {
+    pub fn load(path: &Path) -> anyhow::Result<Self> {
+        let map: GridMap = serde_json::from_slice(&fs::read(path)?).map_err(|err| {
+            anyhow::anyhow!(
+                "{} is not the grid map of a single pattern: {err}",
+                path.display()
+            )
+        })?;
+        if map.cells.len() != (map.width * map.height) as usize
+            || map
+                .cells
+                .iter()
+                .flatten()
+                .any(|idx| *idx >= map.colors.len())
+        {
+            anyhow::bail!(
+                "the cells of {} don't match its size or colors",
+                path.display()
+            );
+        }
+
+        Ok(map)
+    }
+
+    fn stitch(&self, cell: Option<usize>) -> Option<Stitch<'_>> {
+        cell.map(|idx| &self.colors[idx]).map(|color| Stitch {
+            symbol: color.symbol,
+            floss: &color.floss,
+        })
+    }
+}
+
+/// Cells whose floss differs between two versions of a pattern, row by row. The symbols and
+/// legend order may differ between the versions.
+pub fn changes<'a>(old: &'a GridMap, new: &'a GridMap) -> anyhow::Result<Vec<Change<'a>>> {
+    if (old.width, old.height) != (new.width, new.height) {
+        anyhow::bail!(
+            "the patterns are {}x{} and {}x{} stitches, only patterns of the same size can be compared",
+            old.width,
+            old.height,
+            new.width,
+            new.height
+        );
+    }
+
+    Ok(old
+        .cells
+        .iter()
+        .zip(&new.cells)
+        .enumerate()
+        .filter(|(_, (old_cell, new_cell))| {
+            old_cell.map(|idx| &old.colors[idx].floss) != new_cell.map(|idx| &new.colors[idx].floss)
+        })
+        .map(|(idx, (old_cell, new_cell))| Change {
+            x: idx as u32 % old.width + 1,
+            y: idx as u32 / old.width + 1,
+            old: old.stitch(*old_cell),
+            new: new.stitch(*new_cell),
+        })
+        .collect())
+}
+
+/// The new pattern with every cell a square of `scale` pixels, unchanged cells faded and changed
+/// ones outlined.
+pub fn diff_image(map: &GridMap, changes: &[Change], scale: u32) -> anyhow::Result<RgbImage> {
+    let colors = map
+        .colors
+        .iter()
+        .map(|color| parse_hex(&color.color).map(Rgb))
+        .collect::<anyhow::Result<Vec<_>>>()?;
+    let white = Rgb([255, 255, 255]);
+    let fade = |Rgb(rgb): Rgb<u8>| Rgb(rgb.map(|c| (c as f32 + (255.0 - c as f32) * FADE) as u8));
+
+    let mut changed = vec![false; map.cells.len()];
+    for change in changes {
+        changed[((change.y - 1) * map.width + change.x - 1) as usize] = true;
+    }
+
+    Ok(RgbImage::from_fn(
+        map.width * scale,
+        map.height * scale,
+        |x, y| {
+            let idx = ((y / scale) * map.width + x / scale) as usize;
+            let color = map.cells[idx].map_or(white, |color| colors[color]);
+            let (x, y) = (x % scale, y % scale);
+            let edge = x == 0 || y == 0 || x == scale - 1 || y == scale - 1;
+
+            match (changed[idx], edge) {
+                (true, true) => HIGHLIGHT,
+                (true, false) => color,
+                (false, _) => fade(color),
+            }
+        },
+    ))
+}
@@ -0,0 +1,325 @@
+use std::{collections::HashMap, fs, path::Path};
+
+use anyhow::{bail, Context};
+use glam::UVec2;
+
+// Version of the project files written by `save_project`
+const PROJECT_VERSION: u32 = 1;
+
+/// A chart made in other charting software, with the flosses and symbols its author picked.
+#[derive(Debug, Clone)]
+pub struct Pattern {
+    pub size: UVec2,
+    pub colors: Vec<PatternColor>,
+    // Index into `colors` of every cell in row-major order, None for unstitched cells
+    pub cells: Vec<Option<usize>>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PatternColor {
+    // Floss number without the brand, or the name of the color
+    pub floss: String,
+    pub rgb: [u8; 3],
+    pub symbol: Option<char>,
+}
+
+/// Stitches of a pattern as pixelart-gen made them, with the threads they were snapped to and the
+/// settings of the run, so pdfgen charts them without matching the colors of an image again.
+#[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
+pub struct Project {
+    pub version: u32,
+    pub width: u32,
+    pub height: u32,
+    // Most used first
+    pub colors: Vec<ProjectColor>,
+    // Index into `colors` of every cell in row-major order, null for unstitched cells
+    pub cells: Vec<Option<usize>>,
+    // Arguments of the run
+    pub settings: serde_json::Value,
+}
+
+#[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
+pub struct ProjectColor {
+    pub rgb: [u8; 3],
+    // Thread the color was snapped to, none with --no-thread-snap
+    pub floss: Option<String>,
+    pub stitches: usize,
+}
+
+impl Project {
+    pub fn new(
+        size: UVec2,
+        colors: Vec<ProjectColor>,
+        cells: Vec<Option<usize>>,
+        settings: serde_json::Value,
+    ) -> Self {
+        Project {
+            version: PROJECT_VERSION,
+            width: size.x,
+            height: size.y,
+            colors,
+            cells,
+            settings,
+        }
+    }
+}
+
+/// Writes `project` to `path` as JSON, to be read back with `load_pattern`.
+pub fn save_project(path: &Path, project: &Project) -> anyhow::Result<()> {
+    fs::write(path, serde_json::to_string(project)?)
+        .with_context(|| format!("failed to save project {}", path.display()))
+}
+
+/// Loads the chart at `path` when it is an Open Cross Stitch, CSV or pixelart-gen project file,
+/// None for any other file, which is then an image.
+pub fn load_pattern(path: &Path) -> anyhow::Result<Option<Pattern>> {
+    let extension = path
+        .extension()
+        .and_then(|ext| ext.to_str())
+        .map(|ext| ext.to_ascii_lowercase());
+
+    let pattern = match extension.as_deref() {
+        Some("oxs") => parse_oxs(&fs::read_to_string(path)?),
+        Some("csv") => parse_csv(&fs::read_to_string(path)?),
+        Some("pxproj") => parse_project(&fs::read_to_string(path)?),
+        _ => return Ok(None),
+    }
+    .with_context(|| format!("failed to load pattern {}", path.display()))?;
+
+    if pattern.cells.iter().all(Option::is_none) {
+        bail!("pattern {} has no stitches", path.display());
+    }
+
+    Ok(Some(pattern))
+}
+
+// Only the full stitches of the chart are read, part stitches, backstitches and beads are left
+// out. Palette entry 0 is the bare fabric.
+fn parse_oxs(xml: &str) -> anyhow::Result<Pattern> {
+    let mut size = None;
+    let mut colors = vec![];
+    let mut palette = HashMap::new();
+    let mut stitches = vec![];
+    let mut in_full_stitches = false;
+
+    for (name, attributes) in xml_tags(xml) {
+        let attribute = |key: &str| {
+            attributes
+                .get(key)
+                .map(String::as_str)
+                .with_context(|| format!("<{name}> has no {key} attribute"))
+        };
+        let number = |key: &str| -> anyhow::Result<u32> {
+            let value = attribute(key)?;
+            value
+                .trim()
+                .parse()
+                .with_context(|| format!("invalid {key} {value:?}"))
+        };
+
+        match name {
+            "properties" => size = Some(UVec2::new(number("chartwidth")?, number("chartheight")?)),
+            "palette_item" if number("index")? > 0 => {
+                let floss = attribute("number")?;
+                // Charting software prefixes the floss number with the brand
+                let floss = ["DMC", "Anchor"]
+                    .iter()
+                    .find_map(|brand| floss.strip_prefix(brand))
+                    .unwrap_or(floss)
+                    .trim();
+                let floss = if floss.is_empty() {
+                    attribute("name")?.trim()
+                } else {
+                    floss
+                };
+
+                palette.insert(number("index")?, colors.len());
+                colors.push(PatternColor {
+                    floss: floss.to_string(),
+                    rgb: parse_hex(attribute("color")?)?,
+                    symbol: single_char(attributes.get("symbol").map_or("", String::as_str)),
+                });
+            }
+            "fullstitches" => in_full_stitches = true,
+            "/fullstitches" => in_full_stitches = false,
+            "stitch" if in_full_stitches => {
+                stitches.push((UVec2::new(number("x")?, number("y")?), number("palindex")?));
+            }
+            _ => {}
+        }
+    }
+
+    let size = size.context("missing the chart <properties>")?;
+    let mut cells = vec![None; (size.x * size.y) as usize];
+    for (cell, index) in stitches {
+        if cell.x >= size.x || cell.y >= size.y {
+            bail!("stitch at {}, {} is outside of the chart", cell.x, cell.y);
+        }
+        // Stitches in the fabric color are left unstitched
+        if index > 0 {
+            let color = *palette
+                .get(&index)
+                .with_context(|| format!("no palette entry {index}"))?;
+            cells[(cell.y * size.x + cell.x) as usize] = Some(color);
+        }
+    }
+
+    Ok(Pattern {
+        size,
+        colors,
+        cells,
+    })
+}
+
+// One stitch per line under a header naming the columns, which can come in any order:
+// x, y, floss, color as a hex code and an optional symbol
+fn parse_csv(csv: &str) -> anyhow::Result<Pattern> {
+    let mut lines = csv.lines().filter(|line| !line.trim().is_empty());
+    let header = lines
+        .next()
+        .context("missing the header")?
+        .split(',')
+        .map(|column| column.trim().to_ascii_lowercase())
+        .collect::<Vec<_>>();
+    let column = |name: &str| header.iter().position(|column| column == name);
+    let [x, y, floss, color] = ["x", "y", "floss", "color"].map(column);
+    let (Some(x), Some(y), Some(floss), Some(color)) = (x, y, floss, color) else {
+        bail!("the header needs x, y, floss and color columns");
+    };
+    let symbol = column("symbol");
+
+    let mut colors: Vec<PatternColor> = vec![];
+    let mut stitches = vec![];
+    for line in lines {
+        let fields = line.split(',').map(str::trim).collect::<Vec<_>>();
+        let field = |idx: usize| {
+            fields
+                .get(idx)
+                .copied()
+                .with_context(|| format!("missing a column in {line:?}"))
+        };
+        let coordinate = |idx: usize| -> anyhow::Result<u32> {
+            field(idx)?
+                .parse()
+                .with_context(|| format!("invalid coordinate in {line:?}"))
+        };
+
+        let entry = PatternColor {
+            floss: field(floss)?.to_string(),
+            rgb: parse_hex(field(color)?)?,
+            symbol: symbol.and_then(|idx| single_char(fields.get(idx).copied().unwrap_or(""))),
+        };
+        let idx = match colors.iter().position(|color| color.floss == entry.floss) {
+            Some(idx) => idx,
+            None => {
+                colors.push(entry);
+                colors.len() - 1
+            }
+        };
+        stitches.push((UVec2::new(coordinate(x)?, coordinate(y)?), idx));
+    }
+
+    let size = stitches
+        .iter()
+        .fold(UVec2::ZERO, |size, (cell, _)| size.max(*cell + 1));
+    let mut cells = vec![None; (size.x * size.y) as usize];
+    for (cell, idx) in stitches {
+        cells[(cell.y * size.x + cell.x) as usize] = Some(idx);
+    }
+
+    Ok(Pattern {
+        size,
+        colors,
+        cells,
+    })
+}
+
+// Colors that weren't snapped to a thread go by their hex code
+fn parse_project(json: &str) -> anyhow::Result<Pattern> {
+    let project: Project = serde_json::from_str(json)?;
+    if project.version > PROJECT_VERSION {
+        bail!(
+            "project version {} is newer than this version of pdfgen",
+            project.version
+        );
+    }
+    if project.cells.len() != (project.width * project.height) as usize {
+        bail!("the cells don't match the size of the project");
+    }
+    if project
+        .cells
+        .iter()
+        .flatten()
+        .any(|idx| *idx >= project.colors.len())
+    {
+        bail!("a cell has no color");
+    }
+
+    Ok(Pattern {
+        size: UVec2::new(project.width, project.height),
+        colors: project
+            .colors
+            .into_iter()
+            .map(|color| {
+                let [r, g, b] = color.rgb;
+                PatternColor {
+                    floss: color
+                        .floss
+                        .unwrap_or_else(|| format!("#{r:02x}{g:02x}{b:02x}")),
+                    rgb: color.rgb,
+                    symbol: None,
+                }
+            })
+            .collect(),
+        cells: project.cells,
+    })
+}
+
+/// Parses colors written as `#rrggbb`, with or without the `#`.
+pub fn parse_hex(hex: &str) -> anyhow::Result<[u8; 3]> {
+    let digits = hex.trim().trim_start_matches('#');
+    let value = u32::from_str_radix(digits, 16)
+        .ok()
+        .filter(|_| digits.len() == 6)
+        .with_context(|| format!("invalid color {hex:?}"))?;
+
+    Ok([(value >> 16) as u8, (value >> 8) as u8, value as u8])
+}
+
+// Symbols are kept when they are a single character, some software writes a glyph index of its
+// own font instead
+fn single_char(text: &str) -> Option<char> {
+    let mut chars = text.chars();
+    chars.next().filter(|_| chars.next().is_none())
+}
+
+// Name and attributes of every tag, closing tags named with their slash. Enough for the flat
+// markup of charts, which has no text content to speak of
+pub(crate) fn xml_tags(xml: &str) -> impl Iterator<Item = (&str, HashMap<String, String>)> {
+    xml.split('<').skip(1).filter_map(|tag| {
+        let tag = tag.split_once('>')?.0.trim_end_matches('/').trim();
+        if tag.starts_with('?') || tag.starts_with('!') {
+            return None;
+        }
+
+        let (name, mut rest) = tag.split_once(char::is_whitespace).unwrap_or((tag, ""));
+        let mut attributes = HashMap::new();
+        while let Some((key, value)) = rest.split_once('=') {
+            let value = value.trim_start();
+            let quote = value.chars().next()?;
+            let (value, remainder) = value[1..].split_once(quote)?;
+            attributes.insert(key.trim().to_string(), xml_unescape(value));
+            rest = remainder;
+        }
+
+        Some((name, attributes))
+    })
+}
+
+fn xml_unescape(text: &str) -> String {
+    text.replace("&lt;", "<")
+        .replace("&gt;", ">")
+        .replace("&quot;", "\"")
+        .replace("&apos;", "'")
+        .replace("&amp;", "&")
+}
@@ -0,0 +1,119 @@
+use std::fmt;
+
+use glam::UVec2;
+
+// Stitches per inch of the Aida the patterns are made for
+pub const DEFAULT_FABRIC_COUNT: u32 = 16;
+
+// Common pre-cut fabric sizes in inches
+const FABRIC_CUTS: [(&str, UVec2); 5] = [
+    ("12\" x 18\"", UVec2 { x: 12, y: 18 }),
+    ("15\" x 18\"", UVec2 { x: 15, y: 18 }),
+    ("18\" x 24\"", UVec2 { x: 18, y: 24 }),
+    ("24\" x 30\"", UVec2 { x: 24, y: 30 }),
+    ("36\" x 43\"", UVec2 { x: 36, y: 43 }),
+];
+
+// Fabric left unstitched on every side for framing
+const CUT_MARGIN: u32 = 2;
+
+#[derive(Debug)]
+pub struct OversizedPattern {
+    pub size: UVec2,
+    pub limit: UVec2,
+    // Largest size fitting a common fabric cut, along with the cut
+    pub suggestion: Option<(UVec2, &'static str)>,
+    pub fabric_count: u32,
+}
+
+impl fmt::Display for OversizedPattern {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "the pattern is {}x{} stitches which is above the limit of {}x{}, pass --force to generate it anyway",
+            self.size.x, self.size.y, self.limit.x, self.limit.y
+        )?;
+
+        if let Some((size, cut)) = self.suggestion {
+            write!(
+                f,
+                "\nthe largest size fitting a {cut} cut of {} count fabric is {}x{} stitches",
+                self.fabric_count, size.x, size.y
+            )?;
+        }
+
+        Ok(())
+    }
+}
+
+impl std::error::Error for OversizedPattern {}
+
+/// Errors when `size` is above `limit`, suggesting a size that fits both the limit and a common fabric cut.
+pub fn check_pattern_size(
+    size: UVec2,
+    limit: UVec2,
+    fabric_count: u32,
+) -> Result<(), OversizedPattern> {
+    if size.cmple(limit).all() {
+        return Ok(());
+    }
+
+    let suggestion = FABRIC_CUTS
+        .iter()
+        .map(|(name, cut)| {
+            // Turn the cut the same way as the pattern
+            let cut = if size.x > size.y {
+                UVec2::new(cut.y, cut.x)
+            } else {
+                *cut
+            };
+            let available = ((cut - CUT_MARGIN * 2) * fabric_count).min(limit);
+            let scale = (available.as_dvec2() / size.as_dvec2()).min_element();
+
+            ((size.as_dvec2() * scale).floor().as_uvec2(), *name)
+        })
+        .filter(|(size, _)| size.cmpgt(UVec2::ZERO).all())
+        .max_by_key(|(size, _)| size.x * size.y);
+
+    Err(OversizedPattern {
+        size,
+        limit,
+        suggestion,
+        fabric_count,
+    })
+}
+
+/// Parses sizes written as `WxH`.
+pub fn parse_size(s: &str) -> Result<UVec2, String> {
+    let (x, y) = s
+        .split_once(['x', 'X'])
+        .ok_or_else(|| format!("expected a size like 500x500, got {s:?}"))?;
+
+    Ok(UVec2 {
+        x: x.trim()
+            .parse()
+            .map_err(|err| format!("invalid width: {err}"))?,
+        y: y.trim()
+            .parse()
+            .map_err(|err| format!("invalid height: {err}"))?,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_sizes() {
+        assert_eq!(parse_size("500x500"), Ok(UVec2::new(500, 500)));
+        assert_eq!(parse_size("120X80"), Ok(UVec2::new(120, 80)));
+        assert_eq!(parse_size(" 64 x 48 "), Ok(UVec2::new(64, 48)));
+    }
+
+    #[test]
+    fn rejects_invalid_sizes() {
+        for invalid in ["", "500", "500x", "x500", "-1x5", "5x5x5", "ax5", "500*500"] {
+            assert!(parse_size(invalid).is_err(), "{invalid:?} parsed");
+        }
+    }
+}
@@ -0,0 +1,96 @@
+use glam::{IVec2, UVec2};
+use image::{ImageBuffer, Pixel, RgbImage};
+
+/// Cells of pixel art upscaled with nearest neighbour, each of them being one pixel of the art.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PixelGrid {
+    // Pixels per cell
+    pub scale: UVec2,
+    // Where the first whole cell starts, the cells before it are cut off
+    pub offset: UVec2,
+    // Size of the upscaled image
+    size: UVec2,
+}
+
+impl PixelGrid {
+    /// Finds the cells from where neighbouring pixels differ. Only works on art saved losslessly
+    /// and upscaled by a whole factor, anything else comes out as a grid of single pixels.
+    pub fn detect(img: &RgbImage) -> Self {
+        let size = UVec2::new(img.width(), img.height());
+        // Columns and rows where some pixel differs from the one before it
+        let columns = (1..size.x)
+            .filter(|&x| (0..size.y).any(|y| img.get_pixel(x, y) != img.get_pixel(x - 1, y)));
+        let rows = (1..size.y)
+            .filter(|&y| (0..size.x).any(|x| img.get_pixel(x, y) != img.get_pixel(x, y - 1)));
+        let (scale_x, offset_x) = grid_axis(columns, size.x);
+        let (scale_y, offset_y) = grid_axis(rows, size.y);
+
+        PixelGrid {
+            scale: UVec2::new(scale_x, scale_y),
+            offset: UVec2::new(offset_x, offset_y),
+            size,
+        }
+    }
+
+    /// Cells along each side, counting the ones cut off at the edges.
+    pub fn cells(&self) -> UVec2 {
+        let scale = self.scale.as_ivec2();
+        ((self.size.as_ivec2() - self.start() + scale - 1) / scale).as_uvec2()
+    }
+
+    /// One pixel per cell, from the middle of the part of the cell inside the image.
+    pub fn sample<P: Pixel>(
+        &self,
+        img: &ImageBuffer<P, Vec<P::Subpixel>>,
+    ) -> ImageBuffer<P, Vec<P::Subpixel>> {
+        let cells = self.cells();
+        let scale = self.scale.as_ivec2();
+
+        ImageBuffer::from_fn(cells.x, cells.y, |x, y| {
+            let cell = IVec2::new(x as i32, y as i32);
+            let first = (self.start() + cell * scale).max(IVec2::ZERO);
+            let last = (self.start() + (cell + 1) * scale).min(self.size.as_ivec2());
+            let at = ((first + last) / 2).as_uvec2();
+            *img.get_pixel(at.x, at.y)
+        })
+    }
+
+    // Top left of the first cell, which is partly outside of the image when there is an offset
+    fn start(&self) -> IVec2 {
+        IVec2::select(
+            self.offset.cmpgt(UVec2::ZERO),
+            self.offset.as_ivec2() - self.scale.as_ivec2(),
+            IVec2::ZERO,
+        )
+    }
+}
+
+// Scale and offset of the cells along a side of `size` pixels from the pixels where they change
+fn grid_axis(boundaries: impl Iterator<Item = u32>, size: u32) -> (u32, u32) {
+    let mut first = None;
+    let mut scale = 0;
+    let mut prev = 0;
+    for boundary in boundaries {
+        if first.is_some() {
+            scale = gcd(scale, boundary - prev);
+        }
+        first.get_or_insert(boundary);
+        prev = boundary;
+    }
+
+    match first {
+        // A single change, both sides of it have to be whole cells
+        Some(first) if scale == 0 => (gcd(first, size - first), 0),
+        Some(first) => (scale, first % scale),
+        // One color all the way
+        None => (size, 0),
+    }
+}
+
+fn gcd(a: u32, b: u32) -> u32 {
+    if b == 0 {
+        a
+    } else {
+        gcd(b, a % b)
+    }
+}
@@ -0,0 +1,158 @@
+use glam::UVec2;
+
+use crate::palette_file::PaletteColor;
+
+// The 2C02 PPU palette with its repeated blacks left out
+const NES: [[u8; 3]; 55] = [
+    [0x00, 0x00, 0x00],
+    [0x7c, 0x7c, 0x7c],
+    [0x00, 0x00, 0xfc],
+    [0x00, 0x00, 0xbc],
+    [0x44, 0x28, 0xbc],
+    [0x94, 0x00, 0x84],
+    [0xa8, 0x00, 0x20],
+    [0xa8, 0x10, 0x00],
+    [0x88, 0x14, 0x00],
+    [0x50, 0x30, 0x00],
+    [0x00, 0x78, 0x00],
+    [0x00, 0x68, 0x00],
+    [0x00, 0x58, 0x00],
+    [0x00, 0x40, 0x58],
+    [0xbc, 0xbc, 0xbc],
+    [0x00, 0x78, 0xf8],
+    [0x00, 0x58, 0xf8],
+    [0x68, 0x44, 0xfc],
+    [0xd8, 0x00, 0xcc],
+    [0xe4, 0x00, 0x58],
+    [0xf8, 0x38, 0x00],
+    [0xe4, 0x5c, 0x10],
+    [0xac, 0x7c, 0x00],
+    [0x00, 0xb8, 0x00],
+    [0x00, 0xa8, 0x00],
+    [0x00, 0xa8, 0x44],
+    [0x00, 0x88, 0x88],
+    [0xf8, 0xf8, 0xf8],
+    [0x3c, 0xbc, 0xfc],
+    [0x68, 0x88, 0xfc],
+    [0x98, 0x78, 0xf8],
+    [0xf8, 0x78, 0xf8],
+    [0xf8, 0x58, 0x98],
+    [0xf8, 0x78, 0x58],
+    [0xfc, 0xa0, 0x44],
+    [0xf8, 0xb8, 0x00],
+    [0xb8, 0xf8, 0x18],
+    [0x58, 0xd8, 0x54],
+    [0x58, 0xf8, 0x98],
+    [0x00, 0xe8, 0xd8],
+    [0x78, 0x78, 0x78],
+    [0xfc, 0xfc, 0xfc],
+    [0xa4, 0xe4, 0xfc],
+    [0xb8, 0xb8, 0xf8],
+    [0xd8, 0xb8, 0xf8],
+    [0xf8, 0xb8, 0xf8],
+    [0xf8, 0xa4, 0xc0],
+    [0xf0, 0xd0, 0xb0],
+    [0xfc, 0xe0, 0xa8],
+    [0xf8, 0xd8, 0x78],
+    [0xd8, 0xf8, 0x78],
+    [0xb8, 0xf8, 0xb8],
+    [0xb8, 0xf8, 0xd8],
+    [0x00, 0xfc, 0xfc],
+    [0xf8, 0xd8, 0xf8],
+];
+
+// The four shades of green of the original Game Boy screen
+const GAMEBOY: [[u8; 3]; 4] = [
+    [0x0f, 0x38, 0x0f],
+    [0x30, 0x62, 0x30],
+    [0x8b, 0xac, 0x0f],
+    [0x9b, 0xbc, 0x0f],
+];
+
+const PICO8: [[u8; 3]; 16] = [
+    [0x00, 0x00, 0x00],
+    [0x1d, 0x2b, 0x53],
+    [0x7e, 0x25, 0x53],
+    [0x00, 0x87, 0x51],
+    [0xab, 0x52, 0x36],
+    [0x5f, 0x57, 0x4f],
+    [0xc2, 0xc3, 0xc7],
+    [0xff, 0xf1, 0xe8],
+    [0xff, 0x00, 0x4d],
+    [0xff, 0xa3, 0x00],
+    [0xff, 0xec, 0x27],
+    [0x00, 0xe4, 0x36],
+    [0x29, 0xad, 0xff],
+    [0x83, 0x76, 0x9c],
+    [0xff, 0x77, 0xa8],
+    [0xff, 0xcc, 0xaa],
+];
+
+// Pepto's measurements of the VIC-II colors
+const C64: [[u8; 3]; 16] = [
+    [0x00, 0x00, 0x00],
+    [0xff, 0xff, 0xff],
+    [0x68, 0x37, 0x2b],
+    [0x70, 0xa4, 0xb2],
+    [0x6f, 0x3d, 0x86],
+    [0x58, 0x8d, 0x43],
+    [0x35, 0x28, 0x79],
+    [0xb8, 0xc7, 0x6f],
+    [0x6f, 0x4f, 0x25],
+    [0x43, 0x39, 0x00],
+    [0x9a, 0x67, 0x59],
+    [0x44, 0x44, 0x44],
+    [0x6c, 0x6c, 0x6c],
+    [0x9a, 0xd2, 0x84],
+    [0x6c, 0x5e, 0xb5],
+    [0x95, 0x95, 0x95],
+];
+
+// Palette 1 at high intensity of the 320x200 four color mode
+const CGA: [[u8; 3]; 4] = [
+    [0x00, 0x00, 0x00],
+    [0x55, 0xff, 0xff],
+    [0xff, 0x55, 0xff],
+    [0xff, 0xff, 0xff],
+];
+
+/// Palette and screen of a retro console or computer.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, clap::ValueEnum, serde::Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Preset {
+    Nes,
+    Gameboy,
+    Pico8,
+    C64,
+    Cga,
+}
+
+impl Preset {
+    pub fn colors(self) -> Vec<PaletteColor> {
+        let colors: &[[u8; 3]] = match self {
+            Preset::Nes => &NES,
+            Preset::Gameboy => &GAMEBOY,
+            Preset::Pico8 => &PICO8,
+            Preset::C64 => &C64,
+            Preset::Cga => &CGA,
+        };
+
+        colors
+            .iter()
+            .map(|rgb| PaletteColor {
+                name: None,
+                rgb: *rgb,
+            })
+            .collect()
+    }
+
+    /// Resolution of the screen, in pixels.
+    pub fn screen_size(self) -> UVec2 {
+        match self {
+            Preset::Nes => UVec2::new(256, 240),
+            Preset::Gameboy => UVec2::new(160, 144),
+            Preset::Pico8 => UVec2::new(128, 128),
+            Preset::C64 | Preset::Cga => UVec2::new(320, 200),
+        }
+    }
+}
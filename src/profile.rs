@@ -0,0 +1,172 @@
+use std::{
+    alloc::{GlobalAlloc, Layout, System},
+    collections::BTreeMap,
+    fs,
+    io::{self, BufWriter, Write},
+    path::Path,
+    sync::atomic::{AtomicBool, AtomicUsize, Ordering},
+    time::{Duration, Instant},
+};
+
+static TRACKING: AtomicBool = AtomicBool::new(false);
+static ALLOCATED: AtomicUsize = AtomicUsize::new(0);
+static PEAK: AtomicUsize = AtomicUsize::new(0);
+
+/// Makes `TrackingAllocator` keep track of the heap from now on, it only passes the allocations
+/// on to the system allocator before. The memory allocated before isn't counted.
+pub fn track_allocations() {
+    TRACKING.store(true, Ordering::Relaxed);
+}
+
+fn grow(size: usize) {
+    let allocated = ALLOCATED.fetch_add(size, Ordering::Relaxed) + size;
+    PEAK.fetch_max(allocated, Ordering::Relaxed);
+}
+
+fn shrink(size: usize) {
+    // Freeing memory allocated before the tracking started doesn't take the heap below zero
+    let _ = ALLOCATED.fetch_update(Ordering::Relaxed, Ordering::Relaxed, |allocated| {
+        Some(allocated.saturating_sub(size))
+    });
+}
+
+/// System allocator keeping track of the heap size and its peak once `track_allocations` is
+/// called.
+pub struct TrackingAllocator;
+
+unsafe impl GlobalAlloc for TrackingAllocator {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        let ptr = System.alloc(layout);
+        if !ptr.is_null() && TRACKING.load(Ordering::Relaxed) {
+            grow(layout.size());
+        }
+        ptr
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        System.dealloc(ptr, layout);
+        if TRACKING.load(Ordering::Relaxed) {
+            shrink(layout.size());
+        }
+    }
+
+    unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+        let new_ptr = System.realloc(ptr, layout, new_size);
+        if !new_ptr.is_null() && TRACKING.load(Ordering::Relaxed) {
+            if new_size >= layout.size() {
+                grow(new_size - layout.size());
+            } else {
+                shrink(layout.size() - new_size);
+            }
+        }
+        new_ptr
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Stage {
+    Assignment,
+    Smoothing,
+    Association,
+    Refinement,
+    Snapping,
+    Saving,
+}
+
+impl Stage {
+    fn name(self) -> &'static str {
+        match self {
+            Stage::Assignment => "assignment",
+            Stage::Smoothing => "smoothing",
+            Stage::Association => "association",
+            Stage::Refinement => "refinement",
+            Stage::Snapping => "snapping",
+            Stage::Saving => "saving",
+        }
+    }
+}
+
+struct StageRecord {
+    stage: Stage,
+    duration: Duration,
+    // Largest the heap got during the stage
+    peak_bytes: usize,
+}
+
+/// Times the stages of every iteration along with how much memory they peak at, does nothing
+/// unless enabled.
+pub struct Profiler {
+    enabled: bool,
+    records: Vec<StageRecord>,
+}
+
+impl Profiler {
+    pub fn new(enabled: bool) -> Self {
+        Profiler {
+            enabled,
+            records: Vec::default(),
+        }
+    }
+
+    pub fn stage<R>(&mut self, stage: Stage, f: impl FnOnce() -> R) -> R {
+        if !self.enabled {
+            return f();
+        }
+
+        PEAK.store(ALLOCATED.load(Ordering::Relaxed), Ordering::Relaxed);
+        let start = Instant::now();
+        let result = f();
+
+        self.records.push(StageRecord {
+            stage,
+            duration: start.elapsed(),
+            peak_bytes: PEAK.load(Ordering::Relaxed),
+        });
+        result
+    }
+
+    /// Writes the time spent in each stage as folded stacks in microseconds, one line per stage
+    /// of every iteration in order. Flamegraph tools add the lines of a stage up.
+    pub fn write_folded(&self, path: &Path) -> io::Result<()> {
+        let mut out = BufWriter::new(fs::File::create(path)?);
+        for record in &self.records {
+            writeln!(
+                out,
+                "pixelart-gen;{} {}",
+                record.stage.name(),
+                record.duration.as_micros()
+            )?;
+        }
+
+        out.flush()
+    }
+
+    /// One line per stage with its total and slowest time and the peak memory over all iterations.
+    pub fn summary(&self) -> Vec<String> {
+        let mut stages: BTreeMap<Stage, (Duration, Duration, usize, usize)> = BTreeMap::new();
+        for record in &self.records {
+            let (total, slowest, peak_bytes, count) =
+                stages
+                    .entry(record.stage)
+                    .or_insert((Duration::ZERO, Duration::ZERO, 0, 0));
+            *total += record.duration;
+            *slowest = (*slowest).max(record.duration);
+            *peak_bytes = (*peak_bytes).max(record.peak_bytes);
+            *count += 1;
+        }
+
+        stages
+            .into_iter()
+            .map(|(stage, (total, slowest, peak_bytes, count))| {
+                format!(
+                    "{:<12} total: {:>10.3?}, mean: {:>10.3?}, slowest: {:>10.3?}, peak memory: {:>8.1} MiB",
+                    stage.name(),
+                    total,
+                    total / count as u32,
+                    slowest,
+                    peak_bytes as f64 / (1024.0 * 1024.0)
+                )
+            })
+            .collect()
+    }
+}
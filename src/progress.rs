@@ -0,0 +1,169 @@
+use std::{
+    fs,
+    io::{self, BufWriter, Write},
+    path::Path,
+    time::Instant,
+};
+
+use indicatif::{ProgressBar, ProgressStyle};
+
+use pixelart_gen::solver::IterationReport;
+
+// Resolution of the progress bar
+const STEPS: u64 = 1000;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, clap::ValueEnum, serde::Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ProgressFormat {
+    // Human readable progress bar
+    Bar,
+    // One JSON object per iteration
+    Json,
+}
+
+pub enum Progress {
+    Bar(ProgressBar),
+    Json { start: Instant, out: Box<dyn Write> },
+    Quiet,
+}
+
+impl Progress {
+    /// JSON progress goes to `output` when given, stderr otherwise.
+    pub fn new(quiet: bool, format: ProgressFormat, output: Option<&Path>) -> io::Result<Self> {
+        if quiet {
+            return Ok(Progress::Quiet);
+        }
+
+        Ok(match format {
+            ProgressFormat::Bar => {
+                let bar = ProgressBar::new(STEPS);
+                bar.set_style(
+                    ProgressStyle::with_template(
+                        "[{elapsed_precise}] {wide_bar} {percent:>3}% ETA {eta_precise} {msg}",
+                    )
+                    .unwrap(),
+                );
+                Progress::Bar(bar)
+            }
+            ProgressFormat::Json => Progress::Json {
+                start: Instant::now(),
+                out: match output {
+                    Some(path) => Box::new(fs::File::create(path)?),
+                    None => Box::new(io::stderr()),
+                },
+            },
+        })
+    }
+
+    pub fn update(&mut self, report: IterationReport) -> io::Result<()> {
+        let fraction = report.fraction.clamp(0.0, 1.0);
+
+        match self {
+            Progress::Bar(bar) => {
+                bar.set_position((fraction * STEPS as f64) as u64);
+                bar.set_message(format!(
+                    "k: {}, t: {:.3}, change: {:.3}, iteration: {}",
+                    report.k, report.t, report.total_change, report.iteration
+                ));
+            }
+            Progress::Json { start, out } => {
+                let elapsed = start.elapsed();
+                let eta = (fraction > 0.0)
+                    .then(|| elapsed.mul_f64((1.0 - fraction) / fraction))
+                    .map(|eta| eta.as_secs_f64());
+                let palette = report
+                    .palette
+                    .iter()
+                    .map(|(color, probability)| {
+                        let [r, g, b] = color.to_srgb();
+                        serde_json::json!({
+                            "color": format!("#{r:02x}{g:02x}{b:02x}"),
+                            "lab": color.to_array(),
+                            "probability": probability,
+                        })
+                    })
+                    .collect::<Vec<_>>();
+
+                writeln!(
+                    out,
+                    "{}",
+                    serde_json::json!({
+                        "iteration": report.iteration,
+                        "t": report.t,
+                        "k": report.k,
+                        "total_change": report.total_change,
+                        "progress": fraction,
+                        "palette": palette,
+                        "elapsed_secs": elapsed.as_secs_f64(),
+                        "eta_secs": eta,
+                    })
+                )?;
+                out.flush()?;
+            }
+            Progress::Quiet => {}
+        }
+
+        Ok(())
+    }
+
+    /// Prints a message without breaking the progress output.
+    pub fn println(&self, msg: impl AsRef<str>) {
+        match self {
+            Progress::Bar(bar) => bar.println(msg),
+            Progress::Json { .. } => eprintln!("{}", msg.as_ref()),
+            Progress::Quiet => {}
+        }
+    }
+
+    /// Hides the progress bar while `f` uses the terminal.
+    pub fn suspend<R>(&self, f: impl FnOnce() -> R) -> R {
+        match self {
+            Progress::Bar(bar) => bar.suspend(f),
+            _ => f(),
+        }
+    }
+
+    pub fn finish(&self) {
+        if let Progress::Bar(bar) = self {
+            bar.finish_with_message("done");
+        }
+    }
+}
+
+/// Metrics of every iteration as CSV, to plot how the run converges.
+pub struct ConvergenceLog {
+    start: Instant,
+    out: BufWriter<fs::File>,
+}
+
+impl ConvergenceLog {
+    pub fn new(path: &Path) -> io::Result<Self> {
+        let mut out = BufWriter::new(fs::File::create(path)?);
+        writeln!(
+            out,
+            "iteration,t,k,total_change,variance,unique_colors,elapsed_secs"
+        )?;
+
+        Ok(ConvergenceLog {
+            start: Instant::now(),
+            out,
+        })
+    }
+
+    pub fn log(&mut self, report: &IterationReport, unique_colors: usize) -> io::Result<()> {
+        writeln!(
+            self.out,
+            "{},{},{},{},{},{unique_colors},{}",
+            report.iteration,
+            report.t,
+            report.k,
+            report.total_change,
+            report.variance,
+            self.start.elapsed().as_secs_f64()
+        )
+    }
+
+    pub fn finish(mut self) -> io::Result<()> {
+        self.out.flush()
+    }
+}
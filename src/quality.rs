@@ -0,0 +1,113 @@
+use ::image::RgbImage;
+use glam::UVec2;
+use rayon::prelude::{
+    IntoParallelIterator, IntoParallelRefIterator, ParallelIterator, ParallelSliceMut,
+};
+
+use pixelart_gen::{color::Color, image::LabImage, metric::Metric};
+
+// Side of the windows SSIM is computed over, and the step between them
+const SSIM_WINDOW: u32 = 8;
+const SSIM_STEP: u32 = 4;
+// Stabilizers of SSIM, relative to the range of the Lab lightness
+const SSIM_K1: f64 = 0.01;
+const SSIM_K2: f64 = 0.03;
+
+/// How close the output, upscaled back to the size of the input, is to the input.
+#[derive(Debug, serde::Serialize)]
+pub struct QualityReport {
+    // CIEDE2000 difference of the stitched pixels
+    pub mean_delta_e: f64,
+    pub median_delta_e: f64,
+    pub p90_delta_e: f64,
+    pub p99_delta_e: f64,
+    pub max_delta_e: f64,
+    // Mean structural similarity of the lightness, over the windows without unstitched pixels.
+    // Null when there are none
+    pub ssim: Option<f64>,
+}
+
+impl QualityReport {
+    pub fn new(input: &LabImage, output: &RgbImage, unstitched: &[bool]) -> Self {
+        let out_size = UVec2::new(output.width(), output.height());
+        // Every input pixel next to the stitch it ends up in, None when it is left unstitched
+        let pairs = (0..input.size.x * input.size.y)
+            .into_par_iter()
+            .map(|idx| {
+                let coord = UVec2::new(idx % input.size.x, idx / input.size.x);
+                let stitch = coord * out_size / input.size;
+                let stitch_idx = (stitch.y * out_size.x + stitch.x) as usize;
+
+                (!unstitched[stitch_idx]).then(|| {
+                    (
+                        input.pixels[idx as usize],
+                        Color::from_srgb(output.get_pixel(stitch.x, stitch.y).0),
+                    )
+                })
+            })
+            .collect::<Vec<_>>();
+
+        let mut delta_es = pairs
+            .par_iter()
+            .filter_map(Option::as_ref)
+            .map(|(input, output)| Metric::Ciede2000.distance(input.to_array(), output.to_array()))
+            .collect::<Vec<_>>();
+        delta_es.par_sort_unstable_by(f64::total_cmp);
+        let percentile = |p: f64| {
+            delta_es
+                .get(((delta_es.len() as f64 - 1.0) * p).round() as usize)
+                .copied()
+                .unwrap_or(0.0)
+        };
+
+        QualityReport {
+            mean_delta_e: delta_es.iter().sum::<f64>() / delta_es.len().max(1) as f64,
+            median_delta_e: percentile(0.5),
+            p90_delta_e: percentile(0.9),
+            p99_delta_e: percentile(0.99),
+            max_delta_e: percentile(1.0),
+            ssim: ssim(&pairs, input.size),
+        }
+    }
+}
+
+// Mean SSIM of the lightness over windows of SSIM_WINDOW pixels
+fn ssim(pairs: &[Option<(Color, Color)>], size: UVec2) -> Option<f64> {
+    let (c1, c2) = ((SSIM_K1 * 100.0).powi(2), (SSIM_K2 * 100.0).powi(2));
+    let windows = |len: u32| (0..len.saturating_sub(SSIM_WINDOW - 1)).step_by(SSIM_STEP as usize);
+    let origins = windows(size.y)
+        .flat_map(|y| windows(size.x).map(move |x| UVec2::new(x, y)))
+        .collect::<Vec<_>>();
+
+    let (sum, count) = origins
+        .into_par_iter()
+        .filter_map(|origin| {
+            let mut lightness = Vec::with_capacity((SSIM_WINDOW * SSIM_WINDOW) as usize);
+            for y in origin.y..origin.y + SSIM_WINDOW {
+                for x in origin.x..origin.x + SSIM_WINDOW {
+                    let (input, output) = pairs[(y * size.x + x) as usize]?;
+                    lightness.push((input.l(), output.l()));
+                }
+            }
+
+            let n = lightness.len() as f64;
+            let mean_x = lightness.iter().map(|(x, _)| x).sum::<f64>() / n;
+            let mean_y = lightness.iter().map(|(_, y)| y).sum::<f64>() / n;
+            let (var_x, var_y, cov) =
+                lightness
+                    .iter()
+                    .fold((0.0, 0.0, 0.0), |(var_x, var_y, cov), (x, y)| {
+                        let (dx, dy) = (x - mean_x, y - mean_y);
+                        (var_x + dx * dx / n, var_y + dy * dy / n, cov + dx * dy / n)
+                    });
+
+            Some(
+                ((2.0 * mean_x * mean_y + c1) * (2.0 * cov + c2))
+                    / ((mean_x.powi(2) + mean_y.powi(2) + c1) * (var_x + var_y + c2)),
+            )
+        })
+        .fold(|| (0.0, 0), |(sum, count), ssim| (sum + ssim, count + 1))
+        .reduce(|| (0.0, 0), |a, b| (a.0 + b.0, a.1 + b.1));
+
+    (count > 0).then(|| sum / count as f64)
+}
@@ -0,0 +1,102 @@
+use std::path::Path;
+
+use ::image::GrayImage;
+use glam::UVec2;
+
+use crate::{color::Color, solver::SuperPixel};
+
+/// Loads a grayscale mask where every gray level marks a region, scaled to `size`.
+pub fn load_region_mask(path: &Path, size: UVec2) -> anyhow::Result<GrayImage> {
+    let mask = ::image::open(path)?.to_luma8();
+
+    Ok(::image::imageops::resize(
+        &mask,
+        size.x,
+        size.y,
+        ::image::imageops::FilterType::Nearest,
+    ))
+}
+
+/// Parses budgets written as `<mask value>:<color count>`.
+pub fn parse_region_budget(s: &str) -> Result<(u8, usize), String> {
+    let (region, budget) = s
+        .split_once(':')
+        .ok_or_else(|| format!("expected a budget like 255:8, got {s:?}"))?;
+
+    Ok((
+        region
+            .trim()
+            .parse()
+            .map_err(|err| format!("invalid mask value: {err}"))?,
+        budget
+            .trim()
+            .parse()
+            .map_err(|err| format!("invalid color count: {err}"))?,
+    ))
+}
+
+/// Reserves palette entries for the regions with a budget, picking the entries each region
+/// already uses the most. Reserved entries can only be claimed by super pixels in their region.
+pub fn assign_region_budgets(
+    super_pixels: &[SuperPixel],
+    palette: &[(Color, f64)],
+    budgets: &[(u8, usize)],
+) -> Vec<Option<u8>> {
+    let mut owners = vec![None; palette.len()];
+
+    for (region, budget) in budgets {
+        let mut usage = (0..palette.len())
+            .filter(|i| owners[*i].is_none())
+            .map(|i| {
+                let usage = super_pixels
+                    .iter()
+                    .filter(|sp| sp.region == *region)
+                    .map(|sp| sp.conditional_probability[i] * sp.probability)
+                    .sum::<f64>();
+                (i, usage)
+            })
+            .filter(|(_, usage)| *usage > 0.0)
+            .collect::<Vec<_>>();
+        usage.sort_by(|a, b| b.1.total_cmp(&a.1));
+
+        for (i, _) in usage.into_iter().take(*budget) {
+            owners[i] = Some(*region);
+        }
+    }
+
+    owners
+}
+
+/// Scales the probability of every super pixel by the average importance of the pixels it starts
+/// out covering, then normalizes them again. `false` when nothing has any importance left.
+pub fn apply_importance(
+    super_pixels: &mut [SuperPixel],
+    importance: &GrayImage,
+    sp_size: UVec2,
+) -> bool {
+    let in_size = UVec2::new(importance.width(), importance.height());
+
+    for (idx, sp) in super_pixels.iter_mut().enumerate() {
+        let cell = UVec2::new(idx as u32 % sp_size.x, idx as u32 / sp_size.x);
+        let start = (cell * in_size) / sp_size;
+        let end = (((cell + 1) * in_size) / sp_size).max(start + 1);
+
+        let mut sum = 0.0;
+        for y in start.y..end.y {
+            for x in start.x..end.x {
+                sum += importance.get_pixel(x, y).0[0] as f64 / 255.0;
+            }
+        }
+        sp.probability *= sum / ((end - start).x * (end - start).y) as f64;
+    }
+
+    let total = super_pixels.iter().map(|sp| sp.probability).sum::<f64>();
+    if total <= 0.0 {
+        return false;
+    }
+    for sp in super_pixels {
+        sp.probability /= total;
+    }
+
+    true
+}
@@ -0,0 +1,1672 @@
+use std::{
+    collections::{HashMap, HashSet, VecDeque},
+    time::Instant,
+};
+
+use ::image::GrayImage;
+use anyhow::bail;
+use glam::{DVec2, DVec3, IVec2, UVec2};
+use rayon::prelude::{
+    IndexedParallelIterator, IntoParallelIterator, IntoParallelRefIterator,
+    IntoParallelRefMutIterator, ParallelIterator,
+};
+use tracing::{debug, info};
+
+#[cfg(feature = "gpu")]
+use crate::gpu::GpuLabeler;
+use crate::{
+    background::is_background,
+    checkpoint::{Checkpoint, Convergence, SuperPixelState},
+    color::Color,
+    image::LabImage,
+    metric::Metric,
+    profile::{Profiler, Stage},
+    regions::{apply_importance, assign_region_budgets},
+};
+
+// Fractions of the way from a super pixel to a pixel where the edges between them get sampled
+const EDGE_SAMPLES: [f64; 3] = [0.25, 0.5, 0.75];
+// Offsets to the four neighbours of a super pixel on the square grid
+const SQUARE_NEIGHBOURS: [IVec2; 4] = [
+    IVec2::new(0, 1),
+    IVec2::new(0, -1),
+    IVec2::new(-1, 0),
+    IVec2::new(1, 0),
+];
+// Offsets to the six neighbours of a super pixel on the hex grid, where the odd rows are shifted
+// right by half a super pixel
+const HEX_NEIGHBOURS_EVEN: [IVec2; 6] = [
+    IVec2::new(-1, 0),
+    IVec2::new(1, 0),
+    IVec2::new(-1, -1),
+    IVec2::new(0, -1),
+    IVec2::new(-1, 1),
+    IVec2::new(0, 1),
+];
+const HEX_NEIGHBOURS_ODD: [IVec2; 6] = [
+    IVec2::new(-1, 0),
+    IVec2::new(1, 0),
+    IVec2::new(0, -1),
+    IVec2::new(1, -1),
+    IVec2::new(0, 1),
+    IVec2::new(1, 1),
+];
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, clap::ValueEnum, serde::Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Smoothing {
+    // Weighs neighbours by how close their lightness is
+    Bilateral,
+    // Edge preserving guided filter using the input image as the guide
+    Guided,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, clap::ValueEnum, serde::Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Grid {
+    // Super pixels start on a square lattice
+    Square,
+    // Super pixels start on a hex lattice, every odd row shifted by half a super pixel, for
+    // rounder cells. The stitches stay square
+    Hex,
+}
+
+impl Grid {
+    // Offsets to the super pixels next to one in row `row`
+    fn neighbours(self, row: u32) -> &'static [IVec2] {
+        match self {
+            Grid::Square => &SQUARE_NEIGHBOURS,
+            Grid::Hex if row.is_multiple_of(2) => &HEX_NEIGHBOURS_EVEN,
+            Grid::Hex => &HEX_NEIGHBOURS_ODD,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, clap::ValueEnum, serde::Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum SuperpixelMode {
+    // Every pixel goes to the cheapest super pixel around it, which can leave super pixels in
+    // several pieces
+    Unconstrained,
+    // Like SLIC, the pieces of a super pixel cut off from its largest one go to a neighbour
+    Slic,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, clap::ValueEnum, serde::Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Schedule {
+    // Multiply the temperature by alpha
+    Geometric,
+    // Lower the temperature by the same amount every time, taking as many steps as geometric
+    // cooling would
+    Linear,
+    // Multiply the temperature by alpha, up to twice over when the palette barely moved
+    Adaptive,
+}
+
+#[derive(Debug, Clone)]
+pub struct SolverConfig {
+    // Temperature multiplier applied each time the palette converges
+    pub alpha: f64,
+    // Temperature at which the optimization stops
+    pub t_final: f64,
+    // How the temperature gets lowered each time the palette converges
+    pub schedule: Schedule,
+    // Iterations after which the optimization stops whatever the temperature
+    pub max_iterations: Option<usize>,
+    // Total palette change below which the palette counts as converged
+    pub epsilon_palette: f64,
+    // Distance between the two colors of a cluster above which it gets split
+    pub epsilon_cluster: f64,
+    // Weight of the spatial distance against the color distance when assigning pixels
+    pub spatial_weight: f64,
+    // Width and height of a stitch, the spatial distance treats them as the same length
+    pub pixel_aspect: UVec2,
+    // Weight of the strongest edge of the input between a pixel and a super pixel when assigning
+    // pixels, so super pixels keep to one side of strong edges. 0 leaves the edges out
+    pub edge_weight: f64,
+    // Multiplier of the principal component used to perturb new palette colors
+    pub delta_scale: f64,
+    // Lattice the super pixels start on
+    pub grid: Grid,
+    // How the pixels get assigned to the super pixels
+    pub superpixel: SuperpixelMode,
+    // Filter used to smooth the super pixel colors
+    pub smoothing: Smoothing,
+    // Share of the average position of the neighbours moved into the position of every super
+    // pixel each iteration, 0 skips the Laplacian smoothing
+    pub smooth_position: f64,
+    // Share of the filtered color mixed into the color of every super pixel each iteration, 0
+    // skips the color smoothing
+    pub smooth_color: f64,
+    // Colors reserved for a region of the region mask as `(mask value, color count)`
+    pub region_budget: Vec<(u8, usize)>,
+    // Every super pixel keeps to its own input pixel and its color, for inputs that are already
+    // at the output size. Only the palette gets optimized
+    pub pinned: bool,
+    // Color difference every comparison of the optimization uses
+    pub metric: Metric,
+    // Animation frames stacked from top to bottom in the input, 1 for a still image. Neither the
+    // pixels nor the super pixels reach across from one frame into the next
+    pub frames: u32,
+}
+
+/// Masks over the input, all of them at its size.
+#[derive(Debug, Default, Clone, Copy)]
+pub struct Masks<'m> {
+    // Every gray level marks a region
+    pub region: Option<&'m GrayImage>,
+    // Black pixels are background, left out of the palette
+    pub background: Option<&'m GrayImage>,
+    // How much each pixel matters, brighter pixels get more colors
+    pub importance: Option<&'m GrayImage>,
+}
+
+pub struct IterationReport<'p> {
+    pub iteration: usize,
+    pub t: f64,
+    pub k: usize,
+    pub total_change: f64,
+    // Spread of the last 100 total changes, which settles the palette once it stops moving
+    pub variance: f64,
+    // How far the temperature has come towards its final value, from 0 to 1
+    pub fraction: f64,
+    pub palette: &'p [(Color, f64)],
+    // The complete palette converged during the iteration, before the temperature got lowered
+    pub settled: bool,
+}
+
+/// The optimization, one iteration per `step` so the caller runs the loop.
+pub struct Solver<'i> {
+    img: &'i LabImage,
+    sp_size: UVec2,
+    config: SolverConfig,
+    delta: DVec2,
+    super_pixels: Vec<SuperPixel<'i>>,
+    palette: Vec<(Color, f64)>,
+    clusters: Vec<UVec2>,
+    // Region each palette entry is reserved for, empty until the palette is complete
+    owners: Vec<Option<u8>>,
+    // Palette entries that palette_refine leaves in place
+    locked: Vec<bool>,
+    // Index of the super pixel each input pixel belongs to
+    labels: Vec<u32>,
+    // Edge strength of every input pixel, empty without an edge weight
+    edges: Vec<f64>,
+    t: f64,
+    t_initial: f64,
+    k: usize,
+    // Colors left in the palette, only lowered by editing the palette
+    k_max: usize,
+    // Iterations done so far
+    iteration: usize,
+    total_change: f64,
+    variance: f64,
+    settled: bool,
+    running_average: f64,
+    prev_changes: VecDeque<f64>,
+    running_variance_avg: f64,
+    prev_variances: VecDeque<f64>,
+    variance_check_passed_count: usize,
+    #[cfg(feature = "gpu")]
+    gpu: Option<GpuLabeler>,
+}
+
+impl<'i> Solver<'i> {
+    /// Sets up the optimization of `img` into `sp_size` super pixels and `color_count` colors,
+    /// starting from the average color split along the principal component.
+    pub fn new(
+        img: &'i LabImage,
+        sp_size: UVec2,
+        color_count: usize,
+        config: SolverConfig,
+        masks: Masks,
+    ) -> anyhow::Result<Self> {
+        // Only the pixels the palette gets used for count, as much as they matter
+        let weights = (masks.importance.is_some() || masks.background.is_some()).then(|| {
+            (0..img.pixels.len())
+                .map(|idx| {
+                    let coord = UVec2::new(idx as u32 % img.size.x, idx as u32 / img.size.x);
+                    if masks
+                        .background
+                        .is_some_and(|mask| is_background(mask, coord))
+                    {
+                        return 0.0;
+                    }
+
+                    masks.importance.map_or(1.0, |importance| {
+                        importance.get_pixel(coord.x, coord.y).0[0] as f64 / 255.0
+                    })
+                })
+                .collect::<Vec<_>>()
+        });
+        let (component, variance) = match &weights {
+            Some(weights) => img.weighted_principal_component(weights),
+            None => {
+                let pca = img.pca()?;
+                let component = pca.components().axis_iter(ndarray::Axis(0)).next().unwrap();
+                let component = component.as_slice().unwrap();
+
+                (
+                    DVec3 {
+                        x: component[0],
+                        y: component[1],
+                        z: component[2],
+                    },
+                    *pca.explained_variance().first().unwrap(),
+                )
+            }
+        };
+        let delta = (component * config.delta_scale).truncate();
+        let t = 1.1 * variance;
+
+        let init_color = Color::average_from(img, img.size);
+        let mut palette = vec![(init_color, 0.5), (init_color, 0.5)];
+        palette[1].0.perturb(delta);
+
+        let mut solver = Solver::init(img, sp_size, init_color, config, masks)?;
+        solver.delta = delta;
+        solver.palette = palette;
+        solver.clusters = vec![UVec2 { x: 0, y: 1 }];
+        solver.t = t;
+        solver.t_initial = t;
+        solver.k = 1;
+        solver.k_max = color_count;
+        Ok(solver)
+    }
+
+    /// Sets up the optimization of `img` into `sp_size` super pixels using exactly the colors of
+    /// `palette`, which never move nor get split. Only the super pixels and their association
+    /// with the colors get optimized.
+    pub fn with_palette(
+        img: &'i LabImage,
+        sp_size: UVec2,
+        palette: Vec<Color>,
+        config: SolverConfig,
+        masks: Masks,
+    ) -> anyhow::Result<Self> {
+        if palette.is_empty() {
+            bail!("the fixed palette has no colors");
+        }
+
+        let init_color = Color::average_from(img, img.size);
+        // Spread of the input around its average, standing in for the variance along the
+        // principal component. At least one step runs however low it is
+        let variance = img
+            .pixels
+            .iter()
+            .map(|color| color.difference(init_color, config.metric).powi(2))
+            .sum::<f64>()
+            / img.pixels.len() as f64;
+        let t = (1.1 * variance).max(config.t_final / config.alpha);
+
+        let mut solver = Solver::init(img, sp_size, init_color, config, masks)?;
+        let k = palette.len();
+        solver.palette = palette
+            .into_iter()
+            .map(|color| (color, 1.0 / k as f64))
+            .collect();
+        solver.clusters = (0..k).map(|i| UVec2::new(i as u32, 0)).collect();
+        solver.locked = vec![true; k];
+        solver.t = t;
+        solver.t_initial = t;
+        solver.k = k;
+        solver.k_max = k;
+        Ok(solver)
+    }
+
+    // Super pixels and masks shared by every way to start, with an empty palette
+    fn init(
+        img: &'i LabImage,
+        sp_size: UVec2,
+        init_color: Color,
+        config: SolverConfig,
+        masks: Masks,
+    ) -> anyhow::Result<Self> {
+        let mut super_pixels = init_super_pixels(img, sp_size, init_color, &config, masks);
+        if super_pixels.iter().all(|sp| sp.background) {
+            bail!("the whole input is background, there is nothing left to stitch");
+        }
+        if config.pinned {
+            if sp_size != img.size {
+                bail!(
+                    "pinned super pixels need the input to be {sp_size}, not {}",
+                    img.size
+                );
+            }
+            for sp in &mut super_pixels {
+                sp.sp_color = sp.original_color;
+            }
+        }
+        if let Some(importance) = masks.importance {
+            if !apply_importance(&mut super_pixels, importance, sp_size) {
+                bail!("the importance map is black over everything that gets stitched");
+            }
+        }
+
+        // Pinned super pixels never get pixels assigned, they keep their own
+        let labels = if config.pinned {
+            (0..img.pixels.len() as u32).collect()
+        } else {
+            vec![0; img.pixels.len()]
+        };
+        let edges = if config.edge_weight > 0.0 {
+            img.edge_strength()
+        } else {
+            Vec::default()
+        };
+
+        Ok(Solver {
+            img,
+            sp_size,
+            config,
+            delta: DVec2::ZERO,
+            super_pixels,
+            palette: Vec::default(),
+            clusters: Vec::default(),
+            owners: Vec::default(),
+            locked: Vec::default(),
+            labels,
+            edges,
+            t: 0.0,
+            t_initial: 0.0,
+            k: 0,
+            k_max: 0,
+            iteration: 0,
+            total_change: 0.0,
+            variance: 0.0,
+            settled: false,
+            running_average: 0.0,
+            prev_changes: VecDeque::with_capacity(100),
+            running_variance_avg: 0.0,
+            prev_variances: VecDeque::with_capacity(100),
+            variance_check_passed_count: 0,
+            #[cfg(feature = "gpu")]
+            gpu: None,
+        })
+    }
+
+    /// Picks up the palette and temperature of a solver run on a downscaled copy of the input,
+    /// with its super pixels moved to where the coarse ones ended up.
+    pub fn start_from(&mut self, coarse: &Solver) {
+        for (sp, coarse_sp) in self.super_pixels.iter_mut().zip(&coarse.super_pixels) {
+            sp.coord = (coarse_sp.coord * self.img.size) / coarse.img.size;
+        }
+        self.k = coarse.palette.len();
+        self.clusters = (0..self.k).map(|i| UVec2::new(i as u32, 0)).collect();
+        self.palette = coarse.palette.clone();
+        self.t = coarse.t;
+    }
+
+    /// Labels the pixels with a compute shader from now on, false when there is no usable GPU.
+    #[cfg(feature = "gpu")]
+    pub fn use_gpu(&mut self) -> bool {
+        self.gpu = GpuLabeler::new(self.img, self.sp_size, self.config.frames);
+        self.gpu.is_some()
+    }
+
+    /// Runs one iteration, lowering the temperature and growing the palette once it converges.
+    pub fn step(&mut self) -> IterationReport<'_> {
+        self.step_profiled(&mut Profiler::new(false))
+    }
+
+    /// Same as `step`, timing each stage with `profiler`.
+    pub fn step_profiled(&mut self, profiler: &mut Profiler) -> IterationReport<'_> {
+        let start = Instant::now();
+
+        if !self.config.pinned {
+            profiler.stage(Stage::Assignment, || {
+                #[cfg(feature = "gpu")]
+                let on_gpu = self
+                    .gpu
+                    .as_ref()
+                    .is_some_and(|gpu| gpu.assign_labels(&self.super_pixels, &mut self.labels));
+                #[cfg(not(feature = "gpu"))]
+                let on_gpu = false;
+                if !on_gpu {
+                    assign_labels(
+                        &self.super_pixels,
+                        self.img,
+                        &mut self.labels,
+                        self.sp_size,
+                        self.config.grid,
+                        (!self.edges.is_empty())
+                            .then(|| (&self.edges[..], self.config.edge_weight)),
+                        self.config.frames,
+                    );
+                }
+                if self.config.superpixel == SuperpixelMode::Slic {
+                    enforce_connectivity(&mut self.labels, self.img.size, self.config.frames);
+                }
+            });
+
+            profiler.stage(Stage::Smoothing, || {
+                sp_refine(
+                    &mut self.super_pixels,
+                    self.img,
+                    &self.labels,
+                    self.sp_size,
+                    &self.config,
+                )
+            });
+        }
+
+        profiler.stage(Stage::Association, || {
+            associate(
+                &mut self.super_pixels,
+                &mut self.palette,
+                &self.clusters,
+                &self.owners,
+                self.k,
+                self.t,
+            );
+
+            if self.owners.is_empty()
+                && !self.config.region_budget.is_empty()
+                && self.k >= self.k_max
+            {
+                self.owners = assign_region_budgets(
+                    &self.super_pixels,
+                    &self.palette,
+                    &self.config.region_budget,
+                );
+                associate(
+                    &mut self.super_pixels,
+                    &mut self.palette,
+                    &self.clusters,
+                    &self.owners,
+                    self.k,
+                    self.t,
+                );
+            }
+        });
+
+        let total_change = profiler.stage(Stage::Refinement, || {
+            palette_refine(
+                &mut self.super_pixels,
+                &mut self.palette,
+                &self.locked,
+                self.config.metric,
+            )
+        });
+
+        if self.prev_changes.len() == 100 {
+            self.running_average -= self.prev_changes.pop_front().unwrap();
+        }
+
+        self.prev_changes.push_back(total_change);
+        self.running_average += total_change;
+
+        let mean = self.running_average / 100.0;
+        let variance = self
+            .prev_changes
+            .iter()
+            .map(|change| (mean - change).powi(2))
+            .sum::<f64>()
+            .sqrt()
+            / 100.0;
+
+        if self.prev_variances.len() == 100 {
+            self.running_variance_avg -= self.prev_variances.pop_front().unwrap();
+        }
+
+        self.prev_variances.push_back(variance);
+        self.running_variance_avg += variance;
+
+        if ((self.running_variance_avg / 100.0) - variance).abs() < 0.001 {
+            self.variance_check_passed_count += 1;
+            debug!("Trigger due to variance");
+        } else {
+            self.variance_check_passed_count = 0;
+        }
+
+        self.settled = false;
+        if total_change < self.config.epsilon_palette || self.variance_check_passed_count > 100 {
+            self.variance_check_passed_count = 0;
+            self.settled = self.k >= self.k_max;
+            self.cool(total_change);
+            if self.k < self.k_max {
+                expand(
+                    &mut self.clusters,
+                    &mut self.palette,
+                    &mut self.k,
+                    self.k_max,
+                    self.delta,
+                    &self.config,
+                );
+            }
+        }
+
+        debug!(
+            "{}: Total Change: {total_change:.3}, k: {}, t: {:.3}, time_delta: {:?}, \
+             variance: {variance:.4}, avg. variance: {:.4} variance count: {}",
+            self.iteration,
+            self.k,
+            self.t,
+            start.elapsed(),
+            self.running_variance_avg / 100.0,
+            self.variance_check_passed_count
+        );
+        self.total_change = total_change;
+        self.variance = variance;
+        self.iteration += 1;
+
+        self.report()
+    }
+
+    /// Where the optimization is at after the last step.
+    pub fn report(&self) -> IterationReport<'_> {
+        IterationReport {
+            // Counting from 0
+            iteration: self.iteration.saturating_sub(1),
+            t: self.t,
+            k: self.k,
+            total_change: self.total_change,
+            variance: self.variance,
+            fraction: match self.config.schedule {
+                Schedule::Linear => {
+                    (self.t_initial - self.t) / (self.t_initial - self.config.t_final)
+                }
+                Schedule::Geometric | Schedule::Adaptive => {
+                    (self.t_initial / self.t).ln() / (self.t_initial / self.config.t_final).ln()
+                }
+            },
+            palette: &self.palette,
+            settled: self.settled,
+        }
+    }
+
+    /// Whether the temperature reached its final value, or the iterations their maximum.
+    pub fn is_done(&self) -> bool {
+        self.t <= self.config.t_final
+            || self
+                .config
+                .max_iterations
+                .is_some_and(|max| self.iteration >= max)
+    }
+
+    // Lowers the temperature once the palette converged with a change of `total_change`
+    fn cool(&mut self, total_change: f64) {
+        let SolverConfig {
+            alpha,
+            t_final,
+            epsilon_palette,
+            ..
+        } = self.config;
+
+        match self.config.schedule {
+            Schedule::Geometric => self.t *= alpha,
+            Schedule::Linear => {
+                let steps = ((t_final / self.t_initial).ln() / alpha.ln())
+                    .ceil()
+                    .max(1.0);
+                self.t -= (self.t_initial - t_final) / steps;
+            }
+            Schedule::Adaptive => {
+                let stillness = 1.0 - (total_change / epsilon_palette).clamp(0.0, 1.0);
+                self.t *= alpha.powf(1.0 + stillness);
+            }
+        }
+    }
+
+    pub fn t(&self) -> f64 {
+        self.t
+    }
+
+    /// Lets the palette grow to `color_count` colors from here on.
+    pub fn set_color_count(&mut self, color_count: usize) {
+        // A complete palette has its clusters merged into single colors, every one of them gets
+        // paired with a perturbed copy again like the initial color so they split one by one
+        if self.k >= self.k_max && color_count > self.k {
+            let palette = std::mem::take(&mut self.palette);
+            for (i, (color, probability)) in palette.into_iter().enumerate() {
+                let mut copy = color;
+                copy.perturb(self.delta);
+                self.palette.push((color, probability / 2.0));
+                self.palette.push((copy, probability / 2.0));
+                self.clusters[i] = UVec2::new(2 * i as u32, 2 * i as u32 + 1);
+            }
+            self.locked = self.locked.iter().flat_map(|locked| [*locked; 2]).collect();
+            // The region budgets get assigned again once the larger palette is complete
+            self.owners.clear();
+        }
+        self.k_max = color_count;
+    }
+
+    pub fn k(&self) -> usize {
+        self.k
+    }
+
+    /// Keeps the palette at its current size from here on.
+    pub fn freeze_color_count(&mut self) {
+        if self.k < self.k_max {
+            merge_clusters(&mut self.clusters, &mut self.palette, self.k);
+            self.k_max = self.k;
+        }
+    }
+
+    /// Mean distance between the stitched super pixels and the palette colors they use.
+    pub fn reconstruction_error(&self) -> f64 {
+        let (sum, count) = self
+            .super_pixels
+            .iter()
+            .filter(|sp| !sp.is_background())
+            .fold((0.0, 0), |(sum, count), sp| {
+                let distance = sp
+                    .sp_color()
+                    .difference(sp.palette_color(), self.config.metric);
+                (sum + distance, count + 1)
+            });
+
+        sum / count.max(1) as f64
+    }
+
+    /// Number of distinct palette colors the stitched super pixels currently use.
+    pub fn used_colors(&self) -> usize {
+        self.super_pixels
+            .iter()
+            .filter(|sp| !sp.is_background())
+            .map(|sp| sp.palette_color().to_array().map(f64::to_bits))
+            .collect::<HashSet<_>>()
+            .len()
+    }
+
+    pub fn palette(&self) -> &[(Color, f64)] {
+        &self.palette
+    }
+
+    /// Super pixels row by row.
+    pub fn super_pixels(&self) -> &[SuperPixel<'i>] {
+        &self.super_pixels
+    }
+
+    /// Super pixel every input pixel was last assigned to, row by row.
+    pub fn labels(&self) -> &[u32] {
+        &self.labels
+    }
+
+    /// Lets `edit` change the palette, which of its colors are locked and the regions they are
+    /// reserved for. Every color is its own cluster after.
+    pub fn edit_palette<R>(
+        &mut self,
+        edit: impl FnOnce(&mut Vec<(Color, f64)>, &mut Vec<bool>, &mut Vec<Option<u8>>) -> R,
+    ) -> R {
+        self.locked.resize(self.palette.len(), false);
+        let result = edit(&mut self.palette, &mut self.locked, &mut self.owners);
+
+        self.k = self.palette.len();
+        self.k_max = self.k;
+        self.clusters = (0..self.k).map(|i| UVec2::new(i as u32, 0)).collect();
+        result
+    }
+
+    /// State to continue from later, `input_hash` identifying the input.
+    pub fn checkpoint(&self, input_hash: String) -> Checkpoint {
+        Checkpoint {
+            input_hash,
+            iteration: self.iteration,
+            t: self.t,
+            t_initial: self.t_initial,
+            k: self.k,
+            k_max: self.k_max,
+            super_pixels: self.super_pixels.iter().map(SuperPixelState::new).collect(),
+            palette: self.palette.clone(),
+            clusters: self.clusters.clone(),
+            owners: self.owners.clone(),
+            locked: self.locked.clone(),
+            convergence: Convergence {
+                settled: self.settled,
+                running_average: self.running_average,
+                prev_changes: self.prev_changes.clone(),
+                running_variance_avg: self.running_variance_avg,
+                prev_variances: self.prev_variances.clone(),
+                variance_check_passed_count: self.variance_check_passed_count,
+            },
+        }
+    }
+
+    /// Continues from a checkpoint, false when it has another number of super pixels.
+    pub fn restore(&mut self, checkpoint: Checkpoint) -> bool {
+        if checkpoint.super_pixels.len() != self.super_pixels.len() {
+            return false;
+        }
+
+        for (sp, state) in self.super_pixels.iter_mut().zip(checkpoint.super_pixels) {
+            state.restore(sp);
+        }
+        self.palette = checkpoint.palette;
+        self.clusters = checkpoint.clusters;
+        self.owners = checkpoint.owners;
+        self.t = checkpoint.t;
+        self.t_initial = checkpoint.t_initial;
+        self.k = checkpoint.k;
+        self.k_max = checkpoint.k_max;
+        self.locked = checkpoint.locked;
+        self.iteration = checkpoint.iteration;
+
+        let convergence = checkpoint.convergence;
+        self.settled = convergence.settled;
+        self.running_average = convergence.running_average;
+        self.prev_changes = convergence.prev_changes;
+        self.running_variance_avg = convergence.running_variance_avg;
+        self.prev_variances = convergence.prev_variances;
+        self.variance_check_passed_count = convergence.variance_check_passed_count;
+
+        true
+    }
+}
+
+#[derive(Debug)]
+pub struct SuperPixel<'s> {
+    img: &'s LabImage,
+    pub(crate) coord: UVec2,
+    pub(crate) palette_color: Color,
+    pub(crate) probability: f64,
+    pub(crate) conditional_probability: Vec<f64>,
+    pub(crate) sp_color: Color,
+    original_coord: UVec2,
+    original_color: Color,
+    pub(crate) n: f64,
+    pub(crate) m: f64,
+    pub(crate) spatial_weight: f64,
+    // Scale of the distances along both axes, for stitches that aren't square
+    pub(crate) stretch: DVec2,
+    pub(crate) metric: Metric,
+    pub(crate) region: u8,
+    // Left unstitched, doesn't count towards the palette
+    background: bool,
+}
+
+impl<'s> SuperPixel<'s> {
+    pub fn new<'i: 's>(
+        img: &'i LabImage,
+        coord: UVec2,
+        color: Color,
+        out_size: UVec2,
+        spatial_weight: f64,
+        region: u8,
+        background: bool,
+    ) -> Self {
+        SuperPixel {
+            img,
+            coord,
+            palette_color: color,
+            probability: 1.0 / (out_size.x * out_size.y) as f64,
+            conditional_probability: vec![0.5, 0.5],
+            sp_color: Color::BLACK,
+            original_coord: coord,
+            original_color: img[coord],
+            n: (out_size.x * out_size.y) as f64,
+            m: (img.size.x * img.size.y) as f64,
+            spatial_weight,
+            stretch: DVec2::ONE,
+            metric: Metric::Euclidean,
+            region,
+            background,
+        }
+    }
+
+    pub fn cost(&self, coord: UVec2) -> f64 {
+        let c_diff = self.img[coord].difference(self.palette_color, self.metric);
+        let spatial_diff = ((self.coord.as_dvec2() - coord.as_dvec2()) * self.stretch).length();
+
+        c_diff + self.spatial_weight * (self.n / self.m).powf(0.5) * spatial_diff
+    }
+
+    /// Position in the input.
+    pub fn coord(&self) -> UVec2 {
+        self.coord
+    }
+
+    /// Strongest of `edges` on the way from the super pixel to `coord`, sampled at a few points.
+    pub fn edge_between(&self, coord: UVec2, edges: &[f64]) -> f64 {
+        let (from, to) = (self.coord.as_dvec2(), coord.as_dvec2());
+        EDGE_SAMPLES
+            .iter()
+            .map(|t| from.lerp(to, *t).round().as_uvec2())
+            .map(|sample| edges[(sample.y * self.img.size.x + sample.x) as usize])
+            .fold(0.0, f64::max)
+    }
+
+    pub fn normalize_probs(&mut self, palette: &[(Color, f64)], clusters: &[UVec2], k: usize) {
+        let denom: f64 = self.conditional_probability.iter().sum();
+        let mut hi = self
+            .conditional_probability
+            .iter()
+            .copied()
+            .reduce(f64::max)
+            .unwrap();
+
+        for (i, probability) in self.conditional_probability.iter_mut().enumerate() {
+            if *probability == hi {
+                self.palette_color = palette[i].0;
+            }
+
+            *probability /= denom;
+        }
+
+        hi = -1.0;
+        for &cluster in &clusters[..k] {
+            let mut prob = 0.0;
+            let mut color = Color::BLACK;
+
+            for ci in cluster.to_array() {
+                let cur = palette[ci as usize];
+                color += cur.0;
+                prob += cur.1;
+            }
+
+            color /= cluster.to_array().len() as f64;
+
+            if prob > hi {
+                hi = prob;
+                // self.palette_color = color;
+            }
+        }
+    }
+
+    pub fn palette_color(&self) -> Color {
+        self.palette_color
+    }
+
+    /// Mean color of the input pixels assigned to the super pixel.
+    pub fn sp_color(&self) -> Color {
+        self.sp_color
+    }
+
+    pub fn is_background(&self) -> bool {
+        self.background
+    }
+
+    pub fn update_position(&mut self, stats: &LabelStats) {
+        if stats.count == 0 {
+            info!("super pixel without pixels failure");
+            self.coord = self.original_coord;
+        } else {
+            self.coord = stats.coord_sum / stats.count;
+        }
+    }
+
+    pub fn update_sp_color(&mut self, stats: &LabelStats) {
+        if stats.count == 0 {
+            self.sp_color = self.original_color;
+        } else {
+            self.sp_color = stats.color_sum / stats.count as f64;
+        }
+    }
+}
+
+// Sums over the input pixels labelled with the same super pixel
+#[derive(Debug, Clone, Copy)]
+pub struct LabelStats {
+    count: u32,
+    coord_sum: UVec2,
+    color_sum: Color,
+}
+
+impl LabelStats {
+    const EMPTY: LabelStats = LabelStats {
+        count: 0,
+        coord_sum: UVec2::ZERO,
+        color_sum: Color::BLACK,
+    };
+
+    fn merge(&mut self, other: &LabelStats) {
+        self.count += other.count;
+        self.coord_sum += other.coord_sum;
+        self.color_sum += other.color_sum;
+    }
+}
+
+fn init_super_pixels<'i>(
+    img: &'i LabImage,
+    sp_size: UVec2,
+    color: Color,
+    config: &SolverConfig,
+    masks: Masks,
+) -> Vec<SuperPixel<'i>> {
+    let mut super_pixels = Vec::with_capacity((sp_size.x * sp_size.y) as usize);
+
+    for row in 0..sp_size.y {
+        let y = (row * img.size.y) / sp_size.y;
+        // Counting in half super pixels to shift the odd rows of the hex grid
+        let shift = (config.grid == Grid::Hex && row % 2 == 1) as u32;
+        for x in (0..sp_size.x).map(|x| ((2 * x + shift) * img.size.x) / (2 * sp_size.x)) {
+            super_pixels.push(SuperPixel::new(
+                img,
+                UVec2 { x, y },
+                color,
+                sp_size,
+                config.spatial_weight,
+                masks.region.map_or(0, |mask| mask.get_pixel(x, y).0[0]),
+                masks
+                    .background
+                    .is_some_and(|mask| is_background(mask, UVec2 { x, y })),
+            ));
+        }
+    }
+
+    // A stitch spans as many input pixels as it is wide and tall, the distances get scaled back to
+    // square ones without changing their overall weight
+    let aspect = config.pixel_aspect.as_dvec2();
+    let stretch = DVec2::new((aspect.y / aspect.x).sqrt(), (aspect.x / aspect.y).sqrt());
+    // The palette probabilities only add up over the stitched super pixels
+    let stitched = super_pixels.iter().filter(|sp| !sp.background).count();
+    for sp in &mut super_pixels {
+        sp.stretch = stretch;
+        sp.metric = config.metric;
+        sp.probability = if sp.background {
+            0.0
+        } else {
+            1.0 / stitched as f64
+        };
+    }
+
+    super_pixels
+}
+
+// Labels every input pixel with the cheapest of the super pixels around it in its own frame,
+// counting the edges between them when given along with their weight
+fn assign_labels(
+    super_pixels: &[SuperPixel],
+    img: &LabImage,
+    labels: &mut [u32],
+    out_size: UVec2,
+    grid: Grid,
+    edges: Option<(&[f64], f64)>,
+    frames: u32,
+) {
+    let in_size = img.size;
+
+    labels.par_iter_mut().enumerate().for_each(|(idx, label)| {
+        let coord = UVec2 {
+            x: idx as u32 % in_size.x,
+            y: idx as u32 / in_size.x,
+        };
+        let sp_coord = match grid {
+            Grid::Square => (coord * out_size) / in_size,
+            Grid::Hex => {
+                let row = coord.y * out_size.y / in_size.y;
+                let half = coord.x * 2 * out_size.x / in_size.x;
+                UVec2::new(half.saturating_sub(row % 2) / 2, row)
+            }
+        };
+        const D_COORDS: [IVec2; 9] = [
+            IVec2::new(-1, -1),
+            IVec2::new(-1, 0),
+            IVec2::new(-1, 1),
+            IVec2::new(0, -1),
+            IVec2::new(0, 0),
+            IVec2::new(0, 1),
+            IVec2::new(1, -1),
+            IVec2::new(1, 0),
+            IVec2::new(1, 1),
+        ];
+
+        // The super pixel itself and the six around it on the hex grid
+        let mut hex_coords = [IVec2::ZERO; 7];
+        hex_coords[1..].copy_from_slice(Grid::Hex.neighbours(sp_coord.y));
+        let d_coords: &[IVec2] = match grid {
+            Grid::Square => &D_COORDS,
+            Grid::Hex => &hex_coords,
+        };
+
+        let mut best_cost = f64::MAX;
+        let mut best_coord = UVec2::ZERO;
+        for d_coord in d_coords.iter().copied() {
+            let n_coord = sp_coord.as_ivec2() + d_coord;
+            if n_coord.x >= 0
+                && n_coord.y >= 0
+                && n_coord.x < out_size.x as i32
+                && n_coord.y < out_size.y as i32
+                && same_frame(sp_coord.y, n_coord.y as u32, out_size.y, frames)
+            {
+                let n_coord = n_coord.as_uvec2();
+                let sp = &super_pixels[(n_coord.x + n_coord.y * out_size.x) as usize];
+                let new_cost = sp.cost(coord)
+                    + edges.map_or(0.0, |(edges, weight)| {
+                        weight * sp.edge_between(coord, edges)
+                    });
+                if new_cost < best_cost {
+                    best_cost = new_cost;
+                    best_coord = n_coord;
+                }
+            }
+        }
+
+        *label = best_coord.x + best_coord.y * out_size.x;
+    });
+}
+
+// Keeps the largest 4-connected piece of every super pixel and gives the pixels of the other
+// pieces to the super pixel they touch the most, within their frame
+fn enforce_connectivity(labels: &mut [u32], size: UVec2, frames: u32) {
+    let neighbours = |idx: usize| {
+        let coord = UVec2::new(idx as u32 % size.x, idx as u32 / size.x);
+        [
+            (coord.x > 0).then(|| idx - 1),
+            (coord.x + 1 < size.x).then(|| idx + 1),
+            (coord.y > 0 && same_frame(coord.y, coord.y - 1, size.y, frames))
+                .then(|| idx - size.x as usize),
+            (coord.y + 1 < size.y && same_frame(coord.y, coord.y + 1, size.y, frames))
+                .then(|| idx + size.x as usize),
+        ]
+        .into_iter()
+        .flatten()
+    };
+
+    // Every piece as its label and its pixels
+    let mut seen = vec![false; labels.len()];
+    let mut pieces = vec![];
+    for start in 0..labels.len() {
+        if seen[start] {
+            continue;
+        }
+
+        let label = labels[start];
+        let mut piece = vec![start];
+        seen[start] = true;
+        let mut next = 0;
+        while let Some(idx) = piece.get(next).copied() {
+            for neighbour in neighbours(idx) {
+                if !seen[neighbour] && labels[neighbour] == label {
+                    seen[neighbour] = true;
+                    piece.push(neighbour);
+                }
+            }
+            next += 1;
+        }
+
+        pieces.push((label, piece));
+    }
+
+    let mut largest = HashMap::<u32, usize>::new();
+    for (label, piece) in &pieces {
+        let size = largest.entry(*label).or_default();
+        *size = (*size).max(piece.len());
+    }
+
+    let mut kept = HashSet::new();
+    for (label, piece) in pieces {
+        // Of pieces of the same size, the first one is kept
+        if piece.len() == largest[&label] && kept.insert(label) {
+            continue;
+        }
+
+        let mut touching = HashMap::<u32, usize>::new();
+        for idx in &piece {
+            for neighbour in neighbours(*idx) {
+                if labels[neighbour] != label {
+                    *touching.entry(labels[neighbour]).or_default() += 1;
+                }
+            }
+        }
+        let new_label = touching
+            .into_iter()
+            .max_by_key(|(label, count)| (*count, *label))
+            .map(|(label, _)| label);
+        if let Some(new_label) = new_label {
+            for idx in piece {
+                labels[idx] = new_label;
+            }
+        }
+    }
+}
+
+fn sp_refine(
+    super_pixels: &mut Vec<SuperPixel>,
+    img: &LabImage,
+    labels: &[u32],
+    out_size: UVec2,
+    config: &SolverConfig,
+) {
+    let in_size = img.size;
+
+    // One partial sum per thread, merged at the end
+    let stats = labels
+        .par_iter()
+        .enumerate()
+        .with_min_len(labels.len() / rayon::current_num_threads() + 1)
+        .fold(
+            || vec![LabelStats::EMPTY; super_pixels.len()],
+            |mut stats, (idx, label)| {
+                let coord = UVec2 {
+                    x: idx as u32 % in_size.x,
+                    y: idx as u32 / in_size.x,
+                };
+                let stats_entry = &mut stats[*label as usize];
+                stats_entry.count += 1;
+                stats_entry.coord_sum += coord;
+                stats_entry.color_sum += img[coord];
+
+                stats
+            },
+        )
+        .reduce(
+            || vec![LabelStats::EMPTY; super_pixels.len()],
+            |mut stats, other| {
+                for (stats_entry, other) in stats.iter_mut().zip(&other) {
+                    stats_entry.merge(other);
+                }
+
+                stats
+            },
+        );
+
+    super_pixels
+        .par_iter_mut()
+        .zip(stats.par_iter())
+        .for_each(|(sp, stats)| {
+            sp.update_position(stats);
+            sp.update_sp_color(stats);
+        });
+
+    // The colors get filtered around the positions from before the smoothing
+    let filtered = (config.smooth_color > 0.0).then(|| match config.smoothing {
+        Smoothing::Bilateral => bilateral_filter(super_pixels, out_size, config.frames),
+        Smoothing::Guided => guided_filter(super_pixels, out_size, config.frames),
+    });
+
+    if config.smooth_position > 0.0 {
+        laplacian_smoothing(
+            super_pixels,
+            out_size,
+            config.grid,
+            config.smooth_position,
+            config.frames,
+        );
+    }
+
+    if let Some(filtered) = filtered {
+        for (sp, filtered) in super_pixels.iter_mut().zip(filtered) {
+            sp.sp_color =
+                sp.sp_color * (1.0 - config.smooth_color) + filtered * config.smooth_color;
+        }
+    }
+}
+
+// Moves every super pixel `strength` of the way towards the average position of its neighbours
+// in the same frame, keeping it inside that frame
+fn laplacian_smoothing(
+    super_pixels: &mut [SuperPixel],
+    out_size: UVec2,
+    grid: Grid,
+    strength: f64,
+    frames: u32,
+) {
+    let mut new_coords = (0..(out_size.x * out_size.y))
+        .map(|_| UVec2::ZERO)
+        .collect::<Vec<_>>();
+
+    for j in 0..out_size.y {
+        for i in 0..out_size.x {
+            let sp = &super_pixels[(i + j * out_size.x) as usize];
+            let mut n = 0;
+            let mut new = UVec2::ZERO;
+
+            for coord in grid.neighbours(j).iter().copied() {
+                let n_coord = IVec2::new(i as i32, j as i32) + coord;
+                if n_coord.x >= 0
+                    && n_coord.y >= 0
+                    && n_coord.x < out_size.x as i32
+                    && n_coord.y < out_size.y as i32
+                    && same_frame(j, n_coord.y as u32, out_size.y, frames)
+                {
+                    let n_coord = n_coord.as_uvec2();
+                    n += 1;
+
+                    new += super_pixels[(n_coord.x + n_coord.y * out_size.x) as usize].coord;
+                }
+            }
+
+            // A super pixel without neighbours in its frame stays put
+            let new = if n == 0 {
+                sp.coord.as_dvec2()
+            } else {
+                new.as_dvec2() / n as f64
+            };
+
+            // Rows of the input the frame of the super pixel covers
+            let frame_height = sp.img.size.y / frames;
+            let frame_top = j / (out_size.y / frames) * frame_height;
+            let coord = (strength * new + (1.0 - strength) * sp.coord.as_dvec2()).as_uvec2();
+            new_coords[(i + j * out_size.x) as usize] = UVec2::new(
+                coord.x,
+                coord.y.clamp(frame_top, frame_top + frame_height - 1),
+            );
+        }
+    }
+
+    for (sp, coord) in super_pixels.iter_mut().zip(new_coords) {
+        sp.coord = coord;
+    }
+}
+
+// Bilateral Filter Approximation, over the neighbours in the same frame
+fn bilateral_filter(super_pixels: &[SuperPixel], out_size: UVec2, frames: u32) -> Vec<Color> {
+    let mut new_colors = (0..(out_size.x * out_size.y))
+        .map(|_| Color::BLACK)
+        .collect::<Vec<_>>();
+    for j in 0..out_size.y {
+        for i in 0..out_size.x {
+            const D_COORDS: [IVec2; 9] = [
+                IVec2::new(-1, -1),
+                IVec2::new(-1, 0),
+                IVec2::new(-1, 1),
+                IVec2::new(0, -1),
+                IVec2::new(0, 0),
+                IVec2::new(0, 1),
+                IVec2::new(1, -1),
+                IVec2::new(1, 0),
+                IVec2::new(1, 1),
+            ];
+            let sp = &super_pixels[(i + j * out_size.x) as usize];
+            let mut n = 0.0;
+            let mut avg_color = Color::BLACK;
+
+            for coord in D_COORDS {
+                let n_coord = IVec2::new(i as i32, j as i32) + coord;
+                if n_coord.x >= 0
+                    && n_coord.y >= 0
+                    && n_coord.x < out_size.x as i32
+                    && n_coord.y < out_size.y as i32
+                    && same_frame(j, n_coord.y as u32, out_size.y, frames)
+                {
+                    let n_coord = n_coord.as_uvec2();
+
+                    let next = super_pixels[(n_coord.x + n_coord.y * out_size.x) as usize].sp_color;
+                    let weight = std::f64::consts::E.powf(-(sp.sp_color.l() - next.l()).abs());
+
+                    avg_color += next * weight;
+
+                    n += weight;
+                }
+            }
+
+            avg_color /= n;
+
+            new_colors[(i + j * out_size.x) as usize] = avg_color;
+        }
+    }
+
+    new_colors
+}
+
+// Guided filter over the super pixel grid, using the lightness of the input image at each
+// super pixel as the guide so color edges follow the edges of the original image. The windows
+// stay within a frame
+fn guided_filter(super_pixels: &[SuperPixel], out_size: UVec2, frames: u32) -> Vec<Color> {
+    const D_COORDS: [IVec2; 9] = [
+        IVec2::new(-1, -1),
+        IVec2::new(-1, 0),
+        IVec2::new(-1, 1),
+        IVec2::new(0, -1),
+        IVec2::new(0, 0),
+        IVec2::new(0, 1),
+        IVec2::new(1, -1),
+        IVec2::new(1, 0),
+        IVec2::new(1, 1),
+    ];
+    // Regularization of the filter, larger values smooth more across edges
+    const EPSILON: f64 = 25.0;
+
+    let neighbours = |i: u32, j: u32| {
+        D_COORDS.into_iter().filter_map(move |coord| {
+            let n_coord = IVec2::new(i as i32, j as i32) + coord;
+            (n_coord.x >= 0
+                && n_coord.y >= 0
+                && n_coord.x < out_size.x as i32
+                && n_coord.y < out_size.y as i32
+                && same_frame(j, n_coord.y as u32, out_size.y, frames))
+            .then(|| (n_coord.x as u32 + n_coord.y as u32 * out_size.x) as usize)
+        })
+    };
+    let guide = super_pixels
+        .iter()
+        .map(|sp| sp.img[sp.coord].l())
+        .collect::<Vec<_>>();
+
+    // Linear coefficients of every window
+    let mut coefficients = Vec::with_capacity(super_pixels.len());
+    for j in 0..out_size.y {
+        for i in 0..out_size.x {
+            let mut n = 0.0;
+            let mut mean_guide = 0.0;
+            let mut mean_guide_sq = 0.0;
+            let mut mean_color = Color::BLACK;
+            let mut mean_product = Color::BLACK;
+
+            for idx in neighbours(i, j) {
+                let color = super_pixels[idx].sp_color;
+                n += 1.0;
+                mean_guide += guide[idx];
+                mean_guide_sq += guide[idx] * guide[idx];
+                mean_color += color;
+                mean_product += color * guide[idx];
+            }
+
+            mean_guide /= n;
+            mean_guide_sq /= n;
+            mean_color /= n;
+            mean_product /= n;
+
+            let variance = mean_guide_sq - mean_guide * mean_guide;
+            let a = (mean_product - mean_color * mean_guide) / (variance + EPSILON);
+            let b = mean_color - a * mean_guide;
+
+            coefficients.push((a, b));
+        }
+    }
+
+    let mut new_colors = Vec::with_capacity(super_pixels.len());
+    for j in 0..out_size.y {
+        for i in 0..out_size.x {
+            let sp_idx = (i + j * out_size.x) as usize;
+            let mut n = 0.0;
+            let mut filtered = Color::BLACK;
+
+            for idx in neighbours(i, j) {
+                let (a, b) = coefficients[idx];
+                filtered += a * guide[sp_idx] + b;
+                n += 1.0;
+            }
+
+            filtered /= n;
+
+            new_colors.push(filtered);
+        }
+    }
+
+    new_colors
+}
+
+// Whether the rows `a` and `b` of a grid `rows` high are in the same of the `frames` stacked in it
+fn same_frame(a: u32, b: u32, rows: u32, frames: u32) -> bool {
+    let frame_height = rows / frames;
+    a / frame_height == b / frame_height
+}
+
+fn associate(
+    super_pixels: &mut Vec<SuperPixel>,
+    palettes: &mut Vec<(Color, f64)>,
+    clusters: &[UVec2],
+    owners: &[Option<u8>],
+    k: usize,
+    t: f64,
+) {
+    super_pixels.into_par_iter().for_each(|sp| {
+        sp.conditional_probability.resize(palettes.len(), 0.0);
+        for (i, palette) in palettes.iter().enumerate() {
+            sp.conditional_probability[i] = match owners.get(i) {
+                Some(Some(region)) if *region != sp.region => 0.0,
+                _ => palette.0.condit_prob(palette.1, sp, t),
+            };
+        }
+        sp.normalize_probs(palettes, clusters, k);
+    });
+
+    palettes
+        .into_par_iter()
+        .enumerate()
+        .for_each(|(i, palette)| {
+            palette.1 = 0.0;
+
+            for sp in super_pixels.iter() {
+                palette.1 += sp.conditional_probability[i] * sp.probability;
+            }
+        });
+}
+
+fn palette_refine(
+    super_pixels: &mut Vec<SuperPixel>,
+    palettes: &mut Vec<(Color, f64)>,
+    locked: &[bool],
+    metric: Metric,
+) -> f64 {
+    palettes
+        .into_par_iter()
+        .enumerate()
+        .map(|(i, palette)| {
+            if locked.get(i) == Some(&true) {
+                return 0.0;
+            }
+
+            let mut new_color = Color::BLACK;
+
+            for sp in super_pixels.iter() {
+                new_color +=
+                    (sp.sp_color * sp.conditional_probability[i] * sp.probability) / palette.1;
+            }
+
+            let distance = palette.0.difference(new_color, metric);
+            palette.0 = new_color;
+            distance
+        })
+        .sum()
+}
+
+fn expand(
+    clusters: &mut Vec<UVec2>,
+    palettes: &mut Vec<(Color, f64)>,
+    k: &mut usize,
+    k_max: usize,
+    delta: DVec2,
+    config: &SolverConfig,
+) {
+    let epsilon_cluster = config.epsilon_cluster;
+    for i in 0..(*k).min(k_max) {
+        // Splitting stops right at the color count so every count of a sweep gets reached
+        if *k >= k_max {
+            break;
+        }
+        let (i1, i2) = (clusters[i].x as usize, clusters[i].y as usize);
+
+        if palettes[i1].0.difference(palettes[i2].0, config.metric) > epsilon_cluster {
+            *k += 1;
+
+            palettes[i1].1 /= 2.0;
+            palettes[i2].1 /= 2.0;
+
+            let c1 = palettes[i1];
+            let c2 = palettes[i2];
+
+            palettes.push(c1);
+            palettes.push(c2);
+
+            clusters.push(UVec2::new(clusters[i][1], (palettes.len() - 1) as u32));
+            clusters[i] = UVec2::new(clusters[i][0], (palettes.len() - 2) as u32);
+
+            assert!(
+                (palettes[clusters[i].x as usize].1 - palettes[clusters[i].y as usize].1).abs()
+                    < epsilon_cluster
+            );
+            assert!(
+                (palettes[clusters.last().unwrap().x as usize].1
+                    - palettes[clusters.last().unwrap().y as usize].1)
+                    .abs()
+                    < epsilon_cluster
+            );
+        }
+    }
+
+    if *k >= k_max {
+        merge_clusters(clusters, palettes, *k);
+    } else {
+        for i in 0..(*k) {
+            let c = &mut palettes[clusters[i].y as usize];
+            c.0.perturb(delta);
+        }
+    }
+}
+
+// Replaces the two colors of every cluster with their average
+fn merge_clusters(clusters: &mut Vec<UVec2>, palettes: &mut Vec<(Color, f64)>, k: usize) {
+    let mut new_palette = Vec::default();
+    let mut new_clusters = Vec::default();
+
+    for i in 0..k {
+        let c1 = palettes[clusters[i].x as usize];
+        let c2 = palettes[clusters[i].y as usize];
+        let new_color = (c1.0 + c2.0) / 2.0;
+
+        new_palette.push((new_color, c1.1 + c2.1));
+        new_clusters.push(UVec2 { x: i as u32, y: 0 });
+    }
+
+    *palettes = new_palette;
+    *clusters = new_clusters;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Six blocks of clearly different colors side by side
+    fn blocks() -> LabImage {
+        const COLORS: [[u8; 3]; 6] = [
+            [220, 30, 30],
+            [30, 200, 40],
+            [30, 50, 220],
+            [240, 230, 40],
+            [20, 20, 20],
+            [250, 250, 250],
+        ];
+        let size = UVec2::new(48, 16);
+        let pixels = (0..size.y)
+            .flat_map(|_| (0..size.x).map(|x| Color::from_srgb(COLORS[(x / 8) as usize])))
+            .collect();
+
+        LabImage { pixels, size }
+    }
+
+    fn config() -> SolverConfig {
+        SolverConfig {
+            alpha: 0.7,
+            t_final: 1.0,
+            schedule: Schedule::Geometric,
+            max_iterations: Some(5000),
+            epsilon_palette: 1.0,
+            epsilon_cluster: 0.25,
+            spatial_weight: 45.0,
+            pixel_aspect: UVec2::ONE,
+            edge_weight: 0.0,
+            delta_scale: 1.5,
+            grid: Grid::Square,
+            superpixel: SuperpixelMode::Unconstrained,
+            smoothing: Smoothing::Bilateral,
+            smooth_position: 0.0,
+            smooth_color: 0.0,
+            region_budget: Vec::default(),
+            pinned: false,
+            metric: Metric::Euclidean,
+            frames: 1,
+        }
+    }
+
+    #[test]
+    fn sweep_stops_at_every_count() {
+        let img = blocks();
+        let sp_size = UVec2::new(12, 4);
+        // Weighing every pixel the same keeps the principal component away from the PCA
+        let importance = GrayImage::from_pixel(img.size.x, img.size.y, ::image::Luma([255]));
+        let masks = Masks {
+            importance: Some(&importance),
+            ..Masks::default()
+        };
+        let sweep = [2, 3, 5];
+
+        let mut solver = Solver::new(&img, sp_size, sweep[0], config(), masks).unwrap();
+        let mut checkpoints = vec![];
+        while !solver.is_done() {
+            solver.step();
+            if let Some(next) = sweep.get(checkpoints.len() + 1) {
+                if solver.k() >= sweep[checkpoints.len()] {
+                    checkpoints.push(solver.checkpoint(String::new()));
+                    solver.set_color_count(*next);
+                    // Every color gets paired with a copy of its own, not with another color
+                    let paired = solver.clusters[..solver.k()]
+                        .iter()
+                        .flat_map(|cluster| cluster.to_array())
+                        .collect::<HashSet<_>>();
+                    assert_eq!(paired.len(), 2 * solver.k());
+                }
+            }
+        }
+
+        let mut counts = checkpoints
+            .into_iter()
+            .map(|checkpoint| {
+                let mut restored = Solver::new(&img, sp_size, sweep[0], config(), masks).unwrap();
+                assert!(restored.restore(checkpoint));
+                restored.k()
+            })
+            .collect::<Vec<_>>();
+        counts.push(solver.k());
+        assert_eq!(counts, sweep);
+        assert_eq!(solver.palette().len(), sweep[2]);
+    }
+
+    #[test]
+    fn restores_how_far_the_palette_settled() {
+        let img = blocks();
+        let sp_size = UVec2::new(12, 4);
+        let importance = GrayImage::from_pixel(img.size.x, img.size.y, ::image::Luma([255]));
+        let masks = Masks {
+            importance: Some(&importance),
+            ..Masks::default()
+        };
+        let mut solver = Solver::new(&img, sp_size, 4, config(), masks).unwrap();
+        while !solver.is_done() {
+            solver.step();
+        }
+
+        let mut restored = Solver::new(&img, sp_size, 4, config(), masks).unwrap();
+        assert!(restored.restore(solver.checkpoint(String::new())));
+        assert_eq!(restored.settled, solver.settled);
+        assert_eq!(restored.running_average, solver.running_average);
+        assert_eq!(restored.prev_changes, solver.prev_changes);
+        assert_eq!(restored.running_variance_avg, solver.running_variance_avg);
+        assert_eq!(restored.prev_variances, solver.prev_variances);
+        assert_eq!(
+            restored.variance_check_passed_count,
+            solver.variance_check_passed_count
+        );
+        assert!(restored.settled && !restored.prev_changes.is_empty());
+    }
+
+    #[test]
+    fn keeps_to_the_frame_of_every_pixel() {
+        let img = LabImage::stack(vec![blocks(), blocks()]);
+        let sp_size = UVec2::new(12, 8);
+        let config = SolverConfig {
+            superpixel: SuperpixelMode::Slic,
+            smooth_position: 0.5,
+            smooth_color: 0.5,
+            frames: 2,
+            ..config()
+        };
+        let palette = [[220, 30, 30], [30, 200, 40], [30, 50, 220]]
+            .map(Color::from_srgb)
+            .to_vec();
+
+        let mut solver =
+            Solver::with_palette(&img, sp_size, palette, config, Masks::default()).unwrap();
+        for _ in 0..20 {
+            solver.step();
+
+            for (idx, label) in solver.labels().iter().enumerate() {
+                let pixel_frame = idx as u32 / img.size.x / (img.size.y / 2);
+                let sp_frame = label / sp_size.x / (sp_size.y / 2);
+                assert_eq!(pixel_frame, sp_frame, "pixel {idx} labelled {label}");
+            }
+            for (idx, sp) in solver.super_pixels().iter().enumerate() {
+                let sp_frame = idx as u32 / sp_size.x / (sp_size.y / 2);
+                assert_eq!(
+                    sp.coord().y / (img.size.y / 2),
+                    sp_frame,
+                    "super pixel {idx}"
+                );
+            }
+        }
+    }
+}
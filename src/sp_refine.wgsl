@@ -0,0 +1,57 @@
+struct SuperPixel {
+    color: vec4<f32>,
+    coord: vec2<u32>,
+    padding: vec2<u32>,
+}
+
+struct Params {
+    in_size: vec2<u32>,
+    out_size: vec2<u32>,
+    spatial_scale: f32,
+    // Super pixel rows of every stacked animation frame
+    frame_height: u32,
+}
+
+@group(0) @binding(0) var<uniform> params: Params;
+// Lab components of the input pixels, three per pixel
+@group(0) @binding(1) var<storage, read> image: array<f32>;
+@group(0) @binding(2) var<storage, read> super_pixels: array<SuperPixel>;
+@group(0) @binding(3) var<storage, read_write> labels: array<u32>;
+
+@compute @workgroup_size(16, 16)
+fn main(@builtin(global_invocation_id) id: vec3<u32>) {
+    let coord = id.xy;
+    if (coord.x >= params.in_size.x || coord.y >= params.in_size.y) {
+        return;
+    }
+
+    let idx = coord.x + coord.y * params.in_size.x;
+    let color = vec3<f32>(image[idx * 3u], image[idx * 3u + 1u], image[idx * 3u + 2u]);
+    let sp_coord = vec2<i32>((coord * params.out_size) / params.in_size);
+
+    var best_cost = 3.40282347e38;
+    var best_label = 0u;
+    for (var dx = -1; dx <= 1; dx += 1) {
+        for (var dy = -1; dy <= 1; dy += 1) {
+            let n_coord = sp_coord + vec2<i32>(dx, dy);
+            if (n_coord.x < 0 || n_coord.y < 0 || n_coord.x >= i32(params.out_size.x) || n_coord.y >= i32(params.out_size.y)) {
+                continue;
+            }
+            // The pixels stay with the super pixels of their own frame
+            if (u32(n_coord.y) / params.frame_height != u32(sp_coord.y) / params.frame_height) {
+                continue;
+            }
+
+            let label = u32(n_coord.x) + u32(n_coord.y) * params.out_size.x;
+            let sp = super_pixels[label];
+            let cost = distance(color, sp.color.xyz)
+                + params.spatial_scale * distance(vec2<f32>(coord), vec2<f32>(sp.coord));
+            if (cost < best_cost) {
+                best_cost = cost;
+                best_label = label;
+            }
+        }
+    }
+
+    labels[idx] = best_label;
+}
@@ -0,0 +1,73 @@
+use std::{fs, path::Path};
+
+use anyhow::{bail, Context};
+use glam::UVec2;
+use image::Rgb;
+
+use crate::pattern_file::parse_hex;
+
+/// Stitch worked on top of a cell instead of a cross, charted with a marker of its own.
+#[derive(
+    Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, serde::Serialize, serde::Deserialize,
+)]
+#[serde(rename_all = "kebab-case")]
+pub enum SpecialtyKind {
+    FrenchKnot,
+    LazyDaisy,
+}
+
+impl SpecialtyKind {
+    pub fn name(self) -> &'static str {
+        match self {
+            SpecialtyKind::FrenchKnot => "French knot",
+            SpecialtyKind::LazyDaisy => "lazy daisy",
+        }
+    }
+}
+
+/// A specialty stitch in the cell `cell`, counted in stitches from the top left corner of the
+/// design.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SpecialtyStitch {
+    pub kind: SpecialtyKind,
+    pub cell: UVec2,
+    pub color: Rgb<u8>,
+}
+
+// Entry of the sidecar file, in chart coordinates starting from 1 like the bookmarks
+#[derive(serde::Deserialize)]
+struct SpecialtyEntry {
+    stitch: SpecialtyKind,
+    x: u32,
+    y: u32,
+    color: String,
+}
+
+/// Loads a JSON list of specialty stitches as `{"stitch", "x", "y", "color"}`, checking they are
+/// all on a design of `size` stitches.
+pub fn load_specialty_stitches(path: &Path, size: UVec2) -> anyhow::Result<Vec<SpecialtyStitch>> {
+    let entries: Vec<SpecialtyEntry> = serde_json::from_slice(&fs::read(path)?)
+        .with_context(|| format!("failed to load specialty stitches {}", path.display()))?;
+
+    entries
+        .into_iter()
+        .map(|entry| {
+            if entry.x == 0 || entry.y == 0 || entry.x > size.x || entry.y > size.y {
+                bail!(
+                    "the {} at {}, {} is outside of the {}x{} chart",
+                    entry.stitch.name(),
+                    entry.x,
+                    entry.y,
+                    size.x,
+                    size.y
+                );
+            }
+
+            Ok(SpecialtyStitch {
+                kind: entry.stitch,
+                cell: UVec2::new(entry.x - 1, entry.y - 1),
+                color: Rgb(parse_hex(&entry.color)?),
+            })
+        })
+        .collect()
+}
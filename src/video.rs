@@ -0,0 +1,76 @@
+use std::{fs, path::Path, process::Command, time::Duration};
+
+use ::image::{Delay, Frame};
+use anyhow::Context;
+
+#[derive(Debug, clap::Args, serde::Serialize)]
+pub struct VideoArgs {
+    // Frames sampled per second of the clip
+    #[arg(long, default_value_t = 10.0)]
+    pub fps: f64,
+    // Frames per row of the sprite sheet, as close to a square as it gets by default
+    #[arg(long, value_parser = clap::value_parser!(u32).range(1..))]
+    pub columns: Option<u32>,
+}
+
+impl VideoArgs {
+    pub fn columns(&self, frame_count: usize) -> u32 {
+        self.columns
+            .unwrap_or_else(|| (frame_count as f64).sqrt().ceil() as u32)
+            .max(1)
+    }
+}
+
+/// Samples `fps` frames a second of the clip at `path` with the ffmpeg executable.
+pub fn extract_frames(path: &Path, fps: f64) -> anyhow::Result<Vec<Frame>> {
+    if fps.is_nan() || fps <= 0.0 {
+        anyhow::bail!("--fps has to be positive");
+    }
+
+    let dir = std::env::temp_dir().join(format!("pixelart-gen-{}", std::process::id()));
+    fs::create_dir_all(&dir)?;
+    let frames = extract_frames_into(path, fps, &dir);
+    fs::remove_dir_all(&dir)?;
+
+    frames
+}
+
+// Has ffmpeg write the frames into `dir` as numbered PNGs and reads them back in order
+fn extract_frames_into(path: &Path, fps: f64, dir: &Path) -> anyhow::Result<Vec<Frame>> {
+    let output = Command::new("ffmpeg")
+        .args(["-loglevel", "error", "-i"])
+        .arg(path)
+        .arg("-vf")
+        .arg(format!("fps={fps}"))
+        .arg(dir.join("%06d.png"))
+        .output()
+        .context("couldn't run ffmpeg, is it installed?")?;
+    if !output.status.success() {
+        anyhow::bail!(
+            "ffmpeg couldn't read {}: {}",
+            path.display(),
+            String::from_utf8_lossy(&output.stderr).trim()
+        );
+    }
+
+    let mut paths = fs::read_dir(dir)?
+        .map(|entry| entry.map(|entry| entry.path()))
+        .collect::<Result<Vec<_>, _>>()?;
+    paths.sort();
+    if paths.is_empty() {
+        anyhow::bail!("{} has no frames", path.display());
+    }
+
+    let delay = Delay::from_saturating_duration(Duration::from_secs_f64(1.0 / fps));
+    paths
+        .iter()
+        .map(|path| {
+            Ok(Frame::from_parts(
+                ::image::open(path)?.to_rgba8(),
+                0,
+                0,
+                delay,
+            ))
+        })
+        .collect()
+}